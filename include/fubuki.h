@@ -37,11 +37,18 @@ struct FubukiStartOptions {
   // flags
   // V3 required
   uint64_t flags;
+  // the callback function to notify user program an ipv6 address should be added, addr points to 16 bytes in network order
+  // V4 optional, ipv6 addresses are not added if it is NULL
+  void (*add_addr_v6_fn)(const uint8_t *addr, uint8_t prefix_len, void *ctx);
+  // the callback function to notify user program an ipv6 address should be deleted
+  // V4 optional
+  void (*delete_addr_v6_fn)(const uint8_t *addr, uint8_t prefix_len, void *ctx);
 };
 
 #define FUBUKI_START_OPTIONS_VERSION (1)
 #define FUBUKI_START_OPTIONS_VERSION2 (2)
 #define FUBUKI_START_OPTIONS_VERSION3 (3)
+#define FUBUKI_START_OPTIONS_VERSION4 (4)
 
 // need to use fubuki_block_on to launch fubuki if this flag is set
 #define FUBUKI_FLAG_NO_AUTO_SPAWN (0x0001)
@@ -0,0 +1,211 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::common::conntrack::{Flow, FlowTable};
+use crate::common::net::{get_ip_dst_addr, get_ip_src_addr, get_ip_version, get_ipv6_dst_addr, get_ipv6_src_addr};
+
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+pub const ICMP: u8 = 1;
+pub const ICMPV6: u8 = 58;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AclProtocol {
+    #[default]
+    Any,
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl AclProtocol {
+    pub fn matches(self, protocol: u8) -> bool {
+        match self {
+            AclProtocol::Any => true,
+            AclProtocol::Tcp => protocol == TCP,
+            AclProtocol::Udp => protocol == UDP,
+            AclProtocol::Icmp => protocol == ICMP || protocol == ICMPV6,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AclTarget {
+    Any,
+    // virtual addresses of the node
+    Node(String),
+    Cidr(IpNet),
+}
+
+impl AclTarget {
+    fn matches<'a>(&self, addr: IpAddr, node_name: &impl Fn(IpAddr) -> Option<&'a str>) -> bool {
+        match self {
+            AclTarget::Any => true,
+            AclTarget::Node(name) => node_name(addr) == Some(name.as_str()),
+            AclTarget::Cidr(cidr) => cidr.contains(&addr),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AclRule {
+    pub src: Vec<AclTarget>,
+    pub dst: Vec<AclTarget>,
+    pub protocol: AclProtocol,
+    // destination port ranges of tcp and udp, empty means every port
+    pub ports: Vec<(u16, u16)>,
+}
+
+pub struct PacketInfo {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub protocol: u8,
+    // (source, destination), None for the fragments after the first one and the protocols without ports
+    pub ports: Option<(u16, u16)>,
+    // not the first fragment
+    pub fragment: bool,
+    pub payload_offset: usize,
+}
+
+pub fn parse_packet(packet: &[u8]) -> Option<PacketInfo> {
+    let (src, dst, protocol, offset, fragment) = match get_ip_version(packet).ok()? {
+        4 => {
+            let header_len = (*packet.first()? & 0x0f) as usize * 4;
+            let flags = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+            let fragment = flags & 0x1fff != 0;
+
+            (
+                IpAddr::V4(get_ip_src_addr(packet).ok()?),
+                IpAddr::V4(get_ip_dst_addr(packet).ok()?),
+                *packet.get(9)?,
+                header_len,
+                fragment
+            )
+        }
+        // extension headers are not parsed, such packets only match the rules of any protocol
+        6 => (
+            IpAddr::V6(get_ipv6_src_addr(packet).ok()?),
+            IpAddr::V6(get_ipv6_dst_addr(packet).ok()?),
+            *packet.get(6)?,
+            40,
+            false
+        ),
+        _ => return None
+    };
+
+    let ports = match protocol {
+        TCP | UDP if !fragment => {
+            let p = packet.get(offset..offset + 4)?;
+            Some((u16::from_be_bytes([p[0], p[1]]), u16::from_be_bytes([p[2], p[3]])))
+        }
+        _ => None
+    };
+
+    let info = PacketInfo {
+        src,
+        dst,
+        protocol,
+        ports,
+        fragment,
+        payload_offset: offset,
+    };
+    Some(info)
+}
+
+impl AclRule {
+    fn matches<'a>(&self, src: IpAddr, dst: IpAddr, info: &PacketInfo, port: Option<u16>, node_name: &impl Fn(IpAddr) -> Option<&'a str>) -> bool {
+        if !self.protocol.matches(info.protocol) {
+            return false;
+        }
+
+        let port_matched = self.ports.is_empty() || match port {
+            Some(port) => self.ports.iter().any(|(start, end)| (*start..=*end).contains(&port)),
+            // the first fragment has been checked
+            None => info.fragment && (info.protocol == TCP || info.protocol == UDP),
+        };
+
+        port_matched &&
+            self.src.iter().any(|t| t.matches(src, node_name)) &&
+            self.dst.iter().any(|t| t.matches(dst, node_name))
+    }
+}
+
+// traffic not allowed by any rule is dropped
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Acl {
+    pub rules: Vec<AclRule>,
+}
+
+impl Acl {
+    // rules are directional, the replies are allowed by the flows of the allowed packets,
+    // node_name looks up the node owning the virtual address
+    pub fn allows<'a>(&self, packet: &[u8], flows: &FlowTable, node_name: impl Fn(IpAddr) -> Option<&'a str>) -> bool {
+        let info = match parse_packet(packet) {
+            None => return false,
+            Some(v) => v
+        };
+
+        let dst_port = info.ports.map(|(_, dst)| dst);
+        let flow = Flow::of(packet, &info);
+
+        if self.rules.iter().any(|rule| rule.matches(info.src, info.dst, &info, dst_port, &node_name)) {
+            if let Flow::Packet(key) = flow {
+                flows.insert(key);
+            }
+            return true;
+        }
+        flows.is_reply(&flow)
+    }
+}
+
+// ipv4 header and the ports, shared by the packet filter tests
+#[cfg(test)]
+pub fn ipv4_packet(src: [u8; 4], dst: [u8; 4], protocol: u8, src_port: u16, dst_port: u16) -> Vec<u8> {
+    let mut p = vec![0u8; 28];
+    p[0] = 0x45;
+    p[9] = protocol;
+    p[12..16].copy_from_slice(&src);
+    p[16..20].copy_from_slice(&dst);
+    p[20..22].copy_from_slice(&src_port.to_be_bytes());
+    p[22..24].copy_from_slice(&dst_port.to_be_bytes());
+    p
+}
+
+#[test]
+fn test() {
+    use ipv4_packet as packet;
+
+    let acl = Acl {
+        rules: vec![
+            AclRule {
+                src: vec![AclTarget::Node(String::from("admin"))],
+                dst: vec![AclTarget::Cidr("192.168.200.0/24".parse().unwrap())],
+                protocol: AclProtocol::Tcp,
+                ports: vec![(22, 22), (8000, 8100)],
+            }
+        ],
+    };
+
+    let node_name = |addr: IpAddr| (addr == IpAddr::from([10, 0, 0, 1])).then_some("admin");
+    let flows = FlowTable::new();
+
+    // no flow has been established
+    assert!(!acl.allows(&packet([192, 168, 200, 5], [10, 0, 0, 1], TCP, 22, 40000), &flows, node_name));
+
+    assert!(acl.allows(&packet([10, 0, 0, 1], [192, 168, 200, 5], TCP, 40000, 22), &flows, node_name));
+    assert!(acl.allows(&packet([10, 0, 0, 1], [192, 168, 200, 5], TCP, 40000, 8080), &flows, node_name));
+    // reply
+    assert!(acl.allows(&packet([192, 168, 200, 5], [10, 0, 0, 1], TCP, 22, 40000), &flows, node_name));
+    // the destination can't open connections from the allowed port
+    assert!(!acl.allows(&packet([192, 168, 200, 5], [10, 0, 0, 1], TCP, 22, 40001), &flows, node_name));
+
+    assert!(!acl.allows(&packet([10, 0, 0, 1], [192, 168, 200, 5], TCP, 40000, 80), &flows, node_name));
+    assert!(!acl.allows(&packet([10, 0, 0, 1], [192, 168, 200, 5], UDP, 40000, 22), &flows, node_name));
+    assert!(!acl.allows(&packet([10, 0, 0, 2], [192, 168, 200, 5], TCP, 40000, 22), &flows, node_name));
+    assert!(!acl.allows(&packet([192, 168, 200, 5], [10, 0, 0, 2], TCP, 22, 40000), &flows, node_name));
+    assert!(!acl.allows(&[0x45, 0], &flows, node_name));
+}
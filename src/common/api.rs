@@ -0,0 +1,300 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use http_body_util::{BodyExt, Either, Empty, Full, LengthLimitError, Limited, StreamBody};
+use http_body_util::combinators::BoxBody;
+use hyper::{header, http, Method, Request, Response, StatusCode};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+
+use crate::common::event::EventBus;
+
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(cert_path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to load certificate from {}", cert_path.display()))?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", cert_path.display()));
+    }
+    Ok(certs)
+}
+
+pub fn tls_server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<rustls::ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("failed to load private key from {}", key_path.display()))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+// compare in constant time, the token should not leak through the response time
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Authorization: Bearer {token}
+pub fn authorized(req: &Request<Bytes>, token: Option<&str>) -> bool {
+    let token = match token {
+        None => return true,
+        Some(token) => token
+    };
+
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| token_eq(v.as_bytes(), token.as_bytes()))
+}
+
+pub fn query_param<'a>(req: &'a Request<Bytes>, key: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+pub fn unauthorized() -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::new()));
+    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+    resp.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    resp
+}
+
+// the event stream is the only response that is not a complete body
+pub type ApiBody = Either<Full<Bytes>, BoxBody<Bytes, Infallible>>;
+
+pub fn full(resp: Response<Full<Bytes>>) -> Response<ApiBody> {
+    resp.map(Either::Left)
+}
+
+// GET /events, the events are sent as server-sent events until the client disconnects
+pub fn event_stream(events: &EventBus) -> Result<Response<ApiBody>, http::Error> {
+    let body = StreamBody::new(events.subscribe()).boxed();
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Either::Right(body))
+}
+
+// request bodies are small json documents
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+async fn serve_connection<IO, F>(io: IO, handler: F) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request<Bytes>) -> Result<Response<ApiBody>, http::Error> + Clone + Send + Sync + 'static
+{
+    http1::Builder::new()
+        .serve_connection(
+            TokioIo::new(io),
+            service_fn(move |req: Request<Incoming>| {
+                let handler = handler.clone();
+
+                async move {
+                    let (parts, body) = req.into_parts();
+
+                    match Limited::new(body, MAX_BODY_SIZE).collect().await {
+                        Ok(body) => handler(Request::from_parts(parts, body.to_bytes())),
+                        Err(e) => {
+                            let status = if e.is::<LengthLimitError>() {
+                                StatusCode::PAYLOAD_TOO_LARGE
+                            } else {
+                                StatusCode::BAD_REQUEST
+                            };
+
+                            Response::builder()
+                                .status(status)
+                                .body(Either::Left(Full::new(Bytes::new())))
+                        }
+                    }
+                }
+            }),
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn serve<F>(
+    bind: SocketAddr,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    handler: F
+) -> Result<()>
+where
+    F: Fn(Request<Bytes>) -> Result<Response<ApiBody>, http::Error> + Clone + Send + Sync + 'static
+{
+    let listener = TcpListener::bind(bind).await?;
+    let acceptor = tls.map(TlsAcceptor::from);
+    let scheme = if acceptor.is_some() { "https" } else { "http" };
+    info!("api listening on {}://{}", scheme, bind);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+
+        tokio::spawn(async move {
+            let res = match acceptor {
+                None => serve_connection(stream, handler).await,
+                Some(acceptor) => {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(stream, handler).await,
+                        Err(e) => Err(anyhow!(e).context("tls handshake error"))
+                    }
+                }
+            };
+
+            if let Err(e) = res {
+                warn!("error serving connection: {:?}", e);
+            }
+        });
+    }
+}
+
+// trust the configured certificate only, api certificates are usually self-signed
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    // None trusts any certificate
+    cert: Option<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.cert.as_ref().is_none_or(|cert| end_entity.as_ref() == cert.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from("server certificate does not match")))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub fn pinned_client_config(cert_path: Option<&Path>) -> Result<rustls::ClientConfig> {
+    let provider = crypto_provider();
+
+    let cert = match cert_path {
+        None => None,
+        Some(cert_path) => Some(load_certs(cert_path)?.swap_remove(0))
+    };
+
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { cert, provider }))
+        .with_no_client_auth();
+
+    Ok(config)
+}
+
+pub struct ApiClient {
+    pub addr: String,
+    pub token: Option<String>,
+    // connect with tls and trust this certificate
+    pub tls_cert: Option<PathBuf>,
+}
+
+impl ApiClient {
+    async fn send(&self, req: Request<Empty<Bytes>>) -> Result<Response<Incoming>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+
+        let resp = match &self.tls_cert {
+            None => {
+                let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+                tokio::spawn(conn);
+                sender.send_request(req).await?
+            }
+            Some(cert_path) => {
+                let config = pinned_client_config(Some(cert_path))?;
+
+                let host = self.addr
+                    .rsplit_once(':')
+                    .map(|(host, _)| host)
+                    .unwrap_or(&self.addr)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+
+                let server_name = ServerName::try_from(host.to_string())?;
+                let stream = TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?;
+
+                let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+                tokio::spawn(conn);
+                sender.send_request(req).await?
+            }
+        };
+        Ok(resp)
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Bytes> {
+        let mut req = Request::builder()
+            .method(Method::GET)
+            .uri(format!("http://{}{}", self.addr, path));
+
+        if let Some(token) = &self.token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let resp = self.send(req.body(Empty::<Bytes>::new())?).await?;
+        let (parts, body) = resp.into_parts();
+        let bytes = body.collect().await?.to_bytes();
+
+        if parts.status != 200 {
+            let msg = String::from_utf8(bytes.to_vec())?;
+            return Err(anyhow!("http response code: {}, message: {}", parts.status.as_u16(), msg));
+        }
+        Ok(bytes)
+    }
+}
@@ -1,24 +1,55 @@
 use std::cmp::min;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::simd::u8x32;
 
-pub struct CipherContext {
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::Deserialize;
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+pub type Nonce = [u8; NONCE_LEN];
+pub type Tag = [u8; TAG_LEN];
+
+pub struct CipherContext<'a> {
     pub offset: usize,
-    pub nonce: u16
+    pub nonce: Nonce,
+    // additional authenticated data, ignored by non-aead ciphers
+    pub aad: &'a [u8]
 }
 
-impl Default for CipherContext {
+impl Default for CipherContext<'_> {
     fn default() -> Self {
         CipherContext {
             offset: 0,
-            nonce: 0
+            nonce: [0u8; NONCE_LEN],
+            aad: &[]
         }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct AuthenticationError;
+
+impl Display for AuthenticationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message authentication failed")
+    }
+}
+
+impl Error for AuthenticationError {}
+
 pub trait Cipher {
-    fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext);
+    // returns the authentication tag, non-aead ciphers return an empty tag
+    fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext) -> Tag;
+
+    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext, tag: &Tag) -> Result<(), AuthenticationError>;
 
-    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext);
+    // whether the nonce is authenticated, replay protection relies on it
+    fn is_aead(&self) -> bool;
 }
 
 #[derive(Clone, Copy)]
@@ -32,11 +63,11 @@ fn key_mix(base_key: &mut [u8; 32], nonce: u8) {
     }
 }
 
-impl Cipher for XorCipher {
-    fn encrypt(&self, mut data: &mut [u8], context: &CipherContext) {
+impl XorCipher {
+    fn apply(&self, mut data: &mut [u8], context: &CipherContext) {
         let mut key = self.basekey;
-        let nonce: [u8; 2] = context.nonce.to_be_bytes();
-        key_mix(&mut key, nonce[0] ^ nonce[1]);
+        let nonce = context.nonce.iter().fold(0, |a, b| a ^ b);
+        key_mix(&mut key, nonce);
         let key = u8x32::from_array(key);
 
         let offset = context.offset;
@@ -62,10 +93,22 @@ impl Cipher for XorCipher {
             .zip(key.as_array())
             .for_each(|(a, b)| *a ^= b);
     }
+}
+
+impl Cipher for XorCipher {
+    fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext) -> Tag {
+        self.apply(plaintext_to_ciphertext, context);
+        [0u8; TAG_LEN]
+    }
 
     #[inline]
-    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext) {
-        self.encrypt(ciphertext_to_plaintext, context)
+    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext, _tag: &Tag) -> Result<(), AuthenticationError> {
+        self.apply(ciphertext_to_plaintext, context);
+        Ok(())
+    }
+
+    fn is_aead(&self) -> bool {
+        false
     }
 }
 
@@ -89,29 +132,116 @@ impl From<&[u8]> for NoOpCipher {
 }
 
 impl Cipher for NoOpCipher {
-    fn encrypt(&self, _plaintext_to_ciphertext: &mut [u8], _context: &CipherContext) {}
+    fn encrypt(&self, _plaintext_to_ciphertext: &mut [u8], _context: &CipherContext) -> Tag {
+        [0u8; TAG_LEN]
+    }
+
+    fn decrypt(&self, _ciphertext_to_plaintext: &mut [u8], _context: &CipherContext, _tag: &Tag) -> Result<(), AuthenticationError> {
+        Ok(())
+    }
 
-    fn decrypt(&self, _ciphertext_to_plaintext: &mut [u8], _context: &CipherContext) {}
+    fn is_aead(&self) -> bool {
+        false
+    }
+}
+
+macro_rules! build_aead_cipher {
+    ($name: ident, $inner: ty) => {
+        #[derive(Clone)]
+        pub struct $name {
+            inner: $inner
+        }
+
+        impl From<&[u8]> for $name {
+            fn from(value: &[u8]) -> Self {
+                let out = blake3::hash(value);
+
+                $name {
+                    inner: <$inner>::new(out.as_bytes().into())
+                }
+            }
+        }
+
+        impl Cipher for $name {
+            fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext) -> Tag {
+                let tag = self.inner.encrypt_in_place_detached(
+                    (&context.nonce).into(),
+                    context.aad,
+                    plaintext_to_ciphertext
+                ).expect("message too long");
+
+                tag.into()
+            }
+
+            fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext, tag: &Tag) -> Result<(), AuthenticationError> {
+                self.inner.decrypt_in_place_detached(
+                    (&context.nonce).into(),
+                    context.aad,
+                    ciphertext_to_plaintext,
+                    tag.into()
+                ).map_err(|_| AuthenticationError)
+            }
+
+            fn is_aead(&self) -> bool {
+                true
+            }
+        }
+    };
+}
+
+build_aead_cipher!(ChaCha20Poly1305Cipher, ChaCha20Poly1305);
+build_aead_cipher!(Aes256GcmCipher, Aes256Gcm);
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CipherType {
+    Xor,
+    ChaCha20Poly1305,
+    Aes256Gcm,
 }
 
 #[derive(Clone)]
 pub enum CipherEnum {
     XorCipher(XorCipher),
     NoOpCipher(NoOpCipher),
+    ChaCha20Poly1305Cipher(ChaCha20Poly1305Cipher),
+    Aes256GcmCipher(Aes256GcmCipher),
 }
 
 impl Cipher for CipherEnum {
-    fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext) {
+    fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext) -> Tag {
         match self {
             CipherEnum::XorCipher(k) => k.encrypt(plaintext_to_ciphertext, context),
             CipherEnum::NoOpCipher(k) => k.encrypt(plaintext_to_ciphertext, context),
+            CipherEnum::ChaCha20Poly1305Cipher(k) => k.encrypt(plaintext_to_ciphertext, context),
+            CipherEnum::Aes256GcmCipher(k) => k.encrypt(plaintext_to_ciphertext, context),
+        }
+    }
+
+    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext, tag: &Tag) -> Result<(), AuthenticationError> {
+        match self {
+            CipherEnum::XorCipher(k) => k.decrypt(ciphertext_to_plaintext, context, tag),
+            CipherEnum::NoOpCipher(k) => k.decrypt(ciphertext_to_plaintext, context, tag),
+            CipherEnum::ChaCha20Poly1305Cipher(k) => k.decrypt(ciphertext_to_plaintext, context, tag),
+            CipherEnum::Aes256GcmCipher(k) => k.decrypt(ciphertext_to_plaintext, context, tag),
         }
     }
 
-    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext) {
+    fn is_aead(&self) -> bool {
         match self {
-            CipherEnum::XorCipher(k) => k.decrypt(ciphertext_to_plaintext, context),
-            CipherEnum::NoOpCipher(k) => k.decrypt(ciphertext_to_plaintext, context),
+            CipherEnum::XorCipher(k) => k.is_aead(),
+            CipherEnum::NoOpCipher(k) => k.is_aead(),
+            CipherEnum::ChaCha20Poly1305Cipher(k) => k.is_aead(),
+            CipherEnum::Aes256GcmCipher(k) => k.is_aead(),
+        }
+    }
+}
+
+impl CipherEnum {
+    pub fn new(cipher_type: CipherType, key: &[u8]) -> Self {
+        match cipher_type {
+            CipherType::Xor => CipherEnum::XorCipher(XorCipher::from(key)),
+            CipherType::ChaCha20Poly1305 => CipherEnum::ChaCha20Poly1305Cipher(ChaCha20Poly1305Cipher::from(key)),
+            CipherType::Aes256Gcm => CipherEnum::Aes256GcmCipher(Aes256GcmCipher::from(key)),
         }
     }
 }
@@ -120,13 +250,33 @@ impl Cipher for CipherEnum {
 fn test() {
     let k = XorCipher::try_from(b"abc".as_ref()).unwrap();
     let mut text = *b"abcdef";
+    let tag = [0u8; TAG_LEN];
 
     k.encrypt(&mut text, &CipherContext::default());
-    k.decrypt(&mut text[..2], &CipherContext::default());
+    k.decrypt(&mut text[..2], &CipherContext::default(), &tag).unwrap();
     k.decrypt(&mut text[2..], &CipherContext {
         offset: 2,
-        nonce: 0
-    });
+        ..CipherContext::default()
+    }, &tag).unwrap();
+
+    assert_eq!(&text, b"abcdef");
+}
+
+#[test]
+fn aead_test() {
+    let k = ChaCha20Poly1305Cipher::from(b"abc".as_ref());
+    let mut text = *b"abcdef";
+    let ctx = CipherContext {
+        nonce: [1u8; NONCE_LEN],
+        aad: b"header",
+        ..CipherContext::default()
+    };
+
+    let tag = k.encrypt(&mut text, &ctx);
+    let mut tampered = text;
+    tampered[0] ^= 1;
 
+    assert!(k.decrypt(&mut tampered, &ctx, &tag).is_err());
+    k.decrypt(&mut text, &ctx, &tag).unwrap();
     assert_eq!(&text, b"abcdef");
 }
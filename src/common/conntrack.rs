@@ -0,0 +1,222 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use ahash::{HashMap, HashMapExt, RandomState};
+use parking_lot::Mutex;
+
+use crate::common::acl::{parse_packet, PacketInfo, ICMP, ICMPV6, TCP};
+
+const TCP_FLOW_TIMEOUT: Duration = Duration::from_secs(600);
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_FLOWS: usize = 65536;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+const SHARDS: usize = 16;
+
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug)]
+pub struct FlowKey {
+    protocol: u8,
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
+}
+
+impl FlowKey {
+    // the fragments of a datagram share the ip identification, only the first one carries the ports
+    pub fn fragment(info: &PacketInfo, id: u16) -> Self {
+        FlowKey {
+            protocol: info.protocol,
+            src: (info.src, id),
+            dst: (info.dst, 0),
+        }
+    }
+
+    pub fn reverse(&self) -> Self {
+        FlowKey {
+            protocol: self.protocol,
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+}
+
+pub enum Flow {
+    Packet(FlowKey),
+    // icmp error quoting a packet of the flow
+    Error(FlowKey),
+}
+
+// destination unreachable, time exceeded, parameter problem
+fn is_icmp_error(protocol: u8, icmp_type: u8) -> bool {
+    match protocol {
+        ICMP => matches!(icmp_type, 3 | 11 | 12),
+        ICMPV6 => matches!(icmp_type, 1..=4),
+        _ => false
+    }
+}
+
+// tcp and udp ports, the identifier of icmp echo
+fn flow_ports(packet: &[u8], info: &PacketInfo) -> (u16, u16) {
+    if let Some(ports) = info.ports {
+        return ports;
+    }
+
+    let echo = match info.protocol {
+        ICMP => [0u8, 8],
+        ICMPV6 => [128, 129],
+        _ => return (0, 0)
+    };
+
+    let offset = info.payload_offset;
+
+    match packet.get(offset..offset + 6) {
+        Some(icmp) if !info.fragment && echo.contains(&icmp[0]) => {
+            let id = u16::from_be_bytes([icmp[4], icmp[5]]);
+            (id, id)
+        }
+        _ => (0, 0)
+    }
+}
+
+fn flow_key(packet: &[u8], info: &PacketInfo) -> FlowKey {
+    let (src_port, dst_port) = flow_ports(packet, info);
+
+    FlowKey {
+        protocol: info.protocol,
+        src: (info.src, src_port),
+        dst: (info.dst, dst_port),
+    }
+}
+
+impl Flow {
+    pub fn of(packet: &[u8], info: &PacketInfo) -> Flow {
+        let offset = info.payload_offset;
+
+        let quoted = match packet.get(offset) {
+            Some(icmp_type) if !info.fragment && is_icmp_error(info.protocol, *icmp_type) => {
+                packet.get(offset + 8..).and_then(parse_packet).map(|quoted_info| (quoted_info, &packet[offset + 8..]))
+            }
+            _ => None
+        };
+
+        match quoted {
+            // the error is sent back to the source of the quoted packet
+            Some((quoted_info, quoted)) if quoted_info.src == info.dst => Flow::Error(flow_key(quoted, &quoted_info)),
+            _ => Flow::Packet(flow_key(packet, info))
+        }
+    }
+}
+
+fn flow_timeout(protocol: u8) -> Duration {
+    if protocol == TCP {
+        TCP_FLOW_TIMEOUT
+    } else {
+        FLOW_TIMEOUT
+    }
+}
+
+struct Shard {
+    // flow -> last seen
+    flows: HashMap<FlowKey, Instant>,
+    last_sweep: Instant,
+}
+
+// flows established by the allowed packets, sharded by the flow key
+pub struct FlowTable {
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        let now = Instant::now();
+
+        FlowTable {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(Shard { flows: HashMap::new(), last_sweep: now }))
+                .collect(),
+        }
+    }
+
+    // the flow and its reverse are in the same shard
+    fn shard(&self, key: &FlowKey) -> &Mutex<Shard> {
+        let (a, b) = if key.src <= key.dst { (key.src, key.dst) } else { (key.dst, key.src) };
+        let hash = self.hasher.hash_one((key.protocol, a, b));
+        &self.shards[hash as usize % SHARDS]
+    }
+
+    pub fn insert(&self, key: FlowKey) {
+        let now = Instant::now();
+        let mut guard = self.shard(&key).lock();
+        let shard = &mut *guard;
+
+        if now.duration_since(shard.last_sweep) >= SWEEP_INTERVAL {
+            shard.flows.retain(|k, t| now.duration_since(*t) < flow_timeout(k.protocol));
+            shard.last_sweep = now;
+        }
+
+        if shard.flows.len() < MAX_FLOWS / SHARDS || shard.flows.contains_key(&key) {
+            shard.flows.insert(key, now);
+        }
+    }
+
+    // refreshes the flow if it is alive
+    pub fn contains(&self, key: &FlowKey) -> bool {
+        let now = Instant::now();
+        let mut guard = self.shard(key).lock();
+
+        match guard.flows.get_mut(key) {
+            Some(t) if now.duration_since(*t) < flow_timeout(key.protocol) => {
+                *t = now;
+                true
+            }
+            _ => false
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().flows.len()).sum()
+    }
+
+    // the packet replies to a tracked flow, or is an icmp error of it
+    pub fn is_reply(&self, flow: &Flow) -> bool {
+        match flow {
+            Flow::Packet(key) => self.contains(&key.reverse()),
+            Flow::Error(quoted) => self.contains(quoted),
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::common::acl::{ipv4_packet as packet, UDP};
+
+    fn flow(packet: &[u8]) -> Flow {
+        Flow::of(packet, &parse_packet(packet).unwrap())
+    }
+
+    let table = FlowTable::new();
+    let request = packet([10, 0, 0, 1], [10, 0, 0, 2], UDP, 40000, 53);
+
+    match flow(&request) {
+        Flow::Packet(key) => table.insert(key),
+        Flow::Error(_) => unreachable!()
+    }
+
+    assert!(table.is_reply(&flow(&packet([10, 0, 0, 2], [10, 0, 0, 1], UDP, 53, 40000))));
+    assert!(!table.is_reply(&flow(&packet([10, 0, 0, 2], [10, 0, 0, 1], UDP, 54, 40000))));
+    assert!(!table.is_reply(&flow(&request)));
+
+    // port unreachable from a router on the path
+    let mut error = packet([10, 0, 0, 9], [10, 0, 0, 1], ICMP, 0, 0);
+    error[20] = 3;
+    error.truncate(28);
+    error.extend_from_slice(&request);
+    assert!(table.is_reply(&flow(&error)));
+
+    // quoting a packet that was not sent by the destination
+    let mut error = packet([10, 0, 0, 9], [10, 0, 0, 3], ICMP, 0, 0);
+    error[20] = 3;
+    error.truncate(28);
+    error.extend_from_slice(&request);
+    assert!(!table.is_reply(&flow(&error)));
+}
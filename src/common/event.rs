@@ -0,0 +1,169 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::Stream;
+use hyper::body::{Bytes, Frame};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::common::net::UdpStatus;
+use crate::common::net::protocol::VirtualAddr;
+use crate::QuotaPeriod;
+
+// events kept for the slow subscribers
+const CAPACITY: usize = 1024;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PeerPath {
+    P2p,
+    // forwarded by another node
+    NodeRelay { via: VirtualAddr },
+    TcpRelay,
+    UdpRelay,
+    QuicRelay,
+    Unreachable,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    NodeRegistered {
+        node: String,
+        addr: VirtualAddr,
+    },
+    NodeDisconnected {
+        node: String,
+        addr: VirtualAddr,
+    },
+    RegisterFailed {
+        node: Option<String>,
+        // RegisterError kind, None if the error is not returned by the server
+        reason: Option<&'static str>,
+        error: String,
+    },
+    // node is None for the udp link between the node and the server
+    UdpStatusChanged {
+        node: Option<String>,
+        status: UdpStatus,
+    },
+    PathChanged {
+        node: String,
+        addr: VirtualAddr,
+        path: PeerPath,
+    },
+    // next hop of the node relay path selected by the node
+    RouteChanged {
+        dst: VirtualAddr,
+        next_hop: Option<VirtualAddr>,
+        cost: Option<u64>,
+    },
+    // the relay quota of the node is used up in the current period
+    QuotaExceeded {
+        node: String,
+        limit: u64,
+        period: QuotaPeriod,
+    },
+    // relayed packets of the node are dropped by the flow control rules, reported at most once a minute for each direction
+    FlowControlRejected {
+        node: String,
+        addr: VirtualAddr,
+        // upload or download
+        direction: &'static str,
+    },
+    // the subscriber is too slow and missed some events
+    Lagged {
+        skipped: u64,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Record {
+    // unix timestamp in milliseconds
+    pub time: i64,
+    pub group: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Record {
+    fn new(group: Option<&str>, event: Event) -> Self {
+        Record {
+            time: Utc::now().timestamp_millis(),
+            group: group.map(|v| v.to_string()),
+            event,
+        }
+    }
+}
+
+// server-sent event
+fn encode(record: &Record) -> Bytes {
+    let mut buff = b"data: ".to_vec();
+    serde_json::to_writer(&mut buff, record).expect("failed to serialize event");
+    buff.extend_from_slice(b"\n\n");
+    Bytes::from(buff)
+}
+
+// events of the api and the webhooks
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<Record>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            tx: broadcast::Sender::new(CAPACITY),
+        }
+    }
+
+    pub fn publish(&self, group: Option<&str>, event: Event) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+
+        let _ = self.tx.send(Arc::new(Record::new(group, event)));
+    }
+
+    pub fn receiver(&self) -> broadcast::Receiver<Arc<Record>> {
+        self.tx.subscribe()
+    }
+
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Frame<Bytes>, Infallible>> + Send + 'static {
+        let rx = self.tx.subscribe();
+
+        futures_util::stream::unfold(rx, |mut rx| async move {
+            let data = match time::timeout(KEEPALIVE_INTERVAL, rx.recv()).await {
+                Ok(Ok(record)) => encode(&record),
+                Ok(Err(RecvError::Lagged(skipped))) => encode(&Record::new(None, Event::Lagged { skipped })),
+                Ok(Err(RecvError::Closed)) => return None,
+                // comment line, detects the closed connections
+                Err(_) => Bytes::from_static(b": keepalive\n\n"),
+            };
+            Some((Ok(Frame::data(data)), rx))
+        })
+    }
+}
+
+#[test]
+fn test() {
+    let event = Event::PathChanged {
+        node: String::from("node1"),
+        addr: VirtualAddr::new(10, 0, 0, 2),
+        path: PeerPath::NodeRelay { via: VirtualAddr::new(10, 0, 0, 3) },
+    };
+
+    let data = encode(&Record::new(Some("group1"), event));
+    let json = data.strip_prefix(b"data: ").unwrap().strip_suffix(b"\n\n").unwrap();
+    let v: serde_json::Value = serde_json::from_slice(json).unwrap();
+
+    assert_eq!(v["group"], "group1");
+    assert_eq!(v["type"], "path_changed");
+    assert_eq!(v["node"], "node1");
+    assert_eq!(v["path"]["kind"], "node_relay");
+    assert_eq!(v["path"]["via"], "10.0.0.3");
+}
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::common::net::protocol::{ProtocolVersion, Register};
+
+pub fn parse_signing_key(hex_str: &str) -> Result<SigningKey> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(hex_str, &mut bytes).map_err(|e| anyhow!("invalid identity key: {}", e))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub fn parse_verifying_key(hex_str: &str) -> Result<VerifyingKey> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(hex_str, &mut bytes).map_err(|e| anyhow!("invalid identity public key: {}", e))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| anyhow!("invalid identity public key: {}", e))
+}
+
+// returns (private key, public key)
+pub fn generate() -> (String, String) {
+    let key = SigningKey::from_bytes(&rand::random());
+    (hex::encode(key.to_bytes()), hex::encode(key.verifying_key().to_bytes()))
+}
+
+// version is the negotiated protocol version
+pub fn sign(key: &SigningKey, version: ProtocolVersion, reg: &mut Register) -> Result<()> {
+    let content = reg.signed_content(version)?;
+    reg.signature = Some(key.sign(&content).to_vec());
+    Ok(())
+}
+
+pub fn verify(key: &VerifyingKey, version: ProtocolVersion, reg: &Register) -> Result<()> {
+    let signature = reg.signature.as_deref().ok_or_else(|| anyhow!("missing signature"))?;
+    let signature = Signature::from_slice(signature)?;
+    let content = reg.signed_content(version)?;
+
+    key.verify(&content, &signature)?;
+    Ok(())
+}
+
+#[test]
+fn test() {
+    use std::net::Ipv4Addr;
+    use crate::common::net::protocol::{ProtocolMode, PROTOCOL_VERSION};
+
+    let (private_key, public_key) = generate();
+    let signing_key = parse_signing_key(&private_key).unwrap();
+    let verifying_key = parse_verifying_key(&public_key).unwrap();
+
+    let mut reg = Register {
+        node_name: String::from("node"),
+        virtual_addr: Ipv4Addr::new(10, 0, 0, 2),
+        virtual_addr_v6: None,
+        lan_udp_socket_addr: None,
+        proto_mod: ProtocolMode::default(),
+        allowed_ips: Vec::new(),
+        register_time: 0,
+        nonce: 0,
+        udp_counter: 0,
+        signature: None
+    };
+
+    assert!(verify(&verifying_key, PROTOCOL_VERSION, &reg).is_err());
+    sign(&signing_key, PROTOCOL_VERSION, &mut reg).unwrap();
+    verify(&verifying_key, PROTOCOL_VERSION, &reg).unwrap();
+
+    reg.node_name = String::from("other");
+    assert!(verify(&verifying_key, PROTOCOL_VERSION, &reg).is_err());
+}
@@ -0,0 +1,69 @@
+use std::fmt::{Display, Write};
+
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// prometheus text exposition format
+pub struct MetricsWriter {
+    buff: String,
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        MetricsWriter {
+            buff: String::new(),
+        }
+    }
+
+    // samples of a metric must follow its header
+    pub fn header(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.buff, "# HELP {} {}", name, help);
+        let _ = writeln!(self.buff, "# TYPE {} {}", name, metric_type);
+    }
+
+    pub fn sample<V: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        self.buff.push_str(name);
+
+        if !labels.is_empty() {
+            self.buff.push('{');
+
+            for (i, (k, v)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.buff.push(',');
+                }
+
+                self.buff.push_str(k);
+                self.buff.push_str("=\"");
+
+                for c in v.chars() {
+                    match c {
+                        '\\' => self.buff.push_str("\\\\"),
+                        '"' => self.buff.push_str("\\\""),
+                        '\n' => self.buff.push_str("\\n"),
+                        c => self.buff.push(c)
+                    }
+                }
+                self.buff.push('"');
+            }
+            self.buff.push('}');
+        }
+
+        let _ = writeln!(self.buff, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.buff
+    }
+}
+
+#[test]
+fn test() {
+    let mut w = MetricsWriter::new();
+    w.header("fubuki_test", "gauge", "test metric");
+    w.sample("fubuki_test", &[("group", "a\"b"), ("node", "n1")], 1);
+    w.sample("fubuki_test", &[], 2.5);
+
+    assert_eq!(
+        w.finish(),
+        "# HELP fubuki_test test metric\n# TYPE fubuki_test gauge\nfubuki_test{group=\"a\\\"b\",node=\"n1\"} 1\nfubuki_test 2.5\n"
+    );
+}
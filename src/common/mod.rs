@@ -5,6 +5,13 @@ pub mod cipher;
 pub mod net;
 pub mod allocator;
 pub mod hook;
+pub mod identity;
+pub mod api;
+pub mod metrics;
+pub mod acl;
+pub mod conntrack;
+pub mod event;
+pub mod quic;
 
 macro_rules! ternary {
     ($condition: expr, $_true: expr, $_false: expr) => {
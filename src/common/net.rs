@@ -3,17 +3,18 @@ use std::io;
 use std::io::Result;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
-use chrono::Utc;
+use ahash::HashMap;
 use crossbeam_utils::atomic::AtomicCell;
 use ipnet::Ipv4Net;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use serde::{Deserialize, Serialize};
 use socket2::TcpKeepalive;
 use tokio::time::Duration;
 
-use crate::common::net::protocol::Seq;
+use crate::common::net::protocol::{NetProtocol, Seq};
 
 pub trait SocketExt {
     fn set_keepalive(&self) -> Result<()>;
@@ -240,6 +241,34 @@ pub fn get_ip_src_addr(ip_packet: &[u8]) -> Result<Ipv4Addr> {
     Ok(Ipv4Addr::from(buff))
 }
 
+const IPV6_SRC_ADDR: Range<usize> = 8..24;
+const IPV6_DST_ADDR: Range<usize> = 24..40;
+
+pub fn get_ip_version(ip_packet: &[u8]) -> Result<u8> {
+    let first = get!(ip_packet, 0, "get packet version failed");
+    Ok(*first >> 4)
+}
+
+pub fn get_ipv6_dst_addr(ip_packet: &[u8]) -> Result<Ipv6Addr> {
+    let mut buff = [0u8; 16];
+    buff.copy_from_slice(get!(
+        ip_packet,
+        IPV6_DST_ADDR,
+        "get packet destination address failed"
+    ));
+    Ok(Ipv6Addr::from(buff))
+}
+
+pub fn get_ipv6_src_addr(ip_packet: &[u8]) -> Result<Ipv6Addr> {
+    let mut buff = [0u8; 16];
+    buff.copy_from_slice(get!(
+        ip_packet,
+        IPV6_SRC_ADDR,
+        "get packet source address failed"
+    ));
+    Ok(Ipv6Addr::from(buff))
+}
+
 pub fn get_interface_addr(dest_addr: SocketAddr) -> Result<IpAddr> {
     let bind_addr = match dest_addr {
         SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -267,6 +296,37 @@ impl Display for UdpStatus {
     }
 }
 
+// counts the changes between available and unavailable
+pub struct UdpStatusCell {
+    status: AtomicCell<UdpStatus>,
+    transitions: AtomicU64,
+}
+
+impl UdpStatusCell {
+    pub fn new() -> Self {
+        UdpStatusCell {
+            status: AtomicCell::new(UdpStatus::Unavailable),
+            transitions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn load(&self) -> UdpStatus {
+        self.status.load()
+    }
+
+    pub fn store(&self, status: UdpStatus) {
+        let old = self.status.swap(status);
+
+        if (old == UdpStatus::Unavailable) != (status == UdpStatus::Unavailable) {
+            self.transitions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn transitions(&self) -> u64 {
+        self.transitions.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Clone)]
 pub struct HeartbeatCache {
     pub seq: Seq,
@@ -348,97 +408,400 @@ impl From<&HeartbeatCache> for HeartbeatInfo {
     }
 }
 
+// rx/tx counters of a single path
+#[derive(Default)]
+pub struct TrafficCounter {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+impl TrafficCounter {
+    pub fn rx(&self, len: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn tx(&self, len: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct TrafficInfo {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+}
+
+impl From<&TrafficCounter> for TrafficInfo {
+    fn from(value: &TrafficCounter) -> Self {
+        TrafficInfo {
+            rx_packets: value.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: value.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: value.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: value.tx_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Display for TrafficInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rx {} ({} pkts) / tx {} ({} pkts)",
+            format_bytes(self.rx_bytes),
+            self.rx_packets,
+            format_bytes(self.tx_bytes),
+            self.tx_packets
+        )
+    }
+}
+
+// letters, digits and hyphens, the domains are passed to the system dns settings
+pub fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253 && domain.split('.').all(|label| {
+        !label.is_empty() &&
+            label.len() <= 63 &&
+            !label.starts_with('-') &&
+            !label.ends_with('-') &&
+            label.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+    })
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut v = bytes as f64;
+    let mut unit = 0;
+
+    while v >= 1024.0 && unit < UNITS.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", v, UNITS[unit])
+    }
+}
+
+// traffic exchanged with a node, the direction is seen from the local side
+#[derive(Default)]
+pub struct Traffic {
+    pub udp_p2p: TrafficCounter,
+    pub udp_relay: TrafficCounter,
+    pub tcp_relay: TrafficCounter,
+    pub quic_relay: TrafficCounter,
+}
+
+impl Traffic {
+    pub fn relay(&self, protocol: NetProtocol) -> &TrafficCounter {
+        match protocol {
+            NetProtocol::TCP => &self.tcp_relay,
+            NetProtocol::UDP => &self.udp_relay,
+            NetProtocol::QUIC => &self.quic_relay,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TrafficStats {
+    pub udp_p2p: TrafficInfo,
+    pub udp_relay: TrafficInfo,
+    pub tcp_relay: TrafficInfo,
+    #[serde(default)]
+    pub quic_relay: TrafficInfo,
+}
+
+impl From<&Traffic> for TrafficStats {
+    fn from(value: &Traffic) -> Self {
+        TrafficStats {
+            udp_p2p: TrafficInfo::from(&value.udp_p2p),
+            udp_relay: TrafficInfo::from(&value.udp_relay),
+            tcp_relay: TrafficInfo::from(&value.tcp_relay),
+            quic_relay: TrafficInfo::from(&value.quic_relay),
+        }
+    }
+}
+
+const REPLAY_WINDOW_SIZE: u64 = 1024;
+
+// sliding window of the udp message counters received from one peer
+pub struct ReplayWindow {
+    // highest counter received
+    top: u64,
+    bitmap: [u64; (REPLAY_WINDOW_SIZE / 64) as usize],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        ReplayWindow {
+            top: 0,
+            bitmap: [0; (REPLAY_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    // the counters up to the floor are taken as received, such as the ones of a previous session
+    pub fn with_floor(floor: u64) -> Self {
+        ReplayWindow {
+            top: floor,
+            bitmap: [u64::MAX; (REPLAY_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let i = counter % REPLAY_WINDOW_SIZE;
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    // returns false if the counter has been received or is too old
+    pub fn check(&mut self, counter: u64) -> bool {
+        if counter > self.top {
+            if counter - self.top >= REPLAY_WINDOW_SIZE {
+                self.bitmap = [0; (REPLAY_WINDOW_SIZE / 64) as usize];
+            } else {
+                for c in self.top + 1..=counter {
+                    let (i, mask) = Self::bit(c);
+                    self.bitmap[i] &= !mask;
+                }
+            }
+
+            self.top = counter;
+        } else if self.top - counter >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+
+        let (i, mask) = Self::bit(counter);
+
+        if self.bitmap[i] & mask != 0 {
+            return false;
+        }
+
+        self.bitmap[i] |= mask;
+        true
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct FlowControlRule {
+    pub cidr: Ipv4Net,
+    // bytes per second sent by each node in the range
+    pub upload: Option<u64>,
+    // bytes per second received by each node in the range
+    pub download: Option<u64>,
+    // bucket capacity, one second of traffic by default
+    pub burst: Option<u64>,
+    // packets over the limit wait up to this long instead of being dropped
+    pub max_queue_delay: Option<Duration>,
+}
+
+// flow control rejections of a bucket are reported at most once per interval
+const REJECT_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct TokenBucket {
+    // bytes per second
+    rate: u64,
+    burst: u64,
+    max_queue_delay: Option<Duration>,
+    // (tokens, refill time), queued packets take tokens in advance so the count can go negative
+    state: Mutex<(f64, Instant)>,
+    last_report: Mutex<Option<Instant>>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64, max_queue_delay: Option<Duration>) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            max_queue_delay,
+            state: Mutex::new((burst as f64, Instant::now())),
+            last_report: Mutex::new(None),
+        }
+    }
+
+    fn should_report(&self, now: Instant) -> bool {
+        let mut last = self.last_report.lock();
+
+        match *last {
+            Some(t) if now.saturating_duration_since(t) < REJECT_REPORT_INTERVAL => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+
+    // how long the packet has to wait, None means drop it
+    fn check(&self, state: &mut (f64, Instant), packet_len: u64, now: Instant) -> Option<Duration> {
+        let (tokens, last) = state;
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+
+        *tokens = (*tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        *last = now;
+
+        // a full bucket lets a packet larger than the burst size through
+        let need = packet_len.min(self.burst) as f64;
+
+        if *tokens >= need {
+            return Some(Duration::ZERO);
+        }
+
+        let wait = Duration::from_secs_f64((need - *tokens) / self.rate as f64);
+
+        match self.max_queue_delay {
+            Some(max) if wait <= max => Some(wait),
+            _ => None
+        }
+    }
+
+    pub fn take(&self, packet_len: u64) -> PushResult {
+        let mut state = self.state.lock();
+
+        match self.check(&mut state, packet_len, Instant::now()) {
+            None => PushResult::Reject,
+            Some(wait) => {
+                state.0 -= packet_len as f64;
+
+                if wait.is_zero() {
+                    PushResult::Accept
+                } else {
+                    PushResult::Delay(wait)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct NodeBuckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
 pub struct FlowControl {
-    // flow rule, (address range, byte/s), example: { "192.168.8.0/24": "2048", "192.168.8.10/32": "1024" }
-    rule: Vec<(Ipv4Net, u64)>,
-    // sorted address mapping, (address, traffic at current timestamp, UTC timestamp(seconds))
-    pool: RwLock<Vec<(Ipv4Addr, AtomicCell<(u64, i64)>)>>
+    // sorted by prefix length, the most specific rule of each direction applies
+    rules: Vec<FlowControlRule>,
+    // node address -> buckets, the rules are matched when the node joins
+    buckets: RwLock<HashMap<Ipv4Addr, NodeBuckets>>,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum PushResult {
     Accept,
-    Reject
+    Reject,
+    // send the packet after the delay
+    Delay(Duration),
 }
 
 impl FlowControl {
-    pub fn new(mut rule: Vec<(Ipv4Net, u64)>) -> Self {
-        rule.sort_unstable_by_key(|(cidr, _)| std::cmp::Reverse(cidr.prefix_len()));
+    pub fn new(mut rules: Vec<FlowControlRule>) -> Self {
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.cidr.prefix_len()));
 
         FlowControl {
-            rule,
-            pool: RwLock::new(Vec::new())
+            rules,
+            buckets: RwLock::new(HashMap::default()),
         }
     }
 
+    pub fn rules(&self) -> &[FlowControlRule] {
+        &self.rules
+    }
+
+    fn bucket(&self, addr: Ipv4Addr, limit: fn(&FlowControlRule) -> Option<u64>) -> Option<TokenBucket> {
+        let (rule, rate) = self.rules.iter()
+            .filter(|rule| rule.cidr.contains(&addr))
+            .find_map(|rule| Some((rule, limit(rule)?)))?;
+
+        Some(TokenBucket::new(rate, rule.burst.unwrap_or(rate), rule.max_queue_delay))
+    }
+
+    // src is None when the sender is not registered to this server,
+    // on_reject gets the node and the direction that dropped the packet, at most once per interval for each bucket
     pub fn push(
         &self,
+        packet_src: Option<Ipv4Addr>,
         packet_dst: Ipv4Addr,
-        packet_len: u64
+        packet_len: u64,
+        on_reject: impl FnOnce(Ipv4Addr, &'static str)
     ) -> PushResult {
-        let limit_opt = self.rule.iter()
-            .find(|(cidr, _)| cidr.contains(&packet_dst))
-            .map(|(_, limit)| *limit);
-
-        let limit = match limit_opt {
-            None => return PushResult::Accept,
-            Some(limit) => limit
-        };
-
-        if packet_len > limit {
-            return PushResult::Reject;
+        if self.rules.is_empty() {
+            return PushResult::Accept;
         }
 
-        let guard = self.pool.read();
-        let now = Utc::now().timestamp();
+        let guard = self.buckets.read();
 
-        let index_opt = guard.binary_search_by_key(&packet_dst, |(addr, _)| *addr);
+        let upload = packet_src
+            .and_then(|addr| Some((guard.get(&addr)?.upload.as_ref()?, addr, "upload")));
 
-        let index = match index_opt {
-            Ok(v) => v,
-            Err(_) => return PushResult::Reject,
-        };
+        let download = guard.get(&packet_dst)
+            .and_then(|v| v.download.as_ref())
+            .map(|bucket| (bucket, packet_dst, "download"));
 
-        let (_, value) = &(*guard)[index];
+        // upload buckets are always locked before download buckets
+        let mut states: Vec<_> = upload.into_iter()
+            .chain(download)
+            .map(|(bucket, addr, direction)| (bucket, addr, direction, bucket.state.lock()))
+            .collect();
 
-        let res = value.fetch_update(|(f, t)| {
-            if t == now {
-                let new_traffic = f + packet_len;
+        let now = Instant::now();
+        let mut delay = Duration::ZERO;
+        let mut rejected = None;
 
-                if new_traffic <= limit {
-                    Some((new_traffic, t))
-                } else {
-                    None
+        for (bucket, addr, direction, state) in &mut states {
+            match bucket.check(state, packet_len, now) {
+                None => {
+                    rejected = Some((*addr, *direction, bucket.should_report(now)));
+                    break;
                 }
-            } else {
-                Some((packet_len, now))
+                Some(wait) => delay = delay.max(wait)
             }
-        });
+        }
+
+        if let Some((addr, direction, report)) = rejected {
+            drop(states);
+            drop(guard);
 
-        if res.is_ok() {
+            if report {
+                on_reject(addr, direction);
+            }
+            return PushResult::Reject;
+        }
+
+        for (_, _, _, state) in &mut states {
+            state.0 -= packet_len as f64;
+        }
+
+        if delay.is_zero() {
             PushResult::Accept
         } else {
-            PushResult::Reject
+            PushResult::Delay(delay)
         }
     }
 
     pub fn add_address(&self, addr: Ipv4Addr) {
-        let mut guard = self.pool.write();
-        let index_opt = guard.binary_search_by_key(&addr, |(addr, _)| *addr);
-
-        if let Err(i) = index_opt {
-            let v = (addr, AtomicCell::new((0, Utc::now().timestamp())));
-            guard.insert(i, v);
+        if self.rules.is_empty() {
+            return;
         }
+
+        let buckets = NodeBuckets {
+            upload: self.bucket(addr, |rule| rule.upload),
+            download: self.bucket(addr, |rule| rule.download),
+        };
+
+        self.buckets.write().insert(addr, buckets);
     }
 
     pub fn remove_address(&self, addr: &Ipv4Addr) {
-        let mut guard = self.pool.write();
-        let index_opt = guard.binary_search_by_key(addr, |(addr, _)| *addr);
-
-        if let Ok(i) = index_opt {
-            guard.remove(i);
-        }
+        self.buckets.write().remove(addr);
     }
 }
 
@@ -447,18 +810,19 @@ pub mod protocol {
     use std::fmt::{Display, Formatter};
     use std::io;
     use std::mem::size_of;
-    use std::net::{Ipv4Addr, SocketAddr};
-    use std::time::Duration;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use ahash::HashMap;
     use anyhow::{anyhow, Result};
     use arrayvec::ArrayVec;
     use bincode::{config, Decode, Encode};
-    use ipnet::Ipv4Net;
+    use ipnet::{IpNet, Ipv4Net, Ipv6Net};
     use serde::{Deserialize, Serialize};
     use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
     use tokio::net::{ToSocketAddrs, UdpSocket};
-    use crate::common::cipher::{self, CipherContext};
-    use crate::common::cipher::Cipher;
+    use crate::common::acl::Acl;
+    use crate::common::cipher::{Cipher, CipherContext, Nonce, Tag, NONCE_LEN, TAG_LEN};
 
     pub type VirtualAddr = Ipv4Addr;
 
@@ -467,10 +831,8 @@ pub mod protocol {
     pub const TCP_BUFF_SIZE: usize = 65535;
     pub const UDP_BUFF_SIZE: usize = 65535;
 
+    // unchanged so that the version 2 messages still decode, the nodes before hello are told apart by TcpMsg::is_legacy_header
     pub const MAGIC_NUM: u8 = 0x99;
-    pub const REGISTER: u8 = 0x00;
-    pub const REGISTER_RESULT: u8 = 0x05;
-    pub const NODE_MAP: u8 = 0x01;
     pub const HEARTBEAT: u8 = 0x02;
     pub const DATA: u8 = 0x03;
     pub const GET_IDLE_VIRTUAL_ADDR: u8 = 0x06;
@@ -479,19 +841,35 @@ pub mod protocol {
     pub const UPLOAD_PEERS: u8 = 0x09;
     pub const FETCH_PEERS: u8 = 0x0A;
     pub const FETCH_PEERS_RES: u8 = 0x0B;
+    pub const HANDSHAKE: u8 = 0x0C;
+    pub const HELLO: u8 = 0x0D;
+    pub const HELLO_RES: u8 = 0x0E;
+    pub const PUSH_CONFIG: u8 = 0x0F;
+    pub const CLUSTER_HELLO: u8 = 0x10;
+    pub const CLUSTER_SYNC: u8 = 0x11;
+    pub const CLUSTER_RELAY: u8 = 0x12;
+    // re-encoded in version 3, the version 2 codes are in v2
+    pub const REGISTER: u8 = 0x13;
+    pub const REGISTER_RESULT: u8 = 0x14;
+    pub const NODE_MAP: u8 = 0x15;
     pub const REQ: u8 = 0x00;
     pub const RESP: u8 = 0x01;
 
     pub type Seq = u32;
-    pub type NetProtocols = ArrayVec<NetProtocol, 2>;
+    pub type ServerId = u64;
+    // random value sent by each end of a cluster link, the link key is derived from both
+    pub type ClusterChallenge = [u8; 16];
+    pub type NetProtocols = ArrayVec<NetProtocol, 3>;
 
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
     pub enum NetProtocol {
         TCP,
         UDP,
+        // relay only, packets are sent as datagrams of the quic connection to the server
+        QUIC,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct ProtocolMode {
         pub p2p: NetProtocols,
         pub relay: NetProtocols,
@@ -504,7 +882,7 @@ pub mod protocol {
 
             ProtocolMode {
                 p2p,
-                relay: NetProtocols::from([NetProtocol::UDP, NetProtocol::TCP]),
+                relay: NetProtocols::from_iter([NetProtocol::UDP, NetProtocol::TCP]),
             }
         }
     }
@@ -517,6 +895,98 @@ pub mod protocol {
         pub fn is_use_tcp(&self) -> bool {
             self.p2p.contains(&NetProtocol::TCP) || self.relay.contains(&NetProtocol::TCP)
         }
+
+        // the connection to the server is a quic connection instead of tcp
+        pub fn is_use_quic(&self) -> bool {
+            self.relay.contains(&NetProtocol::QUIC)
+        }
+
+        // for the nodes that can't decode QUIC, it relays through the server connection as TCP does
+        pub fn without_quic(&self) -> ProtocolMode {
+            let mut relay = NetProtocols::new();
+
+            for np in &self.relay {
+                let np = if *np == NetProtocol::QUIC { NetProtocol::TCP } else { *np };
+
+                if !relay.contains(&np) {
+                    relay.push(np);
+                }
+            }
+
+            ProtocolMode {
+                p2p: self.p2p.clone(),
+                relay,
+            }
+        }
+    }
+
+    pub type ProtocolVersion = u16;
+    pub type Capabilities = u32;
+
+    // bumped whenever the encoding of a message changes, the messages are encoded for the negotiated version
+    // 2: hello, ipv6 addresses and p2p sessions
+    // 3: pushed config with acl, quic protocol in node maps, node name in GetIdleVirtualAddr, udp counters in registration
+    pub const PROTOCOL_VERSION: ProtocolVersion = 3;
+    pub const MIN_PROTOCOL_VERSION: ProtocolVersion = v2::VERSION;
+
+    // optional features, only the ones supported by both sides are used
+    pub const CAP_P2P_SESSION: Capabilities = 1 << 0;
+    pub const CAP_IPV6: Capabilities = 1 << 1;
+    // the server sends PushConfig when the group config changes
+    pub const CAP_PUSH_CONFIG: Capabilities = 1 << 2;
+    // node maps may contain the QUIC protocol
+    pub const CAP_QUIC: Capabilities = 1 << 3;
+    // the node enforces the pushed acl
+    pub const CAP_ACL: Capabilities = 1 << 4;
+    // the udp counters continue above the ones exchanged at registration
+    pub const CAP_UDP_COUNTER: Capabilities = 1 << 5;
+    pub const CAP_ALL: Capabilities = CAP_P2P_SESSION | CAP_IPV6 | CAP_PUSH_CONFIG | CAP_QUIC | CAP_ACL | CAP_UDP_COUNTER;
+
+    #[derive(Encode, Decode, Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct Hello {
+        pub version: ProtocolVersion,
+        pub capabilities: Capabilities,
+    }
+
+    impl Hello {
+        pub fn current() -> Self {
+            Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: CAP_ALL,
+            }
+        }
+
+        // agree on the version and capabilities with the peer, Err contains the local supported version range
+        pub fn negotiate(&self, remote: &Hello) -> Result<Hello, RegisterError> {
+            if remote.version < MIN_PROTOCOL_VERSION {
+                return Err(RegisterError::IncompatibleVersion {
+                    min: MIN_PROTOCOL_VERSION,
+                    max: self.version,
+                });
+            }
+
+            Ok(Hello {
+                version: std::cmp::min(self.version, remote.version),
+                capabilities: self.capabilities & remote.capabilities,
+            })
+        }
+
+        pub fn has(&self, capabilities: Capabilities) -> bool {
+            self.capabilities & capabilities == capabilities
+        }
+    }
+
+    // settings declared by the server group and applied by the nodes
+    #[derive(Encode, Decode, Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+    pub struct PushedConfig {
+        // gateway virtual address, destination
+        #[bincode(with_serde)]
+        pub routes: Vec<(IpAddr, IpNet)>,
+        pub dns_servers: Vec<IpAddr>,
+        pub search_domains: Vec<String>,
+        // None means every node can reach each other
+        #[bincode(with_serde)]
+        pub acl: Option<Acl>,
     }
 
     #[derive(Encode, Decode, Clone)]
@@ -524,21 +994,55 @@ pub mod protocol {
         pub name: String,
         #[bincode(with_serde)]
         pub cidr: Ipv4Net,
+        // false if the group only has an ipv6 range, the ipv4 address then only identifies the node
+        pub ipv4: bool,
+        #[bincode(with_serde)]
+        pub cidr_v6: Option<Ipv6Net>,
+        // ipv6 address assigned to the registered node
+        pub virtual_addr_v6: Option<Ipv6Addr>,
         pub allow_udp_relay: bool,
-        pub allow_tcp_relay: bool
+        pub allow_tcp_relay: bool,
+        pub pushed: PushedConfig,
+        // udp counter of the next server message, the earlier ones are replays
+        pub udp_counter: u64,
+        // the node continues its udp counter above this one
+        pub node_udp_counter: u64,
     }
 
     #[derive(Encode, Decode, Clone)]
     pub struct Register {
         pub node_name: String,
         pub virtual_addr: VirtualAddr,
+        // requested ipv6 address, None lets the server choose one if the group has an ipv6 range
+        pub virtual_addr_v6: Option<Ipv6Addr>,
         pub lan_udp_socket_addr: Option<SocketAddr>,
         #[bincode(with_serde)]
         pub proto_mod: ProtocolMode,
         #[bincode(with_serde)]
-        pub allowed_ips: Vec<Ipv4Net>,
+        pub allowed_ips: Vec<IpNet>,
         pub register_time: i64,
         pub nonce: u32,
+        // udp counter of the next node message, the earlier ones are replays
+        pub udp_counter: u64,
+        // ed25519 signature of the message without this field, required by groups with node identities
+        pub signature: Option<Vec<u8>>,
+    }
+
+    impl Register {
+        // the message is signed in the encoding of the negotiated version
+        pub fn signed_content(&self, version: ProtocolVersion) -> Result<Vec<u8>> {
+            let content = Register {
+                signature: None,
+                ..self.clone()
+            };
+
+            let out = if version <= v2::VERSION {
+                bincode::encode_to_vec(v2::Register::from(&content), config::standard())?
+            } else {
+                bincode::encode_to_vec(content, config::standard())?
+            };
+            Ok(out)
+        }
     }
 
     #[derive(Eq, PartialEq, Debug, Copy, Clone, Encode, Decode)]
@@ -547,6 +1051,8 @@ pub mod protocol {
         IpSameAsNetworkAddress,
         IpSameAsBroadcastAddress,
         IpAlreadyInUse,
+        // the address is reserved for another node
+        IpReserved,
     }
 
     impl Display for AllocateError {
@@ -562,6 +1068,28 @@ pub mod protocol {
         InvalidVirtualAddress(AllocateError),
         Timeout,
         NonceRepeat,
+        Unauthorized,
+        // the peer version is out of the supported range
+        IncompatibleVersion {
+            min: ProtocolVersion,
+            max: ProtocolVersion,
+        },
+        // after the version 2 variants to keep their encoding
+        Banned,
+    }
+
+    impl RegisterError {
+        // stable label for metrics
+        pub fn kind(&self) -> &'static str {
+            match self {
+                RegisterError::InvalidVirtualAddress(_) => "invalid_virtual_address",
+                RegisterError::Timeout => "timeout",
+                RegisterError::NonceRepeat => "nonce_repeat",
+                RegisterError::Unauthorized => "unauthorized",
+                RegisterError::IncompatibleVersion { .. } => "incompatible_version",
+                RegisterError::Banned => "banned",
+            }
+        }
     }
 
     impl Display for RegisterError {
@@ -576,14 +1104,187 @@ pub mod protocol {
     pub struct Node {
         pub name: String,
         pub virtual_addr: VirtualAddr,
+        pub virtual_addr_v6: Option<Ipv6Addr>,
         pub lan_udp_addr: Option<SocketAddr>,
         pub wan_udp_addr: Option<SocketAddr>,
         #[bincode(with_serde)]
         pub mode: ProtocolMode,
         #[bincode(with_serde)]
-        pub allowed_ips: Vec<Ipv4Net>,
+        pub allowed_ips: Vec<IpNet>,
         pub register_time: i64,
         pub register_nonce: u32,
+        // capabilities negotiated with the server
+        pub capabilities: Capabilities,
+    }
+
+    // the messages of protocol version 2 whose encoding has changed since
+    pub mod v2 {
+        use super::*;
+
+        pub const VERSION: ProtocolVersion = 2;
+
+        pub const REGISTER: u8 = 0x00;
+        pub const REGISTER_RESULT: u8 = 0x05;
+        pub const NODE_MAP: u8 = 0x01;
+
+        #[derive(Encode, Decode, Clone)]
+        pub struct GroupContent {
+            pub name: String,
+            #[bincode(with_serde)]
+            pub cidr: Ipv4Net,
+            #[bincode(with_serde)]
+            pub cidr_v6: Option<Ipv6Net>,
+            pub virtual_addr_v6: Option<Ipv6Addr>,
+            pub allow_udp_relay: bool,
+            pub allow_tcp_relay: bool
+        }
+
+        #[derive(Encode, Decode, Clone)]
+        pub struct Register {
+            pub node_name: String,
+            pub virtual_addr: VirtualAddr,
+            pub virtual_addr_v6: Option<Ipv6Addr>,
+            pub lan_udp_socket_addr: Option<SocketAddr>,
+            #[bincode(with_serde)]
+            pub proto_mod: ProtocolMode,
+            #[bincode(with_serde)]
+            pub allowed_ips: Vec<Ipv4Net>,
+            pub register_time: i64,
+            pub nonce: u32,
+            pub signature: Option<Vec<u8>>,
+        }
+
+        #[derive(Encode, Decode, Clone)]
+        pub struct Node {
+            pub name: String,
+            pub virtual_addr: VirtualAddr,
+            pub virtual_addr_v6: Option<Ipv6Addr>,
+            pub lan_udp_addr: Option<SocketAddr>,
+            pub wan_udp_addr: Option<SocketAddr>,
+            #[bincode(with_serde)]
+            pub mode: ProtocolMode,
+            #[bincode(with_serde)]
+            pub allowed_ips: Vec<Ipv4Net>,
+            pub register_time: i64,
+            pub register_nonce: u32,
+            pub capabilities: Capabilities,
+        }
+
+        // version 2 only routes ipv4 ranges to the nodes
+        fn ipv4_nets(nets: &[IpNet]) -> Vec<Ipv4Net> {
+            nets.iter()
+                .filter_map(|net| match net {
+                    IpNet::V4(net) => Some(*net),
+                    IpNet::V6(_) => None
+                })
+                .collect()
+        }
+
+        // the errors added after version 2 are reported as the closest older ones
+        pub fn register_error(err: RegisterError) -> RegisterError {
+            match err {
+                RegisterError::Banned => RegisterError::Unauthorized,
+                RegisterError::InvalidVirtualAddress(AllocateError::IpReserved) => RegisterError::InvalidVirtualAddress(AllocateError::IpAlreadyInUse),
+                err => err
+            }
+        }
+
+        impl From<&super::GroupContent> for GroupContent {
+            fn from(gc: &super::GroupContent) -> Self {
+                GroupContent {
+                    name: gc.name.clone(),
+                    cidr: gc.cidr,
+                    cidr_v6: gc.cidr_v6,
+                    virtual_addr_v6: gc.virtual_addr_v6,
+                    allow_udp_relay: gc.allow_udp_relay,
+                    allow_tcp_relay: gc.allow_tcp_relay,
+                }
+            }
+        }
+
+        impl From<GroupContent> for super::GroupContent {
+            fn from(gc: GroupContent) -> Self {
+                super::GroupContent {
+                    name: gc.name,
+                    cidr: gc.cidr,
+                    ipv4: true,
+                    cidr_v6: gc.cidr_v6,
+                    virtual_addr_v6: gc.virtual_addr_v6,
+                    allow_udp_relay: gc.allow_udp_relay,
+                    allow_tcp_relay: gc.allow_tcp_relay,
+                    pushed: PushedConfig::default(),
+                    udp_counter: 0,
+                    node_udp_counter: 0,
+                }
+            }
+        }
+
+        impl From<&super::Register> for Register {
+            fn from(reg: &super::Register) -> Self {
+                Register {
+                    node_name: reg.node_name.clone(),
+                    virtual_addr: reg.virtual_addr,
+                    virtual_addr_v6: reg.virtual_addr_v6,
+                    lan_udp_socket_addr: reg.lan_udp_socket_addr,
+                    proto_mod: reg.proto_mod.without_quic(),
+                    allowed_ips: ipv4_nets(&reg.allowed_ips),
+                    register_time: reg.register_time,
+                    nonce: reg.nonce,
+                    signature: reg.signature.clone(),
+                }
+            }
+        }
+
+        impl From<Register> for super::Register {
+            fn from(reg: Register) -> Self {
+                super::Register {
+                    node_name: reg.node_name,
+                    virtual_addr: reg.virtual_addr,
+                    virtual_addr_v6: reg.virtual_addr_v6,
+                    lan_udp_socket_addr: reg.lan_udp_socket_addr,
+                    proto_mod: reg.proto_mod,
+                    allowed_ips: reg.allowed_ips.into_iter().map(IpNet::V4).collect(),
+                    register_time: reg.register_time,
+                    nonce: reg.nonce,
+                    udp_counter: 0,
+                    signature: reg.signature,
+                }
+            }
+        }
+
+        impl From<&super::Node> for Node {
+            fn from(node: &super::Node) -> Self {
+                Node {
+                    name: node.name.clone(),
+                    virtual_addr: node.virtual_addr,
+                    virtual_addr_v6: node.virtual_addr_v6,
+                    lan_udp_addr: node.lan_udp_addr,
+                    wan_udp_addr: node.wan_udp_addr,
+                    mode: node.mode.without_quic(),
+                    allowed_ips: ipv4_nets(&node.allowed_ips),
+                    register_time: node.register_time,
+                    register_nonce: node.register_nonce,
+                    capabilities: node.capabilities,
+                }
+            }
+        }
+
+        impl From<Node> for super::Node {
+            fn from(node: Node) -> Self {
+                super::Node {
+                    name: node.name,
+                    virtual_addr: node.virtual_addr,
+                    virtual_addr_v6: node.virtual_addr_v6,
+                    lan_udp_addr: node.lan_udp_addr,
+                    wan_udp_addr: node.wan_udp_addr,
+                    mode: node.mode,
+                    allowed_ips: node.allowed_ips.into_iter().map(IpNet::V4).collect(),
+                    register_time: node.register_time,
+                    register_nonce: node.register_nonce,
+                    capabilities: node.capabilities,
+                }
+            }
+        }
     }
 
     #[repr(u8)]
@@ -604,6 +1305,73 @@ pub mod protocol {
         }
     }
 
+    #[repr(u8)]
+    #[derive(Clone, Copy, Eq, PartialEq, Debug)]
+    pub enum HandshakeType {
+        Init = 0,
+        Resp,
+    }
+
+    // |   4  |    2   |    6    |
+    // |SENDER|INSTANCE| COUNTER |
+    // udp message nonce, SENDER is the virtual address of the sender or the receiver's session id in a p2p session.
+    // INSTANCE is random for each generator, so the processes sharing a key and a sender never produce the same nonce.
+    // receivers take INSTANCE and COUNTER as one 8 bytes counter
+    pub struct UdpNonceGenerator {
+        counter: AtomicU64
+    }
+
+    impl UdpNonceGenerator {
+        pub fn new() -> Self {
+            // the counter starts from the current time, the receivers learn it at registration.
+            // the top bit keeps it above the counters of the legacy generator
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let instance = rand::random::<u16>() | 0x8000;
+
+            UdpNonceGenerator {
+                counter: AtomicU64::new((instance as u64) << 48 | (now.as_millis() as u64 & (1 << 48) - 1))
+            }
+        }
+
+        // for the receivers without CAP_UDP_COUNTER, they keep the window of a sender across registrations
+        // and only accept a counter that is higher after a restart
+        pub fn legacy() -> Self {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+            UdpNonceGenerator {
+                counter: AtomicU64::new(now.as_nanos() as u64)
+            }
+        }
+
+        pub fn next(&self, sender: [u8; 4]) -> Nonce {
+            let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+
+            let mut nonce = [0u8; NONCE_LEN];
+            nonce[..4].copy_from_slice(&sender);
+            nonce[4..].copy_from_slice(&counter.to_be_bytes());
+            nonce
+        }
+
+        // the counter of the next message
+        pub fn counter(&self) -> u64 {
+            self.counter.load(Ordering::Relaxed)
+        }
+
+        // continue above the counters the receiver has seen from this sender
+        pub fn advance(&self, floor: u64) {
+            self.counter.fetch_max(floor.saturating_add(1), Ordering::Relaxed);
+        }
+    }
+
+    // returns (sender, counter)
+    pub fn udp_nonce_split(nonce: &Nonce) -> ([u8; 4], u64) {
+        let (sender, counter) = nonce.split_first_chunk::<4>().unwrap();
+        (*sender, u64::from_be_bytes(counter.try_into().unwrap()))
+    }
+
+    pub type SessionId = u32;
+    pub type EphemeralKey = [u8; 32];
+
     #[derive(Copy, Clone, Encode, Decode)]
     pub struct PeerStatus {
         pub addr: VirtualAddr,
@@ -611,13 +1379,17 @@ pub mod protocol {
         pub packet_loss: Option<u8>
     }
 
-    // |  2  |    1    |     1     |    2   | dyn |
-    // |NONCE|MAGIC NUM|PACKET TYPE|DATA LEN|DATA|
+    // |  2   |  12 |  16 |    1    |     1     | dyn |
+    // |LENGTH|NONCE| TAG |MAGIC NUM|PACKET TYPE|DATA |
+    // LENGTH is the data length, it is sent in plaintext and authenticated as associated data
     pub enum TcpMsg<'a> {
-        GetIdleVirtualAddr,
+        Hello(Hello),
+        HelloRes(Result<Hello, RegisterError>),
+        PushConfig(PushedConfig),
+        // node name, None from the version 2 nodes
+        GetIdleVirtualAddr(Option<&'a str>),
         // ip address, cidr
         GetIdleVirtualAddrRes(Option<(VirtualAddr, Ipv4Net)>),
-        // todo add signature
         Register(Register),
         RegisterRes(Result<GroupContent, RegisterError>),
         NodeMap(HashMap<VirtualAddr, Node>),
@@ -626,274 +1398,349 @@ pub mod protocol {
         Heartbeat(Seq, HeartbeatType),
         UploadPeers(Vec<PeerStatus>),
         FetchPeers,
-        FetchPeersRes(HashMap<VirtualAddr, Vec<PeerStatus>>)
+        FetchPeersRes(HashMap<VirtualAddr, Vec<PeerStatus>>),
+        // server to server messages
+        ClusterHello(ServerId, ClusterChallenge),
+        // group name, nodes registered to the sending server
+        ClusterSync(String, HashMap<VirtualAddr, Node>),
+        // group name, destination node, packet
+        ClusterRelay(&'a str, VirtualAddr, &'a [u8])
     }
 
-    pub const TCP_MSG_HEADER_LEN: usize = 6;
+    pub const TCP_MSG_HEADER_LEN: usize = 2 + NONCE_LEN + TAG_LEN + 2;
+    const TCP_CIPHERTEXT_START: usize = 2 + NONCE_LEN + TAG_LEN;
+    pub const LEGACY_HEADER_LEN: usize = 6;
+    // varint encoded version and capabilities
+    const HELLO_MAX_LEN: usize = 3 + 5;
+
+    // fill the header of the message whose data has been written to out[TCP_MSG_HEADER_LEN..] and encrypt it
+    fn tcp_seal<K: Cipher>(
+        key: &K,
+        nonce: Nonce,
+        msg_type: u8,
+        data_len: usize,
+        out: &mut [u8]
+    ) -> usize {
+        out[..2].copy_from_slice(&(data_len as u16).to_be_bytes());
+        out[2..2 + NONCE_LEN].copy_from_slice(&nonce);
+        out[TCP_CIPHERTEXT_START] = MAGIC_NUM;
+        out[TCP_CIPHERTEXT_START + 1] = msg_type;
+
+        let ret = TCP_MSG_HEADER_LEN + data_len;
+        let (header, ciphertext) = out[..ret].split_at_mut(TCP_CIPHERTEXT_START);
+
+        let ctx = CipherContext {
+            offset: 0,
+            nonce,
+            aad: &header[..2]
+        };
+
+        let tag = key.encrypt(ciphertext, &ctx);
+        header[2 + NONCE_LEN..].copy_from_slice(&tag);
+        ret
+    }
 
     impl TcpMsg<'_> {
         pub fn get_idle_virtual_addr_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
+            node_name: Option<&str>,
             out: &mut [u8]
-        ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = GET_IDLE_VIRTUAL_ADDR;
-            out[4..6].copy_from_slice(&0u16.to_be_bytes());
+        ) -> Result<usize> {
+            let name = node_name.unwrap_or_default().as_bytes();
 
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
+            out.get_mut(TCP_MSG_HEADER_LEN..TCP_MSG_HEADER_LEN + name.len())
+                .ok_or_else(|| anyhow!("node name is too long"))?
+                .copy_from_slice(name);
 
-            key.encrypt(&mut out[2..TCP_MSG_HEADER_LEN], &ctx);
-            TCP_MSG_HEADER_LEN
+            Ok(tcp_seal(key, nonce, GET_IDLE_VIRTUAL_ADDR, name.len(), out))
         }
 
         pub fn get_idle_virtual_addr_res_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             addr: Option<(VirtualAddr, Ipv4Net)>,
             out: &mut [u8],
         ) -> Result<usize> {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = GET_IDLE_VIRTUAL_ADDR_RES;
-
             let size = bincode::encode_into_slice(
                 bincode::serde::Compat(addr),
                 &mut out[TCP_MSG_HEADER_LEN..],
                 config::standard(),
             )?;
 
-            out[4..6].copy_from_slice(&(size as u16).to_be_bytes());
-            let ret = TCP_MSG_HEADER_LEN + size;
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            key.encrypt(&mut out[2..ret], &ctx);
-            Ok(ret)
+            Ok(tcp_seal(key, nonce, GET_IDLE_VIRTUAL_ADDR_RES, size, out))
         }
 
         pub fn node_map_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
+            version: ProtocolVersion,
             node_map: &HashMap<VirtualAddr, Node>,
             out: &mut [u8],
         ) -> Result<usize> {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = NODE_MAP;
+            if version <= v2::VERSION {
+                let node_map: HashMap<VirtualAddr, v2::Node> = node_map.iter()
+                    .map(|(addr, node)| (*addr, v2::Node::from(node)))
+                    .collect();
+
+                let size = bincode::encode_into_slice(
+                    node_map,
+                    &mut out[TCP_MSG_HEADER_LEN..],
+                    config::standard(),
+                )?;
+
+                return Ok(tcp_seal(key, nonce, v2::NODE_MAP, size, out));
+            }
 
             let size = bincode::encode_into_slice(
                 node_map,
                 &mut out[TCP_MSG_HEADER_LEN..],
                 config::standard(),
             )?;
-            out[4..6].copy_from_slice(&(size as u16).to_be_bytes());
 
-            let ret = TCP_MSG_HEADER_LEN + size;
+            Ok(tcp_seal(key, nonce, NODE_MAP, size, out))
+        }
 
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
+        pub fn hello_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            hello: &Hello,
+            out: &mut [u8]
+        ) -> Result<usize> {
+            let size = bincode::encode_into_slice(
+                hello,
+                &mut out[TCP_MSG_HEADER_LEN..],
+                config::standard(),
+            )?;
+
+            Ok(tcp_seal(key, nonce, HELLO, size, out))
+        }
+
+        pub fn hello_res_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            hello_res: &Result<Hello, RegisterError>,
+            out: &mut [u8]
+        ) -> Result<usize> {
+            let size = bincode::encode_into_slice(
+                hello_res,
+                &mut out[TCP_MSG_HEADER_LEN..],
+                config::standard(),
+            )?;
+
+            Ok(tcp_seal(key, nonce, HELLO_RES, size, out))
+        }
+
+        pub fn push_config_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            pushed: &PushedConfig,
+            out: &mut [u8]
+        ) -> Result<usize> {
+            let size = bincode::encode_into_slice(
+                pushed,
+                &mut out[TCP_MSG_HEADER_LEN..],
+                config::standard(),
+            )?;
 
-            key.encrypt(&mut out[2..ret], &ctx);
-            Ok(ret)
+            Ok(tcp_seal(key, nonce, PUSH_CONFIG, size, out))
         }
 
         pub fn register_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
+            version: ProtocolVersion,
             register: &Register, 
             out: &mut [u8]
         ) -> Result<usize> {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = REGISTER;
+            if version <= v2::VERSION {
+                let size = bincode::encode_into_slice(
+                    v2::Register::from(register),
+                    &mut out[TCP_MSG_HEADER_LEN..],
+                    config::standard(),
+                )?;
+
+                return Ok(tcp_seal(key, nonce, v2::REGISTER, size, out));
+            }
 
             let size = bincode::encode_into_slice(
                 register,
                 &mut out[TCP_MSG_HEADER_LEN..],
                 config::standard(),
             )?;
-            out[4..6].copy_from_slice(&(size as u16).to_be_bytes());
 
-            let ret = TCP_MSG_HEADER_LEN + size;
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            key.encrypt(&mut out[2..ret], &ctx);
-            Ok(ret)
+            Ok(tcp_seal(key, nonce, REGISTER, size, out))
         }
 
         pub fn register_res_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
+            version: ProtocolVersion,
             register_res: &Result<GroupContent, RegisterError>,
             out: &mut [u8],
         ) -> Result<usize> {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = REGISTER_RESULT;
+            if version <= v2::VERSION {
+                let register_res = register_res.as_ref()
+                    .map(v2::GroupContent::from)
+                    .map_err(|e| v2::register_error(*e));
+
+                let size = bincode::encode_into_slice(
+                    register_res,
+                    &mut out[TCP_MSG_HEADER_LEN..],
+                    config::standard(),
+                )?;
+
+                return Ok(tcp_seal(key, nonce, v2::REGISTER_RESULT, size, out));
+            }
 
             let size = bincode::encode_into_slice(
                 register_res,
                 &mut out[TCP_MSG_HEADER_LEN..],
                 config::standard(),
             )?;
-            out[4..6].copy_from_slice(&(size as u16).to_be_bytes());
-
-            let ret = TCP_MSG_HEADER_LEN + size;
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
 
-            key.encrypt(&mut out[2..ret], &ctx);
-            Ok(ret)
+            Ok(tcp_seal(key, nonce, REGISTER_RESULT, size, out))
         }
 
         pub fn relay_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             to: VirtualAddr, 
             packet_size: usize, 
             out: &mut [u8]
         ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = RELAY;
-
             let data_size = size_of::<VirtualAddr>() + packet_size;
-            out[4..6].copy_from_slice(&(data_size as u16).to_be_bytes());
             out[TCP_MSG_HEADER_LEN..TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>()]
                 .copy_from_slice(&to.octets());
 
-            let ret = TCP_MSG_HEADER_LEN + data_size;
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            key.encrypt(&mut out[2..ret], &ctx);
-            ret
+            tcp_seal(key, nonce, RELAY, data_size, out)
         }
 
         pub fn heartbeat_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             seq: Seq, 
             heartbeat_type: HeartbeatType, 
             out: &mut [u8]
         ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = HEARTBEAT;
-
             const DATA_SIZE: usize = size_of::<Seq>() + size_of::<HeartbeatType>();
 
-            out[4..6].copy_from_slice(&(DATA_SIZE as u16).to_be_bytes());
             out[TCP_MSG_HEADER_LEN..TCP_MSG_HEADER_LEN + size_of::<Seq>()]
                 .copy_from_slice(&seq.to_be_bytes());
             out[TCP_MSG_HEADER_LEN + size_of::<Seq>()] = heartbeat_type as u8;
 
-            const RET: usize = TCP_MSG_HEADER_LEN + DATA_SIZE;
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            key.encrypt(&mut out[2..RET], &ctx);
-            RET
+            tcp_seal(key, nonce, HEARTBEAT, DATA_SIZE, out)
         }
 
         pub fn upload_peers_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             peers: &[PeerStatus],
             out: &mut [u8],
         ) -> Result<usize> {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = UPLOAD_PEERS;
-
             let size = bincode::encode_into_slice(
                 peers,
                 &mut out[TCP_MSG_HEADER_LEN..],
                 config::standard(),
             )?;
-            out[4..6].copy_from_slice(&(size as u16).to_be_bytes());
 
-            let ret = TCP_MSG_HEADER_LEN + size;
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            key.encrypt(&mut out[2..ret], &ctx);
-            Ok(ret)
+            Ok(tcp_seal(key, nonce, UPLOAD_PEERS, size, out))
         }
 
         pub fn fetch_peers_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             out: &mut [u8],
         ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = FETCH_PEERS;
-            out[4..6].copy_from_slice(&0u16.to_be_bytes());
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            key.encrypt(&mut out[2..TCP_MSG_HEADER_LEN], &ctx);
-            TCP_MSG_HEADER_LEN
+            tcp_seal(key, nonce, FETCH_PEERS, 0, out)
         }
 
         pub fn fetch_peers_res_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             peers: &HashMap<VirtualAddr, Vec<PeerStatus>>,
             out: &mut [u8],
         ) -> Result<usize> {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = FETCH_PEERS_RES;
-
             let size = bincode::encode_into_slice(
                 peers,
                 &mut out[TCP_MSG_HEADER_LEN..],
                 config::standard(),
             )?;
-            out[4..6].copy_from_slice(&(size as u16).to_be_bytes());
 
-            let ret = TCP_MSG_HEADER_LEN + size;
+            Ok(tcp_seal(key, nonce, FETCH_PEERS_RES, size, out))
+        }
 
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
+        // |SERVER_ID 8|CHALLENGE 16|
+        pub fn cluster_hello_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            server_id: ServerId,
+            challenge: &ClusterChallenge,
+            out: &mut [u8]
+        ) -> usize {
+            const DATA_LEN: usize = size_of::<ServerId>() + size_of::<ClusterChallenge>();
+
+            out[TCP_MSG_HEADER_LEN..TCP_MSG_HEADER_LEN + size_of::<ServerId>()].copy_from_slice(&server_id.to_be_bytes());
+            out[TCP_MSG_HEADER_LEN + size_of::<ServerId>()..TCP_MSG_HEADER_LEN + DATA_LEN].copy_from_slice(challenge);
+            tcp_seal(key, nonce, CLUSTER_HELLO, DATA_LEN, out)
+        }
+
+        pub fn cluster_sync_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            group_name: &str,
+            node_map: &HashMap<VirtualAddr, Node>,
+            out: &mut [u8]
+        ) -> Result<usize> {
+            let size = bincode::encode_into_slice(
+                (group_name, node_map),
+                &mut out[TCP_MSG_HEADER_LEN..],
+                config::standard(),
+            )?;
 
-            key.encrypt(&mut out[2..ret], &ctx);
-            Ok(ret)
+            Ok(tcp_seal(key, nonce, CLUSTER_SYNC, size, out))
+        }
+
+        // |NAME_LEN 1|NAME|TO 4|PACKET|
+        pub fn cluster_relay_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            group_name: &str,
+            to: VirtualAddr,
+            packet: &[u8],
+            out: &mut [u8]
+        ) -> Result<usize> {
+            let name = group_name.as_bytes();
+            let name_len = u8::try_from(name.len()).map_err(|_| anyhow!("group name is too long"))?;
+            let data_len = 1 + name.len() + size_of::<VirtualAddr>() + packet.len();
+
+            let data = out.get_mut(TCP_MSG_HEADER_LEN..TCP_MSG_HEADER_LEN + data_len)
+                .ok_or_else(|| anyhow!("relay packet is too long"))?;
+            data[0] = name_len;
+            data[1..1 + name.len()].copy_from_slice(name);
+            data[1 + name.len()..1 + name.len() + size_of::<VirtualAddr>()].copy_from_slice(&to.octets());
+            data[1 + name.len() + size_of::<VirtualAddr>()..].copy_from_slice(packet);
+
+            Ok(tcp_seal(key, nonce, CLUSTER_RELAY, data_len, out))
         }
 
         fn decode(mode: u8, data: &[u8]) -> Result<TcpMsg> {
             let msg = match mode {
+                HELLO => {
+                    let (hello, _) = bincode::decode_from_slice::<Hello, _>(data, config::standard())?;
+                    TcpMsg::Hello(hello)
+                }
+                HELLO_RES => {
+                    let (res, _) = bincode::decode_from_slice::<
+                        Result<Hello, RegisterError>,
+                        _,
+                    >(data, config::standard())?;
+                    TcpMsg::HelloRes(res)
+                }
+                PUSH_CONFIG => {
+                    let (pushed, _) = bincode::decode_from_slice::<PushedConfig, _>(data, config::standard())?;
+                    TcpMsg::PushConfig(pushed)
+                }
                 REGISTER => {
                     let (register, _) =
                         bincode::decode_from_slice::<Register, _>(data, config::standard())?;
@@ -913,6 +1760,30 @@ pub mod protocol {
                     )?;
                     TcpMsg::NodeMap(node_map)
                 }
+                v2::REGISTER => {
+                    let (register, _) =
+                        bincode::decode_from_slice::<v2::Register, _>(data, config::standard())?;
+                    TcpMsg::Register(register.into())
+                }
+                v2::REGISTER_RESULT => {
+                    let (res, _) = bincode::decode_from_slice::<
+                        Result<v2::GroupContent, RegisterError>,
+                        _,
+                    >(data, config::standard())?;
+                    TcpMsg::RegisterRes(res.map(GroupContent::from))
+                }
+                v2::NODE_MAP => {
+                    let (node_map, _) = bincode::decode_from_slice::<HashMap<VirtualAddr, v2::Node>, _>(
+                        data,
+                        config::standard(),
+                    )?;
+
+                    let node_map = node_map.into_iter()
+                        .map(|(addr, node)| (addr, Node::from(node)))
+                        .collect();
+
+                    TcpMsg::NodeMap(node_map)
+                }
                 RELAY => {
                     const ADDR_SIZE: usize = size_of::<VirtualAddr>();
 
@@ -933,7 +1804,10 @@ pub mod protocol {
                     let heartbeat_type = HeartbeatType::try_from(heartbeat_type)?;
                     TcpMsg::Heartbeat(seq, heartbeat_type)
                 }
-                GET_IDLE_VIRTUAL_ADDR => TcpMsg::GetIdleVirtualAddr,
+                GET_IDLE_VIRTUAL_ADDR => {
+                    let node_name = std::str::from_utf8(data)?;
+                    TcpMsg::GetIdleVirtualAddr(Some(node_name).filter(|name| !name.is_empty()))
+                }
                 GET_IDLE_VIRTUAL_ADDR_RES => {
                     let (opt, _) = bincode::decode_from_slice::<
                         bincode::serde::Compat<Option<(VirtualAddr, Ipv4Net)>>,
@@ -949,6 +1823,30 @@ pub mod protocol {
                     TcpMsg::UploadPeers(peers)
                 }
                 FETCH_PEERS => TcpMsg::FetchPeers,
+                CLUSTER_HELLO => {
+                    let mut server_id = [0u8; size_of::<ServerId>()];
+                    server_id.copy_from_slice(get!(data, ..size_of::<ServerId>()));
+
+                    let mut challenge = ClusterChallenge::default();
+                    challenge.copy_from_slice(get!(data, size_of::<ServerId>()..size_of::<ServerId>() + size_of::<ClusterChallenge>()));
+                    TcpMsg::ClusterHello(ServerId::from_be_bytes(server_id), challenge)
+                }
+                CLUSTER_SYNC => {
+                    let ((group_name, node_map), _) = bincode::decode_from_slice::<
+                        (String, HashMap<VirtualAddr, Node>),
+                        _,
+                    >(data, config::standard())?;
+                    TcpMsg::ClusterSync(group_name, node_map)
+                }
+                CLUSTER_RELAY => {
+                    let name_len = *get!(data, 0) as usize;
+                    let name = std::str::from_utf8(get!(data, 1..1 + name_len))?;
+
+                    let mut to = [0u8; size_of::<VirtualAddr>()];
+                    to.copy_from_slice(get!(data, 1 + name_len..1 + name_len + size_of::<VirtualAddr>()));
+
+                    TcpMsg::ClusterRelay(name, VirtualAddr::from(to), &data[1 + name_len + size_of::<VirtualAddr>()..])
+                }
                 FETCH_PEERS_RES => {
                     let (peers_map, _) = bincode::decode_from_slice::<
                         HashMap<VirtualAddr, Vec<PeerStatus>>,
@@ -961,41 +1859,83 @@ pub mod protocol {
             Ok(msg)
         }
 
+        // the whole frame is read into buff, message data starts at buff[TCP_MSG_HEADER_LEN..]
         pub async fn read_msg<'a, Rx: AsyncRead + Unpin, K: Cipher>(
             rx: &mut Rx,
             key: &K,
             buff: &'a mut [u8],
         ) -> Result<Option<TcpMsg<'a>>> {
-            let nonce_res = rx.read_u16().await;
+            let len_res = rx.read_u16().await;
 
-            let nonce = match nonce_res {
-                Ok(nonce) => nonce,
+            let len = match len_res {
+                Ok(len) => len,
                 Err(_) => return Ok(None)
             };
 
-            rx.read_exact(&mut buff[..4]).await?;
+            let frame = buff.get_mut(..TCP_MSG_HEADER_LEN + len as usize)
+                .ok_or_else(|| anyhow!("tcp message too long"))?;
+
+            frame[..2].copy_from_slice(&len.to_be_bytes());
+            rx.read_exact(&mut frame[2..]).await?;
+
+            TcpMsg::decode_frame(key, frame).map(Some)
+        }
+
+        // decrypt a whole frame in place, such as a quic datagram
+        pub fn decode_frame<'a, K: Cipher>(key: &K, frame: &'a mut [u8]) -> Result<TcpMsg<'a>> {
+            let len = match frame.get(..2) {
+                Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+                None => return Err(anyhow!("tcp message too short"))
+            };
 
-            let mut ctx = cipher::CipherContext {
+            if TCP_MSG_HEADER_LEN + len != frame.len() {
+                return Err(anyhow!("tcp message length mismatch"));
+            }
+
+            let (header, ciphertext) = frame.split_at_mut(TCP_CIPHERTEXT_START);
+            let nonce: Nonce = header[2..2 + NONCE_LEN].try_into().unwrap();
+            let tag: Tag = header[2 + NONCE_LEN..].try_into().unwrap();
+
+            let ctx = CipherContext {
                 offset: 0,
-                nonce
+                nonce,
+                aad: &header[..2]
             };
-            key.decrypt(&mut buff[..4], &ctx);
 
-            if buff[0] != MAGIC_NUM {
+            // reject tampered messages before parsing
+            key.decrypt(ciphertext, &ctx, &tag)?;
+
+            if ciphertext[0] != MAGIC_NUM {
                 return Err(anyhow!("magic number miss match"));
             }
 
-            let mode = buff[1];
-            let mut len_buff = [0u8; 2];
-            len_buff.copy_from_slice(&buff[2..4]);
-            let len = u16::from_be_bytes(len_buff);
+            let mode = ciphertext[1];
+            TcpMsg::decode(mode, &ciphertext[2..])
+        }
 
-            let buff = &mut buff[..len as usize];
-            rx.read_exact(buff).await?;
+        // |  2  |    1    |     1     |  2   |
+        // |NONCE|MAGIC NUM|PACKET TYPE|LENGTH|
+        // header of the nodes before hello, they only have the ciphers without a tag and start with REGISTER or GET_IDLE_VIRTUAL_ADDR.
+        // the later nodes start with a hello, whose LENGTH field is small
+        pub fn is_legacy_header<K: Cipher>(key: &K, header: &[u8; LEGACY_HEADER_LEN]) -> bool {
+            if key.is_aead() || u16::from_be_bytes([header[0], header[1]]) as usize <= HELLO_MAX_LEN {
+                return false;
+            }
 
-            ctx.offset = 4;
-            key.decrypt(buff, &ctx);
-            TcpMsg::decode(mode, buff).map(Some)
+            let mut nonce = Nonce::default();
+            nonce[..2].copy_from_slice(&header[..2]);
+
+            let ctx = CipherContext {
+                offset: 0,
+                nonce,
+                aad: &[]
+            };
+
+            let mut plaintext = [header[2], header[3]];
+
+            key.decrypt(&mut plaintext, &ctx, &Tag::default()).is_ok() &&
+                plaintext[0] == MAGIC_NUM &&
+                (plaintext[1] == v2::REGISTER || plaintext[1] == GET_IDLE_VIRTUAL_ADDR)
         }
 
         pub async fn write_msg<Tx: AsyncWrite + Unpin>(
@@ -1007,8 +1947,8 @@ pub mod protocol {
         }
     }
 
-    // |  2  |    1    | 1  | dyn |
-    // |NONCE|MAGIC NUM|MODE|DATA |
+    // |  12 |  16 |    1    | 1  | dyn |
+    // |NONCE| TAG |MAGIC NUM|MODE|DATA |
     pub enum UdpMsg<'a> {
         // todo Heartbeat(from, to, seq, type)
         Heartbeat(VirtualAddr, Seq, HeartbeatType),
@@ -1016,9 +1956,38 @@ pub mod protocol {
         Data(&'a mut [u8]),
         // todo remove relay
         Relay(VirtualAddr, &'a mut [u8]),
+        // from, type, sender session id, ephemeral public key
+        Handshake(VirtualAddr, HandshakeType, SessionId, EphemeralKey),
     }
 
-    pub const UDP_MSG_HEADER_LEN: usize = 4;
+    pub const UDP_MSG_HEADER_LEN: usize = NONCE_LEN + TAG_LEN + 2;
+    const UDP_CIPHERTEXT_START: usize = NONCE_LEN + TAG_LEN;
+
+    // fill the header of the message whose data has been written to out[UDP_MSG_HEADER_LEN..] and encrypt it
+    fn udp_seal<K: Cipher>(
+        key: &K,
+        nonce: Nonce,
+        mode: u8,
+        data_len: usize,
+        out: &mut [u8]
+    ) -> usize {
+        out[..NONCE_LEN].copy_from_slice(&nonce);
+        out[UDP_CIPHERTEXT_START] = MAGIC_NUM;
+        out[UDP_CIPHERTEXT_START + 1] = mode;
+
+        let ret = UDP_MSG_HEADER_LEN + data_len;
+        let (header, ciphertext) = out[..ret].split_at_mut(UDP_CIPHERTEXT_START);
+
+        let ctx = CipherContext {
+            offset: 0,
+            nonce,
+            aad: &[]
+        };
+
+        let tag = key.encrypt(ciphertext, &ctx);
+        header[NONCE_LEN..].copy_from_slice(&tag);
+        ret
+    }
 
     #[allow(unused)]
     #[derive(Clone)]
@@ -1039,97 +2008,104 @@ pub mod protocol {
     impl UdpMsg<'_> {
         pub fn heartbeat_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             addr: VirtualAddr,
             seq: Seq,
             heartbeat_type: HeartbeatType,
             out: &mut [u8],
         ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = HEARTBEAT;
-            out[4..8].copy_from_slice(&addr.octets());
-            out[8..12].copy_from_slice(&seq.to_be_bytes());
-
-            out[12] = heartbeat_type as u8;
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-
-            const RET: usize = UDP_MSG_HEADER_LEN
-            + size_of::<VirtualAddr>()
+            const DATA_SIZE: usize = size_of::<VirtualAddr>()
             + size_of::<Seq>()
             + size_of::<HeartbeatType>();
 
-            key.encrypt(&mut out[2..RET], &ctx);
+            let data = &mut out[UDP_MSG_HEADER_LEN..UDP_MSG_HEADER_LEN + DATA_SIZE];
+            data[0..4].copy_from_slice(&addr.octets());
+            data[4..8].copy_from_slice(&seq.to_be_bytes());
+            data[8] = heartbeat_type as u8;
 
-            RET
+            udp_seal(key, nonce, HEARTBEAT, DATA_SIZE, out)
         }
 
         pub fn data_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             packet_len: usize, 
             out: &mut [u8]
         ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = DATA;
-
-            let ret = UDP_MSG_HEADER_LEN + packet_len;
-
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-            key.encrypt(&mut out[2..ret], &ctx);
-
-            ret
+            udp_seal(key, nonce, DATA, packet_len, out)
         }
 
         pub fn relay_encode<K: Cipher>(
             key: &K,
-            nonce: u16,
+            nonce: Nonce,
             to: VirtualAddr, 
             packet_len: usize, 
             out: &mut [u8]
         ) -> usize {
-            out[0..2].copy_from_slice(&nonce.to_be_bytes());
-            out[2] = MAGIC_NUM;
-            out[3] = RELAY;
-            out[4..8].copy_from_slice(&to.octets());
-
-            let ret = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + packet_len;
+            out[UDP_MSG_HEADER_LEN..UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>()].copy_from_slice(&to.octets());
+            udp_seal(key, nonce, RELAY, size_of::<VirtualAddr>() + packet_len, out)
+        }
 
-            let ctx = CipherContext {
-                offset: 0,
-                nonce
-            };
-            key.encrypt(&mut out[2..ret], &ctx);
+        pub fn handshake_encode<K: Cipher>(
+            key: &K,
+            nonce: Nonce,
+            from: VirtualAddr,
+            handshake_type: HandshakeType,
+            session_id: SessionId,
+            ephemeral_key: &EphemeralKey,
+            out: &mut [u8],
+        ) -> usize {
+            const DATA_SIZE: usize = size_of::<VirtualAddr>()
+            + size_of::<HandshakeType>()
+            + size_of::<SessionId>()
+            + size_of::<EphemeralKey>();
+
+            let data = &mut out[UDP_MSG_HEADER_LEN..UDP_MSG_HEADER_LEN + DATA_SIZE];
+            data[0..4].copy_from_slice(&from.octets());
+            data[4] = handshake_type as u8;
+            data[5..9].copy_from_slice(&session_id.to_be_bytes());
+            data[9..41].copy_from_slice(ephemeral_key);
+
+            udp_seal(key, nonce, HANDSHAKE, DATA_SIZE, out)
+        }
 
-            ret
+        pub fn nonce(packet: &[u8]) -> Option<Nonce> {
+            packet.get(..NONCE_LEN).map(|v| v.try_into().unwrap())
         }
 
-        pub fn decode<'a, K: Cipher>(
+        // authenticate and decrypt the packet in place, the packet is left untouched if the authentication fails with an aead cipher
+        pub fn decrypt<K: Cipher>(
             key: &K,
-            packet: &'a mut [u8]
-        ) -> Result<UdpMsg<'a>> {
-            let nonce_buf: &[u8; 2] = get!(packet, 0..2).try_into().unwrap();
-            let nonce = u16::from_be_bytes(*nonce_buf);
+            packet: &mut [u8]
+        ) -> Result<()> {
+            if packet.len() < UDP_MSG_HEADER_LEN {
+                return Err(anyhow!("invalid udp message"));
+            }
 
-            let packet = &mut packet[2..];
+            let (header, ciphertext) = packet.split_at_mut(UDP_CIPHERTEXT_START);
+            let nonce: Nonce = header[..NONCE_LEN].try_into().unwrap();
+            let tag: Tag = header[NONCE_LEN..].try_into().unwrap();
 
             let ctx = CipherContext {
                 offset: 0,
-                nonce
+                nonce,
+                aad: &[]
             };
-            key.decrypt(packet, &ctx);
 
-            let magic_num = *get!(packet, 0);
-            let mode = *get!(packet, 1);
-            let data = &mut packet[2..];
+            key.decrypt(ciphertext, &ctx, &tag)?;
+            Ok(())
+        }
+
+        // parse a packet after UdpMsg::decrypt, tampered packets must be rejected before parsing
+        pub fn parse(packet: &mut [u8]) -> Result<UdpMsg<'_>> {
+            if packet.len() < UDP_MSG_HEADER_LEN {
+                return Err(anyhow!("invalid udp message"));
+            }
+
+            let ciphertext = &mut packet[UDP_CIPHERTEXT_START..];
+            let magic_num = ciphertext[0];
+            let mode = ciphertext[1];
+            let data = &mut ciphertext[2..];
 
             if magic_num != MAGIC_NUM {
                 return Err(anyhow!("magic number miss match"));
@@ -1162,6 +2138,26 @@ pub mod protocol {
                     };
                     Ok(UdpMsg::Heartbeat(virtual_addr, seq, heartbeat_type))
                 }
+                HANDSHAKE => {
+                    let mut virtual_addr = [0u8; 4];
+                    virtual_addr.copy_from_slice(get!(data, ..4));
+                    let virtual_addr = VirtualAddr::from(virtual_addr);
+
+                    let handshake_type = match *get!(data, 4) {
+                        0 => HandshakeType::Init,
+                        1 => HandshakeType::Resp,
+                        _ => return Err(anyhow!("invalid udp message")),
+                    };
+
+                    let mut session_id = [0u8; 4];
+                    session_id.copy_from_slice(get!(data, 5..9));
+                    let session_id = SessionId::from_be_bytes(session_id);
+
+                    let mut ephemeral_key = [0u8; 32];
+                    ephemeral_key.copy_from_slice(get!(data, 9..41));
+
+                    Ok(UdpMsg::Handshake(virtual_addr, handshake_type, session_id, ephemeral_key))
+                }
                 _ => Err(anyhow!("invalid udp message")),
             }
         }
@@ -1230,3 +2226,185 @@ pub mod protocol {
         }
     }
 }
+
+#[test]
+fn replay_window_test() {
+    let mut window = ReplayWindow::new();
+
+    assert!(window.check(5000));
+    assert!(!window.check(5000));
+    assert!(window.check(4990));
+    assert!(!window.check(4990));
+    assert!(window.check(5001));
+    assert!(window.check(6500));
+    assert!(!window.check(5001));
+    assert!(window.check(6000));
+
+    let mut window = ReplayWindow::with_floor(5000);
+    assert!(!window.check(5000));
+    assert!(!window.check(4999));
+    assert!(window.check(5001));
+}
+
+#[test]
+fn hello_negotiate_test() {
+    use protocol::*;
+
+    let local = Hello::current();
+
+    let remote = Hello {
+        version: PROTOCOL_VERSION + 1,
+        capabilities: CAP_IPV6 | 1 << 31,
+    };
+    let res = local.negotiate(&remote).unwrap();
+    assert_eq!(res.version, PROTOCOL_VERSION);
+    assert!(res.has(CAP_IPV6));
+    assert!(!res.has(CAP_P2P_SESSION));
+
+    let remote = Hello {
+        version: v2::VERSION,
+        capabilities: CAP_P2P_SESSION | CAP_IPV6,
+    };
+    let res = local.negotiate(&remote).unwrap();
+    assert_eq!(res.version, v2::VERSION);
+    assert!(!res.has(CAP_UDP_COUNTER));
+
+    let remote = Hello {
+        version: MIN_PROTOCOL_VERSION - 1,
+        capabilities: CAP_ALL,
+    };
+    assert!(local.negotiate(&remote).is_err());
+}
+
+#[test]
+fn v2_message_test() {
+    use protocol::*;
+    use crate::common::cipher::{Cipher, CipherContext, CipherEnum, CipherType, Nonce};
+
+    let key = CipherEnum::new(CipherType::Xor, b"key");
+    let mut buff = vec![0u8; 1024];
+
+    let reg = Register {
+        node_name: String::from("node"),
+        virtual_addr: VirtualAddr::new(10, 0, 0, 2),
+        virtual_addr_v6: None,
+        lan_udp_socket_addr: None,
+        proto_mod: ProtocolMode::default(),
+        allowed_ips: vec!["192.168.1.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()],
+        register_time: 0,
+        nonce: 0,
+        udp_counter: 100,
+        signature: None
+    };
+
+    // the ipv6 ranges and the udp counter are not sent to version 2
+    let len = TcpMsg::register_encode(&key, rand::random(), v2::VERSION, &reg, &mut buff).unwrap();
+    let decoded = match TcpMsg::decode_frame(&key, &mut buff[..len]).unwrap() {
+        TcpMsg::Register(reg) => reg,
+        _ => panic!("unexpected message"),
+    };
+    assert_eq!(decoded.allowed_ips, reg.allowed_ips[..1]);
+    assert_eq!(decoded.udp_counter, 0);
+    assert_eq!(decoded.signed_content(v2::VERSION).unwrap(), reg.signed_content(v2::VERSION).unwrap());
+    assert_ne!(reg.signed_content(v2::VERSION).unwrap(), reg.signed_content(PROTOCOL_VERSION).unwrap());
+
+    let len = TcpMsg::register_res_encode(&key, rand::random(), v2::VERSION, &Err(RegisterError::Banned), &mut buff).unwrap();
+    assert!(matches!(TcpMsg::decode_frame(&key, &mut buff[..len]).unwrap(), TcpMsg::RegisterRes(Err(RegisterError::Unauthorized))));
+
+    let len = TcpMsg::get_idle_virtual_addr_encode(&key, rand::random(), None, &mut buff).unwrap();
+    assert!(matches!(TcpMsg::decode_frame(&key, &mut buff[..len]).unwrap(), TcpMsg::GetIdleVirtualAddr(None)));
+
+    // |NONCE 2|MAGIC NUM|PACKET TYPE|LENGTH 2| of the nodes before hello
+    let mut header = [0x12, 0x34, MAGIC_NUM, v2::REGISTER, 0, 100];
+    let mut nonce = Nonce::default();
+    nonce[..2].copy_from_slice(&header[..2]);
+    key.encrypt(&mut header[2..], &CipherContext { offset: 0, nonce, aad: &[] });
+    assert!(TcpMsg::is_legacy_header(&key, &header));
+
+    let len = TcpMsg::hello_encode(&key, rand::random(), &Hello::current(), &mut buff).unwrap();
+    assert!(!TcpMsg::is_legacy_header(&key, buff[..len][..LEGACY_HEADER_LEN].try_into().unwrap()));
+}
+
+#[test]
+fn quic_relay_test() {
+    use protocol::*;
+    use crate::common::cipher::{CipherEnum, CipherType};
+
+    let mode = ProtocolMode {
+        p2p: NetProtocols::from_iter([NetProtocol::UDP]),
+        relay: NetProtocols::from([NetProtocol::QUIC, NetProtocol::UDP, NetProtocol::TCP]),
+    };
+    assert!(mode.is_use_quic());
+    assert_eq!(mode.without_quic().relay.as_slice(), &[NetProtocol::TCP, NetProtocol::UDP]);
+
+    let key = CipherEnum::new(CipherType::ChaCha20Poly1305, b"key");
+    let dst = VirtualAddr::new(10, 0, 0, 2);
+    let mut frame = vec![0u8; TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + 3];
+    frame[TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>()..].copy_from_slice(&[1, 2, 3]);
+    let len = TcpMsg::relay_encode(&key, [7u8; 12], dst, 3, &mut frame);
+    assert_eq!(len, frame.len());
+
+    assert!(TcpMsg::decode_frame(&key, &mut frame.clone()[..len - 1]).is_err());
+
+    match TcpMsg::decode_frame(&key, &mut frame).unwrap() {
+        TcpMsg::Relay(to, packet) => {
+            assert_eq!(to, dst);
+            assert_eq!(packet, &[1, 2, 3]);
+        }
+        _ => panic!("unexpected message"),
+    }
+}
+
+#[test]
+fn flow_control_test() {
+    let rules = vec![
+        FlowControlRule {
+            cidr: "10.0.0.0/24".parse().unwrap(),
+            upload: None,
+            download: Some(1000),
+            burst: Some(3000),
+            max_queue_delay: None,
+        },
+        FlowControlRule {
+            cidr: "10.0.0.2/32".parse().unwrap(),
+            upload: Some(1000),
+            download: None,
+            burst: None,
+            max_queue_delay: Some(Duration::from_secs(2)),
+        },
+    ];
+
+    let fc = FlowControl::new(rules);
+    let a = Ipv4Addr::new(10, 0, 0, 1);
+    let b = Ipv4Addr::new(10, 0, 0, 2);
+    fc.add_address(a);
+    fc.add_address(b);
+
+    let rejected = std::cell::RefCell::new(Vec::new());
+    let report = |addr, direction| rejected.borrow_mut().push((addr, direction));
+
+    // burst, a packet larger than the bucket passes while the bucket is full
+    assert_eq!(fc.push(None, a, 5000, report), PushResult::Accept);
+    assert_eq!(fc.push(None, a, 100, report), PushResult::Reject);
+    assert_eq!(fc.push(None, a, 100, report), PushResult::Reject);
+
+    // b only has an upload limit, queued instead of dropped
+    assert_eq!(fc.push(Some(b), b, 1000, report), PushResult::Accept);
+    assert!(matches!(fc.push(Some(b), b, 1000, report), PushResult::Delay(d) if d > Duration::from_millis(900)));
+    assert!(matches!(fc.push(Some(b), b, 1000, report), PushResult::Delay(d) if d > Duration::from_millis(1900)));
+    assert_eq!(fc.push(Some(b), b, 1000, report), PushResult::Reject);
+
+    // the second rejection of a is not reported
+    assert_eq!(*rejected.borrow(), [(a, "download"), (b, "upload")]);
+}
+
+#[test]
+fn domain_test() {
+    assert!(is_valid_domain("corp"));
+    assert!(is_valid_domain("dev-1.example.com"));
+    assert!(!is_valid_domain(""));
+    assert!(!is_valid_domain("a..com"));
+    assert!(!is_valid_domain("-a.com"));
+    assert!(!is_valid_domain("x'; Remove-Item C:\\"));
+    assert!(!is_valid_domain("x;calc"));
+}
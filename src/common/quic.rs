@@ -0,0 +1,189 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::body::Bytes;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, TransportConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::common::api;
+use crate::common::net::SocketExt;
+
+const ALPN: &[u8] = b"fubuki";
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+    Arc::new(config)
+}
+
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<quinn::ServerConfig> {
+    let mut tls = Arc::unwrap_or_clone(api::tls_server_config(cert_path, key_path)?);
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// cert_path pins the server certificate, without it any certificate is accepted,
+// the messages are still encrypted with the group key
+pub fn client_config(cert_path: Option<&Path>) -> Result<quinn::ClientConfig> {
+    let mut tls = api::pinned_client_config(cert_path)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+pub async fn connect(
+    server_addr: SocketAddr,
+    server_name: &str,
+    config: quinn::ClientConfig,
+    socket_bind_device: Option<&str>
+) -> Result<Connection> {
+    let bind_addr = if server_addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = std::net::UdpSocket::bind(bind_addr)?;
+    socket.set_nonblocking(true)?;
+
+    if let Some(device) = socket_bind_device {
+        SocketExt::bind_device(&socket, device, server_addr.is_ipv6())?;
+    }
+
+    // the endpoint is closed when the connection is dropped
+    let endpoint = Endpoint::new(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?;
+    let conn = endpoint.connect_with(config, server_addr, server_name)?.await?;
+    Ok(conn)
+}
+
+// false if the frame exceeds the datagram size of the path, it should go through the stream instead
+pub fn send_datagram(conn: &Connection, frame: &[u8]) -> Result<bool> {
+    if conn.max_datagram_size().is_none_or(|max| frame.len() > max) {
+        return Ok(false);
+    }
+
+    conn.send_datagram(Bytes::copy_from_slice(frame))?;
+    Ok(true)
+}
+
+// the control channel between the node and the server,
+// a quic connection also carries the relay packets as datagrams
+pub enum ControlStream {
+    Tcp(TcpStream),
+    Quic(Connection, SendStream, RecvStream),
+}
+
+pub enum ControlReader {
+    Tcp(OwnedReadHalf),
+    Quic(RecvStream),
+}
+
+pub enum ControlWriter {
+    Tcp(OwnedWriteHalf),
+    Quic(SendStream),
+}
+
+impl ControlStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ControlStream::Tcp(stream) => stream.peer_addr(),
+            ControlStream::Quic(conn, _, _) => Ok(conn.remote_address()),
+        }
+    }
+
+    pub fn connection(&self) -> Option<&Connection> {
+        match self {
+            ControlStream::Tcp(_) => None,
+            ControlStream::Quic(conn, _, _) => Some(conn),
+        }
+    }
+
+    pub fn into_split(self) -> (ControlReader, ControlWriter) {
+        match self {
+            ControlStream::Tcp(stream) => {
+                let (rx, tx) = stream.into_split();
+                (ControlReader::Tcp(rx), ControlWriter::Tcp(tx))
+            }
+            ControlStream::Quic(_, tx, rx) => (ControlReader::Quic(rx), ControlWriter::Quic(tx)),
+        }
+    }
+}
+
+impl AsyncRead for ControlStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ControlStream::Quic(_, _, rx) => Pin::new(rx).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ControlStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ControlStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ControlStream::Quic(_, tx, _) => AsyncWrite::poll_write(Pin::new(tx), cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ControlStream::Quic(_, tx, _) => Pin::new(tx).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ControlStream::Quic(_, tx, _) => Pin::new(tx).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for ControlReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlReader::Tcp(rx) => Pin::new(rx).poll_read(cx, buf),
+            ControlReader::Quic(rx) => Pin::new(rx).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ControlWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ControlWriter::Tcp(tx) => Pin::new(tx).poll_write(cx, buf),
+            ControlWriter::Quic(tx) => AsyncWrite::poll_write(Pin::new(tx), cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlWriter::Tcp(tx) => Pin::new(tx).poll_flush(cx),
+            ControlWriter::Quic(tx) => Pin::new(tx).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlWriter::Tcp(tx) => Pin::new(tx).poll_shutdown(cx),
+            ControlWriter::Quic(tx) => Pin::new(tx).poll_shutdown(cx),
+        }
+    }
+}
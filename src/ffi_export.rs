@@ -1,12 +1,13 @@
 use std::ffi::{c_char, c_void, CStr, CString};
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::ptr::null_mut;
 use std::slice;
-use std::sync::{Arc, OnceLock};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
 use tokio::runtime::Runtime;
 use tokio::sync::oneshot;
 use crate::node::{generic_interfaces_info, Interface};
@@ -17,18 +18,27 @@ use crate::tun::TunDevice;
 const FUBUKI_START_OPTIONS_VERSION1: u32 = 1;
 const FUBUKI_START_OPTIONS_VERSION2: u32 = 2;
 const FUBUKI_START_OPTIONS_VERSION3: u32 = 3;
+const FUBUKI_START_OPTIONS_VERSION4: u32 = 4;
 
 const FUBUKI_FLAG_NO_AUTO_SPAWN: u64 = 0x0001;
 
+const NO_CONFIG_LOADER: Option<fn() -> Result<NodeConfigFinalize<Key>>> = None;
+
 type FubukiToIfFn = extern "C" fn(packet: *const u8, len: usize, ctx: *mut c_void);
 type AddAddrFn = extern "C" fn(addr: u32, netmask: u32, ctx: *mut c_void);
 type DeleteAddrFn = extern "C" fn(addr: u32, netmask: u32, ctx: *mut c_void);
+// addr points to 16 bytes in network order
+type AddAddrV6Fn = extern "C" fn(addr: *const u8, prefix_len: u8, ctx: *mut c_void);
+type DeleteAddrV6Fn = extern "C" fn(addr: *const u8, prefix_len: u8, ctx: *mut c_void);
 
 struct Bridge {
     ctx: *mut c_void,
     fubuki_to_if_fn: FubukiToIfFn,
     add_addr_fn: AddAddrFn,
     delete_addr_fn: DeleteAddrFn,
+    // set by the hosts using the options version 4
+    add_addr_v6_fn: Option<AddAddrV6Fn>,
+    delete_addr_v6_fn: Option<DeleteAddrV6Fn>,
     if_to_fubuki_rx: flume::Receiver<Bytes>,
     device_index: u32,
 }
@@ -59,13 +69,34 @@ impl TunDevice for Bridge {
         Ok(())
     }
 
-    fn add_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
-        (self.add_addr_fn)(u32::from(addr), u32::from(netmask), self.ctx);
+    fn add_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
+        match (addr, netmask) {
+            (IpAddr::V4(addr), IpAddr::V4(netmask)) => (self.add_addr_fn)(u32::from(addr), u32::from(netmask), self.ctx),
+            (IpAddr::V6(addr), IpAddr::V6(netmask)) => {
+                let prefix_len = ipnet::ipv6_mask_to_prefix(netmask)?;
+
+                match self.add_addr_v6_fn {
+                    Some(f) => f(addr.octets().as_ptr(), prefix_len, self.ctx),
+                    None => warn!("ffi: add_addr_v6_fn is not set, ipv6 address {} is not added", addr)
+                }
+            }
+            _ => return Err(anyhow!("address {} and netmask {} are of different families", addr, netmask))
+        }
         Ok(())
     }
 
-    fn delete_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
-        (self.delete_addr_fn)(u32::from(addr), u32::from(netmask), self.ctx);
+    fn delete_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
+        match (addr, netmask) {
+            (IpAddr::V4(addr), IpAddr::V4(netmask)) => (self.delete_addr_fn)(u32::from(addr), u32::from(netmask), self.ctx),
+            (IpAddr::V6(addr), IpAddr::V6(netmask)) => {
+                let prefix_len = ipnet::ipv6_mask_to_prefix(netmask)?;
+
+                if let Some(f) = self.delete_addr_v6_fn {
+                    f(addr.octets().as_ptr(), prefix_len, self.ctx);
+                }
+            }
+            _ => return Err(anyhow!("address {} and netmask {} are of different families", addr, netmask))
+        }
         Ok(())
     }
 
@@ -87,7 +118,7 @@ pub extern "C" fn if_to_fubuki(handle: *const Handle, packet: *const u8, len: us
 pub struct Handle {
     _rt: Option<Runtime>,
     if_to_fubuki_tx: Option<flume::Sender<Bytes>>,
-    interfaces: Arc<OnceLock<Vec<Arc<Interface<Key>>>>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<Key>>>>>,
     node_start_fut: Option<Pin<Box<dyn Future<Output = Result<()>> + Send + 'static>>>,
     stop_flag: (Option<oneshot::Sender<()>>, Option<oneshot::Receiver<()>>)
 }
@@ -105,6 +136,7 @@ fn fubuki_init_inner(
     fubuki_to_if_fn: FubukiToIfFn,
     add_addr_fn: AddAddrFn,
     delete_addr_fn: DeleteAddrFn,
+    v6_fns: (Option<AddAddrV6Fn>, Option<DeleteAddrV6Fn>),
     device_index: u32,
     no_auto_spawn: bool
 ) -> Result<Handle> {
@@ -118,12 +150,15 @@ fn fubuki_init_inner(
         fubuki_to_if_fn,
         add_addr_fn,
         delete_addr_fn,
+        add_addr_v6_fn: v6_fns.0,
+        delete_addr_v6_fn: v6_fns.1,
         device_index,
         if_to_fubuki_rx: rx,
     };
 
-    let interfaces_hook = Arc::new(OnceLock::new());
-    let start_fut = node::start(c, bridge, interfaces_hook.clone());
+    let interfaces_hook = Arc::new(ArcSwap::from_pointee(Vec::new()));
+    // the config is passed as a string, reloading is not supported
+    let start_fut = node::start(c, bridge, interfaces_hook.clone(), NO_CONFIG_LOADER);
 
     let h = if no_auto_spawn {
         Handle {
@@ -168,7 +203,7 @@ fn fubuki_init_with_tun(
     let c = parse_config(node_config_json)?;
     logger_init()?;
 
-    let interfaces_hook = Arc::new(OnceLock::new());
+    let interfaces_hook = Arc::new(ArcSwap::from_pointee(Vec::new()));
 
     let start_fut = {
         let ih = interfaces_hook.clone();
@@ -176,7 +211,7 @@ fn fubuki_init_with_tun(
         async move {
             // creating AsyncTun must be in the tokio runtime
             let tun = crate::tun::create(tun_fd).context("failed to create tun")?;
-            node::start(c, tun, ih).await
+            node::start(c, tun, ih, NO_CONFIG_LOADER).await
         }
     };
     let h = if no_auto_spawn {
@@ -220,7 +255,9 @@ pub struct FubukiStartOptions {
     add_addr_fn: AddAddrFn,
     delete_addr_fn: DeleteAddrFn,
     tun_fd: i32,
-    flags: u64
+    flags: u64,
+    add_addr_v6_fn: Option<AddAddrV6Fn>,
+    delete_addr_v6_fn: Option<DeleteAddrV6Fn>,
 }
 
 #[no_mangle]
@@ -239,6 +276,7 @@ pub extern "C" fn fubuki_start(
                 options.fubuki_to_if_fn,
                 options.add_addr_fn,
                 options.delete_addr_fn,
+                (None, None),
                 options.device_index,
                 false
             )
@@ -258,25 +296,33 @@ pub extern "C" fn fubuki_start(
                 options.fubuki_to_if_fn,
                 options.add_addr_fn,
                 options.delete_addr_fn,
+                (None, None),
                 options.device_index,
                 false
             )
         } 
         #[cfg(any(target_os = "android", target_os = "ios"))]
-        FUBUKI_START_OPTIONS_VERSION3 if options.tun_fd != 0 => {
+        FUBUKI_START_OPTIONS_VERSION3 | FUBUKI_START_OPTIONS_VERSION4 if options.tun_fd != 0 => {
             fubuki_init_with_tun(
                 options.node_config_json,
                 options.tun_fd as std::os::fd::RawFd,
                 (options.flags & FUBUKI_FLAG_NO_AUTO_SPAWN) != 0
             )
         }
-        FUBUKI_START_OPTIONS_VERSION3 => {
+        FUBUKI_START_OPTIONS_VERSION3 | FUBUKI_START_OPTIONS_VERSION4 => {
+            // the ipv6 callbacks are not part of the older options
+            let v6_fns = match version {
+                FUBUKI_START_OPTIONS_VERSION4 => (options.add_addr_v6_fn, options.delete_addr_v6_fn),
+                _ => (None, None)
+            };
+
             fubuki_init_inner(
                 options.node_config_json,
                 options.ctx,
                 options.fubuki_to_if_fn,
                 options.add_addr_fn,
                 options.delete_addr_fn,
+                v6_fns,
                 options.device_index,
                 (options.flags & FUBUKI_FLAG_NO_AUTO_SPAWN) != 0
             )
@@ -9,26 +9,31 @@
 extern crate log;
 
 use std::ffi::c_void;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, OnceLock};
+use std::sync::Arc;
 use std::time::Duration;
 
-use ahash::HashMap;
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
 use anyhow::{anyhow, Context as AnhyowContext, Result};
+use arc_swap::ArcSwap;
 use clap::{Parser, Subcommand};
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use common::allocator::Bytes;
 use gethostname::gethostname;
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::LevelFilter;
 use node::{Direction, Interface};
-use serde::{de, Deserialize};
+use serde::{de, Deserialize, Serialize};
 use tokio::runtime::Runtime;
 
-use crate::common::cipher::{Cipher, CipherEnum, NoOpCipher, XorCipher};
-use crate::common::net::get_interface_addr;
-use crate::common::net::protocol::{NetProtocol, ProtocolMode, SERVER_VIRTUAL_ADDR, VirtualAddr};
+use crate::common::acl::{Acl, AclProtocol, AclRule, AclTarget};
+use crate::common::api::ApiClient;
+use crate::common::cipher::{Cipher, CipherEnum, CipherType, NoOpCipher};
+use crate::common::identity;
+use crate::common::net::{get_interface_addr, is_valid_domain, FlowControlRule};
+use crate::common::net::protocol::{NetProtocol, ProtocolMode, PushedConfig, SERVER_VIRTUAL_ADDR, VirtualAddr};
 
 #[macro_use]
 mod common;
@@ -50,8 +55,14 @@ mod ffi_export;
 
 type Key = CipherEnum;
 
+// node identities of the groups without an ipv4 range, never assigned to the tun
+const IDENTITY_RANGE: Ipv4Net = match Ipv4Net::new(Ipv4Addr::new(100, 64, 0, 0), 10) {
+    Ok(v) => v,
+    Err(_) => unreachable!(),
+};
+
 pub struct Context<K> {
-    interfaces: Option<Arc<OnceLock<Vec<Arc<Interface<K>>>>>>,
+    interfaces: Option<Arc<ArcSwap<Vec<Arc<Interface<K>>>>>>,
     send_packet_chan: Option<flume::Sender<(Direction, Bytes)>>
 }
 
@@ -68,22 +79,350 @@ struct Group {
     name: String,
     listen_addr: SocketAddr,
     key: Option<String>,
-    address_range: Ipv4Net,
-    flow_control_rules: Option<Vec<(Ipv4Net, byte_unit::Byte)>>,
+    cipher: Option<CipherType>,
+    // node name -> hex encoded ed25519 public key
+    identities: Option<HashMap<String, String>>,
+    // one of address_range and address_range_v6 is required
+    address_range: Option<Ipv4Net>,
+    // nodes also get an ipv6 address from this prefix
+    address_range_v6: Option<Ipv6Net>,
+    // node name -> fixed address
+    reservations: Option<HashMap<String, VirtualAddr>>,
+    flow_control_rules: Option<Vec<FlowControlRuleConfig>>,
+    relay_quotas: Option<Vec<RelayQuota>>,
+    // pushed to the nodes, gateway node address -> destination ranges, same as ips of the node config
+    routes: Option<HashMap<IpAddr, Vec<IpNet>>>,
+    dns_servers: Option<Vec<IpAddr>>,
+    search_domains: Option<Vec<String>>,
+    // enforced by the server on the relay paths and by the nodes on p2p traffic
+    acl: Option<AclConfig>,
+    // nodes may connect over quic instead of tcp
+    quic: Option<QuicConfig>,
     allow_udp_relay: Option<bool>,
+    // also covers the quic relay
     allow_tcp_relay: Option<bool>
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct RelayQuota {
+    // one of node and cidr
+    node: Option<String>,
+    cidr: Option<Ipv4Net>,
+    limit: byte_unit::Byte,
+    period: QuotaPeriod,
+    // byte/s after the quota is used up, relay stops if not set
+    exceeded_rate: Option<byte_unit::Byte>,
+}
+
+#[derive(Clone)]
+enum QuotaTarget {
+    Node(String),
+    // each node in the range has its own quota
+    Cidr(Ipv4Net),
+}
+
+#[derive(Clone)]
+struct RelayQuotaFinalize {
+    target: QuotaTarget,
+    limit: u64,
+    period: QuotaPeriod,
+    exceeded_rate: Option<u64>,
+}
+
+impl TryFrom<RelayQuota> for RelayQuotaFinalize {
+    type Error = anyhow::Error;
+
+    fn try_from(value: RelayQuota) -> std::result::Result<Self, Self::Error> {
+        let target = match (value.node, value.cidr) {
+            (Some(node), None) => QuotaTarget::Node(node),
+            (None, Some(cidr)) => QuotaTarget::Cidr(cidr),
+            _ => return Err(anyhow!("relay quota requires exactly one of node and cidr"))
+        };
+
+        if value.exceeded_rate.is_some_and(|v| v.as_u64() == 0) {
+            return Err(anyhow!("relay quota exceeded_rate must be greater than 0"));
+        }
+
+        let quota = RelayQuotaFinalize {
+            target,
+            limit: value.limit.as_u64(),
+            period: value.period,
+            exceeded_rate: value.exceeded_rate.map(|v| v.as_u64()),
+        };
+        Ok(quota)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct AclConfig {
+    // tag -> node names
+    tags: Option<HashMap<String, Vec<String>>>,
+    rules: Vec<AclRuleConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct AclRuleConfig {
+    // "*", "tag:{tag}", cidr or address, otherwise node name
+    src: Vec<String>,
+    dst: Vec<String>,
+    protocol: Option<AclProtocol>,
+    // "22" or "8000-8100"
+    ports: Option<Vec<String>>,
+}
+
+fn parse_acl_targets(list: Vec<String>, tags: &HashMap<String, Vec<String>>) -> Result<Vec<AclTarget>> {
+    let mut targets = Vec::with_capacity(list.len());
+
+    for item in list {
+        if item == "*" {
+            targets.push(AclTarget::Any);
+            continue;
+        }
+
+        if let Some(tag) = item.strip_prefix("tag:") {
+            let nodes = tags.get(tag).ok_or_else(|| anyhow!("acl tag {} is not defined", tag))?;
+            targets.extend(nodes.iter().cloned().map(AclTarget::Node));
+            continue;
+        }
+
+        let target = match (IpNet::from_str(&item), IpAddr::from_str(&item)) {
+            (Ok(cidr), _) => AclTarget::Cidr(cidr.trunc()),
+            (_, Ok(addr)) => AclTarget::Cidr(IpNet::from(addr)),
+            _ => AclTarget::Node(item),
+        };
+        targets.push(target);
+    }
+    Ok(targets)
+}
+
+fn parse_port_range(s: &str) -> Result<(u16, u16)> {
+    let (start, end) = match s.split_once('-') {
+        None => {
+            let port = u16::from_str(s.trim())?;
+            (port, port)
+        }
+        Some((start, end)) => (u16::from_str(start.trim())?, u16::from_str(end.trim())?)
+    };
+
+    if start > end {
+        return Err(anyhow!("invalid port range {}", s));
+    }
+    Ok((start, end))
+}
+
+impl TryFrom<AclConfig> for Acl {
+    type Error = anyhow::Error;
+
+    fn try_from(value: AclConfig) -> std::result::Result<Self, Self::Error> {
+        let tags = value.tags.unwrap_or_default();
+        let mut rules = Vec::with_capacity(value.rules.len());
+
+        for rule in value.rules {
+            let protocol = rule.protocol.unwrap_or_default();
+
+            let ports = rule.ports
+                .unwrap_or_default()
+                .iter()
+                .map(|s| parse_port_range(s).with_context(|| format!("acl port {} error", s)))
+                .collect::<Result<Vec<_>>>()?;
+
+            if protocol == AclProtocol::Icmp && !ports.is_empty() {
+                return Err(anyhow!("acl ports can't be used with the icmp protocol"));
+            }
+
+            let rule = AclRule {
+                src: parse_acl_targets(rule.src, &tags)?,
+                dst: parse_acl_targets(rule.dst, &tags)?,
+                protocol,
+                ports,
+            };
+            rules.push(rule);
+        }
+        Ok(Acl { rules })
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum FlowControlRuleConfig {
+    // [address range, download limit], the format before token buckets
+    Legacy(Ipv4Net, byte_unit::Byte),
+    Rule(FlowControlRuleItem),
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct FlowControlRuleItem {
+    cidr: Ipv4Net,
+    // per node byte/s
+    upload: Option<byte_unit::Byte>,
+    download: Option<byte_unit::Byte>,
+    burst: Option<byte_unit::Byte>,
+    max_queue_delay_ms: Option<u64>,
+}
+
+impl TryFrom<FlowControlRuleConfig> for FlowControlRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: FlowControlRuleConfig) -> std::result::Result<Self, Self::Error> {
+        let item = match value {
+            FlowControlRuleConfig::Legacy(cidr, limit) => FlowControlRuleItem {
+                cidr,
+                upload: None,
+                download: Some(limit),
+                burst: None,
+                max_queue_delay_ms: None,
+            },
+            FlowControlRuleConfig::Rule(item) => item
+        };
+
+        if item.upload.is_none() && item.download.is_none() {
+            return Err(anyhow!("flow control rule {} has neither upload nor download limit", item.cidr));
+        }
+
+        let limits = [item.upload, item.download, item.burst];
+
+        if limits.iter().flatten().any(|v| v.as_u64() == 0) {
+            return Err(anyhow!("flow control rule {} limit must be greater than 0", item.cidr));
+        }
+
+        let rule = FlowControlRule {
+            cidr: item.cidr,
+            upload: item.upload.map(|v| v.as_u64()),
+            download: item.download.map(|v| v.as_u64()),
+            burst: item.burst.map(|v| v.as_u64()),
+            max_queue_delay: item.max_queue_delay_ms.map(Duration::from_millis),
+        };
+        Ok(rule)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct ApiTlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct QuicConfig {
+    // udp address, different from the listen address of the group
+    listen_addr: SocketAddr,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+#[derive(Clone)]
+struct QuicFinalize {
+    listen_addr: SocketAddr,
+    // certificate files as configured, the group restarts on reload if they change
+    files: (PathBuf, PathBuf),
+    server_config: quinn::ServerConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct ClusterConfig {
+    listen_addr: SocketAddr,
+    key: String,
+    // aead only, ChaCha20Poly1305 by default
+    cipher: Option<CipherType>,
+    // cluster listen addresses of the other servers
+    peers: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum WebhookEvent {
+    NodeRegistered,
+    NodeDisconnected,
+    // only the registrations rejected for nonce repeat or timeout
+    RegisterFailed,
+    QuotaExceeded,
+    FlowControlRejected,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct WebhookConfig {
+    url: String,
+    // sent as Authorization: Bearer {token}
+    token: Option<String>,
+    // every event if not set
+    events: Option<Vec<WebhookEvent>>,
+    max_retries: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct WebhookFinalize {
+    url: hyper::Uri,
+    token: Option<String>,
+    events: Vec<WebhookEvent>,
+    max_retries: u32,
+}
+
+impl TryFrom<WebhookConfig> for WebhookFinalize {
+    type Error = anyhow::Error;
+
+    fn try_from(config: WebhookConfig) -> std::result::Result<Self, Self::Error> {
+        let url: hyper::Uri = config.url.parse().with_context(|| format!("invalid webhook url {}", config.url))?;
+
+        if !matches!(url.scheme_str(), Some("http" | "https")) || url.host().is_none() {
+            return Err(anyhow!("webhook url {} must be an http or https url", config.url));
+        }
+
+        let webhook = WebhookFinalize {
+            url,
+            token: config.token,
+            events: config.events.unwrap_or_else(|| vec![
+                WebhookEvent::NodeRegistered,
+                WebhookEvent::NodeDisconnected,
+                WebhookEvent::RegisterFailed,
+                WebhookEvent::QuotaExceeded,
+                WebhookEvent::FlowControlRejected,
+            ]),
+            max_retries: config.max_retries.unwrap_or(5),
+        };
+        Ok(webhook)
+    }
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 struct ServerConfig {
     channel_limit: Option<usize>,
     api_addr: Option<SocketAddr>,
+    // bearer token required by the api
+    api_token: Option<String>,
+    api_tls: Option<ApiTlsConfig>,
     tcp_heartbeat_interval_secs: Option<u64>,
     tcp_heartbeat_continuous_loss: Option<u64>,
     udp_heartbeat_interval_secs: Option<u64>,
     udp_heartbeat_continuous_loss: Option<u64>,
     udp_heartbeat_continuous_recv: Option<u64>,
+    // node name -> address bindings are persisted to this file
+    lease_file: Option<PathBuf>,
+    lease_duration_secs: Option<u64>,
+    // relay quota usage is persisted to this file
+    quota_file: Option<PathBuf>,
+    // bans are persisted to this file
+    ban_file: Option<PathBuf>,
+    // identities revoked through the api are persisted to this file
+    revocation_file: Option<PathBuf>,
+    cluster: Option<ClusterConfig>,
+    // membership events are posted to these urls
+    webhooks: Option<Vec<WebhookConfig>>,
     groups: Vec<Group>,
 }
 
@@ -92,22 +431,68 @@ struct GroupFinalize<K> {
     name: String,
     listen_addr: SocketAddr,
     key: K,
+    // key and cipher as configured, the group restarts on reload if they change
+    key_config: (Option<String>, Option<CipherType>),
+    identities: Option<HashMap<String, VerifyingKey>>,
     address_range: Ipv4Net,
-    flow_control_rules: Vec<(Ipv4Net, u64)>,
+    // false if the group only has an ipv6 range, address_range then only identifies the nodes
+    ipv4: bool,
+    address_range_v6: Option<Ipv6Net>,
+    reservations: HashMap<String, VirtualAddr>,
+    flow_control_rules: Vec<FlowControlRule>,
+    relay_quotas: Vec<RelayQuotaFinalize>,
+    pushed: PushedConfig,
+    quic: Option<QuicFinalize>,
     allow_udp_relay: bool,
     allow_tcp_relay: bool
 }
 
+#[derive(Clone)]
+struct ClusterConfigFinalize<K> {
+    listen_addr: SocketAddr,
+    // seals the hello messages only, each link derives its own key
+    key: K,
+    cipher: CipherType,
+    // key material of the link keys
+    link_secret: [u8; 32],
+    peers: Vec<String>,
+}
+
 #[derive(Clone)]
 struct ServerConfigFinalize<K> {
     channel_limit: usize,
     api_addr: SocketAddr,
+    api_token: Option<String>,
+    api_tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
     tcp_heartbeat_interval: Duration,
     tcp_heartbeat_continuous_loss: u64,
     udp_heartbeat_interval: Duration,
     udp_heartbeat_continuous_loss: u64,
     udp_heartbeat_continuous_recv: u64,
-    groups: Vec<GroupFinalize<K>>,
+    lease_file: Option<PathBuf>,
+    lease_duration: Duration,
+    quota_file: Option<PathBuf>,
+    ban_file: Option<PathBuf>,
+    revocation_file: Option<PathBuf>,
+    cluster: Option<ClusterConfigFinalize<K>>,
+    webhooks: Vec<WebhookFinalize>,
+    groups: Vec<Arc<GroupFinalize<K>>>,
+}
+
+fn build_key(owner: &str, key: Option<&str>, cipher: Option<CipherType>) -> Result<CipherEnum> {
+    match (key, cipher) {
+        (None, None) => Ok(CipherEnum::NoOpCipher(NoOpCipher{})),
+        (None, Some(_)) => Err(anyhow!("cipher requires a key")),
+        (Some(k), cipher) => {
+            let cipher = cipher.unwrap_or(CipherType::Xor);
+
+            // the nonce of a xor message can be forged, so its replays can't be detected
+            if cipher == CipherType::Xor {
+                warn!("{} uses the Xor cipher, udp messages are not checked for replays, use ChaCha20Poly1305 or Aes256Gcm", owner);
+            }
+            Ok(CipherEnum::new(cipher, k.as_bytes()))
+        }
+    }
 }
 
 impl TryFrom<ServerConfig> for ServerConfigFinalize<CipherEnum> {
@@ -119,6 +504,11 @@ impl TryFrom<ServerConfig> for ServerConfigFinalize<CipherEnum> {
             api_addr: config
                 .api_addr
                 .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 3031))),
+            api_token: config.api_token,
+            api_tls: match config.api_tls {
+                None => None,
+                Some(tls) => Some(common::api::tls_server_config(&tls.cert_path, &tls.key_path)?)
+            },
             tcp_heartbeat_interval: config
                 .tcp_heartbeat_interval_secs
                 .map(Duration::from_secs)
@@ -130,36 +520,191 @@ impl TryFrom<ServerConfig> for ServerConfigFinalize<CipherEnum> {
                 .unwrap_or(Duration::from_secs(5)),
             udp_heartbeat_continuous_loss: config.udp_heartbeat_continuous_loss.unwrap_or(5),
             udp_heartbeat_continuous_recv: config.udp_heartbeat_continuous_recv.unwrap_or(3),
+            lease_file: config.lease_file,
+            lease_duration: config
+                .lease_duration_secs
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_secs(86400)),
+            quota_file: config.quota_file,
+            ban_file: config.ban_file,
+            revocation_file: config.revocation_file,
+            cluster: match config.cluster {
+                None => None,
+                Some(cluster) => {
+                    // the cluster link carries the relayed packets and the node maps of all groups
+                    let cipher = cluster.cipher.unwrap_or(CipherType::ChaCha20Poly1305);
+
+                    if cipher == CipherType::Xor {
+                        return Err(anyhow!("cluster cipher must be ChaCha20Poly1305 or Aes256Gcm"));
+                    }
+
+                    let v = ClusterConfigFinalize {
+                        listen_addr: cluster.listen_addr,
+                        key: CipherEnum::new(cipher, cluster.key.as_bytes()),
+                        cipher,
+                        link_secret: blake3::derive_key("fubuki cluster link secret", cluster.key.as_bytes()),
+                        peers: cluster.peers,
+                    };
+                    Some(v)
+                }
+            },
+            webhooks: config.webhooks
+                .unwrap_or_default()
+                .into_iter()
+                .map(WebhookFinalize::try_from)
+                .collect::<Result<_>>()?,
             groups: {
-                let mut list = Vec::with_capacity(config.groups.len());
+                let mut list: Vec<Arc<GroupFinalize<CipherEnum>>> = Vec::with_capacity(config.groups.len());
 
                 for group in config.groups {
+                    if list.iter().any(|g| g.name == group.name) {
+                        return Err(anyhow!("group name {} is used more than once", group.name));
+                    }
+
                     if group.listen_addr.ip().is_loopback() {
                         return Err(anyhow!("listen address can't be a loopback address"));
                     }
 
-                    if group.address_range.contains(&SERVER_VIRTUAL_ADDR) {
+                    let (address_range, ipv4) = match (group.address_range, group.address_range_v6) {
+                        (Some(range), _) => (range, true),
+                        (None, Some(_)) => (IDENTITY_RANGE, false),
+                        (None, None) => return Err(anyhow!("group {} requires an ipv4 or ipv6 address range", group.name)),
+                    };
+
+                    if address_range.contains(&SERVER_VIRTUAL_ADDR) {
                         warn!("{} is used as a special address, should not be contained in the address range", SERVER_VIRTUAL_ADDR)
                     }
 
+                    if let Some(range) = group.address_range_v6 {
+                        if range.prefix_len() >= 127 {
+                            return Err(anyhow!("group {} ipv6 address range {} is too small", group.name, range));
+                        }
+                    }
+
+                    let reservations = group.reservations.unwrap_or_default();
+                    let mut reserved_addrs = HashSet::with_capacity(reservations.len());
+
+                    if !ipv4 && !reservations.is_empty() {
+                        return Err(anyhow!("group {} has no ipv4 address range to reserve addresses from", group.name));
+                    }
+
+                    for (node_name, addr) in &reservations {
+                        let range = address_range;
+
+                        if !range.contains(addr) || range.network() == *addr || range.broadcast() == *addr {
+                            return Err(anyhow!("group {} reserved address {} of node {} is not a host address of {}", group.name, addr, node_name, range));
+                        }
+
+                        if !reserved_addrs.insert(*addr) {
+                            return Err(anyhow!("group {} address {} is reserved more than once", group.name, addr));
+                        }
+                    }
+
+                    let flow_control_rules = group.flow_control_rules
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(FlowControlRule::try_from)
+                        .collect::<Result<Vec<_>>>()
+                        .with_context(|| format!("group {} flow control rules error", group.name))?;
+
+                    let relay_quotas = group.relay_quotas
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(RelayQuotaFinalize::try_from)
+                        .collect::<Result<Vec<_>>>()
+                        .with_context(|| format!("group {} relay quotas error", group.name))?;
+
+                    let mut routes = Vec::new();
+
+                    for (gateway, cidrs) in group.routes.unwrap_or_default() {
+                        let is_host = match gateway {
+                            IpAddr::V4(gateway) => {
+                                ipv4 && address_range.contains(&gateway) && address_range.network() != gateway && address_range.broadcast() != gateway
+                            }
+                            IpAddr::V6(gateway) => {
+                                group.address_range_v6.is_some_and(|range| range.contains(&gateway) && range.network() != gateway)
+                            }
+                        };
+
+                        if !is_host {
+                            return Err(anyhow!("group {} route gateway {} is not a host address of the group", group.name, gateway));
+                        }
+
+                        if let Some(cidr) = cidrs.iter().find(|cidr| cidr.network().is_ipv4() != gateway.is_ipv4()) {
+                            return Err(anyhow!("group {} route {} and gateway {} are of different address families", group.name, cidr, gateway));
+                        }
+
+                        routes.extend(cidrs.into_iter().map(|cidr| (gateway, cidr.trunc())));
+                    }
+
+                    // the same config always produces the same message
+                    routes.sort_unstable();
+                    routes.dedup();
+
+                    if let Some(domain) = group.search_domains.iter().flatten().find(|v| !is_valid_domain(v)) {
+                        return Err(anyhow!("group {} search domain {:?} is invalid", group.name, domain));
+                    }
+
+                    let pushed = PushedConfig {
+                        routes,
+                        dns_servers: group.dns_servers.unwrap_or_default(),
+                        search_domains: group.search_domains.unwrap_or_default(),
+                        acl: group.acl
+                            .map(Acl::try_from)
+                            .transpose()
+                            .with_context(|| format!("group {} acl error", group.name))?,
+                    };
+
+                    let quic = match group.quic {
+                        None => None,
+                        Some(quic) => {
+                            if quic.listen_addr == group.listen_addr {
+                                return Err(anyhow!("group {} quic listen address conflicts with the udp socket", group.name));
+                            }
+
+                            let server_config = common::quic::server_config(&quic.cert_path, &quic.key_path)
+                                .with_context(|| format!("group {} quic config error", group.name))?;
+
+                            Some(QuicFinalize {
+                                listen_addr: quic.listen_addr,
+                                files: (quic.cert_path, quic.key_path),
+                                server_config,
+                            })
+                        }
+                    };
+
+                    let key = build_key(&format!("group {}", group.name), group.key.as_deref(), group.cipher)?;
+
                     let v = GroupFinalize {
                         name: group.name,
                         listen_addr: group.listen_addr,
-                        address_range: group.address_range,
-                        key: {
-                            let key = group.key.as_ref().map(|v| v.as_bytes());
-                            match key {
-                                None => CipherEnum::NoOpCipher(NoOpCipher{}),
-                                Some(k) => CipherEnum::XorCipher(XorCipher::from(k))
+                        address_range,
+                        ipv4,
+                        address_range_v6: group.address_range_v6.map(|v| v.trunc()),
+                        reservations,
+                        key,
+                        key_config: (group.key, group.cipher),
+                        identities: match group.identities {
+                            None => None,
+                            Some(identities) => {
+                                let mut map = HashMap::with_capacity(identities.len());
+
+                                for (node_name, public_key) in identities {
+                                    let public_key = identity::parse_verifying_key(&public_key)
+                                        .with_context(|| format!("node {} identity error", node_name))?;
+                                    map.insert(node_name, public_key);
+                                }
+                                Some(map)
                             }
                         },
-                        flow_control_rules: group.flow_control_rules
-                            .map(|v| v.into_iter().map(|(range, l)| (range, l.as_u64())).collect::<Vec<_>>())
-                            .unwrap_or_default(),
+                        flow_control_rules,
+                        relay_quotas,
+                        pushed,
+                        quic,
                         allow_udp_relay: group.allow_udp_relay.unwrap_or(true),
                         allow_tcp_relay: group.allow_tcp_relay.unwrap_or(true)
                     };
-                    list.push(v);
+                    list.push(Arc::new(v));
                 }
                 list
             },
@@ -169,26 +714,64 @@ impl TryFrom<ServerConfig> for ServerConfigFinalize<CipherEnum> {
     }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 struct TunAddr {
     ip: VirtualAddr,
     netmask: Ipv4Addr,
 }
 
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum ServerAddrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<ServerAddrs> for Vec<String> {
+    fn from(value: ServerAddrs) -> Self {
+        match value {
+            ServerAddrs::One(addr) => vec![addr],
+            ServerAddrs::Many(addrs) => addrs
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+enum ServerSelection {
+    // use the first available server in the configured order
+    #[default]
+    Ordered,
+    // use the available server with the lowest connection latency
+    Latency,
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 struct TargetGroup {
     node_name: Option<String>,
-    server_addr: String,
+    server_addr: ServerAddrs,
+    server_selection: Option<ServerSelection>,
     tun_addr: Option<TunAddr>,
+    tun_addr_v6: Option<Ipv6Addr>,
     key: Option<String>,
+    cipher: Option<CipherType>,
+    // hex encoded ed25519 private key
+    identity_key: Option<String>,
     mode: Option<ProtocolMode>,
     specify_mode: Option<HashMap<VirtualAddr, ProtocolMode>>,
+    // quic port of the servers, required by the QUIC relay mode
+    quic_port: Option<u16>,
+    // pins the quic certificate of the servers
+    quic_cert_path: Option<PathBuf>,
     lan_ip_addr: Option<IpAddr>,
     node_binding_port: Option<u16>,
-    allowed_ips: Option<Vec<Ipv4Net>>,
-    ips: Option<HashMap<VirtualAddr, Vec<Ipv4Net>>>,
+    allowed_ips: Option<Vec<IpNet>>,
+    ips: Option<HashMap<IpAddr, Vec<IpNet>>>,
+    // add the routes pushed by the server
+    accept_pushed_routes: Option<bool>,
+    // also accept pushed default routes and routes that cover the server address
+    accept_pushed_default_route: Option<bool>,
     auto_route_selection: Option<bool>
 }
 
@@ -198,11 +781,15 @@ struct NodeConfig {
     mtu: Option<usize>,
     channel_limit: Option<usize>,
     api_addr: Option<SocketAddr>,
+    // bearer token required by the api
+    api_token: Option<String>,
+    api_tls: Option<ApiTlsConfig>,
     tcp_heartbeat_interval_secs: Option<u64>,
     udp_heartbeat_interval_secs: Option<u64>,
     tcp_heartbeat_continuous_loss: Option<u64>,
     udp_heartbeat_continuous_loss: Option<u64>,
     udp_heartbeat_continuous_recv: Option<u64>,
+    udp_session_rekey_interval_secs: Option<u64>,
     reconnect_interval_secs: Option<u64>,
     udp_socket_recv_buffer_size: Option<usize>,
     udp_socket_send_buffer_size: Option<usize>,
@@ -213,30 +800,76 @@ struct NodeConfig {
     socket_bind_device: Option<String>,
     #[cfg(feature = "cross-nat")]
     cross_nat: Option<bool>,
+    // embedded dns server answering node names, replaces the hosts file records
+    dns: Option<DnsConfig>,
+    // filters the packets from the overlay to the host
+    firewall: Option<FirewallConfig>,
     groups: Vec<TargetGroup>,
     features: Option<NodeConfigFeature>,
 }
 
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct DnsConfig {
+    listen_port: Option<u16>,
+    upstream: Option<Vec<SocketAddr>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum FirewallAction {
+    Accept,
+    Drop,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct FirewallConfig {
+    // action of the packets matching no rule, default drop
+    default_action: Option<FirewallAction>,
+    rules: Vec<FirewallRule>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct FirewallRule {
+    action: FirewallAction,
+    src: Option<Vec<IpNet>>,
+    dst: Option<Vec<IpNet>>,
+    protocol: Option<AclProtocol>,
+    // destination ports, "22" or "8000-8100"
+    ports: Option<Vec<String>>,
+}
+
 #[derive(Deserialize, Clone)]
 struct NodeConfigFeature {
     disable_hosts_operation: Option<bool>,
     disable_signal_handling: Option<bool>,
     disable_route_operation: Option<bool>,
+    disable_dns_operation: Option<bool>,
     disable_api_server: Option<bool>,
 }
 
 #[derive(Clone)]
 struct TargetGroupFinalize<K> {
     node_name: String,
-    server_addr: String,
+    server_addrs: Vec<String>,
+    server_selection: ServerSelection,
     tun_addr: Option<TunAddr>,
+    tun_addr_v6: Option<Ipv6Addr>,
     key: K,
+    key_config: (Option<String>, Option<CipherType>),
+    identity_key: Option<SigningKey>,
     mode: ProtocolMode,
     specify_mode: HashMap<VirtualAddr, ProtocolMode>,
+    quic_port: Option<u16>,
+    quic_cert_path: Option<PathBuf>,
     lan_ip_addr: Option<IpAddr>,
     node_binding_port: u16,
-    allowed_ips: Vec<Ipv4Net>,
-    ips: HashMap<VirtualAddr, Vec<Ipv4Net>>,
+    allowed_ips: Vec<IpNet>,
+    ips: HashMap<IpAddr, Vec<IpNet>>,
+    accept_pushed_routes: bool,
+    accept_pushed_default_route: bool,
     auto_route_selection: bool
 }
 
@@ -245,11 +878,14 @@ struct NodeConfigFinalize<K> {
     mtu: usize,
     channel_limit: usize,
     api_addr: SocketAddr,
+    api_token: Option<String>,
+    api_tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
     tcp_heartbeat_interval: Duration,
     udp_heartbeat_interval: Duration,
     tcp_heartbeat_continuous_loss: u64,
     udp_heartbeat_continuous_loss: u64,
     udp_heartbeat_continuous_recv: u64,
+    udp_session_rekey_interval: Duration,
     reconnect_interval: Duration,
     udp_socket_recv_buffer_size: Option<usize>,
     udp_socket_send_buffer_size: Option<usize>,
@@ -260,119 +896,233 @@ struct NodeConfigFinalize<K> {
     socket_bind_device: Option<String>,
     #[cfg(feature = "cross-nat")]
     cross_nat: bool,
-    groups: Vec<TargetGroupFinalize<K>>,
+    dns: Option<DnsConfigFinalize>,
+    firewall: Option<FirewallConfigFinalize>,
+    groups: Vec<Arc<TargetGroupFinalize<K>>>,
     features: NodeConfigFeatureFinalize,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
+struct DnsConfigFinalize {
+    listen_port: u16,
+    // queries for other names are forwarded in order
+    upstream: Vec<SocketAddr>,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+struct FirewallRuleFinalize {
+    action: FirewallAction,
+    // empty means any address
+    src: Vec<IpNet>,
+    dst: Vec<IpNet>,
+    protocol: AclProtocol,
+    ports: Vec<(u16, u16)>,
+}
+
+#[derive(Clone, PartialEq)]
+struct FirewallConfigFinalize {
+    default_action: FirewallAction,
+    rules: Vec<FirewallRuleFinalize>,
+}
+
+impl TryFrom<FirewallConfig> for FirewallConfigFinalize {
+    type Error = anyhow::Error;
+
+    fn try_from(value: FirewallConfig) -> std::result::Result<Self, Self::Error> {
+        let mut rules = Vec::with_capacity(value.rules.len());
+
+        for rule in value.rules {
+            let protocol = rule.protocol.unwrap_or_default();
+
+            let ports = rule.ports
+                .unwrap_or_default()
+                .iter()
+                .map(|s| parse_port_range(s).with_context(|| format!("firewall port {} error", s)))
+                .collect::<Result<Vec<_>>>()?;
+
+            if protocol == AclProtocol::Icmp && !ports.is_empty() {
+                return Err(anyhow!("firewall ports can't be used with the icmp protocol"));
+            }
+
+            let rule = FirewallRuleFinalize {
+                action: rule.action,
+                src: rule.src.unwrap_or_default().into_iter().map(|v| v.trunc()).collect(),
+                dst: rule.dst.unwrap_or_default().into_iter().map(|v| v.trunc()).collect(),
+                protocol,
+                ports,
+            };
+            rules.push(rule);
+        }
+
+        let config = FirewallConfigFinalize {
+            default_action: value.default_action.unwrap_or(FirewallAction::Drop),
+            rules,
+        };
+        Ok(config)
+    }
+}
+
+#[derive(Clone, PartialEq)]
 struct NodeConfigFeatureFinalize {
     #[allow(unused)]
     disable_hosts_operation: bool,
     disable_signal_handling: bool,
     disable_route_operation: bool,
+    disable_dns_operation: bool,
     disable_api_server: bool,
 }
 
-impl TryFrom<NodeConfig> for NodeConfigFinalize<CipherEnum> {
+impl TryFrom<TargetGroup> for TargetGroupFinalize<CipherEnum> {
     type Error = anyhow::Error;
 
-    fn try_from(config: NodeConfig) -> Result<Self> {
-        let mut list = Vec::with_capacity(config.groups.len());
-        let mut use_ipv6 = false;
-        let mut use_udp = false;
-        #[allow(unused)]
-        let mut use_gateway = false;
+    fn try_from(group: TargetGroup) -> Result<Self> {
+        let mode = group.mode.unwrap_or_default();
 
-        for group in config.groups {
-            let mode = group.mode.unwrap_or_default();
+        if mode.p2p.iter().any(|np| *np != NetProtocol::UDP) {
+            return Err(anyhow!("p2p only support udp protocol"))
+        }
 
-            if mode.p2p.contains(&NetProtocol::TCP) {
-                return Err(anyhow!("p2p only support udp protocol"))
+        if mode.is_use_quic() {
+            if group.quic_port.is_none() {
+                return Err(anyhow!("quic relay requires quic_port"));
             }
 
-            let resolve_server_addr = group
-                .server_addr
-                .to_socket_addrs()?
-                .next()
-                .ok_or_else(|| anyhow!("{} lookup failed", group.server_addr))?;
+            // fail early on a broken certificate
+            common::quic::client_config(group.quic_cert_path.as_deref())?;
 
-            let lan_ip_addr = match group.lan_ip_addr {
-                None => {
-                    get_interface_addr(resolve_server_addr)?
-                }
-                Some(addr) => {
-                    if addr.is_loopback() {
-                        return Err(anyhow!("lan address cannot be a loopback address"));
-                    }
+            if group.quic_cert_path.is_none() {
+                warn!("quic_cert_path is not set, the quic connection accepts any server certificate");
+            }
+        }
 
-                    if addr.is_unspecified() {
-                        return Err(anyhow!("lan address cannot be unspecified address"));
-                    }
-                    addr
+        let server_addrs: Vec<String> = group.server_addr.into();
+
+        if server_addrs.is_empty() {
+            return Err(anyhow!("server address is empty"));
+        }
+
+        let mut resolve_server_addr = None;
+
+        for server_addr in &server_addrs {
+            let addr = match server_addr.to_socket_addrs().map(|mut v| v.next()) {
+                Ok(Some(addr)) => addr,
+                _ => {
+                    warn!("{} lookup failed", server_addr);
+                    continue;
                 }
             };
 
-            if let Some(map) = group.ips.as_ref() {
-                for items in map.values() {
-                    for x in items {
-                        use_gateway |= *x == Ipv4Net::new(Ipv4Addr::UNSPECIFIED, 0).unwrap();
-                    }
+            if addr.ip().is_loopback() {
+                return Err(anyhow!("server address cannot be a loopback address"));
+            }
+
+            resolve_server_addr.get_or_insert(addr);
+        }
+
+        let resolve_server_addr = resolve_server_addr
+            .ok_or_else(|| anyhow!("{} lookup failed", server_addrs.join(", ")))?;
+
+        let lan_ip_addr = match group.lan_ip_addr {
+            None => {
+                get_interface_addr(resolve_server_addr)?
+            }
+            Some(addr) => {
+                if addr.is_loopback() {
+                    return Err(anyhow!("lan address cannot be a loopback address"));
                 }
+
+                if addr.is_unspecified() {
+                    return Err(anyhow!("lan address cannot be unspecified address"));
+                }
+                addr
+            }
+        };
+
+        let ips = group.ips.unwrap_or_default();
+
+        for (gateway, cidrs) in &ips {
+            if let Some(cidr) = cidrs.iter().find(|cidr| cidr.network().is_ipv4() != gateway.is_ipv4()) {
+                return Err(anyhow!("ips route {} and gateway {} are of different address families", cidr, gateway));
+            }
+        }
+
+        let node_name = match group.node_name {
+            None => {
+                gethostname()
+                    .to_str()
+                    .ok_or_else(|| anyhow!("unable to resolve hostname"))?
+                    .to_string()
             }
+            Some(v) => v
+        };
 
-            let group_use_udp = mode.is_use_udp();
+        let key = build_key(&format!("node {}", node_name), group.key.as_deref(), group.cipher)?;
+
+        let group_finalize = TargetGroupFinalize {
+            node_name,
+            server_addrs,
+            server_selection: group.server_selection.unwrap_or_default(),
+            tun_addr: group.tun_addr,
+            tun_addr_v6: group.tun_addr_v6,
+            key,
+            key_config: (group.key, group.cipher),
+            identity_key: group.identity_key.as_deref().map(identity::parse_signing_key).transpose()?,
+            lan_ip_addr: ternary!(mode.is_use_udp(), Some(lan_ip_addr), None),
+            mode,
+            specify_mode: group.specify_mode.unwrap_or_default(),
+            quic_port: group.quic_port,
+            quic_cert_path: group.quic_cert_path,
+            node_binding_port: group.node_binding_port.unwrap_or(0),
+            allowed_ips: group.allowed_ips.unwrap_or_default(),
+            ips,
+            accept_pushed_routes: group.accept_pushed_routes.unwrap_or(false),
+            accept_pushed_default_route: group.accept_pushed_default_route.unwrap_or(false),
+            auto_route_selection: group.auto_route_selection.unwrap_or(false)
+        };
+        Ok(group_finalize)
+    }
+}
 
-            if group_use_udp {
+impl<K> TargetGroupFinalize<K> {
+    // routes all traffic through a node of the group
+    fn use_gateway(&self) -> bool {
+        self.ips.values()
+            .flatten()
+            .any(|x| x.prefix_len() == 0)
+    }
+}
+
+impl TryFrom<NodeConfig> for NodeConfigFinalize<CipherEnum> {
+    type Error = anyhow::Error;
+
+    fn try_from(config: NodeConfig) -> Result<Self> {
+        let mut list = Vec::with_capacity(config.groups.len());
+        let mut use_ipv6 = false;
+        let mut use_udp = false;
+        #[allow(unused)]
+        let mut use_gateway = false;
+
+        for group in config.groups {
+            let group_finalize = TargetGroupFinalize::try_from(group)?;
+            use_gateway |= group_finalize.use_gateway();
+
+            // lan address is only set when the group uses udp
+            if let Some(lan_ip_addr) = group_finalize.lan_ip_addr {
                 use_ipv6 |= lan_ip_addr.is_ipv6();
                 use_udp = true;
-            };
-
-            let group_finalize = TargetGroupFinalize {
-                node_name: {
-                    match group.node_name {
-                        None => {
-                            gethostname()
-                                .to_str()
-                                .ok_or_else(|| anyhow!("unable to resolve hostname"))?
-                                .to_string()
-                        }
-                        Some(v) => v
-                    }
-                },
-                server_addr: {
-                    if resolve_server_addr.ip().is_loopback() {
-                        return Err(anyhow!("server address cannot be a loopback address"));
-                    }
-                    group.server_addr
-                },
-                tun_addr: group.tun_addr,
-                key: {
-                    let key = group.key.as_ref().map(|v| v.as_bytes());
-                    match key {
-                        None => CipherEnum::NoOpCipher(NoOpCipher{}),
-                        Some(k) => CipherEnum::XorCipher(XorCipher::from(k))
-                    }
-                },
-                mode,
-                specify_mode: group.specify_mode.unwrap_or_default(),
-                lan_ip_addr: ternary!(group_use_udp, Some(lan_ip_addr), None),
-                node_binding_port: group.node_binding_port.unwrap_or(0),
-                allowed_ips: group.allowed_ips.unwrap_or_default(),
-                ips: group.ips.unwrap_or_default(),
-                auto_route_selection: group.auto_route_selection.unwrap_or(false)
-            };
-            list.push(group_finalize)
+            }
+            list.push(Arc::new(group_finalize))
         }
 
         let config_finalize = NodeConfigFinalize {
             mtu: config.mtu.unwrap_or({
                 if use_udp {
                     if use_ipv6 {
-                        // 1500 - 8byte 802.3 SNAP - 4byte 802.1Q VLAN - 8byte PPPOE - 40byte IPV6 HEADER - 8byte UDP HEADER - 4byte UDP MSG HEADER - 4byte UDP MSG RELAY IP ADDRESS
-                        1424
+                        // 1500 - 8byte 802.3 SNAP - 4byte 802.1Q VLAN - 8byte PPPOE - 40byte IPV6 HEADER - 8byte UDP HEADER - 30byte UDP MSG HEADER - 4byte UDP MSG RELAY IP ADDRESS
+                        1398
                     } else {
-                        // 1500 - 8byte 802.3 SNAP - 4byte 802.1Q VLAN - 8byte PPPOE - 20byte IPV4 HEADER - 8byte UDP HEADER - 4byte UDP MSG HEADER - 4byte UDP MSG RELAY IP ADDRESS
-                        1444
+                        // 1500 - 8byte 802.3 SNAP - 4byte 802.1Q VLAN - 8byte PPPOE - 20byte IPV4 HEADER - 8byte UDP HEADER - 30byte UDP MSG HEADER - 4byte UDP MSG RELAY IP ADDRESS
+                        1418
                     }
                 } else {
                     1500
@@ -382,6 +1132,11 @@ impl TryFrom<NodeConfig> for NodeConfigFinalize<CipherEnum> {
             api_addr: config
                 .api_addr
                 .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 3030))),
+            api_token: config.api_token,
+            api_tls: match config.api_tls {
+                None => None,
+                Some(tls) => Some(common::api::tls_server_config(&tls.cert_path, &tls.key_path)?)
+            },
             tcp_heartbeat_interval: config
                 .tcp_heartbeat_interval_secs
                 .map(Duration::from_secs)
@@ -393,6 +1148,10 @@ impl TryFrom<NodeConfig> for NodeConfigFinalize<CipherEnum> {
             tcp_heartbeat_continuous_loss: config.tcp_heartbeat_continuous_loss.unwrap_or(5),
             udp_heartbeat_continuous_loss: config.udp_heartbeat_continuous_loss.unwrap_or(5),
             udp_heartbeat_continuous_recv: config.udp_heartbeat_continuous_recv.unwrap_or(3),
+            udp_session_rekey_interval: config
+                .udp_session_rekey_interval_secs
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_secs(120)),
             reconnect_interval: Duration::from_secs(config.reconnect_interval_secs.unwrap_or(3)),
             udp_socket_recv_buffer_size: config.udp_socket_recv_buffer_size,
             udp_socket_send_buffer_size: config.udp_socket_send_buffer_size,
@@ -403,6 +1162,14 @@ impl TryFrom<NodeConfig> for NodeConfigFinalize<CipherEnum> {
             enable_hook: config.enable_hook.unwrap_or(false),
             #[cfg(feature = "cross-nat")]
             cross_nat: config.cross_nat.unwrap_or(false),
+            dns: config.dns.map(|dns| DnsConfigFinalize {
+                listen_port: dns.listen_port.unwrap_or(53),
+                upstream: dns.upstream.unwrap_or_default(),
+            }),
+            firewall: config.firewall
+                .map(FirewallConfigFinalize::try_from)
+                .transpose()
+                .context("firewall config error")?,
             socket_bind_device: {
                 #[allow(unused_mut)]
                 let mut bind = config.socket_bind_device;
@@ -422,6 +1189,7 @@ impl TryFrom<NodeConfig> for NodeConfigFinalize<CipherEnum> {
                     disable_hosts_operation: features.and_then(|f| f.disable_hosts_operation).unwrap_or(false),
                     disable_signal_handling: features.and_then(|f| f.disable_signal_handling).unwrap_or(false),
                     disable_route_operation: features.and_then(|f| f.disable_route_operation).unwrap_or(false),
+                    disable_dns_operation: features.and_then(|f| f.disable_dns_operation).unwrap_or(false),
                     disable_api_server: features.and_then(|f| f.disable_api_server).unwrap_or(false),
                 }
             },
@@ -458,14 +1226,28 @@ pub enum NodeCmd {
     },
     /// query the current state of the node
     Info {
-        /// api address of the node
-        #[arg(short, long, default_value = "127.0.0.1:3030")]
-        api: String,
+        /// api address of the node, default 127.0.0.1:3030
+        #[arg(short, long)]
+        api: Option<String>,
+
+        /// api token, or set FUBUKI_API_TOKEN
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// connect with tls and trust this certificate
+        #[arg(long)]
+        tls_cert: Option<PathBuf>,
+
+        /// read the api address, token and certificate from the node configuration file
+        #[arg(short, long)]
+        config: Option<PathBuf>,
 
         /// query type
         #[command(subcommand)]
         info_type: NodeInfoType,
-    }
+    },
+    /// generate an identity key pair
+    Keygen
 }
 
 #[derive(Clone, Subcommand)]
@@ -492,9 +1274,21 @@ pub enum ServerCmd {
     },
     /// query the current state of the server
     Info {
-        /// api address of the server
-        #[arg(short, long, default_value = "127.0.0.1:3031")]
-        api: String,
+        /// api address of the server, default 127.0.0.1:3031
+        #[arg(short, long)]
+        api: Option<String>,
+
+        /// api token, or set FUBUKI_API_TOKEN
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// connect with tls and trust this certificate
+        #[arg(long)]
+        tls_cert: Option<PathBuf>,
+
+        /// read the api address, token and certificate from the server configuration file
+        #[arg(short, long)]
+        config: Option<PathBuf>,
 
         /// query type
         #[command(subcommand)]
@@ -525,6 +1319,35 @@ pub enum Args {
     }
 }
 
+// command line arguments first, then the environment and the configuration file
+fn api_client(
+    api: Option<String>,
+    token: Option<String>,
+    tls_cert: Option<PathBuf>,
+    config: Option<(Option<SocketAddr>, Option<String>, Option<ApiTlsConfig>)>,
+    default_addr: SocketAddr
+) -> ApiClient {
+    let (config_addr, config_token, config_tls) = config.unwrap_or_default();
+
+    let addr = api.unwrap_or_else(|| {
+        let mut addr = config_addr.unwrap_or(default_addr);
+
+        // the api listens on all interfaces, connect to the local one
+        if addr.ip().is_unspecified() {
+            addr.set_ip(ternary!(addr.is_ipv4(), IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        }
+        addr.to_string()
+    });
+
+    ApiClient {
+        addr,
+        token: token
+            .or_else(|| std::env::var("FUBUKI_API_TOKEN").ok())
+            .or(config_token),
+        tls_cert: tls_cert.or(config_tls.map(|tls| tls.cert_path)),
+    }
+}
+
 fn load_config<T: de::DeserializeOwned>(path: &Path) -> Result<T> {
     let file = std::fs::File::open(path)
         .with_context(|| format!("failed to read config from: {}", path.to_string_lossy()))?;
@@ -592,17 +1415,30 @@ pub fn launch(args: Args) -> Result<()> {
         Args::Server { cmd } => {
             match cmd {
                 ServerCmd::Daemon { config_path } => {
-                    let t: ServerConfig = load_config(&config_path)?;
-                    let config: ServerConfigFinalize<Key> = ServerConfigFinalize::try_from(t)?;
+                    let load = move || {
+                        let t: ServerConfig = load_config(&config_path)?;
+                        ServerConfigFinalize::<Key>::try_from(t)
+                    };
+
+                    let config = load()?;
                     let rt = Runtime::new()?;
-                    rt.block_on(server::start(config))?;
+                    rt.block_on(server::start(config, load))?;
                 }
-                ServerCmd::Info { api, info_type } => {
+                ServerCmd::Info { api, token, tls_cert, config, info_type } => {
+                    let config = match config {
+                        None => None,
+                        Some(path) => {
+                            let c: ServerConfig = load_config(&path)?;
+                            Some((c.api_addr, c.api_token, c.api_tls))
+                        }
+                    };
+
+                    let client = api_client(api, token, tls_cert, config, SocketAddr::from((Ipv4Addr::LOCALHOST, 3031)));
                     let rt = tokio::runtime::Builder::new_current_thread()
                         .enable_io()
                         .build()?;
 
-                    rt.block_on(server::info(&api, info_type))?;
+                    rt.block_on(server::info(&client, info_type))?;
                 }
             }
         }
@@ -610,22 +1446,40 @@ pub fn launch(args: Args) -> Result<()> {
             match cmd {
                 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
                 NodeCmd::Daemon { config_path } => {
-                    let config: NodeConfig = load_config(&config_path)?;
-                    let c: NodeConfigFinalize<Key> = NodeConfigFinalize::try_from(config)?;
+                    let load = move || {
+                        let t: NodeConfig = load_config(&config_path)?;
+                        NodeConfigFinalize::<Key>::try_from(t)
+                    };
+
+                    let c = load()?;
                     let rt = Runtime::new()?;
 
                     rt.block_on(async {
                         // creating AsyncTun must be in the tokio runtime
                         let tun = tun::create().context("failed to create tun")?;
-                        node::start(c, tun, Arc::new(OnceLock::new())).await
+                        node::start(c, tun, Arc::new(ArcSwap::from_pointee(Vec::new())), Some(load)).await
                     })?;
                 }
-                NodeCmd::Info { api, info_type } => {
+                NodeCmd::Info { api, token, tls_cert, config, info_type } => {
+                    let config = match config {
+                        None => None,
+                        Some(path) => {
+                            let c: NodeConfig = load_config(&path)?;
+                            Some((c.api_addr, c.api_token, c.api_tls))
+                        }
+                    };
+
+                    let client = api_client(api, token, tls_cert, config, SocketAddr::from((Ipv4Addr::LOCALHOST, 3030)));
                     let rt = tokio::runtime::Builder::new_current_thread()
                         .enable_io()
                         .build()?;
 
-                    rt.block_on(node::info(&api, info_type))?;
+                    rt.block_on(node::info(&client, info_type))?;
+                }
+                NodeCmd::Keygen => {
+                    let (private_key, public_key) = identity::generate();
+                    println!("private key: {}", private_key);
+                    println!("public key: {}", public_key);
                 }
                 #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
                 _ => {
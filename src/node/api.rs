@@ -1,22 +1,31 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use http_body_util::Full;
-use hyper::{http, Request, Response};
-use hyper::body::{Bytes, Incoming};
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use tokio::net::TcpListener;
+use hyper::{header, http, Method, Request, Response};
+use hyper::body::Bytes;
+use tokio_rustls::rustls;
 
-use crate::node::{Interface, InterfaceInfo};
+use crate::common::api::{self, query_param, ApiBody};
+use crate::common::event::EventBus;
+use crate::common::metrics::{MetricsWriter, METRICS_CONTENT_TYPE};
+use crate::common::net::{HeartbeatCache, UdpStatus};
+use crate::node::{GroupControl, Interface, InterfaceInfo, TUN_METRICS};
+use crate::node::firewall::Firewall;
 
 struct Context<K> {
-    interfaces: Vec<Arc<Interface<K>>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    token: Option<String>,
+    control: Arc<GroupControl<K>>,
+    firewall: Option<Arc<Firewall>>,
+    events: Arc<EventBus>,
 }
 
 fn info<K>(
-    _req: Request<Incoming>,
+    _req: Request<Bytes>,
     interfaces: &[Arc<Interface<K>>],
 ) -> Result<Response<Full<Bytes>>, http::Error> {
     let mut list = Vec::with_capacity(interfaces.len());
@@ -38,53 +47,232 @@ fn info<K>(
     Ok(resp)
 }
 
+// GET /metrics, prometheus text format
+fn metrics<K>(
+    _req: Request<Bytes>,
+    interfaces: &[Arc<Interface<K>>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let mut w = MetricsWriter::new();
+
+    let groups: Vec<String> = interfaces.iter()
+        .map(|inter| {
+            inter.group_name.load()
+                .as_deref()
+                .cloned()
+                .unwrap_or_else(|| inter.index.to_string())
+        })
+        .collect();
+
+    w.header("fubuki_node_server_connected", "gauge", "Whether the interface is connected to the server");
+    for (inter, group) in interfaces.iter().zip(&groups) {
+        w.sample("fubuki_node_server_connected", &[("group", group)], inter.server_is_connected.load(Ordering::Relaxed) as u8);
+    }
+
+    let heartbeat_metrics: [(&str, &str, &str, &str, fn(&HeartbeatCache) -> Option<f64>); 6] = [
+        ("fubuki_node_server_latency_seconds", "gauge", "Last heartbeat round trip time of the server", "server", |hc| hc.last_elapsed.map(|v| v.as_secs_f64())),
+        ("fubuki_node_server_heartbeat_sent_total", "counter", "Heartbeats sent to the server", "server", |hc| Some(hc.send_count as f64)),
+        ("fubuki_node_server_heartbeat_lost_total", "counter", "Heartbeats to the server without reply", "server", |hc| Some(hc.packet_loss_count as f64)),
+        ("fubuki_node_peer_latency_seconds", "gauge", "Last heartbeat round trip time of the peer", "peer", |hc| hc.last_elapsed.map(|v| v.as_secs_f64())),
+        ("fubuki_node_peer_heartbeat_sent_total", "counter", "Heartbeats sent to the peer", "peer", |hc| Some(hc.send_count as f64)),
+        ("fubuki_node_peer_heartbeat_lost_total", "counter", "Heartbeats to the peer without reply", "peer", |hc| Some(hc.packet_loss_count as f64)),
+    ];
+
+    for (name, metric_type, help, target, f) in heartbeat_metrics {
+        w.header(name, metric_type, help);
+
+        for (inter, group) in interfaces.iter().zip(&groups) {
+            if target == "server" {
+                for (protocol, hc) in [("udp", &inter.server_udp_hc), ("tcp", &inter.server_tcp_hc)] {
+                    if let Some(v) = f(&hc.read()) {
+                        w.sample(name, &[("group", group), ("protocol", protocol)], v);
+                    }
+                }
+            } else {
+                for en in inter.node_list.load().iter() {
+                    if let Some(v) = f(&en.hc.read()) {
+                        w.sample(name, &[("group", group), ("peer", &en.node.name)], v);
+                    }
+                }
+            }
+        }
+    }
+
+    w.header("fubuki_node_server_udp_available", "gauge", "Whether the udp path to the server is available");
+    for (inter, group) in interfaces.iter().zip(&groups) {
+        let available = matches!(inter.server_udp_status.load(), UdpStatus::Available { .. });
+        w.sample("fubuki_node_server_udp_available", &[("group", group)], available as u8);
+    }
+
+    w.header("fubuki_node_server_udp_status_transitions_total", "counter", "Udp path availability changes of the server");
+    for (inter, group) in interfaces.iter().zip(&groups) {
+        w.sample("fubuki_node_server_udp_status_transitions_total", &[("group", group)], inter.server_udp_status.transitions());
+    }
+
+    w.header("fubuki_node_peer_udp_available", "gauge", "Whether the udp path to the peer is available");
+    for (inter, group) in interfaces.iter().zip(&groups) {
+        for en in inter.node_list.load().iter() {
+            let available = matches!(en.udp_status.load(), UdpStatus::Available { .. });
+            w.sample("fubuki_node_peer_udp_available", &[("group", group), ("peer", &en.node.name)], available as u8);
+        }
+    }
+
+    w.header("fubuki_node_peer_udp_status_transitions_total", "counter", "Udp path availability changes of the peer");
+    for (inter, group) in interfaces.iter().zip(&groups) {
+        for en in inter.node_list.load().iter() {
+            w.sample("fubuki_node_peer_udp_status_transitions_total", &[("group", group), ("peer", &en.node.name)], en.udp_status.transitions());
+        }
+    }
+
+    w.header("fubuki_node_tun_packets_total", "counter", "Packets read from and written to the tun device");
+    w.sample("fubuki_node_tun_packets_total", &[("direction", "read")], TUN_METRICS.read_packets.load(Ordering::Relaxed));
+    w.sample("fubuki_node_tun_packets_total", &[("direction", "write")], TUN_METRICS.write_packets.load(Ordering::Relaxed));
+
+    w.header("fubuki_node_tun_bytes_total", "counter", "Bytes read from and written to the tun device");
+    w.sample("fubuki_node_tun_bytes_total", &[("direction", "read")], TUN_METRICS.read_bytes.load(Ordering::Relaxed));
+    w.sample("fubuki_node_tun_bytes_total", &[("direction", "write")], TUN_METRICS.write_bytes.load(Ordering::Relaxed));
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)
+        .body(Full::new(Bytes::from(w.finish())))
+}
+
+// GET /firewall, rule hit counters
+fn firewall(
+    firewall: Option<&Firewall>,
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let firewall = match firewall {
+        None => {
+            return Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::from("firewall is not enabled")));
+        }
+        Some(v) => v
+    };
+
+    match serde_json::to_vec(&firewall.info()) {
+        Ok(v) => Ok(Response::new(Full::new(Bytes::from(v)))),
+        Err(e) => {
+            error!("api server error: {}", e);
+
+            Response::builder()
+                .status(500)
+                .body(Full::new(Bytes::from(e.to_string())))
+        }
+    }
+}
+
+// POST /reload, /group/add with a group config as the body and /group/remove?index={interface index}
+// the changes are applied in the background after they are checked
+fn group_control<K>(
+    req: Request<Bytes>,
+    control: &GroupControl<K>,
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    if req.method() != Method::POST {
+        return Response::builder()
+            .status(405)
+            .body(Full::new(Bytes::new()));
+    }
+
+    let mut body = Bytes::new();
+
+    let res = match req.uri().path() {
+        "/reload" => control.reload(),
+        "/group/add" => {
+            body = Bytes::from_static(b"the group is not saved to the config file, it leaves on the next reload");
+            control.add_group(req.body())
+        }
+        _ => {
+            match query_param(&req, "index").and_then(|v| v.parse().ok()) {
+                Some(index) => control.remove_group(index),
+                None => {
+                    return Response::builder()
+                        .status(400)
+                        .body(Full::new(Bytes::from("missing index parameter")));
+                }
+            }
+        }
+    };
+
+    match res {
+        Ok(()) => {
+            Response::builder()
+                .status(202)
+                .body(Full::new(body))
+        }
+        Err(e) => {
+            Response::builder()
+                .status(400)
+                .body(Full::new(Bytes::from(format!("{:#}", e))))
+        }
+    }
+}
+
+fn subscribe<K>(
+    ctx: &Context<K>,
+    req: &Request<Bytes>,
+) -> Result<Response<ApiBody>, http::Error> {
+    if !api::authorized(req, ctx.token.as_deref()) {
+        return Ok(api::full(api::unauthorized()));
+    }
+    api::event_stream(&ctx.events)
+}
+
+type Handler<K> = fn(Request<Bytes>, &[Arc<Interface<K>>]) -> Result<Response<Full<Bytes>>, http::Error>;
+
 fn router<K>(
     ctx: &Context<K>,
-    req: Request<Incoming>,
+    req: Request<Bytes>,
 ) -> Result<Response<Full<Bytes>>, http::Error> {
     let path = req.uri().path();
 
-    match path {
-        "/info" => info(req, ctx.interfaces.as_slice()),
-        "/type" => Ok(Response::new(Full::new(Bytes::from("node")))),
+    let handler: Handler<K> = match path {
+        "/info" => info,
+        "/metrics" => metrics,
+        "/reload" | "/group/add" | "/group/remove" => {
+            if !api::authorized(&req, ctx.token.as_deref()) {
+                return Ok(api::unauthorized());
+            }
+            return group_control(req, &ctx.control);
+        }
+        "/firewall" => {
+            if !api::authorized(&req, ctx.token.as_deref()) {
+                return Ok(api::unauthorized());
+            }
+            return firewall(ctx.firewall.as_deref());
+        }
+        "/type" => return Ok(Response::new(Full::new(Bytes::from("node")))),
         #[cfg(feature = "web")]
-        path => crate::web::static_files(path.trim_start_matches('/')),
+        path => return crate::web::static_files(path.trim_start_matches('/')),
         #[cfg(not(feature = "web"))]
-        _ => Response::builder()
-            .status(404)
-            .body(Full::new(Bytes::new())),
+        _ => {
+            return Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::new()));
+        }
+    };
+
+    if !api::authorized(&req, ctx.token.as_deref()) {
+        return Ok(api::unauthorized());
     }
+    handler(req, &ctx.interfaces.load())
 }
 
 pub(super) async fn api_start<K: Send + Sync + 'static>(
     bind: SocketAddr,
-    interfaces: Vec<Arc<Interface<K>>>,
+    token: Option<String>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    control: Arc<GroupControl<K>>,
+    firewall: Option<Arc<Firewall>>,
+    events: Arc<EventBus>,
 ) -> Result<()> {
-    let ctx = Context { interfaces };
+    let ctx = Context { interfaces, token, control, firewall, events };
     let ctx = Arc::new(ctx);
 
-    let listener = TcpListener::bind(bind).await?;
-    info!("api listening on http://{}", bind);
-
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let stream = hyper_util::rt::TokioIo::new(stream);
-        let ctx = ctx.clone();
-
-        tokio::spawn(async move {
-            let ctx = &ctx;
-            let res = http1::Builder::new()
-                .serve_connection(
-                    stream,
-                    service_fn(move |req| {
-                        std::future::ready(router(ctx, req))
-                    }),
-                )
-                .await;
-
-            if let Err(e) = res {
-                warn!("error serving connection: {:?}", e);
-            }
-        });
-    }
+    api::serve(bind, tls, move |req| {
+        match req.uri().path() {
+            "/events" => subscribe(&ctx, &req),
+            _ => router(&ctx, req).map(api::full)
+        }
+    }).await
 }
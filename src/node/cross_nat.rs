@@ -4,7 +4,7 @@ use std::{
     ops::Deref,
     sync::{
         atomic::{AtomicI64, Ordering},
-        Arc, OnceLock,
+        Arc,
     },
     time::Duration,
 };
@@ -19,6 +19,7 @@ use futures_util::{
 };
 use netstack_lwip::NetStack;
 use tokio::net::{TcpSocket, UdpSocket};
+use tokio::sync::watch;
 
 use crate::{
     common::{
@@ -174,7 +175,8 @@ async fn tcp_inbound_handler(
 async fn netstatck_handler<T, K, InterRT, ExternRT>(
     mut stack_stream: SplitStream<Pin<Box<NetStack>>>,
     routing_table: Arc<RoutingTableEnum<InterRT, ExternRT>>,
-    interfaces: Arc<OnceLock<Vec<Arc<Interface<K>>>>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    mut interfaces_changed: watch::Receiver<()>,
     hooks: Option<Arc<Hooks<K>>>,
     tun: T,
 ) -> Result<()>
@@ -184,47 +186,50 @@ where
     InterRT: RoutingTable + Send + Sync + 'static,
     ExternRT: RoutingTable + Send + Sync + 'static,
 {
-    let mut sender = None;
-    let mut ifs;
     let mut buff = vec![0u8; UDP_BUFF_SIZE];
     const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
 
-    while let Some(pkt) = stack_stream.next().await {
-        let packet = pkt?;
-
-        let s = match sender.as_mut() {
-            Some(sender) => sender,
-            None => {
-                if let Some(interfaces) = interfaces.get() {
-                    ifs = interfaces.iter().map(|v| &**v).collect::<Vec<_>>();
-                } else {
-                    continue;
-                }
+    // the sender is rebuilt when a group is added or removed
+    loop {
+        interfaces_changed.borrow_and_update();
+        let list = interfaces.load_full();
+        let ifs = list.iter().map(|v| &**v).collect::<Vec<_>>();
+
+        let mut sender = PacketSender::new(
+            &*routing_table,
+            &ifs,
+            &tun,
+            hooks.as_deref(),
+            None,
+            None,
+        );
+
+        loop {
+            let pkt = tokio::select! {
+                pkt = stack_stream.next() => pkt,
+                Ok(_) = interfaces_changed.changed() => break
+            };
 
-                sender = Some(PacketSender::new(
-                    &*routing_table,
-                    &ifs,
-                    &tun,
-                    hooks.as_deref(),
-                    None,
-                ));
-                sender.as_mut().unwrap()
-            }
-        };
+            let packet = match pkt {
+                None => return Ok(()),
+                Some(pkt) => pkt?,
+            };
 
-        let packet_range = START..START + packet.len();
-        buff[packet_range.clone()].copy_from_slice(&packet);
+            let packet_range = START..START + packet.len();
+            buff[packet_range.clone()].copy_from_slice(&packet);
 
-        s.send_packet(Direction::Output, packet_range, &mut buff, true, false)
-            .await?;
+            sender
+                .send_packet(Direction::Output, packet_range, &mut buff, true, false)
+                .await?;
+        }
     }
-    Ok(())
 }
 
 impl SNat {
     pub fn create<T, K, InterRT, ExternRT>(
         routing_table: Arc<RoutingTableEnum<InterRT, ExternRT>>,
-        interfaces: Arc<OnceLock<Vec<Arc<Interface<K>>>>>,
+        interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+        interfaces_changed: watch::Receiver<()>,
         tun: T,
         hooks: Option<Arc<Hooks<K>>>,
     ) -> Result<Self>
@@ -255,7 +260,7 @@ impl SNat {
 
         tokio::spawn(async move {
             if let Err(e) =
-                netstatck_handler(stack_stream, routing_table, interfaces, hooks, tun).await
+                netstatck_handler(stack_stream, routing_table, interfaces, interfaces_changed, hooks, tun).await
             {
                 error!("netstack_handler error: {:?}", e);
             }
@@ -0,0 +1,372 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+
+use crate::DnsConfigFinalize;
+use crate::common::net::protocol::VirtualAddr;
+use crate::node::{Interface, NodeList};
+
+const DNS_BUFF_SIZE: usize = 4096;
+const HEADER_LEN: usize = 12;
+// the node list changes whenever a node joins or leaves
+const TTL: u32 = 10;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+// how often the virtual address is checked, the socket is bound again after it changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_PENDING_FORWARDS: usize = 256;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_OPCODE: u16 = 0x7800;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+struct Question {
+    // lowercase, labels joined with dots
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // end of the question section
+    end: usize,
+}
+
+fn get_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let v = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([v[0], v[1]]))
+}
+
+fn parse_question(packet: &[u8]) -> Option<Question> {
+    // a query carries exactly one question
+    if get_u16(packet, 4)? != 1 {
+        return None;
+    }
+
+    let mut name = String::new();
+    let mut offset = HEADER_LEN;
+
+    loop {
+        let len = *packet.get(offset)? as usize;
+        offset += 1;
+
+        if len == 0 {
+            break;
+        }
+
+        // compression pointers are not expected in the question
+        if len & 0xc0 != 0 || name.len() + len > 255 {
+            return None;
+        }
+
+        let label = std::str::from_utf8(packet.get(offset..offset + len)?).ok()?;
+        offset += len;
+
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&label.to_ascii_lowercase());
+    }
+
+    let question = Question {
+        name,
+        qtype: get_u16(packet, offset)?,
+        qclass: get_u16(packet, offset + 2)?,
+        end: offset + 4,
+    };
+    Some(question)
+}
+
+fn build_response(query: &[u8], question_end: usize, rcode: u16, answers: &[IpAddr]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(question_end + answers.len() * 28);
+    out.extend_from_slice(&query[..question_end]);
+
+    let flags = get_u16(query, 2).unwrap_or(0);
+    let flags = FLAG_QR | (flags & (FLAG_OPCODE | FLAG_RD)) | FLAG_AA | FLAG_RA | rcode;
+    out[2..4].copy_from_slice(&flags.to_be_bytes());
+
+    let qdcount: u16 = if question_end > HEADER_LEN { 1 } else { 0 };
+    out[4..6].copy_from_slice(&qdcount.to_be_bytes());
+    out[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+    // no authority and additional records, the EDNS record of the query is dropped
+    out[8..12].fill(0);
+
+    for addr in answers {
+        // pointer to the name in the question
+        out.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+
+        let (rtype, rdata) = match addr {
+            IpAddr::V4(v) => (TYPE_A, v.octets().to_vec()),
+            IpAddr::V6(v) => (TYPE_AAAA, v.octets().to_vec()),
+        };
+
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&TTL.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+    out
+}
+
+enum Lookup {
+    // the ipv4 address is None if the group has no ipv4 range
+    Found(Option<VirtualAddr>, Option<Ipv6Addr>),
+    // the name is in a group but no such node
+    NotFound,
+    Other,
+}
+
+// names are "{node_name}.{group_name}", every group joined by this node is answered
+fn lookup<'a>(name: &str, groups: impl Iterator<Item = (&'a str, &'a NodeList, bool)>) -> Lookup {
+    let mut res = Lookup::Other;
+
+    for (group_name, node_list, ipv4) in groups {
+        let node_name = match name.strip_suffix(&group_name.to_ascii_lowercase()) {
+            Some(v) => match v.strip_suffix('.') {
+                Some(v) if !v.is_empty() => v,
+                _ => continue
+            },
+            None => continue
+        };
+
+        let node = node_list.iter().find(|node| node.node.name.eq_ignore_ascii_case(node_name));
+
+        match node {
+            Some(node) => return Lookup::Found(ternary!(ipv4, Some(node.node.virtual_addr), None), node.node.virtual_addr_v6),
+            None => res = Lookup::NotFound
+        }
+    }
+    res
+}
+
+enum Reply {
+    Send(Vec<u8>),
+    Forward,
+    Drop,
+}
+
+fn handle_query<K>(packet: &[u8], interfaces: &[Arc<Interface<K>>], can_forward: bool) -> Reply {
+    if packet.len() < HEADER_LEN {
+        return Reply::Drop;
+    }
+
+    let flags = match get_u16(packet, 2) {
+        // responses are ignored
+        Some(flags) if flags & FLAG_QR == 0 => flags,
+        _ => return Reply::Drop
+    };
+
+    let question = match parse_question(packet) {
+        Some(v) => v,
+        None => return Reply::Send(build_response(packet, HEADER_LEN, RCODE_FORMERR, &[]))
+    };
+
+    if flags & FLAG_OPCODE != 0 {
+        return Reply::Send(build_response(packet, question.end, RCODE_NOTIMP, &[]));
+    }
+
+    let group_names: Vec<_> = interfaces.iter()
+        .map(|inter| (inter.group_name.load_full(), inter.node_list.load_full(), inter.ipv4.load(Ordering::Relaxed)))
+        .collect();
+
+    let groups = group_names.iter()
+        .filter_map(|(group_name, node_list, ipv4)| Some((group_name.as_deref()?.as_str(), &**node_list, *ipv4)));
+
+    let res = match lookup(&question.name, groups) {
+        Lookup::Found(..) | Lookup::NotFound if question.qclass != CLASS_IN => Lookup::Other,
+        res => res
+    };
+
+    match res {
+        Lookup::Found(v4, v6) => {
+            let answers: Vec<IpAddr> = match question.qtype {
+                TYPE_A => v4.map(IpAddr::V4).into_iter().collect(),
+                TYPE_AAAA => v6.map(IpAddr::V6).into_iter().collect(),
+                _ => Vec::new()
+            };
+            Reply::Send(build_response(packet, question.end, 0, &answers))
+        }
+        Lookup::NotFound => Reply::Send(build_response(packet, question.end, RCODE_NXDOMAIN, &[])),
+        Lookup::Other if can_forward => Reply::Forward,
+        Lookup::Other => Reply::Send(build_response(packet, question.end, RCODE_REFUSED, &[]))
+    }
+}
+
+async fn forward(upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let bind_addr = match upstream {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = UdpSocket::bind((bind_addr, 0)).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buff = vec![0u8; DNS_BUFF_SIZE];
+
+    let fut = async {
+        loop {
+            let len = socket.recv(&mut buff).await?;
+
+            // the id must match the query
+            if len >= HEADER_LEN && buff[..2] == query[..2] {
+                return Result::<_, anyhow::Error>::Ok(len);
+            }
+        }
+    };
+
+    let len = tokio::time::timeout(UPSTREAM_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow!("timeout"))??;
+
+    buff.truncate(len);
+    Ok(buff)
+}
+
+async fn forward_all(upstream: &[SocketAddr], query: &[u8]) -> Vec<u8> {
+    for addr in upstream {
+        match forward(*addr, query).await {
+            Ok(resp) => return resp,
+            Err(e) => debug!("forward dns query to {} failed: {:?}", addr, e)
+        }
+    }
+
+    let question_end = parse_question(query).map(|q| q.end).unwrap_or(HEADER_LEN);
+    build_response(query, question_end, RCODE_SERVFAIL, &[])
+}
+
+// the ipv4 address of a group without an ipv4 range is not on the tun, serve on the ipv6 address instead
+fn listen_addr<K>(interface: &Interface<K>) -> Option<IpAddr> {
+    if interface.ipv4.load(Ordering::Relaxed) {
+        let addr = interface.addr.load();
+        ternary!(addr == VirtualAddr::UNSPECIFIED, None, Some(IpAddr::V4(addr)))
+    } else {
+        interface.addr_v6.load().map(IpAddr::V6)
+    }
+}
+
+// serve on the virtual address of the interface, only while the address is assigned
+pub async fn dns_handler<K>(
+    config: &'static DnsConfigFinalize,
+    interface: Arc<Interface<K>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+) -> Result<()>
+    where K: Send + Sync + 'static
+{
+    let pending_forwards = Arc::new(Semaphore::new(MAX_PENDING_FORWARDS));
+    let mut buff = vec![0u8; DNS_BUFF_SIZE];
+
+    loop {
+        let addr = match listen_addr(&interface) {
+            Some(addr) if interface.server_is_connected.load(Ordering::Relaxed) => addr,
+            _ => {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let socket = match UdpSocket::bind(SocketAddr::new(addr, config.listen_port)).await {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                warn!("node {} dns server bind {} failed: {}", interface.node_name, SocketAddr::new(addr, config.listen_port), e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        info!("node {} dns server listening on {}", interface.node_name, SocketAddr::new(addr, config.listen_port));
+        let mut check = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            let (len, from) = tokio::select! {
+                res = socket.recv_from(&mut buff) => {
+                    match res {
+                        Ok(v) => v,
+                        Err(e) => {
+                            debug!("node {} dns server receive error: {}", interface.node_name, e);
+                            continue;
+                        }
+                    }
+                }
+                _ = check.tick() => {
+                    if listen_addr(&interface) != Some(addr) {
+                        info!("node {} dns server address {} changed", interface.node_name, addr);
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let query = &buff[..len];
+
+            match handle_query(query, &interfaces.load(), !config.upstream.is_empty()) {
+                Reply::Send(resp) => {
+                    if let Err(e) = socket.send_to(&resp, from).await {
+                        debug!("node {} dns server send to {} error: {}", interface.node_name, from, e);
+                    }
+                }
+                Reply::Forward => {
+                    let permit = match pending_forwards.clone().try_acquire_owned() {
+                        Ok(v) => v,
+                        Err(_) => {
+                            debug!("node {} dns server too many pending queries", interface.node_name);
+                            continue;
+                        }
+                    };
+
+                    let socket = socket.clone();
+                    let query = query.to_vec();
+
+                    tokio::spawn(async move {
+                        let resp = forward_all(&config.upstream, &query).await;
+                        let _ = socket.send_to(&resp, from).await;
+                        drop(permit);
+                    });
+                }
+                Reply::Drop => ()
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    for label in ["N1", "g1"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let question = parse_question(&query).unwrap();
+    assert_eq!(question.name, "n1.g1");
+    assert_eq!(question.qtype, TYPE_A);
+    assert_eq!(question.end, query.len());
+
+    let resp = build_response(&query, question.end, 0, &[IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+    assert_eq!(&resp[..2], &[0x12, 0x34]);
+    assert_eq!(get_u16(&resp, 2).unwrap(), FLAG_QR | FLAG_AA | FLAG_RD | FLAG_RA);
+    assert_eq!(get_u16(&resp, 6).unwrap(), 1);
+    assert_eq!(&resp[resp.len() - 4..], &[10, 0, 0, 2]);
+
+    assert!(matches!(lookup("n2.g1", std::iter::once(("G1", &NodeList::new(), true))), Lookup::NotFound));
+    assert!(matches!(lookup("example.com", std::iter::once(("g1", &NodeList::new(), true))), Lookup::Other));
+}
@@ -0,0 +1,13 @@
+use std::net::IpAddr;
+
+pub struct SystemDnsHandle {}
+
+impl SystemDnsHandle {
+    pub fn new(_if_index: u32) -> Self {
+        SystemDnsHandle {}
+    }
+
+    pub async fn set(&self, _interface_index: usize, _servers: &[IpAddr], _domains: &[String]) {}
+
+    pub async fn remove(&self, _interface_index: usize) {}
+}
@@ -14,6 +14,10 @@ impl SystemRouteHandle {
         Ok(())
     }
 
+    pub async fn delete(&mut self, _routes: &[Route]) -> Result<()> {
+        Ok(())
+    }
+
     #[allow(unused)]
     pub async fn clear(&mut self) -> Result<()> {
         Ok(())
@@ -0,0 +1,198 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::common::acl::{parse_packet, PacketInfo};
+use crate::common::conntrack::{Flow, FlowKey, FlowTable};
+use crate::{FirewallAction, FirewallConfigFinalize, FirewallRuleFinalize};
+
+// (identification, more fragments) of an ipv4 packet
+fn ipv4_fragment(packet: &[u8]) -> Option<(u16, bool)> {
+    if packet.first()? >> 4 != 4 {
+        return None;
+    }
+
+    let id = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]);
+    let flags = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+    Some((id, flags & 0x2000 != 0))
+}
+
+impl FirewallRuleFinalize {
+    fn matches(&self, info: &PacketInfo) -> bool {
+        let port_matched = self.ports.is_empty() || match info.ports {
+            Some((_, dst)) => self.ports.iter().any(|(start, end)| (*start..=*end).contains(&dst)),
+            None => false
+        };
+
+        self.protocol.matches(info.protocol) &&
+            port_matched &&
+            (self.src.is_empty() || self.src.iter().any(|v| v.contains(&info.src))) &&
+            (self.dst.is_empty() || self.dst.iter().any(|v| v.contains(&info.dst)))
+    }
+}
+
+#[derive(Serialize)]
+pub struct FirewallRuleInfo {
+    #[serde(flatten)]
+    rule: FirewallRuleFinalize,
+    hits: u64,
+}
+
+#[derive(Serialize)]
+pub struct FirewallInfo {
+    default_action: FirewallAction,
+    default_hits: u64,
+    // tracked outbound flows
+    flows: usize,
+    rules: Vec<FirewallRuleInfo>,
+}
+
+// rules apply to the packets received from the overlay, the replies of outbound flows always pass
+pub struct Firewall {
+    default_action: FirewallAction,
+    rules: Vec<(FirewallRuleFinalize, AtomicU64)>,
+    default_hits: AtomicU64,
+    // outbound flows
+    flows: FlowTable,
+    // datagrams whose first fragment is accepted, the later fragments follow it
+    fragments: FlowTable,
+}
+
+impl Firewall {
+    pub fn new(config: &FirewallConfigFinalize) -> Self {
+        Firewall {
+            default_action: config.default_action,
+            rules: config.rules.iter().map(|r| (r.clone(), AtomicU64::new(0))).collect(),
+            default_hits: AtomicU64::new(0),
+            flows: FlowTable::new(),
+            fragments: FlowTable::new(),
+        }
+    }
+
+    // record the flow of a packet sent to the overlay
+    pub fn track(&self, packet: &[u8]) {
+        let info = match parse_packet(packet) {
+            Some(info) if !info.fragment => info,
+            _ => return
+        };
+
+        // icmp errors do not start a flow
+        if let Flow::Packet(key) = Flow::of(packet, &info) {
+            self.flows.insert(key);
+        }
+    }
+
+    fn rule_action(&self, info: &PacketInfo) -> FirewallAction {
+        match self.rules.iter().find(|(rule, _)| rule.matches(info)) {
+            Some((rule, hits)) => {
+                hits.fetch_add(1, Ordering::Relaxed);
+                rule.action
+            }
+            None => {
+                self.default_hits.fetch_add(1, Ordering::Relaxed);
+                self.default_action
+            }
+        }
+    }
+
+    // false means the packet received from the overlay is dropped
+    pub fn check(&self, packet: &[u8]) -> bool {
+        let info = match parse_packet(packet) {
+            None => return false,
+            Some(info) => info
+        };
+
+        // the later fragments pass only if the first one arrived before and was accepted
+        if info.fragment {
+            return ipv4_fragment(packet).is_some_and(|(id, _)| self.fragments.contains(&FlowKey::fragment(&info, id)));
+        }
+
+        let accepted = self.flows.is_reply(&Flow::of(packet, &info)) ||
+            self.rule_action(&info) == FirewallAction::Accept;
+
+        if let (true, Some((id, true))) = (accepted, ipv4_fragment(packet)) {
+            self.fragments.insert(FlowKey::fragment(&info, id));
+        }
+        accepted
+    }
+
+    pub fn info(&self) -> FirewallInfo {
+        FirewallInfo {
+            default_action: self.default_action,
+            default_hits: self.default_hits.load(Ordering::Relaxed),
+            flows: self.flows.len(),
+            rules: self.rules.iter()
+                .map(|(rule, hits)| FirewallRuleInfo {
+                    rule: rule.clone(),
+                    hits: hits.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::common::acl::{ipv4_packet, AclProtocol, ICMP, TCP, UDP};
+
+    fn packet(src: [u8; 4], dst: [u8; 4], src_port: u16, dst_port: u16) -> Vec<u8> {
+        ipv4_packet(src, dst, TCP, src_port, dst_port)
+    }
+
+    let config = FirewallConfigFinalize {
+        default_action: FirewallAction::Drop,
+        rules: vec![
+            FirewallRuleFinalize {
+                action: FirewallAction::Accept,
+                src: vec!["10.0.0.0/24".parse().unwrap()],
+                dst: Vec::new(),
+                protocol: AclProtocol::Tcp,
+                ports: vec![(22, 22)],
+            }
+        ],
+    };
+
+    let fw = Firewall::new(&config);
+
+    assert!(fw.check(&packet([10, 0, 0, 2], [10, 0, 0, 1], 40000, 22)));
+    assert!(!fw.check(&packet([10, 0, 0, 2], [10, 0, 0, 1], 40000, 80)));
+    assert!(!fw.check(&packet([10, 0, 1, 2], [10, 0, 0, 1], 40000, 22)));
+
+    // reply of an outbound flow
+    assert!(!fw.check(&packet([10, 0, 1, 2], [10, 0, 0, 1], 80, 50000)));
+    fw.track(&packet([10, 0, 0, 1], [10, 0, 1, 2], 50000, 80));
+    assert!(fw.check(&packet([10, 0, 1, 2], [10, 0, 0, 1], 80, 50000)));
+
+    let info = fw.info();
+    assert_eq!(info.rules[0].hits, 1);
+    assert_eq!(info.default_hits, 3);
+    assert_eq!(info.flows, 1);
+
+    // icmp errors pass only if they quote a tracked flow
+    let request = ipv4_packet([10, 0, 0, 1], [10, 0, 1, 2], UDP, 50001, 53);
+    fw.track(&request);
+
+    let mut error = ipv4_packet([10, 0, 1, 2], [10, 0, 0, 1], ICMP, 0, 0);
+    error[20] = 3;
+    error.extend_from_slice(&request);
+    assert!(fw.check(&error));
+
+    let mut error = ipv4_packet([10, 0, 1, 2], [10, 0, 0, 1], ICMP, 0, 0);
+    error[20] = 3;
+    error.extend_from_slice(&ipv4_packet([10, 0, 0, 1], [10, 0, 1, 2], UDP, 50002, 53));
+    assert!(!fw.check(&error));
+
+    // the later fragments follow the first one
+    let mut first = packet([10, 0, 0, 2], [10, 0, 0, 1], 40001, 22);
+    first[4..6].copy_from_slice(&7u16.to_be_bytes());
+    first[6] = 0x20;
+    assert!(fw.check(&first));
+
+    let mut next = packet([10, 0, 0, 2], [10, 0, 0, 1], 0, 0);
+    next[4..6].copy_from_slice(&7u16.to_be_bytes());
+    next[7] = 1;
+    assert!(fw.check(&next));
+
+    next[4..6].copy_from_slice(&8u16.to_be_bytes());
+    assert!(!fw.check(&next));
+}
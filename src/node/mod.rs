@@ -5,7 +5,7 @@ use std::mem::size_of;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::{Deref, Range};
 use std::{env, slice};
-use std::sync::{Arc, OnceLock};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::time::Duration;
 use std::path::Path;
@@ -22,13 +22,10 @@ use chrono::Utc;
 use crossbeam_utils::atomic::AtomicCell;
 use futures_util::future::BoxFuture;
 use futures_util::FutureExt;
-use http_body_util::{BodyExt, Empty};
-use hyper::{Method, Request};
-use hyper_util::rt::TokioIo;
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use linear_map::LinearMap;
 use sys_route::Route;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use prettytable::{row, Table};
 use rand::{random, Rng, SeedableRng};
 use scopeguard::defer;
@@ -37,17 +34,27 @@ use tokio::io::BufReader;
 use tokio::net::{TcpSocket, TcpStream};
 use tokio::net::UdpSocket;
 use tokio::signal;
+use tokio::sync::{mpsc, watch};
 use tokio::sync::mpsc::{Receiver, Sender, unbounded_channel};
 use tokio::task::JoinHandle;
 use tokio::time::{self, Instant};
 
 use crate::common::hook::{Hooks, PacketRecvOutput};
-use crate::{common, routing_table, Cipher, Context, NodeConfigFinalize, NodeInfoType, ProtocolMode, TargetGroupFinalize};
+use crate::{common, routing_table, Cipher, Context, NodeConfigFinalize, NodeInfoType, ProtocolMode, ServerSelection, TargetGroup, TargetGroupFinalize};
 use crate::common::{allocator, utc_to_str};
+use crate::common::api::ApiClient;
+use crate::common::event::{Event, EventBus, PeerPath};
 use crate::common::allocator::Bytes;
-use crate::common::net::{get_ip_dst_addr, get_ip_src_addr, HeartbeatCache, HeartbeatInfo, SocketExt, UdpStatus};
-use crate::common::net::protocol::{AllocateError, GroupContent, HeartbeatType, NetProtocol, Node, PeerStatus, Register, RegisterError, Seq, TcpMsg, UdpMsg, VirtualAddr, SERVER_VIRTUAL_ADDR, TCP_BUFF_SIZE, TCP_MSG_HEADER_LEN, UDP_BUFF_SIZE, UDP_MSG_HEADER_LEN, UdpSocketErr};
+use crate::common::cipher::Nonce;
+use crate::common::conntrack::FlowTable;
+use crate::common::identity;
+use crate::common::quic::{self, ControlStream};
+use crate::common::net::{get_ip_dst_addr, get_ip_src_addr, get_ip_version, get_ipv6_dst_addr, get_ipv6_src_addr, HeartbeatCache, HeartbeatInfo, ReplayWindow, SocketExt, Traffic, TrafficStats, UdpStatus, UdpStatusCell, format_bytes, is_valid_domain};
+use crate::common::net::protocol::{v2, CAP_P2P_SESSION, Hello, MIN_PROTOCOL_VERSION, AllocateError, EphemeralKey, GroupContent, HandshakeType, HeartbeatType, SessionId, NetProtocol, Node, PeerStatus, PushedConfig, Register, RegisterError, Seq, TcpMsg, UdpMsg, VirtualAddr, SERVER_VIRTUAL_ADDR, TCP_BUFF_SIZE, TCP_MSG_HEADER_LEN, UDP_BUFF_SIZE, UDP_MSG_HEADER_LEN, UdpNonceGenerator, UdpSocketErr, udp_nonce_split};
 use crate::node::api::api_start;
+use crate::node::session::SessionTable;
+use crate::node::firewall::Firewall;
+use crate::node::sys_dns::SystemDnsHandle;
 use crate::node::sys_route::SystemRouteHandle;
 use crate::routing_table::{Item, ItemKind, RoutingTable};
 use crate::tun::TunDevice;
@@ -55,14 +62,94 @@ use crate::tun::TunDevice;
 mod api;
 #[cfg(feature = "cross-nat")]
 mod cross_nat;
+mod dns;
+mod firewall;
+mod session;
+#[cfg_attr(any(target_os = "windows", target_os = "linux"), path = "sys_dns.rs")]
+#[cfg_attr(not(any(target_os = "windows", target_os = "linux")), path = "fake_sys_dns.rs")]
+mod sys_dns;
 #[cfg_attr(any(target_os = "windows", target_os = "linux", target_os = "macos"), path = "sys_route.rs")]
 #[cfg_attr(not(any(target_os = "windows", target_os = "linux", target_os = "macos")), path = "fake_sys_route.rs")]
 mod sys_route;
 
-type NodeList = Vec<ExtendedNode>;
+// sorted by the virtual address
+#[derive(Default)]
+struct NodeList {
+    nodes: Vec<ExtendedNode>,
+    // ipv6 address -> position in nodes
+    v6_index: HashMap<Ipv6Addr, usize>,
+}
+
+impl NodeList {
+    fn new() -> Self {
+        NodeList::default()
+    }
+}
+
+impl From<Vec<ExtendedNode>> for NodeList {
+    fn from(mut nodes: Vec<ExtendedNode>) -> Self {
+        nodes.sort_unstable_by_key(|n| n.node.virtual_addr);
+
+        let v6_index = nodes.iter()
+            .enumerate()
+            .filter_map(|(i, n)| Some((n.node.virtual_addr_v6?, i)))
+            .collect();
+
+        NodeList { nodes, v6_index }
+    }
+}
+
+impl Deref for NodeList {
+    type Target = [ExtendedNode];
+
+    fn deref(&self) -> &Self::Target {
+        &self.nodes
+    }
+}
+
+impl<'a> IntoIterator for &'a NodeList {
+    type Item = &'a ExtendedNode;
+    type IntoIter = std::slice::Iter<'a, ExtendedNode>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.nodes.iter()
+    }
+}
+
+// packets between the tun device and the process
+struct TunMetrics {
+    read_packets: AtomicU64,
+    read_bytes: AtomicU64,
+    write_packets: AtomicU64,
+    write_bytes: AtomicU64,
+}
+
+impl TunMetrics {
+    fn read(&self, len: usize) {
+        self.read_packets.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, len: usize) {
+        self.write_packets.fetch_add(1, Ordering::Relaxed);
+        self.write_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+static TUN_METRICS: TunMetrics = TunMetrics {
+    read_packets: AtomicU64::new(0),
+    read_bytes: AtomicU64::new(0),
+    write_packets: AtomicU64::new(0),
+    write_bytes: AtomicU64::new(0),
+};
 
 trait NodeListOps {
     fn get_node(&self, addr: &VirtualAddr) -> Option<&ExtendedNode>;
+
+    fn get_node_by_v6(&self, addr: &Ipv6Addr) -> Option<&ExtendedNode>;
+
+    // the node that owns the source address of the ip packet
+    fn get_node_by_src(&self, packet: &[u8]) -> Option<&ExtendedNode>;
 }
 
 impl NodeListOps for NodeList {
@@ -71,6 +158,18 @@ impl NodeListOps for NodeList {
             .ok()
             .map(|v| &self[v])
     }
+
+    fn get_node_by_v6(&self, addr: &Ipv6Addr) -> Option<&ExtendedNode> {
+        self.v6_index.get(addr).map(|&v| &self.nodes[v])
+    }
+
+    fn get_node_by_src(&self, packet: &[u8]) -> Option<&ExtendedNode> {
+        match get_ip_version(packet) {
+            Ok(4) => self.get_node(&get_ip_src_addr(packet).ok()?),
+            Ok(6) => self.get_node_by_v6(&get_ipv6_src_addr(packet).ok()?),
+            _ => None
+        }
+    }
 }
 
 enum RoutingTableEnum<A, B> {
@@ -78,6 +177,37 @@ enum RoutingTableEnum<A, B> {
     External(SyncUnsafeCell<B>)
 }
 
+impl <A: RoutingTable + Clone, B: RoutingTable> RoutingTableEnum<A, B> {
+    fn update(&self, f: impl Fn(&mut dyn RoutingTable)) {
+        match self {
+            RoutingTableEnum::Internal(rt) => {
+                rt.rcu(|v| {
+                    let mut t = (**v).clone();
+                    f(&mut t);
+                    t
+                });
+            }
+            RoutingTableEnum::External(rt) => unsafe { f(&mut *rt.get()) }
+        }
+    }
+}
+
+// remove the item of the interface, the items of other interfaces with the same cidr are kept
+fn remove_item(t: &mut dyn RoutingTable, cidr: &IpNet, interface_index: usize, kind: ItemKind) {
+    let mut others = Vec::new();
+
+    while let Some(item) = t.remove(cidr) {
+        if item.interface_index == interface_index && item.extend.item_kind == Some(kind) {
+            break;
+        }
+        others.push(item);
+    }
+
+    for item in others {
+        t.add(item);
+    }
+}
+
 enum RoutingTableRefEnum<'a, A, B> {
     Cache(Cache<&'a ArcSwap<A>, Arc<A>>),
     Ref(&'a SyncUnsafeCell<B>)
@@ -160,20 +290,50 @@ pub struct Interface<K> {
     group_name: ArcSwapOption<String>,
     addr: AtomicAddr,
     cidr: AtomicCidr,
+    // the ipv4 address is on the tun, otherwise the group has no ipv4 range and addr only identifies the node
+    ipv4: AtomicBool,
+    addr_v6: AtomicCell<Option<Ipv6Addr>>,
+    cidr_v6: AtomicCell<Option<Ipv6Net>>,
     mode: ProtocolMode,
-    specify_mode: LinearMap<VirtualAddr, ProtocolMode>,
+    specify_mode: ArcSwap<LinearMap<VirtualAddr, ProtocolMode>>,
     node_list: ArcSwap<NodeList>,
-    server_addr: String,
+    // the server in use
+    server_addr: ArcSwap<String>,
+    server_addrs: Vec<String>,
     server_udp_hc: RwLock<HeartbeatCache>,
-    server_udp_status: AtomicCell<UdpStatus>,
+    server_udp_status: UdpStatusCell,
     server_tcp_hc: RwLock<HeartbeatCache>,
     server_is_connected: AtomicBool,
     server_allow_udp_relay: AtomicBool,
     server_allow_tcp_relay: AtomicBool,
+    // routes and dns settings received from the server
+    pushed: ArcSwap<PushedConfig>,
+    // flows allowed by the pushed acl
+    acl_flows: FlowTable,
     tcp_handler_channel: Option<Sender<Bytes>>,
+    // set while connected to the server over quic
+    quic_connection: ArcSwapOption<quinn::Connection>,
     udp_socket: Option<UdpSocket>,
     key: K,
-    peers_map: Option<RwLock<HashMap<VirtualAddr, Vec<PeerStatus>>>>
+    // p2p sessions, the group key is only used for handshakes and the server relay path
+    sessions: SessionTable,
+    udp_nonce: UdpNonceGenerator,
+    // sender virtual address -> counters received with the group key
+    replay_windows: Mutex<HashMap<VirtualAddr, ReplayWindow>>,
+    udp_replay_dropped: AtomicU64,
+    peers_map: Option<RwLock<HashMap<VirtualAddr, Vec<PeerStatus>>>>,
+    events: Arc<EventBus>,
+}
+
+impl <K> Interface<K> {
+    fn next_udp_nonce(&self) -> Nonce {
+        self.udp_nonce.next(self.addr.load().octets())
+    }
+
+    fn event(&self, event: Event) {
+        let group_name = self.group_name.load();
+        self.events.publish(group_name.as_deref().map(|v| v.as_str()), event);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -183,13 +343,19 @@ pub struct InterfaceInfo {
     group_name: Option<String>,
     addr: VirtualAddr,
     cidr: Ipv4Net,
+    ipv4: bool,
+    addr_v6: Option<Ipv6Addr>,
+    cidr_v6: Option<Ipv6Net>,
     mode: ProtocolMode,
     node_map: HashMap<VirtualAddr, ExtendedNodeInfo>,
     server_addr: String,
+    server_addrs: Vec<String>,
     server_udp_hc: HeartbeatInfo,
     server_udp_status: UdpStatus,
     server_tcp_hc: HeartbeatInfo,
     server_is_connected: bool,
+    pushed: PushedConfig,
+    udp_replay_dropped: u64,
 }
 
 impl <K> From<&Interface<K>> for InterfaceInfo {
@@ -205,6 +371,9 @@ impl <K> From<&Interface<K>> for InterfaceInfo {
             },
             addr: value.addr.load(),
             cidr: value.cidr.load(),
+            ipv4: value.ipv4.load(Ordering::Relaxed),
+            addr_v6: value.addr_v6.load(),
+            cidr_v6: value.cidr_v6.load(),
             mode: value.mode.clone(),
             node_map: {
                 value.node_list
@@ -215,29 +384,37 @@ impl <K> From<&Interface<K>> for InterfaceInfo {
                     })
                     .collect()
             },
-            server_addr: value.server_addr.clone(),
+            server_addr: (**value.server_addr.load()).clone(),
+            server_addrs: value.server_addrs.clone(),
             server_udp_hc: HeartbeatInfo::from(&*value.server_udp_hc.read()),
             server_udp_status: value.server_udp_status.load(),
             server_tcp_hc: HeartbeatInfo::from(&*value.server_tcp_hc.read()),
-            server_is_connected: value.server_is_connected.load(Ordering::Relaxed)
+            server_is_connected: value.server_is_connected.load(Ordering::Relaxed),
+            pushed: (**value.pushed.load()).clone(),
+            udp_replay_dropped: value.udp_replay_dropped.load(Ordering::Relaxed)
         }
     }
 }
 
 struct ExtendedNode {
     pub node: Node,
-    pub udp_status: Arc<AtomicCell<UdpStatus>>,
+    pub udp_status: Arc<UdpStatusCell>,
     pub hc: Arc<RwLock<HeartbeatCache>>,
-    pub peer_addr: Arc<AtomicCell<Option<SocketAddr>>>
+    pub peer_addr: Arc<AtomicCell<Option<SocketAddr>>>,
+    pub traffic: Arc<Traffic>,
+    // the path of the last packet sent to the node
+    pub path: Arc<AtomicCell<Option<PeerPath>>>
 }
 
 impl From<Node> for ExtendedNode {
     fn from(node: Node) -> Self {
         ExtendedNode {
             node,
-            udp_status: Arc::new(AtomicCell::new(UdpStatus::Unavailable)),
+            udp_status: Arc::new(UdpStatusCell::new()),
             hc: Arc::new(RwLock::new(HeartbeatCache::new())),
-            peer_addr: Arc::new(AtomicCell::new(None))
+            peer_addr: Arc::new(AtomicCell::new(None)),
+            traffic: Arc::new(Traffic::default()),
+            path: Arc::new(AtomicCell::new(None))
         }
     }
 }
@@ -247,6 +424,7 @@ struct ExtendedNodeInfo {
     node: Node,
     udp_status: UdpStatus,
     hc: HeartbeatInfo,
+    traffic: TrafficStats,
 }
 
 impl From<&ExtendedNode> for ExtendedNodeInfo {
@@ -254,7 +432,8 @@ impl From<&ExtendedNode> for ExtendedNodeInfo {
         ExtendedNodeInfo {
             node: value.node.clone(),  
             udp_status: value.udp_status.load(),
-            hc: HeartbeatInfo::from(&*value.hc.read())
+            hc: HeartbeatInfo::from(&*value.hc.read()),
+            traffic: TrafficStats::from(&*value.traffic)
         }
     }
 }
@@ -273,7 +452,8 @@ fn find_next_hop(
     curr: VirtualAddr,
     dst: VirtualAddr,
     cache: &mut Vec<(VirtualAddr, Option<NextHop>, Instant)>,
-    peers: &RwLock<HashMap<VirtualAddr, Vec<PeerStatus>>>
+    peers: &RwLock<HashMap<VirtualAddr, Vec<PeerStatus>>>,
+    on_change: impl FnOnce(Option<NextHop>)
 ) -> Option<NextHop> {
     fn find(
         curr: VirtualAddr,
@@ -324,20 +504,29 @@ fn find_next_hop(
 
             let new = find(curr, dst, &peers.read());
             let (_, old, t) = &mut cache[i];
+            let changed = old.map(|v| v.next) != new.map(|v| v.next);
             *old = new;
             *t = Instant::now();
+
+            if changed {
+                on_change(new);
+            }
             new
         }
         Err(i) => {
             let next = find(curr, dst, &peers.read());
             cache.insert(i, (dst, next, Instant::now()));
+
+            if next.is_some() {
+                on_change(next);
+            }
             next
         }
     }
 }
 
 async fn send<K: Cipher>(
-    nonce: u16,
+    tcp_nonce: Nonce,
     inter: &Interface<K>,
     dst_node: &ExtendedNode,
     buff: &mut [u8],
@@ -346,28 +535,46 @@ async fn send<K: Cipher>(
     next_route_cache: &mut Vec<(VirtualAddr, Option<NextHop>, Instant)>,
     node_list: &NodeList
 ) -> Result<()> {
-    let mode = inter.specify_mode.get(&dst_node.node.virtual_addr).unwrap_or(&inter.mode);
+    let specify_mode = inter.specify_mode.load().get(&dst_node.node.virtual_addr).cloned();
+    let mode = specify_mode.as_ref().unwrap_or(&inter.mode);
 
     macro_rules! relay_packet_through_node {
         ($max_cost: expr) => {
             if node_relay {
                 if let Some(peers_map) = &inter.peers_map {
-                    let next = find_next_hop(inter.addr.load(), dst_node.node.virtual_addr, next_route_cache, peers_map);
+                    let next = find_next_hop(
+                        inter.addr.load(),
+                        dst_node.node.virtual_addr,
+                        next_route_cache,
+                        peers_map,
+                        |next| {
+                            inter.event(Event::RouteChanged {
+                                dst: dst_node.node.virtual_addr,
+                                next_hop: next.map(|v| v.next),
+                                cost: next.map(|v| v.cost),
+                            })
+                        }
+                    );
         
                     if let Some(next) = next {
                         if next.cost < $max_cost {
                             if let Some(node) = node_list.get_node(&next.next) {
-                                if let UdpStatus::Available { dst_addr } = node.udp_status.load() {
+                                if let (UdpStatus::Available { dst_addr }, Some(session)) = (node.udp_status.load(), inter.sessions.get_outbound(&next.next)) {
                                     let socket = match &inter.udp_socket {
                                         None => unreachable!(),
                                         Some(socket) => socket,
                                     };
                         
                                     let packet = &mut buff[packet_range.start - UDP_MSG_HEADER_LEN..packet_range.end];
-                                    UdpMsg::data_encode(&inter.key, nonce, packet_range.len(), packet);
+                                    UdpMsg::data_encode(&session.cipher, session.nonce(inter.next_udp_nonce()), packet_range.len(), packet);
                         
                                     match UdpMsg::send_msg(socket, packet, dst_addr).await {
-                                        Ok(_) => return Ok(()),
+                                        Ok(_) => {
+                                            // the packet leaves on the p2p link of the next hop
+                                            node.traffic.udp_p2p.tx(packet_range.len());
+                                            update_path(inter, dst_node, PeerPath::NodeRelay { via: next.next });
+                                            return Ok(());
+                                        }
                                         Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
                                         Err(UdpSocketErr::SuppressError(e)) => {
                                             warn!("node {} send udp packet warn {}", inter.node_name, e);
@@ -386,8 +593,9 @@ async fn send<K: Cipher>(
 
     if support_p2p {
         let udp_status = dst_node.udp_status.load();
+        let session = inter.sessions.get_outbound(&dst_node.node.virtual_addr);
 
-        if let UdpStatus::Available { dst_addr } = udp_status {
+        if let (UdpStatus::Available { dst_addr }, Some(session)) = (udp_status, session) {
             debug!("PacketSender: udp message p2p to node {}", dst_node.node.name);
 
             let socket = match &inter.udp_socket {
@@ -396,10 +604,14 @@ async fn send<K: Cipher>(
             };
 
             let packet = &mut buff[packet_range.start - UDP_MSG_HEADER_LEN..packet_range.end];
-            UdpMsg::data_encode(&inter.key, nonce, packet_range.len(), packet);
+            UdpMsg::data_encode(&session.cipher, session.nonce(inter.next_udp_nonce()), packet_range.len(), packet);
 
             match UdpMsg::send_msg(socket, packet, dst_addr).await {
-                Ok(_) => return Ok(()),
+                Ok(_) => {
+                    dst_node.traffic.udp_p2p.tx(packet_range.len());
+                    update_path(inter, dst_node, PeerPath::P2p);
+                    return Ok(());
+                }
                 Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
                 Err(UdpSocketErr::SuppressError(e)) => {
                     warn!("node {} send udp packet warn {}", inter.node_name, e);
@@ -423,11 +635,48 @@ async fn send<K: Cipher>(
                     let mut packet = allocator::alloc(DATA_START + packet_range.len());
                     packet[DATA_START..].copy_from_slice(&buff[packet_range.start..packet_range.end]);
 
-                    TcpMsg::relay_encode(&inter.key, nonce, dst_node.node.virtual_addr, packet_range.len(), &mut packet);
+                    TcpMsg::relay_encode(&inter.key, tcp_nonce, dst_node.node.virtual_addr, packet_range.len(), &mut packet);
 
                     match tx.try_send(packet) {
                         Ok(_) => {
                             debug!("PacketSender: tcp message relay to node {}", dst_node.node.name);
+                            dst_node.traffic.tcp_relay.tx(packet_range.len());
+                            update_path(inter, dst_node, PeerPath::TcpRelay);
+                            return Ok(());
+                        },
+                        Err(e) => error!("PacketSender: tunnel error: {}", e)
+                    }
+                }
+                NetProtocol::QUIC if inter.server_allow_tcp_relay.load(Ordering::Relaxed) => {
+                    let conn = inter.quic_connection.load();
+
+                    let conn = match &*conn {
+                        None => continue,
+                        Some(conn) => conn,
+                    };
+
+                    let tx = match inter.tcp_handler_channel {
+                        None => unreachable!(),
+                        Some(ref v) => v,
+                    };
+
+                    const DATA_START: usize = TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+                    let mut packet = allocator::alloc(DATA_START + packet_range.len());
+                    packet[DATA_START..].copy_from_slice(&buff[packet_range.start..packet_range.end]);
+
+                    TcpMsg::relay_encode(&inter.key, tcp_nonce, dst_node.node.virtual_addr, packet_range.len(), &mut packet);
+
+                    let res = match quic::send_datagram(conn, &packet) {
+                        Ok(true) => Ok(()),
+                        Ok(false) => tx.try_send(packet).map_err(|e| anyhow!("{}", e)),
+                        Err(e) => Err(e)
+                    };
+
+                    match res {
+                        Ok(_) => {
+                            debug!("PacketSender: quic message relay to node {}", dst_node.node.name);
+                            dst_node.traffic.quic_relay.tx(packet_range.len());
+                            update_path(inter, dst_node, PeerPath::QuicRelay);
                             return Ok(());
                         },
                         Err(e) => error!("PacketSender: tunnel error: {}", e)
@@ -448,10 +697,14 @@ async fn send<K: Cipher>(
 
                     let packet = &mut buff[packet_range.start - size_of::<VirtualAddr>() - UDP_MSG_HEADER_LEN..packet_range.end];
 
-                    UdpMsg::relay_encode(&inter.key, nonce, dst_node.node.virtual_addr, packet_range.len(), packet);
+                    UdpMsg::relay_encode(&inter.key, inter.next_udp_nonce(), dst_node.node.virtual_addr, packet_range.len(), packet);
 
                     match UdpMsg::send_msg(socket, packet, dst_addr).await {
-                        Ok(_) => return Ok(()),
+                        Ok(_) => {
+                            dst_node.traffic.udp_relay.tx(packet_range.len());
+                            update_path(inter, dst_node, PeerPath::UdpRelay);
+                            return Ok(());
+                        }
                         Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
                         Err(UdpSocketErr::SuppressError(e)) => {
                             warn!("node {} send udp packet warn {}", inter.node_name, e);
@@ -468,25 +721,59 @@ async fn send<K: Cipher>(
     }
 
     warn!("no route to {}", dst_node.node.name);
+    update_path(inter, dst_node, PeerPath::Unreachable);
     Ok(())
 }
 
+// publish the fallbacks to the relay paths and the recoveries
+fn update_path<K>(inter: &Interface<K>, dst_node: &ExtendedNode, path: PeerPath) {
+    if dst_node.path.load() == Some(path) {
+        return;
+    }
+
+    if dst_node.path.swap(Some(path)) != Some(path) {
+        inter.event(Event::PathChanged {
+            node: dst_node.node.name.clone(),
+            addr: dst_node.node.virtual_addr,
+            path,
+        });
+    }
+}
+
 enum TransferType {
     Unicast(VirtualAddr),
     Broadcast,
 }
 
+// the acl pushed by the server, checked on the packets leaving the node and the packets received from any path
+fn acl_allows<K>(interface: &Interface<K>, node_list: &NodeList, packet: &[u8]) -> bool {
+    let pushed = interface.pushed.load();
+
+    let acl = match &pushed.acl {
+        None => return true,
+        Some(acl) => acl
+    };
+
+    acl.allows(packet, &interface.acl_flows, |addr| {
+        let node = match addr {
+            IpAddr::V4(addr) => node_list.get_node(&addr),
+            IpAddr::V6(addr) => node_list.get_node_by_v6(&addr)
+        };
+        node.map(|n| n.node.name.as_str())
+    })
+}
+
 #[allow(unused)]
-fn find_once<RT: RoutingTable>(rt: &RT, src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> Option<Cow<Item>>{
+fn find_once<RT: RoutingTable>(rt: &RT, src_addr: IpAddr, dst_addr: IpAddr) -> Option<Cow<Item>>{
     rt.find(src_addr, dst_addr)
 }
 
-fn find_route<RT: RoutingTable>(rt: &RT, src_addr: Ipv4Addr, mut dst_addr: Ipv4Addr) -> Option<(Ipv4Addr, Cow<Item>)> {
+fn find_route<RT: RoutingTable>(rt: &RT, src_addr: IpAddr, mut dst_addr: IpAddr) -> Option<(IpAddr, Cow<Item>)> {
     let mut item = rt.find(src_addr, dst_addr)?;
     let mut count = 1;
 
     // is route on link
-    while item.gateway != Ipv4Addr::UNSPECIFIED {
+    while !item.gateway.is_unspecified() {
         // too many hops, possibly loop routing
         if count > 5 {
             return None;
@@ -506,6 +793,7 @@ struct PacketSender<'a, InterRT, ExternRT, Tun, K> {
     nodes_cache: Vec<Cache<&'a ArcSwap<NodeList>, Arc<NodeList>>>,
     tun: &'a Tun,
     hooks: Option<&'a Hooks<K>>,
+    firewall: Option<&'a Firewall>,
     #[cfg(feature = "cross-nat")]
     snat: Option<&'a cross_nat::SNat>,
     rng: rand::rngs::SmallRng,
@@ -532,6 +820,7 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
         interfaces: &'a [&'a Interface<K>],
         tun: &'a Tun,
         hooks: Option<&'a Hooks<K>>,
+        firewall: Option<&'a Firewall>,
         #[cfg(feature = "cross-nat")]
         snat: Option<&'a cross_nat::SNat>
     ) -> Self {
@@ -541,6 +830,7 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
             nodes_cache: interfaces.iter().map(|v| Cache::new(&v.node_list)).collect::<Vec<_>>(),
             tun,
             hooks,
+            firewall,
             #[cfg(feature = "cross-nat")]
             snat,
             rng: rand::rngs::SmallRng::from_entropy(),
@@ -548,6 +838,11 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
         }
     }
 
+    async fn write_tun(&self, packet: &[u8]) -> Result<()> {
+        TUN_METRICS.write(packet.len());
+        self.tun.send_packet(packet).await.context("error send packet to tun")
+    }
+
     async fn send_packet(
         &mut self,
         direction: Direction,
@@ -560,11 +855,39 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
 
         let packet = &mut buff[packet_range.clone()];
 
-        let (Ok(src_addr), Ok(dst_addr)) = (get_ip_src_addr(packet), get_ip_dst_addr(packet)) else {
-            error!("Illegal ipv4 packet");
-            return Ok(());
+        let (src_addr, dst_addr) = match get_ip_version(packet) {
+            Ok(4) => match (get_ip_src_addr(packet), get_ip_dst_addr(packet)) {
+                (Ok(src), Ok(dst)) => (IpAddr::V4(src), IpAddr::V4(dst)),
+                _ => {
+                    error!("Illegal ipv4 packet");
+                    return Ok(());
+                }
+            },
+            Ok(6) => match (get_ipv6_src_addr(packet), get_ipv6_dst_addr(packet)) {
+                (Ok(src), Ok(dst)) => (IpAddr::V6(src), IpAddr::V6(dst)),
+                _ => {
+                    error!("Illegal ipv6 packet");
+                    return Ok(());
+                }
+            },
+            _ => {
+                error!("Illegal ip packet");
+                return Ok(());
+            }
         };
 
+        if let Some(firewall) = self.firewall {
+            match direction {
+                Direction::Input => {
+                    if !firewall.check(packet) {
+                        debug!("PacketSender: packet {}->{} is dropped by the firewall", src_addr, dst_addr);
+                        return Ok(());
+                    }
+                }
+                Direction::Output => firewall.track(packet)
+            }
+        }
+
         if let Some(hooks) = self.hooks {
             let output = hooks.packet_recv(direction, packet);
 
@@ -574,7 +897,7 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
         }
 
         #[cfg(feature = "cross-nat")]
-        if let Some(snat) = self.snat {
+        if let (Some(snat), IpAddr::V4(_)) = (self.snat, src_addr) {
             let item = match &mut self.rt_ref {
                 RoutingTableRefEnum::Cache(v) => find_once(&**v.load(), src_addr, dst_addr),
                 RoutingTableRefEnum::Ref(v) => unsafe { find_once(&*v.get(), src_addr, dst_addr) }
@@ -585,15 +908,19 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
             }
         }
 
+        // ipv6 multicast is routed by the source, it is sent to the whole group of the source
+        let is_multicast_v6 = dst_addr.is_ipv6() && dst_addr.is_multicast();
+        let route_addr = ternary!(is_multicast_v6, src_addr, dst_addr);
+
         let opt = match &mut self.rt_ref {
-            RoutingTableRefEnum::Cache(v) => find_route(&**v.load(), src_addr, dst_addr),
-            RoutingTableRefEnum::Ref(v) => unsafe { find_route(&*v.get(), src_addr, dst_addr) }
+            RoutingTableRefEnum::Cache(v) => find_route(&**v.load(), src_addr, route_addr),
+            RoutingTableRefEnum::Ref(v) => unsafe { find_route(&*v.get(), src_addr, route_addr) }
         };
 
-        let (dst_addr, item) = match opt {
+        let (hop, item) = match opt {
             None => {
                 if direction == Direction::Input && allow_packet_not_in_rules_send_to_kernel {
-                    self.tun.send_packet(&buff[packet_range]).await.context("error send packet to tun")?;
+                    self.write_tun(&buff[packet_range]).await?;
                 }
 
                 debug!("PacketSender: cannot find route {}->{}", src_addr, dst_addr);
@@ -604,40 +931,82 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
 
         let if_index = item.interface_index;
 
-        let (interface, node_list, next_route_cache) = match interfaces.iter().position(|i| i.index == if_index) {
-            Some(i) => (interfaces[i], &**self.nodes_cache[i].load(), &mut self.next_route[i]),
+        let if_pos = match interfaces.iter().position(|i| i.index == if_index) {
+            Some(i) => i,
             None => return Ok(())
         };
 
-        let interface_addr = interface.addr.load();
-        let interface_cidr = interface.cidr.load();
+        let transfer_type = match (src_addr, hop) {
+            (IpAddr::V4(src_addr), IpAddr::V4(hop)) => {
+                let interface = interfaces[if_pos];
+                let interface_addr = interface.addr.load();
+                let interface_cidr = interface.cidr.load();
 
-        if !interface.server_is_connected.load(Ordering::Relaxed) {
-            return Ok(())
-        }
+                if hop.is_broadcast() {
+                    if direction == Direction::Output && interface_addr != src_addr {
+                        return Ok(())
+                    }
 
-        let transfer_type = if dst_addr.is_broadcast() {
-            if direction == Direction::Output && interface_addr != src_addr {
-                return Ok(())
+                    if direction == Direction::Input && !interface_cidr.contains(&src_addr) {
+                        return Ok(())
+                    }
+
+                    TransferType::Broadcast
+                } else if interface_cidr.broadcast() == hop {
+                    TransferType::Broadcast
+                } else {
+                    TransferType::Unicast(hop)
+                }
             }
+            (IpAddr::V6(src_addr), IpAddr::V6(hop)) => {
+                if is_multicast_v6 {
+                    if direction == Direction::Output && interfaces[if_pos].addr_v6.load() != Some(src_addr) {
+                        return Ok(())
+                    }
 
-            if direction == Direction::Input && !interface_cidr.contains(&src_addr) {
-                return Ok(())
+                    TransferType::Broadcast
+                } else {
+                    match self.nodes_cache[if_pos].load().get_node_by_v6(&hop) {
+                        None => {
+                            debug!("PacketSender: cannot find node {}", hop);
+                            return Ok(())
+                        }
+                        Some(node) => TransferType::Unicast(node.node.virtual_addr)
+                    }
+                }
             }
+            _ => return Ok(())
+        };
 
-            TransferType::Broadcast
-        } else if interface_cidr.broadcast() == dst_addr {
-            TransferType::Broadcast
-        } else {
-            TransferType::Unicast(dst_addr)
+        let (interface, node_list, next_route_cache) = (
+            interfaces[if_pos],
+            &**self.nodes_cache[if_pos].load(),
+            &mut self.next_route[if_pos]
+        );
+
+        let interface_addr = interface.addr.load();
+
+        if !interface.server_is_connected.load(Ordering::Relaxed) {
+            return Ok(())
+        }
+
+        // the packets to the node itself stay local
+        let leaves_node = match transfer_type {
+            TransferType::Unicast(addr) => addr != interface_addr,
+            TransferType::Broadcast => direction == Direction::Output,
         };
 
+        if (leaves_node || direction == Direction::Input) && !acl_allows(interface, node_list, &buff[packet_range.clone()]) {
+            debug!("PacketSender: packet {}->{} is denied by the acl", src_addr, dst_addr);
+            return Ok(());
+        }
+
         match transfer_type {
             TransferType::Unicast(addr) => {
                 debug!("PacketSender: packet {}->{}; gateway: {}", src_addr, dst_addr, addr);
 
                 if interface_addr == addr {
-                    return self.tun.send_packet(&buff[packet_range]).await.context("error send packet to tun");
+                    return self.write_tun(&buff[packet_range]).await;
                 }
 
                 let f = match direction {
@@ -684,7 +1053,7 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
                         }
                     }
                     Direction::Input => {
-                        self.tun.send_packet(&buff[packet_range]).await.context("error send packet to tun")?;
+                        self.write_tun(&buff[packet_range]).await?;
                     }
                 }
             }
@@ -696,8 +1065,10 @@ impl <'a, InterRT, ExternRT, Tun, K> PacketSender<'a, InterRT, ExternRT, Tun, K>
 async fn tun_handler<T, K, InterRT, ExternRT>(
     tun: T,
     routing_table: Arc<RoutingTableEnum<InterRT, ExternRT>>,
-    interfaces: Vec<Arc<Interface<K>>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    interfaces_changed: watch::Receiver<()>,
     hooks: Option<Arc<Hooks<K>>>,
+    firewall: Option<Arc<Firewall>>,
     #[cfg(feature = "cross-nat")]
     snat: Option<Arc<cross_nat::SNat>>
 ) -> Result<()>
@@ -714,43 +1085,55 @@ async fn tun_handler<T, K, InterRT, ExternRT>(
             let tun = tun.clone();
             let routing_table = routing_table.clone();
             let interfaces = interfaces.clone();
+            let mut interfaces_changed = interfaces_changed.clone();
             let hooks = hooks.clone();
+            let firewall = firewall.clone();
             #[cfg(feature = "cross-nat")]
             let snat = snat.clone();
 
             let join: JoinHandle<Result<()>> = tokio::spawn(async move {
-                let interfaces = interfaces.iter().map(|v| &**v).collect::<Vec<_>>();
-
-                let mut sender = PacketSender::new(
-                    &*routing_table,
-                    &interfaces,
-                    &tun,
-                    hooks.as_deref(),
-                    #[cfg(feature = "cross-nat")]
-                    snat.as_deref()
-                );
-
                 let mut buff = vec![0u8; UDP_BUFF_SIZE];
 
+                // the sender is rebuilt when a group is added or removed
                 loop {
-                    const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
-
-                    let packet_range = match tun
-                        .recv_packet(&mut buff[START..])
-                        .await
-                        .context("error receive packet from tun")?
-                    {
-                        0 => continue,
-                        len => START..START + len,
-                    };
+                    interfaces_changed.borrow_and_update();
+                    let list = interfaces.load_full();
+                    let interfaces = list.iter().map(|v| &**v).collect::<Vec<_>>();
+
+                    let mut sender = PacketSender::new(
+                        &*routing_table,
+                        &interfaces,
+                        &tun,
+                        hooks.as_deref(),
+                        firewall.as_deref(),
+                        #[cfg(feature = "cross-nat")]
+                        snat.as_deref()
+                    );
+
+                    loop {
+                        const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
 
-                    sender.send_packet(
-                        Direction::Output,
-                        packet_range,
-                        &mut buff,
-                        true,
-                        false
-                    ).await?;
+                        let res = tokio::select! {
+                            res = tun.recv_packet(&mut buff[START..]) => res,
+                            Ok(_) = interfaces_changed.changed() => break
+                        };
+
+                        let packet_range = match res.context("error receive packet from tun")? {
+                            0 => continue,
+                            len => {
+                                TUN_METRICS.read(len);
+                                START..START + len
+                            }
+                        };
+
+                        sender.send_packet(
+                            Direction::Output,
+                            packet_range,
+                            &mut buff,
+                            true,
+                            false
+                        ).await?;
+                    }
                 }
             });
             join.await?
@@ -765,8 +1148,8 @@ async fn tun_handler<T, K, InterRT, ExternRT>(
 
 fn through_virtual_gateway<RT: RoutingTable + ?Sized>(routing_table: &RT, src: SocketAddr, dst: SocketAddr) -> bool {
     match (src, dst) {
-        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
-           routing_table.find(*src.ip(), *dst.ip()).is_some_and(|i| i.extend.item_kind != Some(ItemKind::AllowedIpsRoute))
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+           routing_table.find(src.ip(), dst.ip()).is_some_and(|i| i.extend.item_kind != Some(ItemKind::AllowedIpsRoute))
         }
         _ => false
     }
@@ -774,13 +1157,15 @@ fn through_virtual_gateway<RT: RoutingTable + ?Sized>(routing_table: &RT, src: S
 
 async fn udp_handler<T, K, InterRT, ExternRT>(
     config: &'static NodeConfigFinalize<K>,
-    group: &'static TargetGroupFinalize<K>,
+    group: Arc<TargetGroupFinalize<K>>,
     table: Arc<RoutingTableEnum<InterRT, ExternRT>>,
     interface: Arc<Interface<K>>,
     tun: T,
     hooks: Option<Arc<Hooks<K>>>,
+    firewall: Option<Arc<Firewall>>,
     #[cfg(feature = "cross-nat")]
-    snat: Option<Arc<cross_nat::SNat>>
+    snat: Option<Arc<cross_nat::SNat>>,
+    notified: watch::Receiver<()>
 ) -> Result<()>
 where
     T: TunDevice + Clone + Send + Sync + 'static,
@@ -791,15 +1176,18 @@ where
     let lan_ip_addr = group.lan_ip_addr.unwrap();
 
     let heartbeat_schedule = async {
+        let group = group.clone();
         let interface = interface.clone();
         let table = table.clone();
 
-        let join = tokio::spawn(async move {
-            let mut rng = rand::rngs::SmallRng::from_entropy();
+        let mut notified = notified.clone();
+
+        let fut = async move {
             let socket = interface.udp_socket.as_ref().expect("must need udp socket");
             let key = &interface.key;
             let is_p2p = interface.mode.p2p.contains(&NetProtocol::UDP);
             let mut packet = [0u8; UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + size_of::<Seq>() + size_of::<HeartbeatType>()];
+            let mut handshake_packet = [0u8; UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + size_of::<HandshakeType>() + size_of::<SessionId>() + size_of::<EphemeralKey>()];
 
             loop {
                 let interface_addr = interface.addr.load();
@@ -814,6 +1202,7 @@ where
                             interface.server_udp_status.load() != UdpStatus::Unavailable
                         {
                             interface.server_udp_status.store(UdpStatus::Unavailable);
+                            interface.event(Event::UdpStatusChanged { node: None, status: UdpStatus::Unavailable });
                         }
 
                         server_hc_guard.ping();
@@ -822,14 +1211,14 @@ where
 
                     UdpMsg::heartbeat_encode(
                         key,
-                        rng.gen(),
+                        interface.next_udp_nonce(),
                         interface_addr,
                         seq,
                         HeartbeatType::Req,
                         &mut packet,
                     );
 
-                    match UdpMsg::send_msg(socket, &packet, &interface.server_addr).await {
+                    match UdpMsg::send_msg(socket, &packet, interface.server_addr.load_full().as_str()).await {
                         Ok(_) => (),
                         Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
                         Err(UdpSocketErr::SuppressError(e)) => {
@@ -839,12 +1228,45 @@ where
 
                     if is_p2p {
                         let node_list = interface.node_list.load_full();
+                        interface.sessions.retain_peers(|addr| node_list.get_node(addr).is_some());
+                        interface.replay_windows.lock().retain(|addr, _| *addr == SERVER_VIRTUAL_ADDR || node_list.get_node(addr).is_some());
 
-                        for ext_node in node_list.as_slice() {
+                        for ext_node in node_list.iter() {
                             if !ext_node.node.mode.p2p.contains(&NetProtocol::UDP) {
                                 continue;
                             }
 
+                            let peer = ext_node.node.virtual_addr;
+
+                            match ext_node.udp_status.load() {
+                                // the node with the smaller address initiates the key exchange, peers without session support are only reachable by relay
+                                UdpStatus::Available { dst_addr } if interface_addr < peer && ext_node.node.capabilities & CAP_P2P_SESSION != 0 => {
+                                    if interface.sessions.needs_handshake(&peer, config.udp_session_rekey_interval) {
+                                        if let Some((session_id, ephemeral_key)) = interface.sessions.initiate(peer) {
+                                            let len = UdpMsg::handshake_encode(
+                                                key,
+                                                interface.next_udp_nonce(),
+                                                interface_addr,
+                                                HandshakeType::Init,
+                                                session_id,
+                                                &ephemeral_key,
+                                                &mut handshake_packet
+                                            );
+
+                                            match UdpMsg::send_msg(socket, &handshake_packet[..len], dst_addr).await {
+                                                Ok(_) => (),
+                                                Err(UdpSocketErr::FatalError(e)) => return Result::<(), _>::Err(anyhow!(e)),
+                                                Err(UdpSocketErr::SuppressError(e)) => {
+                                                    warn!("node {} send udp packet warn {}", group.node_name, e);
+                                                }
+                                            };
+                                        }
+                                    }
+                                }
+                                UdpStatus::Available { .. } => (),
+                                UdpStatus::Unavailable => interface.sessions.remove(&peer)
+                            }
+
                             let is_over: bool;
                             let udp_status = ext_node.udp_status.load();
 
@@ -855,6 +1277,11 @@ where
 
                                 if is_over && udp_status != UdpStatus::Unavailable {
                                     ext_node.udp_status.store(UdpStatus::Unavailable);
+
+                                    interface.event(Event::UdpStatusChanged {
+                                        node: Some(ext_node.node.name.clone()),
+                                        status: UdpStatus::Unavailable,
+                                    });
                                 }
 
                                 if ext_node.node.lan_udp_addr.is_none() ||
@@ -869,7 +1296,7 @@ where
 
                             UdpMsg::heartbeat_encode(
                                 key,
-                                rng.gen(),
+                                interface.next_udp_nonce(),
                                 interface_addr, 
                                 seq, 
                                 HeartbeatType::Req, 
@@ -938,6 +1365,13 @@ where
 
                 time::sleep(config.udp_heartbeat_interval).await
             }
+        };
+
+        let join = tokio::spawn(async move {
+            tokio::select! {
+                res = fut => res,
+                _ = notified.changed() => Err(anyhow!("abort task"))
+            }
         });
 
         join.await?
@@ -948,15 +1382,18 @@ where
 
     for _ in 0..2 {
         let recv_handler = async {
+            let group = group.clone();
             let interface = interface.clone();
             let table = table.clone();
             let tun = tun.clone();
             let hooks = hooks.clone();
+            let firewall = firewall.clone();
             #[cfg(feature = "cross-nat")]
             let snat = snat.clone();
 
-            let join = tokio::spawn(async move {
-                let mut rng = rand::rngs::SmallRng::from_entropy();
+            let mut notified = notified.clone();
+
+            let fut = async move {
                 let socket = interface.udp_socket.as_ref().expect("must need udp socket");
                 let key = &interface.key;
                 let is_p2p = interface.mode.p2p.contains(&NetProtocol::UDP);
@@ -966,7 +1403,8 @@ where
                     &*table, 
                     &arr, 
                     &tun, 
-                    hooks.as_deref(), 
+                    hooks.as_deref(),
+                    firewall.as_deref(),
                     #[cfg(feature = "cross-nat")]
                     snat.as_deref()
                 );
@@ -986,129 +1424,271 @@ where
 
                     let packet = &mut buff[START..START + len];
 
-                    if let Ok(packet) = UdpMsg::decode(key, packet) {
-                        match packet {
-                            UdpMsg::Heartbeat(from_addr, seq, HeartbeatType::Req) => {
-                                let mut is_known = false;
+                    let nonce = match UdpMsg::nonce(packet) {
+                        Some(nonce) => nonce,
+                        None => continue
+                    };
 
-                                if from_addr == SERVER_VIRTUAL_ADDR {
-                                    is_known = true;
-                                } else if is_p2p {
-                                    if let Some(en) = interface.node_list.load().get_node(&from_addr) {
-                                        let old = en.peer_addr.load();
+                    // packets of a p2p session carry the local session id in the nonce
+                    let (nonce_sender, counter) = udp_nonce_split(&nonce);
+                    let session = interface.sessions.get_inbound(SessionId::from_be_bytes(nonce_sender));
 
-                                        if old != Some(peer_addr) {
-                                            en.peer_addr.store(Some(peer_addr));
-                                        }
-                                        is_known = true;
+                    let is_session_packet = match &session {
+                        Some(session) if UdpMsg::decrypt(&session.cipher, packet).is_ok() => true,
+                        _ => {
+                            if UdpMsg::decrypt(key, packet).is_err() {
+                                continue;
+                            }
+                            false
+                        }
+                    };
+
+                    let session = session.filter(|_| is_session_packet);
+                    let session_peer = session.as_ref().map(|session| session.peer);
+
+                    let is_replay = match &session {
+                        Some(session) => !session.replay_window.lock().check(counter),
+                        // the counter of a non-aead cipher can be forged
+                        None if !key.is_aead() => false,
+                        None => {
+                            let from = VirtualAddr::from(nonce_sender);
+
+                            if from != SERVER_VIRTUAL_ADDR && interface.node_list.load().get_node(&from).is_none() {
+                                continue;
+                            }
+
+                            !interface.replay_windows.lock()
+                                .entry(from)
+                                .or_insert_with(ReplayWindow::new)
+                                .check(counter)
+                        }
+                    };
+
+                    if is_replay {
+                        interface.udp_replay_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+
+                    if let Some(session) = &session {
+                        interface.sessions.confirm(session);
+                    }
+
+                    let packet = match UdpMsg::parse(packet) {
+                        Ok(packet) => packet,
+                        Err(_) => continue
+                    };
+
+                    // p2p data must use the session key, and the session key is only used for data
+                    if is_session_packet != matches!(packet, UdpMsg::Data(_)) {
+                        continue;
+                    }
+
+                    match packet {
+                        UdpMsg::Heartbeat(from_addr, seq, HeartbeatType::Req) => {
+                            let mut is_known = false;
+
+                            if from_addr == SERVER_VIRTUAL_ADDR {
+                                is_known = true;
+                            } else if is_p2p {
+                                if let Some(en) = interface.node_list.load().get_node(&from_addr) {
+                                    let old = en.peer_addr.load();
+
+                                    if old != Some(peer_addr) {
+                                        en.peer_addr.store(Some(peer_addr));
                                     }
+                                    is_known = true;
                                 }
+                            }
 
-                                if is_known {
-                                    let interface_addr = interface.addr.load();
+                            if is_known {
+                                let interface_addr = interface.addr.load();
 
-                                    let len = UdpMsg::heartbeat_encode(
-                                        key,
-                                        rng.gen(),
-                                        interface_addr,
-                                        seq,
-                                        HeartbeatType::Resp,
-                                        &mut buff,
-                                    );
+                                let len = UdpMsg::heartbeat_encode(
+                                    key,
+                                    interface.next_udp_nonce(),
+                                    interface_addr,
+                                    seq,
+                                    HeartbeatType::Resp,
+                                    &mut buff,
+                                );
 
-                                    let packet = &mut buff[..len];
+                                let packet = &mut buff[..len];
 
-                                    match UdpMsg::send_msg(socket, packet, peer_addr).await {
-                                        Ok(_) => (),
-                                        Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
-                                        Err(UdpSocketErr::SuppressError(e)) => {
-                                            warn!("node {} send udp packet warn {}", group.node_name, e);
-                                        }
-                                    };
-                                }
+                                match UdpMsg::send_msg(socket, packet, peer_addr).await {
+                                    Ok(_) => (),
+                                    Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
+                                    Err(UdpSocketErr::SuppressError(e)) => {
+                                        warn!("node {} send udp packet warn {}", group.node_name, e);
+                                    }
+                                };
                             }
-                            UdpMsg::Heartbeat(from_addr, seq, HeartbeatType::Resp) => {
-                                if from_addr == SERVER_VIRTUAL_ADDR {
-                                    let udp_status = interface.server_udp_status.load();
-
-                                    match udp_status {
-                                        UdpStatus::Available { dst_addr } => {
-                                            if dst_addr == peer_addr {
-                                                interface.server_udp_hc.write().reply(seq);
-                                                continue;
-                                            }
+                        }
+                        UdpMsg::Heartbeat(from_addr, seq, HeartbeatType::Resp) => {
+                            if from_addr == SERVER_VIRTUAL_ADDR {
+                                let udp_status = interface.server_udp_status.load();
+
+                                match udp_status {
+                                    UdpStatus::Available { dst_addr } => {
+                                        if dst_addr == peer_addr {
+                                            interface.server_udp_hc.write().reply(seq);
+                                            continue;
+                                        }
 
-                                            if lookup_host(&interface.server_addr).await == Some(peer_addr) {
-                                                if interface.server_udp_hc.write().reply(seq).is_some() {
-                                                    interface.server_udp_status.store(UdpStatus::Available {dst_addr: peer_addr});
-                                                }
+                                        if lookup_host(&interface.server_addr.load_full()).await == Some(peer_addr) {
+                                            if interface.server_udp_hc.write().reply(seq).is_some() {
+                                                interface.server_udp_status.store(UdpStatus::Available {dst_addr: peer_addr});
                                             }
                                         }
-                                        UdpStatus::Unavailable => {
-                                            if lookup_host(&interface.server_addr).await == Some(peer_addr)  {
-                                                let mut server_hc_guard = interface.server_udp_hc.write();
+                                    }
+                                    UdpStatus::Unavailable => {
+                                        if lookup_host(&interface.server_addr.load_full()).await == Some(peer_addr)  {
+                                            let mut server_hc_guard = interface.server_udp_hc.write();
 
-                                                if server_hc_guard.reply(seq).is_some() &&
-                                                    server_hc_guard.packet_continuous_recv_count >= config.udp_heartbeat_continuous_recv
-                                                {
-                                                    drop(server_hc_guard);
-                                                    interface.server_udp_status.store(UdpStatus::Available {dst_addr: peer_addr});
-                                                }
+                                            if server_hc_guard.reply(seq).is_some() &&
+                                                server_hc_guard.packet_continuous_recv_count >= config.udp_heartbeat_continuous_recv
+                                            {
+                                                drop(server_hc_guard);
+                                                let status = UdpStatus::Available {dst_addr: peer_addr};
+                                                interface.server_udp_status.store(status);
+                                                interface.event(Event::UdpStatusChanged { node: None, status });
                                             }
                                         }
-                                    };
-                                } else if is_p2p {
-                                    if let Some(node) = interface.node_list.load_full().get_node(&from_addr) {
-                                        let mut hc_guard = node.hc.write();
+                                    }
+                                };
+                            } else if is_p2p {
+                                if let Some(node) = interface.node_list.load_full().get_node(&from_addr) {
+                                    let mut hc_guard = node.hc.write();
 
-                                        if hc_guard.reply(seq).is_some() {
-                                            let through_vgateway = || {
-                                                let src = SocketAddr::new(lan_ip_addr, 0);
+                                    if hc_guard.reply(seq).is_some() {
+                                        let through_vgateway = || {
+                                            let src = SocketAddr::new(lan_ip_addr, 0);
 
-                                                match &*table {
-                                                    RoutingTableEnum::Internal(v) => through_virtual_gateway(&**v.load(), src, peer_addr),
-                                                    RoutingTableEnum::External(v) => through_virtual_gateway(unsafe { &*v.get() }, src, peer_addr)
-                                                }
-                                            };
+                                            match &*table {
+                                                RoutingTableEnum::Internal(v) => through_virtual_gateway(&**v.load(), src, peer_addr),
+                                                RoutingTableEnum::External(v) => through_virtual_gateway(unsafe { &*v.get() }, src, peer_addr)
+                                            }
+                                        };
 
-                                            if node.udp_status.load() == UdpStatus::Unavailable &&
-                                                hc_guard.packet_continuous_recv_count >= config.udp_heartbeat_continuous_recv &&
-                                                (config.socket_bind_device.is_some() || !through_vgateway())
-                                            {
-                                                drop(hc_guard);
+                                        if node.udp_status.load() == UdpStatus::Unavailable &&
+                                            hc_guard.packet_continuous_recv_count >= config.udp_heartbeat_continuous_recv &&
+                                            (config.socket_bind_device.is_some() || !through_vgateway())
+                                        {
+                                            drop(hc_guard);
 
-                                                node.udp_status.store(UdpStatus::Available {
-                                                    dst_addr: peer_addr,
-                                                });
-                                            }
+                                            let status = UdpStatus::Available {
+                                                dst_addr: peer_addr,
+                                            };
+                                            node.udp_status.store(status);
+
+                                            interface.event(Event::UdpStatusChanged {
+                                                node: Some(node.node.name.clone()),
+                                                status,
+                                            });
                                         }
                                     }
-                                };
+                                }
+                            };
+                        }
+                        // todo forward packet ttl minus one
+                        UdpMsg::Data(data) => {
+                            const START_DATA: usize = START + UDP_MSG_HEADER_LEN;
+                            let data_len = data.len();
+
+                            // sent by the initiator to confirm a new session
+                            if data_len == 0 {
+                                continue;
                             }
-                            // todo forward packet ttl minus one
-                            UdpMsg::Data(_) => {
-                                const START_DATA: usize = START + UDP_MSG_HEADER_LEN;
-                                sender.send_packet(
-                                    Direction::Input,
-                                    START_DATA..START_DATA + len,
-                                    &mut buff,
-                                    config.allow_packet_forward,
-                                    config.allow_packet_not_in_rules_send_to_kernel
-                                ).await?;
+
+                            if let Some(peer) = session_peer {
+                                if let Some(en) = interface.node_list.load().get_node(&peer) {
+                                    en.traffic.udp_p2p.rx(data_len);
+                                }
                             }
-                            UdpMsg::Relay(_, _) => {
-                                const START_DATA: usize = START + UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
-                                sender.send_packet(
-                                    Direction::Input,
-                                    START_DATA..START_DATA + len,
-                                    &mut buff,
-                                    config.allow_packet_forward,
-                                    config.allow_packet_not_in_rules_send_to_kernel
-                                ).await?;
+
+                            sender.send_packet(
+                                Direction::Input,
+                                START_DATA..START_DATA + data_len,
+                                &mut buff,
+                                config.allow_packet_forward,
+                                config.allow_packet_not_in_rules_send_to_kernel
+                            ).await?;
+                        }
+                        UdpMsg::Relay(_, data) => {
+                            const START_DATA: usize = START + UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+                            let data_len = data.len();
+
+                            if let Some(en) = interface.node_list.load().get_node_by_src(data) {
+                                en.traffic.udp_relay.rx(data_len);
+                            }
+
+                            sender.send_packet(
+                                Direction::Input,
+                                START_DATA..START_DATA + data_len,
+                                &mut buff,
+                                config.allow_packet_forward,
+                                config.allow_packet_not_in_rules_send_to_kernel
+                            ).await?;
+                        }
+                        UdpMsg::Handshake(from_addr, handshake_type, session_id, ephemeral_key) => {
+                            if !is_p2p || interface.node_list.load().get_node(&from_addr).is_none() {
+                                continue;
+                            }
+
+                            match handshake_type {
+                                HandshakeType::Init => {
+                                    let (local_id, local_key) = match interface.sessions.respond(from_addr, session_id, &ephemeral_key) {
+                                        Some(v) => v,
+                                        None => continue
+                                    };
+
+                                    let len = UdpMsg::handshake_encode(
+                                        key,
+                                        interface.next_udp_nonce(),
+                                        interface.addr.load(),
+                                        HandshakeType::Resp,
+                                        local_id,
+                                        &local_key,
+                                        &mut buff
+                                    );
+
+                                    match UdpMsg::send_msg(socket, &buff[..len], peer_addr).await {
+                                        Ok(_) => (),
+                                        Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
+                                        Err(UdpSocketErr::SuppressError(e)) => {
+                                            warn!("node {} send udp packet warn {}", group.node_name, e);
+                                        }
+                                    };
+                                }
+                                HandshakeType::Resp => {
+                                    let session = match interface.sessions.complete(from_addr, session_id, &ephemeral_key) {
+                                        Some(session) => session,
+                                        None => continue
+                                    };
+
+                                    debug!("node {} established p2p session with {}", group.node_name, from_addr);
+
+                                    // the responder starts using the session after the first packet under it
+                                    let len = UdpMsg::data_encode(&session.cipher, session.nonce(interface.next_udp_nonce()), 0, &mut buff);
+
+                                    match UdpMsg::send_msg(socket, &buff[..len], peer_addr).await {
+                                        Ok(_) => (),
+                                        Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
+                                        Err(UdpSocketErr::SuppressError(e)) => {
+                                            warn!("node {} send udp packet warn {}", group.node_name, e);
+                                        }
+                                    };
+                                }
                             }
                         }
                     }
                 }
+            };
+
+            let join = tokio::spawn(async move {
+                tokio::select! {
+                    res = fut => res,
+                    _ = notified.changed() => Err(anyhow!("abort task"))
+                }
             });
 
             join.await?
@@ -1127,22 +1707,12 @@ enum RegisterVirtualAddr {
     Auto(Option<(VirtualAddr, Ipv4Net)>),
 }
 
-async fn register<K>(
-    group: &'static TargetGroupFinalize<K>,
-    key: &K,
-    register_addr: &mut RegisterVirtualAddr,
-    lan_udp_socket_addr: Option<SocketAddr>,
-    refresh_route: &mut bool,
-    socket_bind_device: Option<&str>
-) -> Result<(TcpStream, GroupContent)>
-where
-    K: Cipher + Clone
-{
-    let server_addr = lookup_host(&group.server_addr)
+async fn connect_server(server_addr: &str, socket_bind_device: Option<&str>) -> Result<TcpStream> {
+    let addr = lookup_host(server_addr)
         .await
-        .ok_or_else(|| anyhow!("failed to resolve server {}", group.server_addr))?;
+        .ok_or_else(|| anyhow!("failed to resolve server {}", server_addr))?;
 
-    let socket = if server_addr.is_ipv4() {
+    let socket = if addr.is_ipv4() {
         TcpSocket::new_v4()?
     } else {
         TcpSocket::new_v6()?
@@ -1151,20 +1721,114 @@ where
     socket.set_nodelay(true)?;
 
     if let Some(device) = socket_bind_device {
-        SocketExt::bind_device(&socket, device, server_addr.is_ipv6())?;
+        SocketExt::bind_device(&socket, device, addr.is_ipv6())?;
     }
 
-    let mut stream = socket.connect(server_addr)
+    socket.connect(addr)
+        .await
+        .with_context(|| format!("connect to {} error", server_addr))
+}
+
+// same host as the tcp address of the server, on the quic port of the group
+async fn connect_quic_server<K>(
+    group: &TargetGroupFinalize<K>,
+    server_addr: &str,
+    socket_bind_device: Option<&str>
+) -> Result<ControlStream> {
+    let addr = lookup_host(server_addr)
+        .await
+        .ok_or_else(|| anyhow!("failed to resolve server {}", server_addr))?;
+
+    let port = group.quic_port.ok_or_else(|| anyhow!("quic port is not set"))?;
+    let addr = SocketAddr::new(addr.ip(), port);
+
+    let host = server_addr
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(server_addr)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    let config = quic::client_config(group.quic_cert_path.as_deref())?;
+
+    let conn = quic::connect(addr, host, config, socket_bind_device)
         .await
-        .with_context(|| format!("connect to {} error", &group.server_addr))?;
+        .with_context(|| format!("quic connect to {} error", addr))?;
+
+    let (tx, rx) = conn.open_bi().await?;
+    Ok(ControlStream::Quic(conn, tx, rx))
+}
+
+// order the servers of the group by the selection policy
+async fn sort_servers<'a, K>(
+    group: &'a TargetGroupFinalize<K>,
+    socket_bind_device: Option<&str>
+) -> Vec<&'a str> {
+    let mut servers: Vec<&str> = group.server_addrs.iter().map(|v| v.as_str()).collect();
+
+    if group.server_selection == ServerSelection::Latency && servers.len() > 1 {
+        let futs = servers.iter().map(|&server_addr| async move {
+            let start = Instant::now();
+            let res = tokio::time::timeout(Duration::from_secs(3), connect_server(server_addr, socket_bind_device)).await;
+
+            match res {
+                Ok(Ok(_)) => start.elapsed(),
+                _ => Duration::MAX
+            }
+        });
+
+        let latency = futures_util::future::join_all(futs).await;
+        let mut list: Vec<(&str, Duration)> = servers.into_iter().zip(latency).collect();
+        list.sort_by_key(|(_, latency)| *latency);
+
+        debug!("node {} server latency: {:?}", group.node_name, list);
+        servers = list.into_iter().map(|(server_addr, _)| server_addr).collect();
+    }
+    servers
+}
+
+async fn register<K>(
+    group: &TargetGroupFinalize<K>,
+    server_addr: &str,
+    key: &K,
+    register_addr: &mut RegisterVirtualAddr,
+    register_addr_v6: &mut Option<Ipv6Addr>,
+    lan_udp_socket_addr: Option<SocketAddr>,
+    udp_counter: u64,
+    refresh_route: &mut bool,
+    socket_bind_device: Option<&str>
+) -> Result<(ControlStream, GroupContent)>
+where
+    K: Cipher + Clone
+{
+    let mut stream = if group.mode.is_use_quic() {
+        connect_quic_server(group, server_addr, socket_bind_device).await?
+    } else {
+        ControlStream::Tcp(connect_server(server_addr, socket_bind_device).await?)
+    };
 
     let mut buff = allocator::alloc(1024);
 
+    let len = TcpMsg::hello_encode(key, rand::random(), &Hello::current(), &mut buff)?;
+    TcpMsg::write_msg(&mut stream, &buff[..len]).await?;
+
+    let msg = TcpMsg::read_msg(&mut stream, key, &mut buff).await?
+        .ok_or_else(|| anyhow!("server connection closed, the server may predate protocol version {}", MIN_PROTOCOL_VERSION))?;
+
+    let hello = match msg {
+        TcpMsg::HelloRes(res) => res?,
+        _ => return Err(anyhow!("invalid message")),
+    };
+
+    debug!("node {} negotiated protocol version {} with capabilities {:#x}", group.node_name, hello.version, hello.capabilities);
+
     let (virtual_addr, cidr) = match register_addr {
         RegisterVirtualAddr::Manual(addr) => *addr,
         RegisterVirtualAddr::Auto(Some(addr)) => *addr,
         RegisterVirtualAddr::Auto(None) => {
-            let len = TcpMsg::get_idle_virtual_addr_encode(key, rand::random(), &mut buff);
+            // version 2 servers don't look up reservations by name
+            let node_name = ternary!(hello.version > v2::VERSION, Some(group.node_name.as_str()), None);
+            let len = TcpMsg::get_idle_virtual_addr_encode(key, rand::random(), node_name, &mut buff)?;
             TcpMsg::write_msg(&mut stream, &buff[..len]).await?;
 
             let msg = TcpMsg::read_msg(&mut stream, key, &mut buff).await?
@@ -1180,17 +1844,24 @@ where
 
     let now = Utc::now().timestamp();
 
-    let reg = Register {
+    let mut reg = Register {
         node_name: group.node_name.clone(),
         virtual_addr,
+        virtual_addr_v6: *register_addr_v6,
         lan_udp_socket_addr,
         proto_mod: group.mode.clone(),
         register_time: now,
         nonce: random(),
-        allowed_ips: group.allowed_ips.clone()
+        allowed_ips: group.allowed_ips.clone(),
+        udp_counter,
+        signature: None
     };
 
-    let len = TcpMsg::register_encode(key, rand::random(), &reg, &mut buff)?;
+    if let Some(identity_key) = &group.identity_key {
+        identity::sign(identity_key, hello.version, &mut reg)?;
+    }
+
+    let len = TcpMsg::register_encode(key, rand::random(), hello.version, &reg, &mut buff)?;
     TcpMsg::write_msg(&mut stream, &buff[..len]).await?;
 
     let ret = TcpMsg::read_msg(&mut stream, key, &mut buff).await?
@@ -1198,10 +1869,14 @@ where
 
     let group_info = match ret {
         TcpMsg::RegisterRes(Err(RegisterError::InvalidVirtualAddress(e))) => {
-            if e == AllocateError::IpAlreadyInUse {
+            if e == AllocateError::IpAlreadyInUse || e == AllocateError::IpReserved {
                 if let RegisterVirtualAddr::Auto(v) = register_addr {
                     *v = None;
                 }
+
+                if group.tun_addr_v6.is_none() {
+                    *register_addr_v6 = None;
+                }
             }
             return Err(anyhow!(e))
         }
@@ -1221,17 +1896,57 @@ where
         *register_addr = RegisterVirtualAddr::Auto(Some((virtual_addr, cidr)));
         *refresh_route = true;
     }
+
+    // keep the assigned ipv6 address for reconnection
+    *register_addr_v6 = group_info.virtual_addr_v6;
     Ok((stream, group_info))
 }
 
+fn virtual_range_item(cidr: IpNet, interface_index: usize) -> Item {
+    let gateway = match cidr {
+        IpNet::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpNet::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    Item {
+        cidr,
+        gateway,
+        interface_index,
+        extend: routing_table::Extend {
+            item_kind: Some(ItemKind::VirtualRange)
+        }
+    }
+}
+
+fn allowed_ips_item(cidr: IpNet, gateway: IpAddr, interface_index: usize) -> Item {
+    Item {
+        cidr,
+        gateway,
+        interface_index,
+        extend: routing_table::Extend {
+            item_kind: Some(ItemKind::AllowedIpsRoute)
+        }
+    }
+}
+
+// the ipv4 allowed ips are translated by the native nat, the ipv6 ones are routed as is
+fn allowed_ips_v4(allowed_ips: &[IpNet]) -> Vec<Ipv4Net> {
+    allowed_ips.iter()
+        .filter_map(|v| match v {
+            IpNet::V4(v) => Some(*v),
+            IpNet::V6(_) => None
+        })
+        .collect()
+}
+
+// ipv4 is false if the group has no ipv4 range, the address is then only the identity of the node
 fn update_tun_addr<T, K, InterRT, ExternRt>(
     tun: &T,
     rt: &RoutingTableEnum<InterRT, ExternRt>,
     interface: &Interface<K>,
-    allowed_ips: &[Ipv4Net],
-    old_addr: VirtualAddr,
+    allowed_ips: &[IpNet],
+    ipv4: bool,
     addr: VirtualAddr,
-    old_cidr: Ipv4Net,
     cidr: Ipv4Net,
 ) -> Result<()>
     where
@@ -1239,71 +1954,100 @@ fn update_tun_addr<T, K, InterRT, ExternRt>(
         InterRT: RoutingTable + Clone,
         ExternRt: RoutingTable
 {
-    let item = Item {
-        cidr,
-        gateway: Ipv4Addr::UNSPECIFIED,
-        interface_index: interface.index,
-        extend: routing_table::Extend {
-            item_kind: Some(ItemKind::VirtualRange)
-        }
-    };
+    let old_addr = interface.addr.load();
+    let old_cidr = interface.cidr.load();
+    let old_ipv4 = interface.ipv4.load(Ordering::Relaxed);
 
-    let allowed_ips_item = allowed_ips.iter()
-        .map(|&allowed| Item {
-            cidr: allowed,
-            gateway: addr,
-            interface_index: interface.index,
-            extend: routing_table::Extend {
-                item_kind: Some(ItemKind::AllowedIpsRoute)
-            }
-        })
-        .collect::<Vec<_>>();
-
-    let update_route = |t: &mut dyn RoutingTable| {
-        // update default tun route
-        if cidr != old_cidr {
-            // todo add method for finding routing item
-            if let Some(i) = t.remove(&old_cidr) {
-                if i.extend.item_kind != Some(ItemKind::VirtualRange) {
-                    t.add(i);
-                }
+    if addr == old_addr && cidr == old_cidr && ipv4 == old_ipv4 {
+        return Ok(());
+    }
+
+    let index = interface.index;
+    let allowed_ips = allowed_ips.iter().filter(|v| matches!(v, IpNet::V4(_)));
+
+    rt.update(|t| {
+        if old_ipv4 {
+            remove_item(t, &IpNet::V4(old_cidr), index, ItemKind::VirtualRange);
+
+            for allowed in allowed_ips.clone() {
+                remove_item(t, allowed, index, ItemKind::AllowedIpsRoute);
             }
+        }
 
-            t.add(item.clone());
+        if ipv4 {
+            t.add(virtual_range_item(IpNet::V4(cidr), index));
+
+            for allowed in allowed_ips.clone() {
+                t.add(allowed_ips_item(*allowed, IpAddr::V4(addr), index));
+            }
         }
+    });
 
-        // update allowed_ips route
-        if addr != old_addr {
-            for i in &allowed_ips_item {
-                if let Some(old_item) = t.remove(&i.cidr) {
-                    if old_item.extend.item_kind != Some(ItemKind::AllowedIpsRoute) {
-                        t.add(old_item);
-                    }
-                }
-               
-                t.add(i.clone());
+    if old_ipv4 {
+        tun.delete_addr(IpAddr::V4(old_addr), IpAddr::V4(old_cidr.netmask()))?;
+    }
+
+    if ipv4 {
+        tun.add_addr(IpAddr::V4(addr), IpAddr::V4(cidr.netmask()))?;
+    }
+
+    interface.addr.store(addr);
+    interface.cidr.store(cidr);
+    interface.ipv4.store(ipv4, Ordering::Relaxed);
+    Ok(())
+}
+
+fn update_tun_addr_v6<T, K, InterRT, ExternRt>(
+    tun: &T,
+    rt: &RoutingTableEnum<InterRT, ExternRt>,
+    interface: &Interface<K>,
+    allowed_ips: &[IpNet],
+    addr: Option<Ipv6Addr>,
+    cidr: Option<Ipv6Net>,
+) -> Result<()>
+    where
+        T: TunDevice,
+        InterRT: RoutingTable + Clone,
+        ExternRt: RoutingTable
+{
+    let old_addr = interface.addr_v6.load();
+    let old_cidr = interface.cidr_v6.load();
+
+    if addr == old_addr && cidr == old_cidr {
+        return Ok(());
+    }
+
+    let index = interface.index;
+    let allowed_ips = allowed_ips.iter().filter(|v| matches!(v, IpNet::V6(_)));
+
+    rt.update(|t| {
+        if let Some(old_cidr) = old_cidr {
+            remove_item(t, &IpNet::V6(old_cidr), index, ItemKind::VirtualRange);
+
+            for allowed in allowed_ips.clone() {
+                remove_item(t, allowed, index, ItemKind::AllowedIpsRoute);
             }
         }
-    };
 
-    match rt {
-        RoutingTableEnum::Internal(rt) => {
-            rt.rcu(|v| {
-                let mut t = (**v).clone();
-                update_route(&mut t);
-                t
-            });
+        if let (Some(addr), Some(cidr)) = (addr, cidr) {
+            t.add(virtual_range_item(IpNet::V6(cidr), index));
+
+            for allowed in allowed_ips.clone() {
+                t.add(allowed_ips_item(*allowed, IpAddr::V6(addr), index));
+            }
         }
-        RoutingTableEnum::External(rt) => unsafe { update_route(&mut *rt.get()) }
-    }
+    });
 
-    if addr != old_addr ||
-        cidr != old_cidr {
-        tun.delete_addr(old_addr, old_cidr.netmask())?;
-        tun.add_addr(addr, cidr.netmask())?;
+    if let (Some(old_addr), Some(old_cidr)) = (old_addr, old_cidr) {
+        tun.delete_addr(IpAddr::V6(old_addr), IpAddr::V6(old_cidr.netmask()))?;
+        interface.addr_v6.store(None);
+        interface.cidr_v6.store(None);
+    }
 
-        interface.addr.store(addr);
-        interface.cidr.store(cidr);
+    if let (Some(addr), Some(cidr)) = (addr, cidr) {
+        tun.add_addr(IpAddr::V6(addr), IpAddr::V6(cidr.netmask()))?;
+        interface.addr_v6.store(Some(addr));
+        interface.cidr_v6.store(Some(cidr));
     }
     Ok(())
 }
@@ -1317,16 +2061,19 @@ fn update_hosts(hb: &hostsfile::HostsBuilder) -> std::io::Result<bool> {
 
 async fn tcp_handler<T, K, InterRT, ExternRt>(
     config: &'static NodeConfigFinalize<K>,
-    group: &'static TargetGroupFinalize<K>,
+    group: Arc<TargetGroupFinalize<K>>,
     routing_table: Arc<RoutingTableEnum<InterRT, ExternRt>>,
     interface: Arc<Interface<K>>,
     tun: T,
     channel_rx: Option<Receiver<Bytes>>,
     sys_routing: Option<Arc<tokio::sync::Mutex<SystemRouteHandle>>>,
-    routes: Vec<Route>,
+    routes: Arc<tokio::sync::Mutex<GroupRoutes>>,
+    sys_dns: Option<Arc<SystemDnsHandle>>,
     hooks: Option<Arc<Hooks<K>>>,
+    firewall: Option<Arc<Firewall>>,
     #[cfg(feature = "cross-nat")]
-    snat: Option<Arc<cross_nat::SNat>>
+    snat: Option<Arc<cross_nat::SNat>>,
+    mut notified: watch::Receiver<()>
 ) -> Result<()>
 where
     T: TunDevice + Clone + Send + Sync + 'static,
@@ -1334,7 +2081,9 @@ where
     InterRT: RoutingTable + Clone + Send + Sync + 'static,
     ExternRt: RoutingTable + Send + Sync + 'static
 {
-    let join: JoinHandle<Result<()>> = tokio::spawn(async move {
+    let node_name = group.node_name.clone();
+
+    let fut = async move {
         let mut sys_route_is_sync = false;
 
         // use defer must use atomic
@@ -1363,7 +2112,7 @@ where
             debug!("exiting tcp handler function ...");
 
             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-            if !config.features.disable_hosts_operation {
+            if !config.features.disable_hosts_operation && config.dns.is_none() {
                 let guard = host_records.lock();
 
                 if !guard.is_empty() {
@@ -1383,7 +2132,7 @@ where
             if is_add_nat.load(Ordering::Relaxed) && native_nat {
                 info!("clear node {} nat list", group.node_name);
 
-                if let Err(e) = crate::nat::del_nat(&group.allowed_ips, interface.cidr.load()) {
+                if let Err(e) = crate::nat::del_nat(&allowed_ips_v4(&group.allowed_ips), interface.cidr.load()) {
                     error!("failed to delete nat: {}", e);
                 }
             }
@@ -1396,6 +2145,8 @@ where
             _ => None,
         };
 
+        let nat_ips = allowed_ips_v4(&group.allowed_ips);
+
         let mut tun_addr = match &group.tun_addr {
             None => RegisterVirtualAddr::Auto(None),
             Some(addr) => {
@@ -1406,15 +2157,14 @@ where
                     &routing_table,
                     &*interface,
                     &group.allowed_ips,
-                    VirtualAddr::UNSPECIFIED,
+                    true,
                     addr.ip,
-                    Ipv4Net::default(),
                     cidr,
                 )?;
 
                 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-                if !group.allowed_ips.is_empty() && native_nat {
-                    crate::nat::add_nat(&group.allowed_ips, cidr)?;
+                if !nat_ips.is_empty() && native_nat {
+                    crate::nat::add_nat(&nat_ips, cidr)?;
                     is_add_nat.store(true, Ordering::Relaxed);
                 }
 
@@ -1422,6 +2172,7 @@ where
             }
         };
 
+        let mut tun_addr_v6 = group.tun_addr_v6;
         let key = &group.key;
 
         loop {
@@ -1430,21 +2181,73 @@ where
             let process = async {
                 let mut refresh_route= false;
 
-                let (stream, group_info) = tokio::time::timeout(
-                    Duration::from_secs(10),
-                    register(
-                        group,
-                        key,
-                        &mut tun_addr,
-                        lan_udp_socket_addr,
-                        &mut refresh_route,
-                        config.socket_bind_device.as_deref()
-                    )
-                ).await.with_context(|| format!("register to {} timeout", group.server_addr))??;
-
-                if refresh_route {
+                let mut registered = None;
+                let mut last_err = None;
+
+                // fail over to the next server if the registration failed
+                for server_addr in sort_servers(&group, config.socket_bind_device.as_deref()).await {
+                    let res = tokio::time::timeout(
+                        Duration::from_secs(10),
+                        register(
+                            &group,
+                            server_addr,
+                            key,
+                            &mut tun_addr,
+                            &mut tun_addr_v6,
+                            lan_udp_socket_addr,
+                            interface.udp_nonce.counter(),
+                            &mut refresh_route,
+                            config.socket_bind_device.as_deref()
+                        )
+                    ).await.with_context(|| format!("register to {} timeout", server_addr));
+
+                    match res.and_then(|v| v) {
+                        Ok(v) => {
+                            registered = Some((server_addr, v));
+                            break;
+                        }
+                        Err(e) => {
+                            warn!("node {} register to {} failed: {:?}", group.node_name, server_addr, e);
+
+                            interface.event(Event::RegisterFailed {
+                                node: Some(group.node_name.clone()),
+                                reason: e.downcast_ref::<RegisterError>().map(|e| e.kind()),
+                                error: format!("register to {} failed: {:#}", server_addr, e),
+                            });
+                            last_err = Some(e);
+                        }
+                    }
+                }
+
+                let (server_addr, (stream, group_info)) = match registered {
+                    Some(v) => v,
+                    None => return Err(last_err.unwrap_or_else(|| anyhow!("no server available")))
+                };
+
+                if **interface.server_addr.load() != server_addr {
+                    info!("node {} switch server to {}", group.node_name, server_addr);
+
+                    // the state of the previous server is no longer valid
+                    interface.server_addr.store(Arc::new(server_addr.to_string()));
+
+                    if interface.server_udp_status.load() != UdpStatus::Unavailable {
+                        interface.server_udp_status.store(UdpStatus::Unavailable);
+                        interface.event(Event::UdpStatusChanged { node: None, status: UdpStatus::Unavailable });
+                    }
+                    *interface.server_udp_hc.write() = HeartbeatCache::new();
+                }
+
+                // the udp messages of the previous sessions are replays in both directions
+                interface.udp_nonce.advance(group_info.node_udp_counter);
+                interface.replay_windows.lock().insert(SERVER_VIRTUAL_ADDR, ReplayWindow::with_floor(group_info.udp_counter.saturating_sub(1)));
+
+                if !group_info.ipv4 && matches!(tun_addr, RegisterVirtualAddr::Manual(_)) {
+                    non_retryable = true;
+                    return Err(anyhow!("group {} has no ipv4 address range, tun_addr can't be set", group_info.name));
+                }
+
+                if refresh_route || interface.ipv4.load(Ordering::Relaxed) != group_info.ipv4 {
                     if let RegisterVirtualAddr::Auto(Some((addr, cidr))) = &tun_addr {
-                        let old_addr = interface.addr.load();
                         let old_cidr = interface.cidr.load();
 
                         let f = || {
@@ -1453,23 +2256,22 @@ where
                                 &routing_table,
                                 &*interface,
                                 &group.allowed_ips,
-                                old_addr,
+                                group_info.ipv4,
                                 *addr,
-                                old_cidr,
                                 *cidr,
                             )?;
 
                             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-                            if !group.allowed_ips.is_empty() && native_nat {
-                                if old_cidr != *cidr &&
+                            if !nat_ips.is_empty() && native_nat {
+                                if (old_cidr != *cidr || !group_info.ipv4) &&
                                     is_add_nat.load(Ordering::Relaxed)
                                 {
-                                    crate::nat::del_nat(&group.allowed_ips,old_cidr)?;
+                                    crate::nat::del_nat(&nat_ips, old_cidr)?;
                                     is_add_nat.store(false, Ordering::Relaxed);
                                 }
 
-                                if !is_add_nat.load(Ordering::Relaxed) {
-                                    crate::nat::add_nat(&group.allowed_ips, *cidr)?;
+                                if group_info.ipv4 && !is_add_nat.load(Ordering::Relaxed) {
+                                    crate::nat::add_nat(&nat_ips, *cidr)?;
                                     is_add_nat.store(true, Ordering::Relaxed);
                                 }
                             }
@@ -1484,15 +2286,35 @@ where
                     }
                 }
 
+                if let Err(e) = update_tun_addr_v6(&tun, &routing_table, &interface, &group.allowed_ips, group_info.virtual_addr_v6, group_info.cidr_v6) {
+                    error!("failed to set ipv6 address: {}", e);
+                }
+
                 interface.group_name.store(Some(Arc::new(group_info.name.clone())));
                 interface.server_allow_udp_relay.store(group_info.allow_udp_relay, Ordering::Relaxed);
                 interface.server_allow_tcp_relay.store(group_info.allow_tcp_relay, Ordering::Relaxed);
 
+                apply_pushed_config(
+                    group_info.pushed,
+                    &group,
+                    &interface,
+                    &routes,
+                    &routing_table,
+                    sys_routing.as_deref(),
+                    sys_dns.as_deref(),
+                    tun.get_index()
+                ).await;
+
                 // tun must first set the ip address
                 if !sys_route_is_sync {
                     let res = match &sys_routing {
                         None => Ok(()),
-                        Some(routing) => routing.lock().await.add(&routes).await
+                        Some(routing) => {
+                            let mut routes = routes.lock().await;
+                            let res = routing.lock().await.add(&sys_routes(&routes.applied, tun.get_index())).await;
+                            routes.synced = res.is_ok();
+                            res
+                        }
                     };
 
                     if let Err(e) = res {
@@ -1506,7 +2328,19 @@ where
 
                 interface.server_is_connected.store(true, Ordering::Relaxed);
                 info!("node {}({}) has joined group {}", group.node_name, interface.addr.load(), group_info.name);
-                info!("group {} address range {}", group_info.name, group_info.cidr);
+                if group_info.ipv4 {
+                    info!("group {} address range {}", group_info.name, group_info.cidr);
+                }
+
+                if let (Some(addr), Some(cidr)) = (group_info.virtual_addr_v6, group_info.cidr_v6) {
+                    info!("node {} ipv6 address {}, group {} ipv6 address range {}", group.node_name, addr, group_info.name, cidr);
+                }
+
+                let quic_conn = stream.connection().cloned();
+                interface.quic_connection.store(quic_conn.clone().map(Arc::new));
+
+                // relay packets on the quic stream are the ones too large for a datagram
+                let relay_protocol = ternary!(quic_conn.is_some(), NetProtocol::QUIC, NetProtocol::TCP);
 
                 let (rx, mut tx) = stream.into_split();
                 let mut rx = BufReader::with_capacity(TCP_BUFF_SIZE, rx);
@@ -1515,6 +2349,7 @@ where
                 let (_notify, notified) = tokio::sync::watch::channel(());
 
                 let recv_handler = async {
+                    let group = group.clone();
                     let mut notified = notified.clone();
                     let interface = interface.clone();
                     let inner_channel_tx = inner_channel_tx.clone();
@@ -1525,40 +2360,46 @@ where
                     let tun = tun.clone();
                     let routing_table = routing_table.clone();
                     let hooks = hooks.clone();
+                    let firewall = firewall.clone();
                     #[cfg(feature = "cross-nat")]
                     let snat = snat.clone();
+                    let routes = routes.clone();
+                    let sys_routing = sys_routing.clone();
+                    let sys_dns = sys_dns.clone();
 
                     let join = tokio::spawn(async move {
+                        let key = &group.key;
+
                         let fut = async {
                             let arr = [interface.as_ref()];
                             let mut sender = PacketSender::new(
                                 &*routing_table, 
                                 &arr, 
                                 &tun, 
-                                hooks.as_deref(), 
+                                hooks.as_deref(),
+                                firewall.as_deref(),
                                 #[cfg(feature = "cross-nat")]
                                 snat.as_deref()
                             );
 
                             let mut rng = rand::rngs::SmallRng::from_entropy();
 
-                            const START: usize = UDP_MSG_HEADER_LEN;
                             let mut buff = vec![0u8; TCP_BUFF_SIZE];
 
                             loop {
-                                let msg = TcpMsg::read_msg(&mut rx, key, &mut buff[START..]).await?
+                                let msg = TcpMsg::read_msg(&mut rx, key, &mut buff).await?
                                     .ok_or_else(|| anyhow!("server connection closed"))?;
 
                                 match msg {
                                     TcpMsg::NodeMap(map) => {
-                                        let mut new_list = NodeList::with_capacity(map.len());
-
-                                        {
+                                        let new_list = {
+                                            let mut new_list = Vec::with_capacity(map.len());
                                             let old_list = interface.node_list.load();
 
                                             for (virtual_addr, node) in map {
                                                 match old_list.get_node(&virtual_addr) {
                                                     None => {
+                                                        interface.event(Event::NodeRegistered { node: node.name.clone(), addr: virtual_addr });
                                                         new_list.push(ExtendedNode::from(node));
                                                     },
                                                     Some(v) => {
@@ -1566,29 +2407,46 @@ where
                                                             node,
                                                             hc: v.hc.clone(),
                                                             udp_status: v.udp_status.clone(),
-                                                            peer_addr: v.peer_addr.clone()
+                                                            peer_addr: v.peer_addr.clone(),
+                                                            traffic: v.traffic.clone(),
+                                                            path: v.path.clone()
                                                         };
                                                         new_list.push(en);
                                                     }
                                                 }
                                             }
-                                        }
 
-                                        new_list.sort_unstable_by_key(|n| n.node.virtual_addr);
+                                            let new_list = NodeList::from(new_list);
+
+                                            for en in old_list.iter() {
+                                                if new_list.get_node(&en.node.virtual_addr).is_none() {
+                                                    interface.event(Event::NodeDisconnected { node: en.node.name.clone(), addr: en.node.virtual_addr });
+                                                }
+                                            }
+                                            new_list
+                                        };
 
                                         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-                                        if !config.features.disable_hosts_operation {
+                                        if !config.features.disable_hosts_operation && config.dns.is_none() {
                                             let host_key = format!("FUBUKI-{}", group_info.name);
                                             let mut hb = hostsfile::HostsBuilder::new(&host_key);
                                             host_records.lock().insert(host_key);
 
                                             for node in &new_list {
                                                 let node = &node.node;
-                                                hb.add_hostname(IpAddr::from(node.virtual_addr), format!("{}.{}", &node.name, &group_info.name));
+
+                                                if group_info.ipv4 {
+                                                    hb.add_hostname(IpAddr::from(node.virtual_addr), format!("{}.{}", &node.name, &group_info.name));
+                                                }
+
+                                                if let Some(addr) = node.virtual_addr_v6 {
+                                                    hb.add_hostname(IpAddr::from(addr), format!("{}.{}", &node.name, &group_info.name));
+                                                }
                                             }
 
+                                            let node_name = group.node_name.clone();
+
                                             tokio::task::spawn_blocking(move || {
-                                                let node_name = &group.node_name;
 
                                                 match update_hosts(&hb) {
                                                     Ok(true) => info!("node {} update hosts", node_name),
@@ -1601,7 +2459,13 @@ where
                                         interface.node_list.store(Arc::new(new_list));
                                     }
                                     TcpMsg::Relay(_, data) => {
-                                        const DATA_START: usize = START + size_of::<VirtualAddr>();
+                                        // the tcp header is longer than the udp header, leaving room to relay the packet over udp
+                                        const DATA_START: usize = TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+
+                                        if let Some(en) = interface.node_list.load().get_node_by_src(data) {
+                                            en.traffic.relay(relay_protocol).rx(data.len());
+                                        }
+
                                         sender.send_packet(
                                             Direction::Input,
                                             DATA_START..DATA_START + data.len(),
@@ -1630,6 +2494,18 @@ where
                                             *peers_map.write() = peers;
                                         }
                                     }
+                                    TcpMsg::PushConfig(pushed) => {
+                                        apply_pushed_config(
+                                            pushed,
+                                            &group,
+                                            &interface,
+                                            &routes,
+                                            &routing_table,
+                                            sys_routing.as_deref(),
+                                            sys_dns.as_deref(),
+                                            tun.get_index()
+                                        ).await;
+                                    }
                                     _ => continue,
                                 }
                             }
@@ -1644,12 +2520,92 @@ where
                     join.await?
                 };
 
+                let datagram_handler = async {
+                    let group = group.clone();
+                    let conn = match &quic_conn {
+                        None => return std::future::pending().await,
+                        Some(conn) => conn.clone()
+                    };
+
+                    let mut notified = notified.clone();
+                    let interface = interface.clone();
+                    let tun = tun.clone();
+                    let routing_table = routing_table.clone();
+                    let hooks = hooks.clone();
+                    let firewall = firewall.clone();
+                    #[cfg(feature = "cross-nat")]
+                    let snat = snat.clone();
+
+                    let join: JoinHandle<Result<()>> = tokio::spawn(async move {
+                        let key = &group.key;
+
+                        let fut = async {
+                            let arr = [interface.as_ref()];
+                            let mut sender = PacketSender::new(
+                                &*routing_table,
+                                &arr,
+                                &tun,
+                                hooks.as_deref(),
+                                firewall.as_deref(),
+                                #[cfg(feature = "cross-nat")]
+                                snat.as_deref()
+                            );
+
+                            let mut buff = vec![0u8; TCP_BUFF_SIZE];
+
+                            loop {
+                                let datagram = conn.read_datagram().await?;
+
+                                let frame = match buff.get_mut(..datagram.len()) {
+                                    None => continue,
+                                    Some(frame) => frame
+                                };
+                                frame.copy_from_slice(&datagram);
+
+                                let data = match TcpMsg::decode_frame(key, frame) {
+                                    Ok(TcpMsg::Relay(_, data)) => data,
+                                    Ok(_) => continue,
+                                    Err(e) => {
+                                        warn!("node {} received invalid quic datagram: {}", group.node_name, e);
+                                        continue;
+                                    }
+                                };
+
+                                const DATA_START: usize = TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+                                let len = data.len();
+
+                                if let Some(en) = interface.node_list.load().get_node_by_src(data) {
+                                    en.traffic.quic_relay.rx(len);
+                                }
+
+                                sender.send_packet(
+                                    Direction::Input,
+                                    DATA_START..DATA_START + len,
+                                    &mut buff,
+                                    config.allow_packet_forward,
+                                    config.allow_packet_not_in_rules_send_to_kernel
+                                ).await?;
+                            }
+                        };
+
+                        tokio::select! {
+                            res = fut => res,
+                            _ = notified.changed() => Err(anyhow!("abort task"))
+                        }
+                    });
+
+                    join.await?
+                };
+
                 let send_handler = async {
+                    let group = group.clone();
                     let mut notified = notified.clone();
                     let channel_rx = channel_rx.clone();
                     let interface = interface.clone();
 
                     let join = tokio::spawn(async move {
+                        let key = &group.key;
+
                         let mut channel_rx = match &channel_rx {
                             None => None,
                             Some(lock) => Some(lock.lock().await)
@@ -1695,11 +2651,14 @@ where
                 };
 
                 let heartbeat_schedule = async {
+                    let group = group.clone();
                     let mut notified = notified.clone();
                     let interface = interface.clone();
                     let inner_channel_tx = inner_channel_tx.clone();
 
                     let join = tokio::spawn(async move {
+                        let key = &group.key;
+
                         let fut = async {
                             let mut rng = rand::rngs::SmallRng::from_entropy();
                             
@@ -1734,11 +2693,14 @@ where
                 };
 
                 let update_peers_schedule = async {
+                    let group = group.clone();
                     let mut notified = notified.clone();
                     let interface = interface.clone();
                     let inner_channel_tx = inner_channel_tx.clone();
 
                     let join: JoinHandle<Result<()>> = tokio::spawn(async move {
+                        let key = &group.key;
+
                         let fut = async {
                             let mut rng = rand::rngs::SmallRng::from_entropy();
                             let node_list = &interface.node_list;
@@ -1750,6 +2712,7 @@ where
 
                                 let peers_status = {
                                     let node_list = node_list.load_full();
+                                    let specify_mode = specify_mode.load();
                                     let mut peers_status = Vec::with_capacity(node_list.len());
 
                                     for node in  &*node_list {
@@ -1805,6 +2768,7 @@ where
 
                 tokio::try_join!(
                     recv_handler, 
+                    datagram_handler,
                     send_handler, 
                     heartbeat_schedule, 
                     update_peers_schedule
@@ -1821,6 +2785,7 @@ where
             }
 
             interface.server_is_connected.store(false, Ordering::Relaxed);
+            interface.quic_connection.store(None);
 
             {
                 let mut guard = interface.server_tcp_hc.write();
@@ -1830,20 +2795,27 @@ where
 
             time::sleep(config.reconnect_interval).await;
         }
+    };
+
+    // dropping the future clears the hosts and nat records
+    let join: JoinHandle<Result<()>> = tokio::spawn(async move {
+        tokio::select! {
+            res = fut => res,
+            _ = notified.changed() => Err(anyhow!("abort task"))
+        }
     });
 
-    join.await?.with_context(|| format!("node {} tcp handler error", group.node_name))
+    join.await?.with_context(|| format!("node {} tcp handler error", node_name))
 }
 
 pub extern "C" fn generic_interfaces_info<K>(
-    interfaces: &OnceLock<Vec<Arc<Interface<K>>>>,
+    interfaces: &ArcSwap<Vec<Arc<Interface<K>>>>,
     info_json: *mut c_char
 ) {
-    let empty_interfaces = Vec::new();
-    let interfaces = interfaces.get().unwrap_or(&empty_interfaces);
+    let interfaces = interfaces.load();
     let mut list = Vec::with_capacity(interfaces.len());
 
-    for inter in interfaces {
+    for inter in interfaces.iter() {
         list.push(InterfaceInfo::from(&**inter));
     }
 
@@ -1878,8 +2850,10 @@ async fn send_packet_hook_handler<T, K, InterRT, ExternRT>(
     send_packet_chan_rx: flume::Receiver<(Direction, Bytes)>,
     tun: T,
     routing_table: Arc<RoutingTableEnum<InterRT, ExternRT>>,
-    interfaces: Vec<Arc<Interface<K>>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    mut interfaces_changed: watch::Receiver<()>,
     hooks: Option<Arc<Hooks<K>>>,
+    firewall: Option<Arc<Firewall>>,
     #[cfg(feature = "cross-nat")]
     snat: Option<Arc<cross_nat::SNat>>
 ) -> Result<()>
@@ -1890,48 +2864,57 @@ async fn send_packet_hook_handler<T, K, InterRT, ExternRT>(
         ExternRT: RoutingTable + Send + Sync + 'static
 {
     let join: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let interfaces = interfaces.iter().map(|v| &**v).collect::<Vec<_>>();
-
-        let mut sender = PacketSender::new(
-            &*routing_table,
-            &interfaces,
-            &tun,
-            hooks.as_deref(),
-            #[cfg(feature = "cross-nat")]
-            snat.as_deref()
-        );
-
         let mut buff = vec![0u8; UDP_BUFF_SIZE];
 
         loop {
-            const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
-            let (direction, packet) = send_packet_chan_rx.recv_async().await?;
+            interfaces_changed.borrow_and_update();
+            let list = interfaces.load_full();
+            let interfaces = list.iter().map(|v| &**v).collect::<Vec<_>>();
+
+            let mut sender = PacketSender::new(
+                &*routing_table,
+                &interfaces,
+                &tun,
+                hooks.as_deref(),
+                firewall.as_deref(),
+                #[cfg(feature = "cross-nat")]
+                snat.as_deref()
+            );
 
-            if packet.len() == 0 {
-                continue;
-            }
+            loop {
+                const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
 
-            let packet_range = START..START + packet.len();
-            buff[packet_range.clone()].copy_from_slice(&packet);
+                let (direction, packet) = tokio::select! {
+                    res = send_packet_chan_rx.recv_async() => res?,
+                    Ok(_) = interfaces_changed.changed() => break
+                };
 
-            match direction {
-                Direction::Input => {
-                    sender.send_packet(
-                        Direction::Input,
-                        packet_range,
-                        &mut buff,
-                        config.allow_packet_forward,
-                        config.allow_packet_not_in_rules_send_to_kernel
-                    ).await?;
+                if packet.len() == 0 {
+                    continue;
                 }
-                Direction::Output => {
-                    sender.send_packet(
-                        Direction::Output,
-                        packet_range,
-                        &mut buff,
-                        true,
-                        false
-                    ).await?;
+
+                let packet_range = START..START + packet.len();
+                buff[packet_range.clone()].copy_from_slice(&packet);
+
+                match direction {
+                    Direction::Input => {
+                        sender.send_packet(
+                            Direction::Input,
+                            packet_range,
+                            &mut buff,
+                            config.allow_packet_forward,
+                            config.allow_packet_not_in_rules_send_to_kernel
+                        ).await?;
+                    }
+                    Direction::Output => {
+                        sender.send_packet(
+                            Direction::Output,
+                            packet_range,
+                            &mut buff,
+                            true,
+                            false
+                        ).await?;
+                    }
                 }
             }
         }
@@ -1939,112 +2922,322 @@ async fn send_packet_hook_handler<T, K, InterRT, ExternRT>(
     join.await?
 }
 
-pub async fn start<K, T>(
-    config: NodeConfigFinalize<K>, 
-    tun: T,
-    interfaces_hook: Arc<OnceLock<Vec<Arc<Interface<K>>>>>
-) -> Result<()>
-    where
-        K: Cipher + Send + Sync + Clone + 'static,
-        T: TunDevice + Send + Sync + 'static,
+// ips routes of a group, the local config takes precedence over the routes pushed by the server
+struct GroupRoutes {
+    local: Vec<(IpAddr, IpNet)>,
+    pushed: Vec<(IpAddr, IpNet)>,
+    // items in the routing table
+    applied: Vec<(IpAddr, IpNet)>,
+    // the system routes are added once the tun address is set
+    synced: bool,
+}
+
+impl GroupRoutes {
+    fn new(local: Vec<(IpAddr, IpNet)>) -> Self {
+        GroupRoutes {
+            local,
+            pushed: Vec::new(),
+            applied: Vec::new(),
+            synced: false,
+        }
+    }
+
+    fn merged(&self) -> Vec<(IpAddr, IpNet)> {
+        let mut list = self.local.clone();
+
+        for (gateway, cidr) in &self.pushed {
+            if !list.iter().any(|(_, v)| v == cidr) {
+                list.push((*gateway, *cidr));
+            }
+        }
+        list
+    }
+}
+
+// addresses of all servers of the group, the routes through the tunnel must not cover them
+async fn server_ips(server_addrs: &[String]) -> Vec<IpAddr> {
+    let mut list = Vec::new();
+
+    for server_addr in server_addrs {
+        match tokio::net::lookup_host(server_addr.as_str()).await {
+            Ok(addrs) => list.extend(addrs.map(|addr| addr.ip())),
+            Err(e) => warn!("failed to resolve server {}: {}", server_addr, e)
+        }
+    }
+    list
+}
+
+async fn apply_pushed_config<K, InterRT, ExternRT>(
+    pushed: PushedConfig,
+    group: &TargetGroupFinalize<K>,
+    interface: &Interface<K>,
+    routes: &tokio::sync::Mutex<GroupRoutes>,
+    routing_table: &RoutingTableEnum<InterRT, ExternRT>,
+    sys_routing: Option<&tokio::sync::Mutex<SystemRouteHandle>>,
+    sys_dns: Option<&SystemDnsHandle>,
+    tun_index: u32,
+)
+where
+    InterRT: RoutingTable + Clone,
+    ExternRT: RoutingTable
 {
-    let config = &*Box::leak(Box::new(config));
-    let tun = Arc::new(tun);
-    tun.set_mtu(config.mtu)?;
+    if **interface.pushed.load() == pushed {
+        return;
+    }
 
-    let (send_packet_chan_tx, send_packet_chan_rx) = flume::bounded(1024);
+    let addr = IpAddr::V4(interface.addr.load());
+    let addr_v6 = interface.addr_v6.load().map(IpAddr::V6);
+    let mut accepted = Vec::new();
 
-    let ctx = Context {
-        interfaces: Some(interfaces_hook.clone()),
-        send_packet_chan: Some(send_packet_chan_tx)
-    };
+    if group.accept_pushed_routes {
+        let server_ips = ternary!(group.accept_pushed_default_route, Vec::new(), server_ips(&interface.server_addrs).await);
 
-    let curr = std::env::current_exe().ok();
-    let parent = curr.as_deref().and_then(|v| v.parent()).unwrap_or(Path::new(""));
+        for (gateway, cidr) in &pushed.routes {
+            // the node itself is the gateway
+            if *gateway == addr || Some(*gateway) == addr_v6 {
+                continue;
+            }
 
-    let ctx = Arc::new(ctx);
+            let hijack = cidr.prefix_len() == 0 || server_ips.iter().any(|ip| cidr.contains(ip));
 
-    let hooks = if config.enable_hook {
-        let dll_name = format!("{}fubukihook{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX);
-        let hooks = common::hook::open_hooks_dll(&parent.join(Path::new(&dll_name)), ctx.clone())?;
-        Some(Arc::new(hooks))
-    } else {
-        None
+            if hijack && !group.accept_pushed_default_route {
+                warn!("node {} ignores pushed route {} via {}, it is a default route or covers the server address", interface.node_name, cidr, gateway);
+                continue;
+            }
+            accepted.push((*gateway, *cidr));
+        }
+    } else if !pushed.routes.is_empty() {
+        debug!("node {} ignores {} pushed routes, accept_pushed_routes is not enabled", interface.node_name, pushed.routes.len());
+    }
+
+    let (added, removed) = {
+        let mut routes = routes.lock().await;
+        routes.pushed = accepted;
+        sync_group_routes(&mut routes, routing_table, sys_routing, tun_index, interface.index).await
     };
 
-    let init_routing_table = |rt: &mut dyn RoutingTable| {
-        for (index, group) in config.groups.iter().enumerate() {
-            for (dst, cidrs) in &group.ips {
-                for cidr in cidrs {
-                    let item = Item {
-                        cidr: *cidr,
-                        gateway: *dst,
-                        interface_index: index,
-                        extend: routing_table::Extend {
-                            item_kind: Some(ItemKind::IpsRoute)
-                        }
-                    };
+    if let Some(sys_dns) = sys_dns {
+        let domains = pushed.search_domains.iter()
+            .filter(|domain| {
+                let valid = is_valid_domain(domain);
 
-                    rt.add(item);
+                if !valid {
+                    warn!("node {} ignores invalid search domain {:?} pushed by the server", interface.node_name, domain);
                 }
-            }
+                valid
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        sys_dns.set(interface.index, &pushed.dns_servers, &domains).await;
+    }
+
+    info!("node {} interface {} received config from server, routes {} added, {} removed", interface.node_name, interface.index, added, removed);
+    interface.pushed.store(Arc::new(pushed));
+}
+
+// apply the merged routes to the routing table and the synced system routes, returns (added, removed)
+async fn sync_group_routes<InterRT, ExternRT>(
+    routes: &mut GroupRoutes,
+    routing_table: &RoutingTableEnum<InterRT, ExternRT>,
+    sys_routing: Option<&tokio::sync::Mutex<SystemRouteHandle>>,
+    tun_index: u32,
+    interface_index: usize,
+) -> (usize, usize)
+where
+    InterRT: RoutingTable + Clone,
+    ExternRT: RoutingTable
+{
+    let new = routes.merged();
+
+    let removed = routes.applied.iter().filter(|v| !new.contains(v)).copied().collect::<Vec<_>>();
+    let added = new.iter().filter(|v| !routes.applied.contains(v)).copied().collect::<Vec<_>>();
+
+    if removed.is_empty() && added.is_empty() {
+        return (0, 0);
+    }
+
+    routing_table.update(|t| {
+        for (_, cidr) in &removed {
+            remove_item(t, cidr, interface_index, ItemKind::IpsRoute);
         }
-    };
 
-    let rt = if config.external_routing_table {
-        let dll_name = format!("{}fubukiextrt{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX);
-    
-        let mut rt = routing_table::external::create::<K>(
-            &parent.join(Path::new(&dll_name)),
-            ctx.clone()
-        )?;
-        init_routing_table(&mut rt);
-        RoutingTableEnum::External(SyncUnsafeCell::new(rt))
-    } else {
-        let mut rt = routing_table::internal::create();
-        init_routing_table(&mut rt);
-        RoutingTableEnum::Internal(ArcSwap::from_pointee(rt))
-    };
+        for (gateway, cidr) in &added {
+            t.add(ips_item(*gateway, *cidr, interface_index));
+        }
+    });
 
-    let rt = Arc::new(rt);
+    if let (Some(sys_routing), true) = (sys_routing, routes.synced) {
+        let mut sys_routing = sys_routing.lock().await;
 
-    #[cfg(feature = "cross-nat")]
-    let allowed_ips_exists = {
-        let mut ret = false;
+        if let Err(e) = sys_routing.delete(&sys_routes(&removed, tun_index)).await {
+            warn!("failed to delete route: {}", e);
+        }
 
-        for group in config.groups.iter() {
-            if !group.allowed_ips.is_empty() {
-                ret = true;
-                break;
-            }
+        if let Err(e) = sys_routing.add(&sys_routes(&added, tun_index)).await {
+            warn!("failed to add route: {}", e);
         }
-        ret
-    };
+    }
+
+    routes.applied = new;
+    (added.len(), removed.len())
+}
+
+fn ips_list(ips: &HashMap<IpAddr, Vec<IpNet>>) -> Vec<(IpAddr, IpNet)> {
+    ips.iter()
+        .flat_map(|(gateway, cidrs)| cidrs.iter().map(|cidr| (*gateway, *cidr)))
+        .collect()
+}
+
+fn ips_item(gateway: IpAddr, cidr: IpNet, interface_index: usize) -> Item {
+    Item {
+        cidr,
+        gateway,
+        interface_index,
+        extend: routing_table::Extend {
+            item_kind: Some(ItemKind::IpsRoute)
+        }
+    }
+}
 
+#[allow(unused_variables)]
+fn sys_routes(ips: &[(IpAddr, IpNet)], tun_index: u32) -> Vec<Route> {
+    #[allow(unused_mut)]
+    let mut routes = Vec::new();
+
+    #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+    for (gateway, cidr) in ips {
+        let route = Route::new(cidr.network(), cidr.prefix_len())
+            .with_gateway(*gateway)
+            .with_ifindex(tun_index);
+
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        let route = route.with_metric(1);
+
+        routes.push(route);
+    }
+    routes
+}
+
+// groups of the old and the new config are matched by node name and server addresses
+fn same_group<K>(a: &TargetGroupFinalize<K>, b: &TargetGroupFinalize<K>) -> bool {
+    a.node_name == b.node_name && a.server_addrs == b.server_addrs
+}
+
+// ips and specify_mode are updated in place, other changes rebuild the interface and register again
+fn needs_restart<K>(old: &TargetGroupFinalize<K>, new: &TargetGroupFinalize<K>) -> bool {
+    old.server_selection != new.server_selection ||
+        old.tun_addr != new.tun_addr ||
+        old.tun_addr_v6 != new.tun_addr_v6 ||
+        old.key_config != new.key_config ||
+        old.identity_key != new.identity_key ||
+        old.mode != new.mode ||
+        old.quic_port != new.quic_port ||
+        old.quic_cert_path != new.quic_cert_path ||
+        old.lan_ip_addr != new.lan_ip_addr ||
+        old.node_binding_port != new.node_binding_port ||
+        old.allowed_ips != new.allowed_ips ||
+        old.accept_pushed_routes != new.accept_pushed_routes ||
+        old.accept_pushed_default_route != new.accept_pushed_default_route ||
+        old.auto_route_selection != new.auto_route_selection
+}
+
+enum GroupCommand<K> {
+    Reload(NodeConfigFinalize<K>),
+    Add(TargetGroupFinalize<K>),
+    // interface index
+    Remove(usize),
+}
+
+// the request is checked before it is queued, so errors go back to the caller
+struct GroupControl<K> {
+    tx: mpsc::UnboundedSender<GroupCommand<K>>,
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    config_loader: Option<Box<dyn Fn() -> Result<NodeConfigFinalize<K>> + Send + Sync>>,
+    parse_group: fn(TargetGroup) -> Result<TargetGroupFinalize<K>>,
+}
+
+impl <K> GroupControl<K> {
+    fn send(&self, cmd: GroupCommand<K>) -> Result<()> {
+        self.tx.send(cmd).map_err(|_| anyhow!("node is shutting down"))
+    }
+
+    fn reload(&self) -> Result<()> {
+        let loader = self.config_loader.as_ref().ok_or_else(|| anyhow!("node config cannot be reloaded"))?;
+        self.send(GroupCommand::Reload(loader()?))
+    }
+
+    fn add_group(&self, group: &[u8]) -> Result<()> {
+        let group: TargetGroup = serde_json::from_slice(group)?;
+        let group = (self.parse_group)(group)?;
+
+        let exists = self.interfaces.load()
+            .iter()
+            .any(|i| i.node_name == group.node_name && i.server_addrs == group.server_addrs);
+
+        if exists {
+            return Err(anyhow!("node {} has joined {}", group.node_name, group.server_addrs.join(", ")));
+        }
+        self.send(GroupCommand::Add(group))
+    }
+
+    fn remove_group(&self, index: usize) -> Result<()> {
+        if !self.interfaces.load().iter().any(|i| i.index == index) {
+            return Err(anyhow!("interface {} not found", index));
+        }
+        self.send(GroupCommand::Remove(index))
+    }
+}
+
+// the handlers of a group stop when abort is dropped
+struct RunningGroup<K> {
+    config: Arc<TargetGroupFinalize<K>>,
+    interface: Arc<Interface<K>>,
+    routes: Arc<tokio::sync::Mutex<GroupRoutes>>,
+    abort: watch::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+struct GroupManager<K: 'static, T, InterRT, ExternRT> {
+    // settings outside of the groups are not reloaded
+    config: &'static NodeConfigFinalize<K>,
+    groups: Vec<RunningGroup<K>>,
+    next_index: usize,
+    // shared with the api, the hooks and the packet senders
+    interfaces: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    interfaces_changed: watch::Sender<()>,
+    routing_table: Arc<RoutingTableEnum<InterRT, ExternRT>>,
+    tun: Arc<T>,
+    tun_index: u32,
+    sys_routing: Option<Arc<tokio::sync::Mutex<SystemRouteHandle>>>,
+    sys_dns: Option<Arc<SystemDnsHandle>>,
+    hooks: Option<Arc<Hooks<K>>>,
+    firewall: Option<Arc<Firewall>>,
+    events: Arc<EventBus>,
     #[cfg(feature = "cross-nat")]
-    let snat = if config.cross_nat && allowed_ips_exists {
-        let snat = cross_nat::SNat::create(
-            rt.clone(), 
-            interfaces_hook.clone(), 
-            tun.clone(), 
-            hooks.clone(), 
-        )?;
-        Some(Arc::new(snat))
-    } else {
-        None
-    };
+    snat: Option<Arc<cross_nat::SNat>>,
+    // a handler error stops the node
+    errors: mpsc::UnboundedSender<Error>,
+}
 
-    let mut future_list: Vec<BoxFuture<Result<()>>> = Vec::new();
-    let mut interfaces = Vec::with_capacity(config.groups.len());
-    let sys_routing = if config.features.disable_route_operation { None } else {
-        let arc = Arc::new(tokio::sync::Mutex::new(SystemRouteHandle::new()?));
-        Some(arc)
-    };
-    #[allow(unused)]
-    let tun_index = tun.get_index();
+impl <K, T, InterRT, ExternRT> GroupManager<K, T, InterRT, ExternRT>
+    where
+        K: Cipher + Clone + Send + Sync + 'static,
+        T: TunDevice + Send + Sync + 'static,
+        InterRT: RoutingTable + Clone + Send + Sync + 'static,
+        ExternRT: RoutingTable + Send + Sync + 'static
+{
+    fn publish(&self) {
+        let list = self.groups.iter().map(|g| g.interface.clone()).collect();
+        self.interfaces.store(Arc::new(list));
+        self.interfaces_changed.send_replace(());
+    }
 
-    for (index, group) in config.groups.iter().enumerate() {
-        let (channel_tx, channel_rx) = if group.mode.is_use_tcp() {
+    async fn run_group(&self, group: Arc<TargetGroupFinalize<K>>, index: usize) -> Result<RunningGroup<K>> {
+        let config = self.config;
+
+        // the quic stream carries the relay packets that don't fit in a datagram
+        let (channel_tx, channel_rx) = if group.mode.is_use_tcp() || group.mode.is_use_quic() {
             let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(config.channel_limit);
             (Some(tx), Some(rx))
         } else {
@@ -2084,91 +3277,488 @@ pub async fn start<K, T>(
             group_name: ArcSwapOption::empty(),
             addr: AtomicAddr::from(VirtualAddr::UNSPECIFIED),
             cidr: AtomicCidr::from(Ipv4Net::default()),
+            ipv4: AtomicBool::new(false),
+            addr_v6: AtomicCell::new(None),
+            cidr_v6: AtomicCell::new(None),
             mode: group.mode.clone(),
-            specify_mode: group.specify_mode.iter().map(|(k, v)| (*k, v.clone())).collect(),
+            specify_mode: ArcSwap::from_pointee(group.specify_mode.iter().map(|(k, v)| (*k, v.clone())).collect()),
             node_list: ArcSwap::from_pointee(NodeList::new()),
-            server_addr: group.server_addr.clone(),
+            server_addr: ArcSwap::from_pointee(group.server_addrs[0].clone()),
+            server_addrs: group.server_addrs.clone(),
             server_udp_hc: RwLock::new(HeartbeatCache::new()),
-            server_udp_status: AtomicCell::new(UdpStatus::Unavailable),
+            server_udp_status: UdpStatusCell::new(),
             server_tcp_hc: RwLock::new(HeartbeatCache::new()),
             server_is_connected: AtomicBool::new(false),
+            pushed: ArcSwap::from_pointee(PushedConfig::default()),
+            acl_flows: FlowTable::new(),
             server_allow_udp_relay: AtomicBool::new(false),
             server_allow_tcp_relay: AtomicBool::new(false),
             tcp_handler_channel: channel_tx,
+            quic_connection: ArcSwapOption::empty(),
             udp_socket: udp_opt,
             key: group.key.clone(),
+            sessions: SessionTable::new(),
+            udp_nonce: UdpNonceGenerator::new(),
+            replay_windows: Mutex::new(HashMap::new()),
+            udp_replay_dropped: AtomicU64::new(0),
             peers_map: {
                 if group.auto_route_selection {
                     Some(RwLock::new(HashMap::new()))
                 } else {
                     None
                 }
-            }
+            },
+            events: self.events.clone(),
         };
 
         let interface = Arc::new(interface);
-        interfaces.push(interface.clone());
 
-        #[allow(unused_mut)]
-        let mut routes = Vec::new();
+        let mut routes = GroupRoutes::new(ips_list(&group.ips));
+        // the system routes are added by the tcp handler
+        sync_group_routes(&mut routes, &self.routing_table, None, self.tun_index, index).await;
+        let routes = Arc::new(tokio::sync::Mutex::new(routes));
 
-        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-        for (gateway, cidrs) in &group.ips {
-            for cidr in cidrs {
-                let route = Route::new(IpAddr::V4(cidr.network()), cidr.prefix_len())
-                    .with_gateway(IpAddr::V4(*gateway))
-                    .with_ifindex(tun_index);
-
-                #[cfg(any(target_os = "windows", target_os = "linux"))]
-                let route = route.with_metric(1);
+        let (abort, notified) = watch::channel(());
 
-                routes.push(route);
-            }
-        }
-
-        let fut = tcp_handler(
+        let tcp_handle = tcp_handler(
             config,
-            group,
-            rt.clone(),
+            group.clone(),
+            self.routing_table.clone(),
             interface.clone(),
-            tun.clone(),
+            self.tun.clone(),
             channel_rx,
-            sys_routing.clone(),
-            routes,
-            hooks.clone(),
+            self.sys_routing.clone(),
+            routes.clone(),
+            self.sys_dns.clone(),
+            self.hooks.clone(),
+            self.firewall.clone(),
             #[cfg(feature = "cross-nat")]
-            snat.clone()
+            self.snat.clone(),
+            notified.clone()
         );
-        future_list.push(Box::pin(fut));
 
-        if interface.udp_socket.is_some() {
+        let udp_handle = if interface.udp_socket.is_some() {
             let fut = udp_handler(
                 config,
-                group,
-                rt.clone(),
-                interface,
-                tun.clone(),
-                hooks.clone(),
+                group.clone(),
+                self.routing_table.clone(),
+                interface.clone(),
+                self.tun.clone(),
+                self.hooks.clone(),
+                self.firewall.clone(),
                 #[cfg(feature = "cross-nat")]
-                snat.clone()
+                self.snat.clone(),
+                notified.clone()
             );
-            future_list.push(Box::pin(fut));
+            fut.left_future()
+        } else {
+            std::future::pending().right_future()
+        };
+
+        let dns_handle = match &config.dns {
+            Some(dns) => dns::dns_handler(dns, interface.clone(), self.interfaces.clone()).left_future(),
+            None => std::future::pending().right_future()
+        };
+
+        let errors = self.errors.clone();
+        let node_name = group.node_name.clone();
+
+        let fut = async move {
+            match tokio::try_join!(tcp_handle, udp_handle, dns_handle) {
+                Ok(_) => (),
+                // the sender is dropped when the group is removed
+                Err(_) if notified.has_changed().is_err() => info!("node {} interface {} stopped", node_name, index),
+                Err(e) => {
+                    let _ = errors.send(e);
+                }
+            }
+        };
+
+        let rg = RunningGroup {
+            config: group,
+            interface,
+            routes,
+            abort,
+            task: tokio::spawn(fut),
+        };
+        Ok(rg)
+    }
+
+    // the hosts and nat records are cleared by the tcp handler, the routes and addresses are removed here
+    async fn stop_group(&self, rg: RunningGroup<K>) {
+        let RunningGroup { config: group, interface, routes, abort, task } = rg;
+
+        interface.server_is_connected.store(false, Ordering::Relaxed);
+        drop(abort);
+        let _ = task.await;
+
+        let index = interface.index;
+        let routes = routes.lock().await;
+
+        self.routing_table.update(|t| {
+            for (_, cidr) in &routes.applied {
+                remove_item(t, cidr, index, ItemKind::IpsRoute);
+            }
+        });
+
+        if let Err(e) = update_tun_addr(&*self.tun, &self.routing_table, &interface, &group.allowed_ips, false, VirtualAddr::UNSPECIFIED, Ipv4Net::default()) {
+            warn!("failed to delete tun address: {}", e);
+        }
+
+        if let Err(e) = update_tun_addr_v6(&*self.tun, &self.routing_table, &interface, &group.allowed_ips, None, None) {
+            warn!("failed to delete tun ipv6 address: {}", e);
         }
+
+        if let (Some(sys_routing), true) = (&self.sys_routing, routes.synced) {
+            if let Err(e) = sys_routing.lock().await.delete(&sys_routes(&routes.applied, self.tun_index)).await {
+                warn!("failed to delete route: {}", e);
+            }
+        }
+
+        if let Some(sys_dns) = &self.sys_dns {
+            sys_dns.remove(index).await;
+        }
+    }
+
+    async fn add_group(&mut self, group: Arc<TargetGroupFinalize<K>>) -> Result<usize> {
+        // the server connections are bound to the device only if a gateway route exists at startup
+        if group.use_gateway() && self.config.socket_bind_device.is_none() {
+            warn!("node {} routes all traffic, socket_bind_device should be set", group.node_name);
+        }
+
+        let index = self.next_index;
+        let rg = self.run_group(group, index).await?;
+        self.next_index += 1;
+        self.groups.push(rg);
+        self.publish();
+        Ok(index)
+    }
+
+    async fn remove_group(&mut self, pos: usize) {
+        let rg = self.groups.remove(pos);
+        // the packet senders release the interface
+        self.publish();
+        self.stop_group(rg).await;
+    }
+
+    // the new interface keeps the index, so the routes of the other groups are untouched
+    async fn restart_group(&mut self, pos: usize, group: Arc<TargetGroupFinalize<K>>) -> Result<()> {
+        let index = self.groups[pos].interface.index;
+        self.remove_group(pos).await;
+
+        // the udp socket is closed after the packet senders dropped the old interface
+        let mut retry = 0;
+
+        let rg = loop {
+            match self.run_group(group.clone(), index).await {
+                Err(_) if retry < 10 => {
+                    retry += 1;
+                    time::sleep(Duration::from_millis(200)).await;
+                }
+                res => break res?
+            }
+        };
+
+        self.groups.insert(pos, rg);
+        self.publish();
+        Ok(())
+    }
+
+    async fn update_group(&mut self, pos: usize, group: Arc<TargetGroupFinalize<K>>) {
+        let rg = &self.groups[pos];
+        let old = &rg.config;
+        let index = rg.interface.index;
+
+        if old.specify_mode != group.specify_mode {
+            let specify_mode = group.specify_mode.iter().map(|(k, v)| (*k, v.clone())).collect();
+            rg.interface.specify_mode.store(Arc::new(specify_mode));
+        }
+
+        if old.ips != group.ips {
+            let mut routes = rg.routes.lock().await;
+            routes.local = ips_list(&group.ips);
+
+            let (added, removed) = sync_group_routes(
+                &mut routes,
+                &self.routing_table,
+                self.sys_routing.as_deref(),
+                self.tun_index,
+                index
+            ).await;
+
+            info!("reload: node {} interface {} ips updated, {} added, {} removed", group.node_name, index, added, removed);
+        }
+
+        self.groups[pos].config = group;
+    }
+
+    async fn reload(&mut self, config: NodeConfigFinalize<K>) {
+        let old = self.config;
+
+        let restart_required = [
+            ("mtu", old.mtu != config.mtu),
+            ("channel_limit", old.channel_limit != config.channel_limit),
+            ("api_addr", old.api_addr != config.api_addr),
+            ("api_token", old.api_token != config.api_token),
+            ("api_tls", old.api_tls.is_some() != config.api_tls.is_some()),
+            ("tcp_heartbeat_interval_secs", old.tcp_heartbeat_interval != config.tcp_heartbeat_interval),
+            ("udp_heartbeat_interval_secs", old.udp_heartbeat_interval != config.udp_heartbeat_interval),
+            ("tcp_heartbeat_continuous_loss", old.tcp_heartbeat_continuous_loss != config.tcp_heartbeat_continuous_loss),
+            ("udp_heartbeat_continuous_loss", old.udp_heartbeat_continuous_loss != config.udp_heartbeat_continuous_loss),
+            ("udp_heartbeat_continuous_recv", old.udp_heartbeat_continuous_recv != config.udp_heartbeat_continuous_recv),
+            ("udp_session_rekey_interval_secs", old.udp_session_rekey_interval != config.udp_session_rekey_interval),
+            ("reconnect_interval_secs", old.reconnect_interval != config.reconnect_interval),
+            ("udp_socket_recv_buffer_size", old.udp_socket_recv_buffer_size != config.udp_socket_recv_buffer_size),
+            ("udp_socket_send_buffer_size", old.udp_socket_send_buffer_size != config.udp_socket_send_buffer_size),
+            ("external_routing_table", old.external_routing_table != config.external_routing_table),
+            ("allow_packet_forward", old.allow_packet_forward != config.allow_packet_forward),
+            ("allow_packet_not_in_rules_send_to_kernel", old.allow_packet_not_in_rules_send_to_kernel != config.allow_packet_not_in_rules_send_to_kernel),
+            ("enable_hook", old.enable_hook != config.enable_hook),
+            ("socket_bind_device", old.socket_bind_device != config.socket_bind_device),
+            ("dns", old.dns != config.dns),
+            ("firewall", old.firewall != config.firewall),
+            ("features", old.features != config.features),
+        ];
+
+        for (field, changed) in restart_required {
+            if changed {
+                warn!("reload: {} has changed, it takes effect after restart", field);
+            }
+        }
+
+        #[cfg(feature = "cross-nat")]
+        if old.cross_nat != config.cross_nat {
+            warn!("reload: cross_nat has changed, it takes effect after restart");
+        }
+
+        let mut i = 0;
+
+        while i < self.groups.len() {
+            let running = self.groups[i].config.clone();
+
+            if config.groups.iter().any(|g| same_group(g, &running)) {
+                i += 1;
+                continue;
+            }
+
+            let index = self.groups[i].interface.index;
+            self.remove_group(i).await;
+            info!("reload: node {} interface {} removed", running.node_name, index);
+        }
+
+        for group in config.groups {
+            let pos = self.groups.iter().position(|g| same_group(&g.config, &group));
+
+            match pos {
+                Some(pos) if needs_restart(&self.groups[pos].config, &group) => {
+                    let index = self.groups[pos].interface.index;
+
+                    match self.restart_group(pos, group.clone()).await {
+                        Ok(()) => info!("reload: node {} interface {} restarted", group.node_name, index),
+                        Err(e) => error!("reload: node {} interface {} restart error: {:?}", group.node_name, index, e)
+                    }
+                }
+                Some(pos) => self.update_group(pos, group).await,
+                None => {
+                    match self.add_group(group.clone()).await {
+                        Ok(index) => info!("reload: node {} interface {} added", group.node_name, index),
+                        Err(e) => error!("reload: node {} start error: {:?}", group.node_name, e)
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, cmd: GroupCommand<K>) {
+        match cmd {
+            GroupCommand::Reload(config) => self.reload(config).await,
+            GroupCommand::Add(group) => {
+                let group = Arc::new(group);
+
+                match self.add_group(group.clone()).await {
+                    Ok(index) => info!("node {} interface {} added", group.node_name, index),
+                    Err(e) => error!("node {} start error: {:?}", group.node_name, e)
+                }
+            }
+            GroupCommand::Remove(index) => {
+                match self.groups.iter().position(|g| g.interface.index == index) {
+                    None => warn!("interface {} not found", index),
+                    Some(pos) => {
+                        let node_name = &self.groups[pos].config.node_name;
+                        info!("node {} interface {} removed", node_name, index);
+                        self.remove_group(pos).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// config_loader reads the config file again on reload
+pub async fn start<K, T, F>(
+    config: NodeConfigFinalize<K>, 
+    tun: T,
+    interfaces_hook: Arc<ArcSwap<Vec<Arc<Interface<K>>>>>,
+    config_loader: Option<F>
+) -> Result<()>
+    where
+        K: Cipher + Send + Sync + Clone + 'static,
+        T: TunDevice + Send + Sync + 'static,
+        F: Fn() -> Result<NodeConfigFinalize<K>> + Send + Sync + 'static,
+        TargetGroupFinalize<K>: TryFrom<TargetGroup, Error = Error>
+{
+    let config = &*Box::leak(Box::new(config));
+    let tun = Arc::new(tun);
+    tun.set_mtu(config.mtu)?;
+
+    let (send_packet_chan_tx, send_packet_chan_rx) = flume::bounded(1024);
+
+    let ctx = Context {
+        interfaces: Some(interfaces_hook.clone()),
+        send_packet_chan: Some(send_packet_chan_tx)
     };
 
-    let _ = interfaces_hook.set(interfaces.clone());
+    let curr = std::env::current_exe().ok();
+    let parent = curr.as_deref().and_then(|v| v.parent()).unwrap_or(Path::new(""));
+
+    let ctx = Arc::new(ctx);
+
+    let hooks = if config.enable_hook {
+        let dll_name = format!("{}fubukihook{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX);
+        let hooks = common::hook::open_hooks_dll(&parent.join(Path::new(&dll_name)), ctx.clone())?;
+        Some(Arc::new(hooks))
+    } else {
+        None
+    };
+
+    let rt = if config.external_routing_table {
+        let dll_name = format!("{}fubukiextrt{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX);
+    
+        let rt = routing_table::external::create::<K>(
+            &parent.join(Path::new(&dll_name)),
+            ctx.clone()
+        )?;
+        RoutingTableEnum::External(SyncUnsafeCell::new(rt))
+    } else {
+        let rt = routing_table::internal::create();
+        RoutingTableEnum::Internal(ArcSwap::from_pointee(rt))
+    };
+
+    let rt = Arc::new(rt);
+    let (interfaces_changed, interfaces_changed_rx) = watch::channel(());
+
+    #[cfg(feature = "cross-nat")]
+    let allowed_ips_exists = {
+        let mut ret = false;
+
+        for group in config.groups.iter() {
+            if !group.allowed_ips.is_empty() {
+                ret = true;
+                break;
+            }
+        }
+        ret
+    };
+
+    #[cfg(feature = "cross-nat")]
+    let snat = if config.cross_nat && allowed_ips_exists {
+        let snat = cross_nat::SNat::create(
+            rt.clone(), 
+            interfaces_hook.clone(), 
+            interfaces_changed_rx.clone(),
+            tun.clone(), 
+            hooks.clone(), 
+        )?;
+        Some(Arc::new(snat))
+    } else {
+        None
+    };
+
+    let firewall = config.firewall.as_ref().map(|v| Arc::new(Firewall::new(v)));
+    let events = Arc::new(EventBus::new());
+
+    let mut future_list: Vec<BoxFuture<Result<()>>> = Vec::new();
+    let sys_routing = if config.features.disable_route_operation { None } else {
+        let arc = Arc::new(tokio::sync::Mutex::new(SystemRouteHandle::new()?));
+        Some(arc)
+    };
+    let sys_dns = if config.features.disable_dns_operation { None } else {
+        Some(Arc::new(SystemDnsHandle::new(tun.get_index())))
+    };
+    let (errors, mut errors_rx) = mpsc::unbounded_channel();
+
+    let mut manager = GroupManager {
+        config,
+        groups: Vec::with_capacity(config.groups.len()),
+        next_index: 0,
+        interfaces: interfaces_hook.clone(),
+        interfaces_changed,
+        routing_table: rt.clone(),
+        tun: tun.clone(),
+        tun_index: tun.get_index(),
+        sys_routing,
+        sys_dns,
+        hooks: hooks.clone(),
+        firewall: firewall.clone(),
+        events: events.clone(),
+        #[cfg(feature = "cross-nat")]
+        snat: snat.clone(),
+        errors,
+    };
+
+    for group in &config.groups {
+        manager.add_group(group.clone()).await?;
+    }
+
+    let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel();
+
+    let control = GroupControl {
+        tx: cmd_tx,
+        interfaces: interfaces_hook.clone(),
+        config_loader: config_loader.map(|f| Box::new(f) as Box<dyn Fn() -> Result<NodeConfigFinalize<K>> + Send + Sync>),
+        parse_group: TargetGroupFinalize::try_from,
+    };
+    let control = Arc::new(control);
+
+    #[cfg(unix)]
+    if !config.features.disable_signal_handling && control.config_loader.is_some() {
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+        let control = control.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("received SIGHUP, reloading config");
+
+                if let Err(e) = control.reload() {
+                    error!("reload config error: {:?}", e);
+                }
+            }
+        });
+    }
+
+    let manager_fut = async move {
+        loop {
+            tokio::select! {
+                Some(cmd) = cmd_rx.recv() => manager.handle(cmd).await,
+                Some(e) = errors_rx.recv() => return Err(e)
+            }
+        }
+    };
+    future_list.push(Box::pin(manager_fut));
    
     let tun_handler_fut = tun_handler(
         tun.clone(), 
         rt.clone(),
-         interfaces.clone(), 
-         hooks, 
-         #[cfg(feature = "cross-nat")]
-         snat.clone()
+        interfaces_hook.clone(),
+        interfaces_changed_rx.clone(),
+        hooks,
+        firewall.clone(),
+        #[cfg(feature = "cross-nat")]
+        snat.clone()
     );
     future_list.push(Box::pin(tun_handler_fut));
     if !config.features.disable_api_server {
-        future_list.push(Box::pin(api_start(config.api_addr, interfaces.clone())));
+        future_list.push(Box::pin(api_start(config.api_addr, config.api_token.clone(), config.api_tls.clone(), interfaces_hook.clone(), control, firewall, events)));
     }
 
     if Arc::strong_count(&ctx) > 1 {
@@ -2177,7 +3767,9 @@ pub async fn start<K, T>(
             send_packet_chan_rx, 
             tun, 
             rt, 
-            interfaces, 
+            interfaces_hook, 
+            interfaces_changed_rx,
+            None,
             None,
             #[cfg(feature = "cross-nat")]
             snat
@@ -2226,26 +3818,8 @@ pub async fn start<K, T>(
     Ok(())
 }
 
-pub async fn info(api_addr: &str, info_type: NodeInfoType) -> Result<()> {
-    let req = Request::builder()
-        .method(Method::GET)
-        .uri(format!("http://{}/info", api_addr))
-        .body(Empty::<hyper::body::Bytes>::new())?;
-
-    let stream = TcpStream::connect(api_addr).await?;
-    let stream = TokioIo::new(stream);
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(stream).await?;
-
-    tokio::spawn(conn);
-
-    let resp = sender.send_request(req).await?;
-    let (parts, body) = resp.into_parts();
-    let bytes = body.collect().await?.to_bytes();
-
-    if parts.status != 200 {
-        let msg = String::from_utf8(bytes.to_vec())?;
-        return Err(anyhow!("http response code: {}, message: {}", parts.status.as_u16(), msg));
-    }
+pub async fn info(client: &ApiClient, info_type: NodeInfoType) -> Result<()> {
+    let bytes = client.get("/info").await?;
 
     let interfaces_info: Vec<InterfaceInfo> = serde_json::from_slice(bytes.deref())?;
 
@@ -2272,6 +3846,8 @@ pub async fn info(api_addr: &str, info_type: NodeInfoType) -> Result<()> {
                     table.add_row(row!["GROUP", info.group_name.unwrap_or_default()]);
                     table.add_row(row!["IP", info.addr]);
                     table.add_row(row!["CIDR", info.cidr]);
+                    table.add_row(row!["IPV6", info.addr_v6.map(|v| v.to_string()).unwrap_or_default()]);
+                    table.add_row(row!["CIDR_V6", info.cidr_v6.map(|v| v.to_string()).unwrap_or_default()]);
                     table.add_row(row!["SERVER_ADDRESS", info.server_addr]);
                     table.add_row(row!["PROTOCOL_MODE", format!("{:?}", info.mode)]);
                     table.add_row(row!["IS_CONNECTED", info.server_is_connected]);
@@ -2285,23 +3861,27 @@ pub async fn info(api_addr: &str, info_type: NodeInfoType) -> Result<()> {
 
                     let tcp_loss_rate =  info.server_tcp_hc.packet_loss_count as f32 / info.server_tcp_hc.send_count as f32 * 100f32;
                     table.add_row(row!["TCP_LOSS_RATE", ternary!(!tcp_loss_rate.is_nan(), format!("{}%", tcp_loss_rate), String::new())]);
+                    table.add_row(row!["UDP_REPLAY_DROPPED", info.udp_replay_dropped]);
 
                     break;
                 }
             }
         }
         NodeInfoType::NodeMap{ interface_index, node_ip: None } => {
-            table.add_row(row!["NAME", "IP", "REGISTER_TIME"]);
+            table.add_row(row!["NAME", "IP", "REGISTER_TIME", "RX", "TX"]);
 
             for info in interfaces_info {
                 if info.index == interface_index {
                     for node in info.node_map.values() {
                         let register_time = utc_to_str(node.node.register_time)?;
+                        let t = &node.traffic;
 
                         table.add_row(row![
                             node.node.name,
                             node.node.virtual_addr,
                             register_time,
+                            format_bytes(t.udp_p2p.rx_bytes + t.udp_relay.rx_bytes + t.tcp_relay.rx_bytes + t.quic_relay.rx_bytes),
+                            format_bytes(t.udp_p2p.tx_bytes + t.udp_relay.tx_bytes + t.tcp_relay.tx_bytes + t.quic_relay.tx_bytes),
                         ]);
                     }
                     break;
@@ -2316,6 +3896,7 @@ pub async fn info(api_addr: &str, info_type: NodeInfoType) -> Result<()> {
 
                         table.add_row(row!["NAME", node.node.name]);
                         table.add_row(row!["IP", node.node.virtual_addr]);
+                        table.add_row(row!["IPV6", node.node.virtual_addr_v6.map(|v| v.to_string()).unwrap_or_default()]);
                         table.add_row(row!["LAN_ADDRESS", format!("{:?}", node.node.lan_udp_addr)]);
                         table.add_row(row!["WAN_ADDRESS", format!("{:?}", node.node.wan_udp_addr)]);
                         table.add_row(row!["PROTOCOL_MODE",  format!("{:?}", node.node.mode)]);
@@ -2326,6 +3907,10 @@ pub async fn info(api_addr: &str, info_type: NodeInfoType) -> Result<()> {
 
                         let loss_rate = node.hc.packet_loss_count as f32 / node.hc.send_count as f32 * 100f32;
                         table.add_row(row!["LOSS_RATE", ternary!(!loss_rate.is_nan(), format!("{}%", loss_rate), String::new())]);
+                        table.add_row(row!["UDP_P2P_TRAFFIC", node.traffic.udp_p2p]);
+                        table.add_row(row!["UDP_RELAY_TRAFFIC", node.traffic.udp_relay]);
+                        table.add_row(row!["TCP_RELAY_TRAFFIC", node.traffic.tcp_relay]);
+                        table.add_row(row!["QUIC_RELAY_TRAFFIC", node.traffic.quic_relay]);
                     }
 
                     break;
@@ -2337,3 +3922,127 @@ pub async fn info(api_addr: &str, info_type: NodeInfoType) -> Result<()> {
     table.printstd();
     Ok(())
 }
+
+#[test]
+fn quic_register_test() {
+    use crate::{NodeConfig, ServerConfig, ServerConfigFinalize};
+
+    let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let dir = std::env::temp_dir().join(format!("fubuki-quic-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+    std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+    let free_port = || std::net::UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let (port, quic_port) = (free_port(), free_port());
+
+    let server_config: ServerConfig = serde_json::from_value(serde_json::json!({
+        "api_addr": "127.0.0.1:0",
+        "groups": [{
+            "name": "g",
+            "listen_addr": format!("0.0.0.0:{}", port),
+            "key": "123",
+            "address_range": "10.200.0.0/24",
+            "quic": {
+                "listen_addr": format!("0.0.0.0:{}", quic_port),
+                "cert_path": cert_path,
+                "key_path": key_path
+            }
+        }]
+    })).unwrap();
+
+    let node_config: NodeConfig = serde_json::from_value(serde_json::json!({
+        "groups": [{
+            "node_name": "n1",
+            "server_addr": format!("192.0.2.1:{}", port),
+            "key": "123",
+            "mode": { "p2p": [], "relay": ["QUIC"] },
+            "quic_port": quic_port,
+            "quic_cert_path": cert_path
+        }]
+    })).unwrap();
+
+    let server_config = ServerConfigFinalize::try_from(server_config).unwrap();
+    let node_config = NodeConfigFinalize::try_from(node_config).unwrap();
+    let mut group = (*node_config.groups[0]).clone();
+    // loopback addresses are rejected by the config
+    group.server_addrs = vec![format!("127.0.0.1:{}", port)];
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    rt.block_on(async {
+        tokio::spawn(crate::server::start(server_config, || Err(anyhow!("reload is not supported"))));
+
+        // the server is starting
+        let mut register_addr = RegisterVirtualAddr::Auto(None);
+        let mut retries = 0;
+
+        let (stream, _) = loop {
+            let res = register(
+                &group,
+                &group.server_addrs[0],
+                &group.key,
+                &mut register_addr,
+                &mut None,
+                None,
+                0,
+                &mut false,
+                None
+            ).await;
+
+            match res {
+                Ok(v) => break v,
+                Err(e) if retries < 20 => {
+                    debug!("register error: {:?}", e);
+                    retries += 1;
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                Err(e) => panic!("{:?}", e)
+            }
+        };
+
+        let virtual_addr = match register_addr {
+            RegisterVirtualAddr::Auto(Some((addr, _))) => addr,
+            _ => unreachable!()
+        };
+        let conn = stream.connection().expect("quic connection").clone();
+
+        // relay one packet to the node itself
+        let mut packet = [0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&virtual_addr.octets());
+        packet[16..20].copy_from_slice(&virtual_addr.octets());
+
+        const DATA_START: usize = TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+        let mut frame = vec![0u8; DATA_START + packet.len()];
+        frame[DATA_START..].copy_from_slice(&packet);
+        TcpMsg::relay_encode(&group.key, rand::random(), virtual_addr, packet.len(), &mut frame);
+
+        // the tunnel may not be joined to the group yet
+        let mut datagram = None;
+
+        for _ in 0..10 {
+            assert!(quic::send_datagram(&conn, &frame).unwrap());
+
+            if let Ok(res) = tokio::time::timeout(Duration::from_millis(500), conn.read_datagram()).await {
+                datagram = Some(res.unwrap());
+                break;
+            }
+        }
+
+        let mut datagram = datagram.expect("relayed datagram").to_vec();
+
+        match TcpMsg::decode_frame(&group.key, &mut datagram).unwrap() {
+            TcpMsg::Relay(dst, data) => {
+                assert_eq!(dst, virtual_addr);
+                assert_eq!(data, packet);
+            }
+            _ => panic!("unexpected message")
+        }
+        drop(stream);
+    });
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
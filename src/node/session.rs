@@ -0,0 +1,286 @@
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use ahash::HashMap;
+use parking_lot::{Mutex, RwLock};
+use rand::rngs::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::common::cipher::{ChaCha20Poly1305Cipher, Nonce};
+use crate::common::net::ReplayWindow;
+use crate::common::net::protocol::{EphemeralKey, SessionId, VirtualAddr};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// minimum interval between the init messages accepted from a peer
+const INIT_INTERVAL: Duration = Duration::from_secs(1);
+
+// p2p session between two nodes, keys come from an ephemeral x25519 exchange
+pub struct Session {
+    pub peer: VirtualAddr,
+    pub local_id: SessionId,
+    // id chosen by the peer, carried in the nonce of the packets sent to it
+    pub remote_id: SessionId,
+    pub cipher: ChaCha20Poly1305Cipher,
+    pub established: Instant,
+    pub replay_window: Mutex<ReplayWindow>,
+    // the responder only sends with the session after receiving a packet under it
+    confirmed: AtomicBool,
+}
+
+impl Session {
+    pub fn nonce(&self, mut nonce: Nonce) -> Nonce {
+        nonce[..size_of::<SessionId>()].copy_from_slice(&self.remote_id.to_be_bytes());
+        nonce
+    }
+}
+
+struct PendingHandshake {
+    local_id: SessionId,
+    secret: EphemeralSecret,
+    public_key: EphemeralKey,
+    start: Instant,
+}
+
+struct PendingResponse {
+    // None after the session is confirmed
+    session: Option<Arc<Session>>,
+    start: Instant,
+}
+
+fn session_cipher(
+    secret: EphemeralSecret,
+    remote_key: &EphemeralKey,
+    init_key: &EphemeralKey,
+    resp_key: &EphemeralKey
+) -> ChaCha20Poly1305Cipher {
+    let shared = secret.diffie_hellman(&PublicKey::from(*remote_key));
+
+    let mut hasher = blake3::Hasher::new_derive_key("fubuki p2p session key");
+    hasher.update(shared.as_bytes());
+    hasher.update(init_key);
+    hasher.update(resp_key);
+
+    ChaCha20Poly1305Cipher::from(hasher.finalize().as_bytes().as_slice())
+}
+
+fn new_secret() -> (EphemeralSecret, EphemeralKey) {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret).to_bytes();
+    (secret, public_key)
+}
+
+pub struct SessionTable {
+    // local id -> session, the replaced session of a peer is kept until the next rekey
+    inbound: RwLock<HashMap<SessionId, Arc<Session>>>,
+    outbound: RwLock<HashMap<VirtualAddr, Arc<Session>>>,
+    pending: Mutex<HashMap<VirtualAddr, PendingHandshake>>,
+    // the last init of each peer, its session only receives until confirmed
+    responding: Mutex<HashMap<VirtualAddr, PendingResponse>>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        SessionTable {
+            inbound: RwLock::new(HashMap::default()),
+            outbound: RwLock::new(HashMap::default()),
+            pending: Mutex::new(HashMap::default()),
+            responding: Mutex::new(HashMap::default()),
+        }
+    }
+
+    pub fn get_inbound(&self, local_id: SessionId) -> Option<Arc<Session>> {
+        self.inbound.read().get(&local_id).cloned()
+    }
+
+    pub fn get_outbound(&self, peer: &VirtualAddr) -> Option<Arc<Session>> {
+        self.outbound.read().get(peer).cloned()
+    }
+
+    pub fn needs_handshake(&self, peer: &VirtualAddr, rekey_interval: Duration) -> bool {
+        match self.outbound.read().get(peer) {
+            None => true,
+            Some(session) => session.established.elapsed() >= rekey_interval
+        }
+    }
+
+    fn new_local_id(&self, pending: &HashMap<VirtualAddr, PendingHandshake>) -> SessionId {
+        let inbound = self.inbound.read();
+
+        loop {
+            let id: SessionId = rand::random();
+
+            if !inbound.contains_key(&id) && !pending.values().any(|p| p.local_id == id) {
+                return id;
+            }
+        }
+    }
+
+    // returns the session id and ephemeral public key of the init message, None if a handshake is in progress
+    pub fn initiate(&self, peer: VirtualAddr) -> Option<(SessionId, EphemeralKey)> {
+        let mut pending = self.pending.lock();
+
+        if let Some(p) = pending.get(&peer) {
+            if p.start.elapsed() < HANDSHAKE_TIMEOUT {
+                return None;
+            }
+        }
+
+        let local_id = self.new_local_id(&pending);
+        let (secret, public_key) = new_secret();
+
+        pending.insert(peer, PendingHandshake {
+            local_id,
+            secret,
+            public_key,
+            start: Instant::now(),
+        });
+        Some((local_id, public_key))
+    }
+
+    // handle the init message and return the session id and ephemeral public key of the response, None if the peer sends init too often
+    // the session only accepts packets until the first one confirms it, an init alone never replaces the session in use
+    pub fn respond(
+        &self,
+        peer: VirtualAddr,
+        remote_id: SessionId,
+        remote_key: &EphemeralKey
+    ) -> Option<(SessionId, EphemeralKey)> {
+        let mut responding = self.responding.lock();
+
+        if let Some(r) = responding.get(&peer) {
+            if r.start.elapsed() < INIT_INTERVAL {
+                return None;
+            }
+        }
+
+        let local_id = self.new_local_id(&self.pending.lock());
+        let (secret, public_key) = new_secret();
+
+        let session = Arc::new(Session {
+            peer,
+            local_id,
+            remote_id,
+            cipher: session_cipher(secret, remote_key, remote_key, &public_key),
+            established: Instant::now(),
+            replay_window: Mutex::new(ReplayWindow::new()),
+            confirmed: AtomicBool::new(false),
+        });
+
+        let mut inbound = self.inbound.write();
+
+        if let Some(PendingResponse { session: Some(old), .. }) = responding.get(&peer) {
+            inbound.remove(&old.local_id);
+        }
+
+        inbound.insert(local_id, session.clone());
+        responding.insert(peer, PendingResponse { session: Some(session), start: Instant::now() });
+        Some((local_id, public_key))
+    }
+
+    // handle the response message, returns None if there is no matching handshake
+    pub fn complete(
+        &self,
+        peer: VirtualAddr,
+        remote_id: SessionId,
+        remote_key: &EphemeralKey
+    ) -> Option<Arc<Session>> {
+        let p = match self.pending.lock().remove(&peer) {
+            Some(p) if p.start.elapsed() < HANDSHAKE_TIMEOUT => p,
+            _ => return None
+        };
+
+        let session = Arc::new(Session {
+            peer,
+            local_id: p.local_id,
+            remote_id,
+            cipher: session_cipher(p.secret, remote_key, &p.public_key, remote_key),
+            established: Instant::now(),
+            replay_window: Mutex::new(ReplayWindow::new()),
+            confirmed: AtomicBool::new(true),
+        });
+
+        self.install(session.clone());
+        Some(session)
+    }
+
+    // called on every authenticated packet of the session, the first one promotes a responder session
+    pub fn confirm(&self, session: &Arc<Session>) {
+        if session.confirmed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut responding = self.responding.lock();
+
+        match responding.get_mut(&session.peer) {
+            Some(r) if r.session.as_ref().is_some_and(|s| s.local_id == session.local_id) => r.session = None,
+            _ => return
+        }
+
+        session.confirmed.store(true, Ordering::Relaxed);
+        self.install(session.clone());
+    }
+
+    fn install(&self, session: Arc<Session>) {
+        let peer = session.peer;
+        let old = self.outbound.write().insert(peer, session.clone());
+        let old_id = old.map(|s| s.local_id);
+
+        let mut inbound = self.inbound.write();
+        inbound.retain(|id, s| s.peer != peer || Some(*id) == old_id);
+        inbound.insert(session.local_id, session);
+    }
+
+    pub fn remove(&self, peer: &VirtualAddr) {
+        if self.outbound.write().remove(peer).is_some() {
+            self.inbound.write().retain(|_, s| s.peer != *peer);
+        }
+        self.pending.lock().remove(peer);
+        self.responding.lock().remove(peer);
+    }
+
+    pub fn retain_peers<F: Fn(&VirtualAddr) -> bool>(&self, f: F) {
+        self.outbound.write().retain(|peer, _| f(peer));
+        self.inbound.write().retain(|_, s| f(&s.peer));
+        self.pending.lock().retain(|peer, _| f(peer));
+        self.responding.lock().retain(|peer, _| f(peer));
+    }
+}
+
+#[test]
+fn test() {
+    use crate::common::cipher::{Cipher, CipherContext};
+
+    let a = SessionTable::new();
+    let b = SessionTable::new();
+    let a_addr = VirtualAddr::new(10, 0, 0, 1);
+    let b_addr = VirtualAddr::new(10, 0, 0, 2);
+
+    let (init_id, init_key) = a.initiate(b_addr).unwrap();
+    assert!(a.initiate(b_addr).is_none());
+
+    let (resp_id, resp_key) = b.respond(a_addr, init_id, &init_key).unwrap();
+    assert!(b.respond(a_addr, init_id, &init_key).is_none());
+
+    let a_session = a.complete(b_addr, resp_id, &resp_key).unwrap();
+    assert!(a.get_outbound(&b_addr).is_some());
+
+    let nonce = a_session.nonce([0u8; 12]);
+    let b_session = b.get_inbound(SessionId::from_be_bytes(nonce[..4].try_into().unwrap())).unwrap();
+
+    // the responder sends with the session after receiving a packet under it
+    assert!(b.get_outbound(&a_addr).is_none());
+    b.confirm(&b_session);
+    assert_eq!(b.get_outbound(&a_addr).unwrap().local_id, resp_id);
+
+    let ctx = CipherContext {
+        nonce,
+        ..CipherContext::default()
+    };
+
+    let mut text = *b"abcdef";
+    let tag = a_session.cipher.encrypt(&mut text, &ctx);
+    b_session.cipher.decrypt(&mut text, &ctx, &tag).unwrap();
+    assert_eq!(&text, b"abcdef");
+}
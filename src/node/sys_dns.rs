@@ -0,0 +1,204 @@
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+
+use ahash::{HashMap, HashMapExt};
+use anyhow::{anyhow, Context, Result};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Default, PartialEq)]
+struct DnsSettings {
+    servers: Vec<IpAddr>,
+    domains: Vec<String>,
+}
+
+impl DnsSettings {
+    fn is_empty(&self) -> bool {
+        self.servers.is_empty() && self.domains.is_empty()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn resolvectl(args: &[String]) -> Result<()> {
+    let status = Command::new("resolvectl")
+        .args(args)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("failed to execute resolvectl")?
+        .status;
+
+    if !status.success() {
+        return Err(anyhow!("resolvectl {} failed", args.join(" ")));
+    }
+    Ok(())
+}
+
+// per link settings of systemd-resolved
+#[cfg(target_os = "linux")]
+fn apply(if_index: u32, settings: &DnsSettings) -> Result<()> {
+    let link = if_index.to_string();
+
+    if settings.is_empty() {
+        return resolvectl(&[String::from("revert"), link]);
+    }
+
+    let mut args = vec![String::from("dns"), link.clone()];
+
+    if settings.servers.is_empty() {
+        args.push(String::new());
+    } else {
+        args.extend(settings.servers.iter().map(|v| v.to_string()));
+    }
+    resolvectl(&args)?;
+
+    let mut args = vec![String::from("domain"), link];
+
+    if settings.domains.is_empty() {
+        args.push(String::new());
+    } else {
+        args.extend(settings.domains.iter().cloned());
+    }
+    resolvectl(&args)
+}
+
+// single quoted powershell string literal, the typographic single quotes are quotes to powershell too
+#[cfg(target_os = "windows")]
+fn quote(s: &str) -> String {
+    let mut v = String::from("'");
+
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+            v.push(c);
+        }
+        v.push(c);
+    }
+
+    v.push('\'');
+    v
+}
+
+// the whole command is a single script, arguments must be quoted
+#[cfg(target_os = "windows")]
+fn powershell(command: &str) -> Result<()> {
+    let status = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", command])
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .context("failed to execute powershell")?
+        .status;
+
+    if !status.success() {
+        return Err(anyhow!("{} failed", command));
+    }
+    Ok(())
+}
+
+// windows has a single connection specific suffix, only the first domain is used
+#[cfg(target_os = "windows")]
+fn apply(if_index: u32, settings: &DnsSettings) -> Result<()> {
+    if settings.servers.is_empty() {
+        powershell(&format!("Set-DnsClientServerAddress -InterfaceIndex {} -ResetServerAddresses", if_index))?;
+    } else {
+        let servers = settings.servers.iter()
+            .map(|v| quote(&v.to_string()))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        powershell(&format!("Set-DnsClientServerAddress -InterfaceIndex {} -ServerAddresses @({})", if_index, servers))?;
+    }
+
+    let suffix = quote(settings.domains.first().map(|v| v.as_str()).unwrap_or(""));
+    powershell(&format!("Set-DnsClient -InterfaceIndex {} -ConnectionSpecificSuffix {}", if_index, suffix))
+}
+
+// dns servers and search domains of the tun interface, merged from the settings pushed to every group
+pub struct SystemDnsHandle {
+    if_index: u32,
+    // interface index -> settings, applied settings
+    state: Mutex<(HashMap<usize, DnsSettings>, DnsSettings)>,
+}
+
+impl SystemDnsHandle {
+    pub fn new(if_index: u32) -> Self {
+        SystemDnsHandle {
+            if_index,
+            state: Mutex::new((HashMap::new(), DnsSettings::default())),
+        }
+    }
+
+    // the commands run on the blocking pool, the lock keeps the updates in order
+    async fn update(&self, f: impl FnOnce(&mut HashMap<usize, DnsSettings>)) {
+        let mut guard = self.state.lock().await;
+        let (groups, applied) = &mut *guard;
+        f(groups);
+
+        let mut indexes = groups.keys().copied().collect::<Vec<_>>();
+        indexes.sort_unstable();
+
+        let mut merged = DnsSettings::default();
+
+        for index in indexes {
+            let settings = &groups[&index];
+
+            for server in &settings.servers {
+                if !merged.servers.contains(server) {
+                    merged.servers.push(*server);
+                }
+            }
+
+            for domain in &settings.domains {
+                if !merged.domains.contains(domain) {
+                    merged.domains.push(domain.clone());
+                }
+            }
+        }
+
+        if merged == *applied {
+            return;
+        }
+
+        let if_index = self.if_index;
+        let settings = merged.clone();
+
+        let res = tokio::task::spawn_blocking(move || apply(if_index, &settings))
+            .await
+            .unwrap_or_else(|e| Err(anyhow!(e)));
+
+        match res {
+            Ok(_) => {
+                info!("set dns servers {:?}, search domains {:?}", merged.servers, merged.domains);
+                *applied = merged;
+            }
+            Err(e) => warn!("failed to set dns: {}", e)
+        }
+    }
+
+    pub async fn set(&self, interface_index: usize, servers: &[IpAddr], domains: &[String]) {
+        let settings = DnsSettings {
+            servers: servers.to_vec(),
+            domains: domains.to_vec(),
+        };
+
+        self.update(|groups| {
+            groups.insert(interface_index, settings);
+        }).await;
+    }
+
+    pub async fn remove(&self, interface_index: usize) {
+        self.update(|groups| {
+            groups.remove(&interface_index);
+        }).await;
+    }
+}
+
+impl Drop for SystemDnsHandle {
+    fn drop(&mut self) {
+        if !self.state.get_mut().1.is_empty() {
+            info!("clear dns settings");
+
+            if let Err(e) = apply(self.if_index, &DnsSettings::default()) {
+                warn!("failed to clear dns: {}", e);
+            }
+        }
+    }
+}
@@ -6,6 +6,13 @@ use net_route::Handle;
 
 pub use net_route::Route;
 
+fn is_same(a: &Route, b: &Route) -> bool {
+    a.destination == b.destination &&
+        a.prefix == b.prefix &&
+        a.gateway == b.gateway &&
+        a.ifindex == b.ifindex
+}
+
 pub struct SystemRouteHandle {
     handle: Handle,
     routes: Vec<Route>,
@@ -42,17 +49,19 @@ impl SystemRouteHandle {
                 use tokio::process::Command;
 
                 let gateway = x.gateway.ok_or_else(|| anyhow!("must have gateway"))?;
+                let destination = x.destination.to_string();
+                let gateway = gateway.to_string();
+                let prefix = x.prefix.to_string();
+                let mask = x.mask().to_string();
+
+                let args = if x.destination.is_ipv6() {
+                    vec!["-n", "add", "-inet6", "-net", destination.as_str(), "-prefixlen", prefix.as_str(), gateway.as_str()]
+                } else {
+                    vec!["-n", "add", "-net", destination.as_str(), "-netmask", mask.as_str(), gateway.as_str()]
+                };
 
                 let status = Command::new("route")
-                    .args([
-                        "-n",
-                        "add",
-                        "-net",
-                        x.destination.to_string().as_str(),
-                        "-netmask",
-                        x.mask().to_string().as_str(),
-                        gateway.to_string().as_str(),
-                    ])
+                    .args(args)
                     .stderr(Stdio::inherit())
                     .output()
                     .await?
@@ -71,16 +80,24 @@ impl SystemRouteHandle {
         Ok(())
     }
 
+    // delete the routes added by this handle
+    pub async fn delete(&mut self, routes: &[Route]) -> Result<()> {
+        for x in routes {
+            if let Some(i) = self.routes.iter().position(|a| is_same(a, x)) {
+                self.handle.delete(x).await?;
+                self.routes.remove(i);
+                debug!("delete route: {:?}", x);
+            }
+        }
+        Ok(())
+    }
+
     pub async fn clear(&mut self) -> Result<()> {
         let list = self.handle.list().await?;
 
         for a in &self.routes {
             for b in &list {
-                if a.destination == b.destination &&
-                    a.prefix == b.prefix &&
-                    a.gateway == b.gateway &&
-                    a.ifindex == b.ifindex
-                {
+                if is_same(a, b) {
                     self.handle.delete(a).await?;
                     debug!("delete route: {:?}", a);
                 }
@@ -1,6 +1,6 @@
 use std::borrow::Cow;
-use std::net::Ipv4Addr;
-use ipnet::Ipv4Net;
+use std::net::IpAddr;
+use ipnet::IpNet;
 use crate::routing_table::{Item, RoutingTable};
 
 #[derive(Clone, Default)]
@@ -14,7 +14,7 @@ impl RoutingTable for ArrayRoutingTable {
         self.inner.insert(index, item);
     }
 
-    fn remove(&mut self, cidr: &Ipv4Net) -> Option<Item> {
+    fn remove(&mut self, cidr: &IpNet) -> Option<Item> {
         let index = self.inner
             .iter()
             .position(|v| v.cidr == *cidr);
@@ -22,7 +22,7 @@ impl RoutingTable for ArrayRoutingTable {
         index.map(|index| self.inner.remove(index))
     }
 
-    fn find(&self, _src: Ipv4Addr, to: Ipv4Addr) -> Option<Cow<Item>> {
+    fn find(&self, _src: IpAddr, to: IpAddr) -> Option<Cow<Item>> {
         self.inner
             .iter()
             .find(|v| v.cidr.contains(&to))
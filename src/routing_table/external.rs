@@ -1,12 +1,12 @@
 use std::borrow::Cow;
 use std::ffi::c_void;
 use std::mem::{transmute, MaybeUninit};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::Result;
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use libloading::{Library, Symbol};
 
 use crate::routing_table::{Item, ItemKind, RoutingTable};
@@ -34,6 +34,34 @@ impl From<Ipv4Net> for CidrC {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Ipv6AddrC {
+    octets: [u8; 16],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CidrV6C {
+    addr: Ipv6AddrC,
+    prefix_len: u8,
+}
+
+impl From<CidrV6C> for Ipv6Net {
+    fn from(value: CidrV6C) -> Self {
+        Ipv6Net::new(Ipv6Addr::from(value.addr.octets), value.prefix_len).unwrap()
+    }
+}
+
+impl From<Ipv6Net> for CidrV6C {
+    fn from(value: Ipv6Net) -> Self {
+        CidrV6C {
+            addr: Ipv6AddrC { octets: value.addr().octets() },
+            prefix_len: value.prefix_len(),
+        }
+    }
+}
+
 #[repr(C)]
 struct OptionC<T> {
     is_some: bool,
@@ -108,28 +136,71 @@ struct ItemC {
 impl From<ItemC> for Item {
     fn from(value: ItemC) -> Self {
         Item {
-            cidr: Ipv4Net::from(value.cidr),
-            gateway: Ipv4Addr::from(value.gateway),
+            cidr: IpNet::V4(Ipv4Net::from(value.cidr)),
+            gateway: IpAddr::V4(Ipv4Addr::from(value.gateway)),
             interface_index: value.interface_index,
             extend: super::Extend::from(value.extend),
         }
     }
 }
 
-impl From<Item> for ItemC {
-    fn from(value: Item) -> Self {
-        ItemC {
-            cidr: CidrC::from(value.cidr),
-            gateway: u32::from(value.gateway),
+#[repr(C)]
+#[derive(Clone)]
+struct ItemV6C {
+    cidr: CidrV6C,
+    gateway: Ipv6AddrC,
+    interface_index: usize,
+    extend: ExtendC,
+}
+
+impl From<ItemV6C> for Item {
+    fn from(value: ItemV6C) -> Self {
+        Item {
+            cidr: IpNet::V6(Ipv6Net::from(value.cidr)),
+            gateway: IpAddr::V6(Ipv6Addr::from(value.gateway.octets)),
             interface_index: value.interface_index,
-            extend: ExtendC::from(value.extend),
+            extend: super::Extend::from(value.extend),
         }
     }
 }
 
+enum ItemEnumC {
+    V4(ItemC),
+    V6(ItemV6C),
+}
+
+impl TryFrom<Item> for ItemEnumC {
+    type Error = Item;
+
+    fn try_from(value: Item) -> Result<Self, Item> {
+        let extend = ExtendC::from(value.extend.clone());
+
+        let item = match (value.cidr, value.gateway) {
+            (IpNet::V4(cidr), IpAddr::V4(gateway)) => ItemEnumC::V4(ItemC {
+                cidr: CidrC::from(cidr),
+                gateway: u32::from(gateway),
+                interface_index: value.interface_index,
+                extend,
+            }),
+            (IpNet::V6(cidr), IpAddr::V6(gateway)) => ItemEnumC::V6(ItemV6C {
+                cidr: CidrV6C::from(cidr),
+                gateway: Ipv6AddrC { octets: gateway.octets() },
+                interface_index: value.interface_index,
+                extend,
+            }),
+            _ => return Err(value)
+        };
+        Ok(item)
+    }
+}
+
 type AddFn = extern "C" fn(handle: *mut c_void, item_c: ItemC);
 type RemoveFn = extern "C" fn(handle: *mut c_void, cidr: *const CidrC) -> OptionC<ItemC>;
 type FindFn = extern "C" fn(handle: *mut c_void, src: u32, to: u32) -> OptionC<ItemC>;
+// optional, the ipv6 routes are dropped if the library doesn't export them
+type AddV6Fn = extern "C" fn(handle: *mut c_void, item_c: ItemV6C);
+type RemoveV6Fn = extern "C" fn(handle: *mut c_void, cidr: *const CidrV6C) -> OptionC<ItemV6C>;
+type FindV6Fn = extern "C" fn(handle: *mut c_void, src: Ipv6AddrC, to: Ipv6AddrC) -> OptionC<ItemV6C>;
 type CreateFn = extern "C" fn(ctx: ExternalContext) -> *mut c_void;
 type DropFn = extern "C" fn(*mut c_void);
 
@@ -141,6 +212,7 @@ pub struct ExternalRoutingTable<K> {
     add_fn: Symbol<'static, AddFn>,
     remove_fn: Symbol<'static, RemoveFn>,
     find_fn: Symbol<'static, FindFn>,
+    v6_fns: Option<(Symbol<'static, AddV6Fn>, Symbol<'static, RemoveV6Fn>, Symbol<'static, FindV6Fn>)>,
     drop_fn: Symbol<'static, DropFn>,
 }
 
@@ -150,17 +222,40 @@ unsafe impl<K: Sync> Sync for ExternalRoutingTable<K> {}
 
 impl<K> RoutingTable for ExternalRoutingTable<K> {
     fn add(&mut self, item: Item) {
-        (self.add_fn)(self.handle, ItemC::from(item))
+        match (ItemEnumC::try_from(item), &self.v6_fns) {
+            (Ok(ItemEnumC::V4(item)), _) => (self.add_fn)(self.handle, item),
+            (Ok(ItemEnumC::V6(item)), Some((add_fn, _, _))) => add_fn(self.handle, item),
+            (Ok(ItemEnumC::V6(_)), None) => (),
+            (Err(item), _) => warn!("route {} has gateway {} of another address family", item.cidr, item.gateway)
+        }
     }
 
-    fn remove(&mut self, cidr: &Ipv4Net) -> Option<Item> {
-        let optc = (self.remove_fn)(self.handle, &CidrC::from(*cidr));
-        Option::<ItemC>::from(optc).map(Item::from)
+    fn remove(&mut self, cidr: &IpNet) -> Option<Item> {
+        match (cidr, &self.v6_fns) {
+            (IpNet::V4(cidr), _) => {
+                let optc = (self.remove_fn)(self.handle, &CidrC::from(*cidr));
+                Option::<ItemC>::from(optc).map(Item::from)
+            }
+            (IpNet::V6(cidr), Some((_, remove_fn, _))) => {
+                let optc = remove_fn(self.handle, &CidrV6C::from(*cidr));
+                Option::<ItemV6C>::from(optc).map(Item::from)
+            }
+            (IpNet::V6(_), None) => None
+        }
     }
 
-    fn find(&self, src: Ipv4Addr, to: Ipv4Addr) -> Option<Cow<Item>> {
-        let optc = (self.find_fn)(self.handle, u32::from(src), u32::from(to));
-        Option::<ItemC>::from(optc).map(|i| Cow::Owned(Item::from(i)))
+    fn find(&self, src: IpAddr, to: IpAddr) -> Option<Cow<Item>> {
+        match (src, to, &self.v6_fns) {
+            (IpAddr::V4(src), IpAddr::V4(to), _) => {
+                let optc = (self.find_fn)(self.handle, u32::from(src), u32::from(to));
+                Option::<ItemC>::from(optc).map(|i| Cow::Owned(Item::from(i)))
+            }
+            (IpAddr::V6(src), IpAddr::V6(to), Some((_, _, find_fn))) => {
+                let optc = find_fn(self.handle, Ipv6AddrC { octets: src.octets() }, Ipv6AddrC { octets: to.octets() });
+                Option::<ItemV6C>::from(optc).map(|i| Cow::Owned(Item::from(i)))
+            }
+            _ => None
+        }
     }
 }
 
@@ -189,6 +284,14 @@ pub fn create<K>(
         let find_fn = transmute(lib.get::<FindFn>(b"find_route")?);
         let drop_fn = transmute(lib.get::<DropFn>(b"drop_routing_table")?);
 
+        let v6_fns = match (lib.get::<AddV6Fn>(b"add_route_v6"), lib.get::<RemoveV6Fn>(b"remove_route_v6"), lib.get::<FindV6Fn>(b"find_route_v6")) {
+            (Ok(add_fn), Ok(remove_fn), Ok(find_fn)) => Some((transmute(add_fn), transmute(remove_fn), transmute(find_fn))),
+            _ => {
+                warn!("external routing table has no ipv6 functions, ipv6 packets are not routed");
+                None
+            }
+        };
+
         let handle = create_fn(extern_ctx);
 
         let rt = ExternalRoutingTable {
@@ -198,6 +301,7 @@ pub fn create<K>(
             add_fn,
             remove_fn,
             find_fn,
+            v6_fns,
             drop_fn,
         };
         Ok(rt)
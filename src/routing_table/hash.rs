@@ -1,34 +1,51 @@
 use std::borrow::Cow;
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 
 use ahash::HashMap;
-use ipnet::Ipv4Net;
+use ipnet::IpNet;
 
 use crate::routing_table::{Item, RoutingTable};
 
 #[derive(Clone, Default)]
 pub struct HashRoutingTable {
-    cidrs: HashMap<Ipv4Net, Item>,
+    cidrs: HashMap<IpNet, Item>,
+    // (is ipv6, prefix length) -> number of cidrs, only these lengths are looked up
+    prefix_lens: BTreeMap<(bool, u8), usize>,
 }
 
 impl RoutingTable for HashRoutingTable {
     fn add(&mut self, item: Item) {
-        self.cidrs.insert(item.cidr, item);
+        let key = (item.cidr.addr().is_ipv6(), item.cidr.prefix_len());
+
+        if self.cidrs.insert(item.cidr, item).is_none() {
+            *self.prefix_lens.entry(key).or_default() += 1;
+        }
     }
 
-    fn remove(&mut self, cidr: &Ipv4Net) -> Option<Item> {
-        self.cidrs.remove(cidr)
+    fn remove(&mut self, cidr: &IpNet) -> Option<Item> {
+        let item = self.cidrs.remove(cidr)?;
+        let key = (cidr.addr().is_ipv6(), cidr.prefix_len());
+
+        if let Some(count) = self.prefix_lens.get_mut(&key) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.prefix_lens.remove(&key);
+            }
+        }
+        Some(item)
     }
 
-    fn find(&self, _src: Ipv4Addr, to: Ipv4Addr) -> Option<Cow<Item>> {
-        let cidrs = &self.cidrs;
+    fn find(&self, _src: IpAddr, to: IpAddr) -> Option<Cow<Item>> {
+        let is_ipv6 = to.is_ipv6();
+        let lens = self.prefix_lens.range((is_ipv6, 0)..=(is_ipv6, u8::MAX));
 
-        for len in (0..=32).rev() {
-            let cidr = Ipv4Net::new(to, len).unwrap().trunc();
-            let item = cidrs.get(&cidr).map(|v| Cow::Borrowed(v));
+        for (&(_, len), _) in lens.rev() {
+            let cidr = IpNet::new(to, len).unwrap().trunc();
 
-            if item.is_some() {
-                return item;
+            if let Some(item) = self.cidrs.get(&cidr) {
+                return Some(Cow::Borrowed(item));
             }
         }
         None
@@ -1,6 +1,6 @@
 use std::borrow::Cow;
-use std::net::Ipv4Addr;
-use ipnet::Ipv4Net;
+use std::net::IpAddr;
+use ipnet::IpNet;
 
 #[cfg_attr(not(feature = "hash-routing-table"), path = "array.rs")]
 #[cfg_attr(feature = "hash-routing-table", path = "hash.rs")]
@@ -20,10 +20,11 @@ pub struct Extend {
     pub item_kind: Option<ItemKind>
 }
 
+// the gateway has the address family of the cidr, unspecified means on link
 #[derive(Clone)]
 pub struct Item {
-    pub cidr: Ipv4Net,
-    pub gateway: Ipv4Addr,
+    pub cidr: IpNet,
+    pub gateway: IpAddr,
     pub interface_index: usize,
     pub extend: Extend
 }
@@ -31,9 +32,9 @@ pub struct Item {
 pub trait RoutingTable {
     fn add(&mut self, item: Item);
 
-    fn remove(&mut self, cidr: &Ipv4Net) -> Option<Item>;
+    fn remove(&mut self, cidr: &IpNet) -> Option<Item>;
 
-    fn find(&self, src: Ipv4Addr, to: Ipv4Addr) -> Option<Cow<Item>>;
+    fn find(&self, src: IpAddr, to: IpAddr) -> Option<Cow<Item>>;
 }
 
 #[test]
@@ -43,15 +44,32 @@ fn test() {
     let mut router = internal::create();
 
     router.add(Item {
-        cidr: Ipv4Net::from_str("0.0.0.0/0").unwrap(),
-        gateway: Ipv4Addr::from_str("10.0.199.2").unwrap(),
+        cidr: IpNet::from_str("0.0.0.0/0").unwrap(),
+        gateway: IpAddr::from_str("10.0.199.2").unwrap(),
         interface_index: 0,
         extend: Extend {
             item_kind: Some(ItemKind::IpsRoute)
         }
     });
 
-    let src = Ipv4Addr::UNSPECIFIED;
-    let dst = Ipv4Addr::from_str("1.1.1.1").unwrap();
+    router.add(Item {
+        cidr: IpNet::from_str("fd00::/64").unwrap(),
+        gateway: IpAddr::from_str("::").unwrap(),
+        interface_index: 0,
+        extend: Extend {
+            item_kind: Some(ItemKind::VirtualRange)
+        }
+    });
+
+    let src = IpAddr::from_str("0.0.0.0").unwrap();
+    let dst = IpAddr::from_str("1.1.1.1").unwrap();
     assert!(router.find(src, dst).is_some());
-}
\ No newline at end of file
+
+    // the ipv4 default route doesn't cover ipv6
+    let src = IpAddr::from_str("::").unwrap();
+    assert!(router.find(src, IpAddr::from_str("fd00::2").unwrap()).is_some_and(|i| i.extend.item_kind == Some(ItemKind::VirtualRange)));
+    assert!(router.find(src, IpAddr::from_str("fd01::2").unwrap()).is_none());
+
+    assert!(router.remove(&IpNet::from_str("fd00::/64").unwrap()).is_some());
+    assert!(router.find(src, IpAddr::from_str("fd00::2").unwrap()).is_none());
+}
@@ -1,22 +1,35 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use http_body_util::Full;
-use hyper::{http, Request, Response};
-use hyper::body::{Bytes, Incoming};
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use tokio::net::TcpListener;
+use hyper::{header, http, Method, Request, Response};
+use hyper::body::Bytes;
+use serde::Serialize;
+use tokio_rustls::rustls;
 
-use crate::server::{GroupHandle, GroupInfo};
+use crate::common::api::{self, query_param, ApiBody};
+use crate::common::event::EventBus;
+use crate::common::metrics::{MetricsWriter, METRICS_CONTENT_TYPE};
+use crate::common::net::{HeartbeatCache, UdpStatus};
+use crate::common::net::protocol::VirtualAddr;
+use crate::server::{Ban, BanTarget, GroupHandle, GroupInfo, NodeHandle};
+
+// reads the config file and queues it for reloading
+pub(super) type Reload = Arc<dyn Fn() -> Result<()> + Send + Sync>;
 
 struct Context {
-    group_handles: Vec<Arc<GroupHandle>>,
+    group_handles: Arc<ArcSwap<Vec<Arc<GroupHandle>>>>,
+    token: Option<String>,
+    reload: Reload,
+    events: Arc<EventBus>,
 }
 
 fn info(
-    _req: Request<Incoming>,
+    _req: Request<Bytes>,
     group_handles: &[Arc<GroupHandle>],
 ) -> Result<Response<Full<Bytes>>, http::Error> {
     let mut list = Vec::with_capacity(group_handles.len());
@@ -38,47 +51,382 @@ fn info(
     Ok(resp)
 }
 
-fn router(ctx: &Context, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, http::Error> {
+// POST /revoke?group={group name}&node={node name}
+fn revoke(
+    req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let gh = match find_group(&req, group_handles) {
+        Ok(gh) => gh,
+        Err(resp) => return Ok(resp)
+    };
+
+    let node_name = match query_param(&req, "node") {
+        Some(node) => node,
+        None => return Ok(bad_request("missing node parameter"))
+    };
+
+    match gh.revoke_identity(node_name) {
+        Ok(()) => {
+            warn!("group {} node {} identity has been revoked", gh.name, node_name);
+            Ok(Response::new(Full::new(Bytes::new())))
+        }
+        Err(e) => Ok(bad_request(e))
+    }
+}
+
+fn find_group<'a>(
+    req: &Request<Bytes>,
+    group_handles: &'a [Arc<GroupHandle>],
+) -> Result<&'a Arc<GroupHandle>, Response<Full<Bytes>>> {
+    let group_name = query_param(req, "group").ok_or_else(|| bad_request("missing group parameter"))?;
+
+    group_handles.iter()
+        .find(|gh| gh.name == group_name)
+        .ok_or_else(|| {
+            let mut resp = Response::new(Full::new(Bytes::from(format!("group {} not found", group_name))));
+            *resp.status_mut() = http::StatusCode::NOT_FOUND;
+            resp
+        })
+}
+
+fn bad_request<T: ToString>(msg: T) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(msg.to_string())));
+    *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+    resp
+}
+
+fn ban_target(req: &Request<Bytes>) -> Result<BanTarget, Response<Full<Bytes>>> {
+    match (query_param(req, "node"), query_param(req, "ip")) {
+        (Some(node), None) => Ok(BanTarget::Node(node.to_string())),
+        (None, Some(ip)) => {
+            let ip: IpAddr = ip.parse().map_err(bad_request)?;
+            Ok(BanTarget::Ip(ip))
+        }
+        _ => Err(bad_request("either node or ip parameter is required")),
+    }
+}
+
+// POST /kick?group={group name}&addr={virtual address}
+fn kick(
+    req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let gh = match find_group(&req, group_handles) {
+        Ok(gh) => gh,
+        Err(resp) => return Ok(resp)
+    };
+
+    let addr: VirtualAddr = match query_param(&req, "addr").map(|v| v.parse()) {
+        Some(Ok(addr)) => addr,
+        Some(Err(e)) => return Ok(bad_request(e)),
+        None => return Ok(bad_request("missing addr parameter"))
+    };
+
+    match gh.kick(&addr) {
+        Ok(()) => {
+            warn!("group {} node {} has been kicked", gh.name, addr);
+            Ok(Response::new(Full::new(Bytes::new())))
+        }
+        Err(e) => {
+            Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::from(e.to_string())))
+        }
+    }
+}
+
+// POST /ban?group={group name}&node={node name}|ip={source ip}[&secs={duration}]
+fn ban(
+    req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let res = find_group(&req, group_handles)
+        .and_then(|gh| Ok((gh, ban_target(&req)?)));
+
+    let (gh, target) = match res {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp)
+    };
+
+    let duration = match query_param(&req, "secs").map(|v| v.parse::<u64>()) {
+        None => None,
+        Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+        Some(Err(e)) => return Ok(bad_request(e))
+    };
+
+    warn!("group {} {} has been banned", gh.name, target);
+    gh.ban(target, duration);
+    Ok(Response::new(Full::new(Bytes::new())))
+}
+
+// POST /unban?group={group name}&node={node name}|ip={source ip}
+fn unban(
+    req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let res = find_group(&req, group_handles)
+        .and_then(|gh| Ok((gh, ban_target(&req)?)));
+
+    let (gh, target) = match res {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp)
+    };
+
+    match gh.unban(&target) {
+        Ok(()) => Ok(Response::new(Full::new(Bytes::new()))),
+        Err(e) => {
+            Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::from(e.to_string())))
+        }
+    }
+}
+
+// POST /quota/reset?group={group name}&node={node name}, resets every node of the group without node
+fn reset_quota(
+    req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let gh = match find_group(&req, group_handles) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp)
+    };
+
+    let node_name = query_param(&req, "node");
+    let count = gh.quotas.reset(&gh.name, node_name);
+
+    if let Some(node_name) = node_name {
+        if count == 0 {
+            return Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::from(format!("node {} has no relay quota", node_name))));
+        }
+    }
+
+    let quotas = gh.quotas.clone();
+
+    tokio::spawn(async move {
+        if let Err(e) = quotas.save().await {
+            warn!("save quota usage error: {:?}", e);
+        }
+    });
+
+    info!("group {} reset relay quota of {} node(s)", gh.name, count);
+    Ok(Response::new(Full::new(Bytes::new())))
+}
+
+// POST /reload, the config is applied in the background after it is loaded
+fn reload(reload: &Reload) -> Result<Response<Full<Bytes>>, http::Error> {
+    match reload() {
+        Ok(()) => {
+            info!("api: reloading config");
+
+            Response::builder()
+                .status(202)
+                .body(Full::new(Bytes::new()))
+        }
+        Err(e) => {
+            Response::builder()
+                .status(400)
+                .body(Full::new(Bytes::from(format!("{:#}", e))))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GroupBans<'a> {
+    group: &'a str,
+    bans: Vec<Ban>,
+}
+
+// GET /bans
+fn bans(
+    _req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let list: Vec<GroupBans> = group_handles.iter()
+        .map(|gh| GroupBans { group: &gh.name, bans: gh.bans() })
+        .collect();
+
+    let resp = match serde_json::to_vec(&list) {
+        Ok(v) => Response::new(Full::new(Bytes::from(v))),
+        Err(e) => {
+            error!("api server error: {}", e);
+
+            Response::builder()
+                .status(500)
+                .body(Full::new(Bytes::from(e.to_string())))?
+        }
+    };
+    Ok(resp)
+}
+
+// GET /metrics, prometheus text format
+fn metrics(
+    _req: Request<Bytes>,
+    group_handles: &[Arc<GroupHandle>],
+) -> Result<Response<Full<Bytes>>, http::Error> {
+    let mut w = MetricsWriter::new();
+
+    w.header("fubuki_server_group_nodes", "gauge", "Number of nodes registered to the group");
+    for gh in group_handles {
+        w.sample("fubuki_server_group_nodes", &[("group", &gh.name)], gh.mapping.read().len());
+    }
+
+    let nodes: Vec<(&str, Arc<NodeHandle>)> = group_handles.iter()
+        .flat_map(|gh| {
+            gh.mapping.read()
+                .values()
+                .map(|v| (gh.name.as_str(), v.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let heartbeat_metrics: [(&str, &str, &str, fn(&HeartbeatCache) -> Option<f64>); 3] = [
+        ("fubuki_server_node_latency_seconds", "gauge", "Last heartbeat round trip time of the node", |hc| hc.last_elapsed.map(|v| v.as_secs_f64())),
+        ("fubuki_server_node_heartbeat_sent_total", "counter", "Heartbeats sent to the node", |hc| Some(hc.send_count as f64)),
+        ("fubuki_server_node_heartbeat_lost_total", "counter", "Heartbeats to the node without reply", |hc| Some(hc.packet_loss_count as f64)),
+    ];
+
+    for (name, metric_type, help, f) in heartbeat_metrics {
+        w.header(name, metric_type, help);
+
+        for (group, nh) in &nodes {
+            let node = nh.node.load();
+
+            for (protocol, hc) in [("udp", &nh.udp_heartbeat_cache), ("tcp", &nh.tcp_heartbeat_cache)] {
+                if let Some(v) = f(&hc.read()) {
+                    w.sample(name, &[("group", group), ("node", &node.name), ("protocol", protocol)], v);
+                }
+            }
+        }
+    }
+
+    w.header("fubuki_server_node_udp_available", "gauge", "Whether the udp path to the node is available");
+    for (group, nh) in &nodes {
+        let available = matches!(nh.udp_status.load(), UdpStatus::Available { .. });
+        w.sample("fubuki_server_node_udp_available", &[("group", group), ("node", &nh.node.load().name)], available as u8);
+    }
+
+    w.header("fubuki_server_node_udp_status_transitions_total", "counter", "Udp path availability changes of the node");
+    for (group, nh) in &nodes {
+        w.sample("fubuki_server_node_udp_status_transitions_total", &[("group", group), ("node", &nh.node.load().name)], nh.udp_status.transitions());
+    }
+
+    w.header("fubuki_server_relay_packets_total", "counter", "Packets relayed by the server");
+    for gh in group_handles {
+        w.sample("fubuki_server_relay_packets_total", &[("group", &gh.name), ("protocol", "udp")], gh.metrics.udp_relay_packets.load(Ordering::Relaxed));
+        w.sample("fubuki_server_relay_packets_total", &[("group", &gh.name), ("protocol", "tcp")], gh.metrics.tcp_relay_packets.load(Ordering::Relaxed));
+        w.sample("fubuki_server_relay_packets_total", &[("group", &gh.name), ("protocol", "quic")], gh.metrics.quic_relay_packets.load(Ordering::Relaxed));
+    }
+
+    w.header("fubuki_server_relay_bytes_total", "counter", "Bytes relayed by the server");
+    for gh in group_handles {
+        w.sample("fubuki_server_relay_bytes_total", &[("group", &gh.name), ("protocol", "udp")], gh.metrics.udp_relay_bytes.load(Ordering::Relaxed));
+        w.sample("fubuki_server_relay_bytes_total", &[("group", &gh.name), ("protocol", "tcp")], gh.metrics.tcp_relay_bytes.load(Ordering::Relaxed));
+        w.sample("fubuki_server_relay_bytes_total", &[("group", &gh.name), ("protocol", "quic")], gh.metrics.quic_relay_bytes.load(Ordering::Relaxed));
+    }
+
+    w.header("fubuki_server_flow_control_rejections_total", "counter", "Relay packets dropped by flow control or relay quota");
+    for gh in group_handles {
+        w.sample("fubuki_server_flow_control_rejections_total", &[("group", &gh.name)], gh.metrics.flow_control_rejections.load(Ordering::Relaxed));
+    }
+
+    w.header("fubuki_server_acl_rejections_total", "counter", "Relay packets dropped by the acl");
+    for gh in group_handles {
+        w.sample("fubuki_server_acl_rejections_total", &[("group", &gh.name)], gh.metrics.acl_rejections.load(Ordering::Relaxed));
+    }
+
+    w.header("fubuki_server_register_failures_total", "counter", "Rejected node registrations");
+    for gh in group_handles {
+        for (reason, count) in gh.metrics.register_failures.lock().iter() {
+            w.sample("fubuki_server_register_failures_total", &[("group", &gh.name), ("reason", reason)], count);
+        }
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)
+        .body(Full::new(Bytes::from(w.finish())))
+}
+
+fn subscribe(ctx: &Context, req: &Request<Bytes>) -> Result<Response<ApiBody>, http::Error> {
+    if !api::authorized(req, ctx.token.as_deref()) {
+        return Ok(api::full(api::unauthorized()));
+    }
+    api::event_stream(&ctx.events)
+}
+
+type Handler = fn(Request<Bytes>, &[Arc<GroupHandle>]) -> Result<Response<Full<Bytes>>, http::Error>;
+
+// endpoints changing the server state, POST only
+const WRITE_PATHS: &[&str] = &["/revoke", "/kick", "/ban", "/unban", "/quota/reset", "/reload"];
+
+fn router(ctx: &Context, req: Request<Bytes>) -> Result<Response<Full<Bytes>>, http::Error> {
     let path = req.uri().path();
 
-    match path {
-        "/info" => info(req, ctx.group_handles.as_slice()),
-        "/type" => Ok(Response::new(Full::new(Bytes::from("server")))),
+    if WRITE_PATHS.contains(&path) {
+        // without a token anyone reaching the api could change the server state
+        if ctx.token.is_none() {
+            return Response::builder()
+                .status(403)
+                .body(Full::new(Bytes::from("api_token is not configured")));
+        }
+
+        if req.method() != Method::POST {
+            return Response::builder()
+                .status(405)
+                .body(Full::new(Bytes::new()));
+        }
+    }
+
+    let handler: Handler = match path {
+        "/info" => info,
+        "/revoke" => revoke,
+        "/kick" => kick,
+        "/ban" => ban,
+        "/unban" => unban,
+        "/bans" => bans,
+        "/quota/reset" => reset_quota,
+        "/metrics" => metrics,
+        "/reload" => {
+            if !api::authorized(&req, ctx.token.as_deref()) {
+                return Ok(api::unauthorized());
+            }
+            return reload(&ctx.reload);
+        }
+        "/type" => return Ok(Response::new(Full::new(Bytes::from("server")))),
         #[cfg(feature = "web")]
-        path => crate::web::static_files(path.trim_start_matches('/')),
+        path => return crate::web::static_files(path.trim_start_matches('/')),
         #[cfg(not(feature = "web"))]
-        _ => Response::builder()
-            .status(404)
-            .body(Full::new(Bytes::new())),
+        _ => {
+            return Response::builder()
+                .status(404)
+                .body(Full::new(Bytes::new()));
+        }
+    };
+
+    if !api::authorized(&req, ctx.token.as_deref()) {
+        return Ok(api::unauthorized());
     }
+    handler(req, &ctx.group_handles.load())
 }
 
-pub(super) async fn api_start(bind: SocketAddr, ghs: Vec<Arc<GroupHandle>>) -> Result<()> {
-    let ctx = Context { group_handles: ghs };
+pub(super) async fn api_start(
+    bind: SocketAddr,
+    token: Option<String>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    ghs: Arc<ArcSwap<Vec<Arc<GroupHandle>>>>,
+    reload: Reload,
+    events: Arc<EventBus>
+) -> Result<()> {
+    let ctx = Context { group_handles: ghs, token, reload, events };
     let ctx = Arc::new(ctx);
 
-    let listener = TcpListener::bind(bind).await?;
-    info!("api listening on http://{}", bind);
-
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let stream = hyper_util::rt::TokioIo::new(stream);
-        let ctx = ctx.clone();
-
-        tokio::spawn(async move {
-            let ctx = &ctx;
-            let res = http1::Builder::new()
-                .serve_connection(
-                    stream,
-                    service_fn(move |req| {
-                         std::future::ready(router(ctx, req))
-                    }),
-                )
-                .await;
-
-            if let Err(e) = res {
-                warn!("error serving connection: {:?}", e);
-            }
-        });
-    }
+    api::serve(bind, tls, move |req| {
+        match req.uri().path() {
+            "/events" => subscribe(&ctx, &req),
+            _ => router(&ctx, req).map(api::full)
+        }
+    }).await
 }
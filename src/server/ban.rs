@@ -0,0 +1,148 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use ahash::HashMap;
+use anyhow::Result;
+use chrono::Utc;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::server::{Ban, BanTarget};
+use crate::server::store_file::StoreFile;
+
+// group name -> bans
+type Bans = HashMap<String, Vec<Ban>>;
+
+fn is_expired(ban: &Ban, now: i64) -> bool {
+    ban.expire.is_some_and(|t| t <= now)
+}
+
+// bans of all groups, they survive the group restarts and, with a ban file, the server restarts
+pub struct BanStore {
+    file: StoreFile,
+    bans: Mutex<Bans>,
+    // wakes the save task
+    changed: Notify,
+}
+
+impl BanStore {
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let file = StoreFile::new(path, "ban");
+        let mut bans: Bans = file.load()?;
+
+        let now = Utc::now().timestamp();
+
+        for list in bans.values_mut() {
+            list.retain(|ban| !is_expired(ban, now));
+        }
+
+        let store = BanStore {
+            file,
+            bans: Mutex::new(bans),
+            changed: Notify::new(),
+        };
+        Ok(store)
+    }
+
+    // replaces the previous ban of the target
+    pub fn insert(&self, group: &str, target: BanTarget, expire: Option<i64>) {
+        {
+            let mut guard = self.bans.lock();
+            let list = guard.entry(group.to_string()).or_default();
+            list.retain(|ban| ban.target != target);
+            list.push(Ban { target, expire });
+        }
+
+        self.changed.notify_one();
+    }
+
+    pub fn remove(&self, group: &str, target: &BanTarget) -> bool {
+        let removed = {
+            let mut guard = self.bans.lock();
+
+            match guard.get_mut(group) {
+                None => false,
+                Some(list) => {
+                    let len = list.len();
+                    list.retain(|ban| ban.target != *target);
+                    list.len() != len
+                }
+            }
+        };
+
+        if removed {
+            self.changed.notify_one();
+        }
+        removed
+    }
+
+    pub fn is_banned(&self, group: &str, node_name: Option<&str>, ip: IpAddr) -> bool {
+        let now = Utc::now().timestamp();
+
+        self.bans.lock()
+            .get(group)
+            .is_some_and(|list| {
+                list.iter()
+                    .filter(|ban| !is_expired(ban, now))
+                    .any(|ban| {
+                        match &ban.target {
+                            BanTarget::Node(name) => Some(name.as_str()) == node_name,
+                            BanTarget::Ip(addr) => addr.to_canonical() == ip.to_canonical()
+                        }
+                    })
+            })
+    }
+
+    // active bans of the group
+    pub fn list(&self, group: &str) -> Vec<Ban> {
+        let now = Utc::now().timestamp();
+
+        self.bans.lock()
+            .get(group)
+            .map(|list| list.iter().filter(|ban| !is_expired(ban, now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // the expired bans are dropped from the file
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(|| {
+            let now = Utc::now().timestamp();
+            let mut guard = self.bans.lock();
+
+            for list in guard.values_mut() {
+                list.retain(|ban| !is_expired(ban, now));
+            }
+            serde_json::to_vec_pretty(&*guard)
+        }).await
+    }
+
+    // write the bans after each change
+    pub async fn save_schedule(&self) {
+        loop {
+            self.changed.notified().await;
+
+            if let Err(e) = self.save().await {
+                warn!("save bans error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let store = BanStore::load(None).unwrap();
+    let ip: IpAddr = "192.168.1.2".parse().unwrap();
+
+    store.insert("g", BanTarget::Node(String::from("n1")), None);
+    store.insert("g", BanTarget::Ip(ip), Some(Utc::now().timestamp() - 1));
+
+    assert!(store.is_banned("g", Some("n1"), "10.0.0.1".parse().unwrap()));
+    assert!(!store.is_banned("g2", Some("n1"), "10.0.0.1".parse().unwrap()));
+    // expired
+    assert!(!store.is_banned("g", None, ip));
+    assert_eq!(store.list("g").len(), 1);
+
+    assert!(store.remove("g", &BanTarget::Node(String::from("n1"))));
+    assert!(!store.remove("g", &BanTarget::Node(String::from("n1"))));
+    assert!(!store.is_banned("g", Some("n1"), "10.0.0.1".parse().unwrap()));
+}
@@ -0,0 +1,372 @@
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use ahash::{HashMap, HashMapExt};
+use anyhow::{anyhow, Context, Result};
+use parking_lot::RwLock;
+use rand::{Rng, SeedableRng};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+use crate::common::allocator::{self, Bytes};
+use crate::common::cipher::{AuthenticationError, Cipher, CipherContext, CipherEnum, CipherType, Nonce, Tag, NONCE_LEN};
+use crate::common::net::{PushResult, SocketExt};
+use crate::common::net::protocol::{ClusterChallenge, ServerId, TcpMsg, VirtualAddr, TCP_BUFF_SIZE, TCP_MSG_HEADER_LEN, UDP_MSG_HEADER_LEN};
+use crate::{ClusterConfigFinalize, GroupFinalize};
+use crate::server::{GroupHandle, NodeMap};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// the full node map is resent at this interval, it also keeps the link alive
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const LINK_TIMEOUT: Duration = Duration::from_secs(30);
+const RELAY_CHANNEL_LIMIT: usize = 1024;
+
+struct RelayPacket {
+    group_name: String,
+    to: VirtualAddr,
+    packet: Bytes,
+}
+
+pub struct ClusterHandle {
+    pub server_id: ServerId,
+    // server id -> outgoing link, each server only sends on the links it dialed
+    links: RwLock<HashMap<ServerId, mpsc::Sender<RelayPacket>>>,
+}
+
+impl ClusterHandle {
+    pub fn new() -> Self {
+        ClusterHandle {
+            server_id: rand::random(),
+            links: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn relay(&self, server_id: ServerId, group_name: &str, to: VirtualAddr, packet: &[u8]) {
+        let guard = self.links.read();
+
+        let tx = match guard.get(&server_id) {
+            None => return,
+            Some(tx) => tx
+        };
+
+        let mut buff = allocator::alloc(packet.len());
+        buff.copy_from_slice(packet);
+
+        let packet = RelayPacket {
+            group_name: group_name.to_string(),
+            to,
+            packet: buff,
+        };
+
+        if let Err(e) = tx.try_send(packet) {
+            warn!("cluster: relay packet to server {:x} error: {}", server_id, e);
+        }
+    }
+}
+
+// the udp socket of each group relays the packets to the local nodes
+type Groups<K> = Vec<(Arc<GroupFinalize<K>>, Arc<GroupHandle>, Arc<UdpSocket>)>;
+
+// key of one link, the frames recorded on another link don't decrypt.
+// the frames are numbered in the nonce, a replayed or reordered frame is rejected
+struct LinkKey {
+    key: CipherEnum,
+    // the next frame to send on the outbound side, to receive on the inbound side
+    seq: AtomicU64,
+}
+
+impl LinkKey {
+    fn new(secret: &[u8; 32], cipher: CipherType, dialer: &ClusterChallenge, listener: &ClusterChallenge) -> Self {
+        let mut hasher = blake3::Hasher::new_keyed(secret);
+        hasher.update(dialer);
+        hasher.update(listener);
+
+        LinkKey {
+            key: CipherEnum::new(cipher, hasher.finalize().as_bytes()),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    fn seq_nonce(seq: u64) -> Nonce {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[NONCE_LEN - size_of::<u64>()..].copy_from_slice(&seq.to_be_bytes());
+        nonce
+    }
+
+    fn next_nonce(&self) -> Nonce {
+        Self::seq_nonce(self.seq.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Cipher for LinkKey {
+    fn encrypt(&self, plaintext_to_ciphertext: &mut [u8], context: &CipherContext) -> Tag {
+        self.key.encrypt(plaintext_to_ciphertext, context)
+    }
+
+    fn decrypt(&self, ciphertext_to_plaintext: &mut [u8], context: &CipherContext, tag: &Tag) -> Result<(), AuthenticationError> {
+        let seq = self.seq.load(Ordering::Relaxed);
+
+        if context.nonce != Self::seq_nonce(seq) {
+            return Err(AuthenticationError);
+        }
+
+        self.key.decrypt(ciphertext_to_plaintext, context, tag)?;
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn is_aead(&self) -> bool {
+        self.key.is_aead()
+    }
+}
+
+// returns the id and the challenge of the remote server
+async fn hello<K: Cipher>(
+    stream: &mut TcpStream,
+    key: &K,
+    server_id: ServerId,
+    challenge: &ClusterChallenge,
+    buff: &mut [u8]
+) -> Result<(ServerId, ClusterChallenge)> {
+    let mut out = [0u8; TCP_MSG_HEADER_LEN + size_of::<ServerId>() + size_of::<ClusterChallenge>()];
+    let len = TcpMsg::cluster_hello_encode(key, rand::random(), server_id, challenge, &mut out);
+    TcpMsg::write_msg(stream, &out[..len]).await?;
+
+    let msg = time::timeout(LINK_TIMEOUT, TcpMsg::read_msg(stream, key, buff)).await??
+        .ok_or_else(|| anyhow!("cluster connection closed"))?;
+
+    let (remote_id, remote_challenge) = match msg {
+        TcpMsg::ClusterHello(id, challenge) => (id, challenge),
+        _ => return Err(anyhow!("invalid cluster message"))
+    };
+
+    if remote_id == server_id {
+        return Err(anyhow!("connected to itself"));
+    }
+
+    // our own hello sent back
+    if remote_challenge == *challenge {
+        return Err(anyhow!("cluster hello reflected"));
+    }
+    Ok((remote_id, remote_challenge))
+}
+
+// receive the node maps of the remote server and the packets relayed to the local nodes
+async fn inbound<K: Cipher + Clone + Send + Sync + 'static>(
+    mut stream: TcpStream,
+    config: &ClusterConfigFinalize<K>,
+    handle: &ClusterHandle,
+    groups: &Groups<K>
+) -> Result<()> {
+    let mut buff = vec![0u8; TCP_BUFF_SIZE];
+    let challenge: ClusterChallenge = rand::random();
+    let (remote_id, remote_challenge) = hello(&mut stream, &config.key, handle.server_id, &challenge, &mut buff).await?;
+    let key = &LinkKey::new(&config.link_secret, config.cipher, &remote_challenge, &challenge);
+    info!("cluster: server {:x} joined", remote_id);
+
+    let mut rx = BufReader::with_capacity(TCP_BUFF_SIZE, stream);
+    let mut rng = rand::rngs::SmallRng::from_entropy();
+
+    let fut = async {
+        loop {
+            let msg = time::timeout(LINK_TIMEOUT, TcpMsg::read_msg(&mut rx, key, &mut buff)).await
+                .map_err(|_| anyhow!("cluster link timeout"))??;
+
+            let msg = match msg {
+                None => return Ok(()),
+                Some(msg) => msg
+            };
+
+            match msg {
+                TcpMsg::ClusterSync(group_name, nodes) => {
+                    if let Some((_, gh, _)) = groups.iter().find(|(g, _, _)| g.name == group_name) {
+                        gh.update_remote(remote_id, Some(nodes))?;
+                    }
+                }
+                TcpMsg::ClusterRelay(group_name, to, packet) => {
+                    let (group, gh, socket) = match groups.iter().find(|(g, _, _)| g.name == group_name) {
+                        None => continue,
+                        Some(v) => v
+                    };
+
+                    // the flows are tracked on both servers, the replies are checked by the server of the other end
+                    if !gh.is_remote_source(remote_id, packet) || !gh.acl_allows(packet) {
+                        continue;
+                    }
+
+                    // the sender is registered to another server, only the limits of the receiver apply
+                    match gh.admit(None, to, packet.len()) {
+                        PushResult::Accept => {
+                            const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+
+                            let mut out = allocator::alloc(START + packet.len());
+                            out[START..].copy_from_slice(packet);
+                            gh.relay(&group.key, socket, rng.gen(), to, &mut out, START..START + packet.len()).await?;
+                        }
+                        PushResult::Delay(delay) => gh.relay_later(&group.key, socket, to, packet, delay),
+                        PushResult::Reject => {
+                            gh.metrics.flow_control_rejections.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                _ => return Err(anyhow!("invalid cluster message"))
+            }
+        }
+    };
+
+    let res = fut.await;
+
+    for (_, gh, _) in groups {
+        gh.update_remote(remote_id, None)?;
+    }
+
+    info!("cluster: server {:x} left", remote_id);
+    res
+}
+
+// send the local node maps and relay packets to the remote server
+async fn outbound<K: Cipher>(
+    peer: &str,
+    config: &ClusterConfigFinalize<K>,
+    handle: &ClusterHandle,
+    groups: &Groups<K>
+) -> Result<()> {
+    let mut stream = TcpStream::connect(peer).await.with_context(|| format!("connect to {} error", peer))?;
+    stream.set_keepalive()?;
+    stream.set_nodelay(true)?;
+
+    let mut buff = vec![0u8; TCP_BUFF_SIZE];
+    let challenge: ClusterChallenge = rand::random();
+    let (remote_id, remote_challenge) = hello(&mut stream, &config.key, handle.server_id, &challenge, &mut buff).await?;
+    let key = &LinkKey::new(&config.link_secret, config.cipher, &challenge, &remote_challenge);
+
+    let (tx, mut rx) = mpsc::channel(RELAY_CHANNEL_LIMIT);
+    handle.links.write().insert(remote_id, tx.clone());
+
+    let mut watchers: Vec<watch::Receiver<Arc<NodeMap>>> = groups.iter()
+        .map(|(_, gh, _)| gh.local_nodes.1.clone())
+        .collect();
+
+    let fut = async {
+        let mut sync_interval = time::interval(SYNC_INTERVAL);
+        let mut check_interval = time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                _ = sync_interval.tick() => {
+                    for ((group, _, _), watcher) in groups.iter().zip(watchers.iter_mut()) {
+                        let nodes = watcher.borrow_and_update().clone();
+                        let len = TcpMsg::cluster_sync_encode(key, key.next_nonce(), &group.name, &nodes, &mut buff)?;
+                        TcpMsg::write_msg(&mut stream, &buff[..len]).await?;
+                    }
+                }
+                _ = check_interval.tick() => {
+                    for ((group, _, _), watcher) in groups.iter().zip(watchers.iter_mut()) {
+                        if !watcher.has_changed()? {
+                            continue;
+                        }
+
+                        let nodes = watcher.borrow_and_update().clone();
+                        let len = TcpMsg::cluster_sync_encode(key, key.next_nonce(), &group.name, &nodes, &mut buff)?;
+                        TcpMsg::write_msg(&mut stream, &buff[..len]).await?;
+                    }
+                }
+                opt = rx.recv() => {
+                    let relay = match opt {
+                        None => return Ok(()),
+                        Some(v) => v
+                    };
+
+                    let len = TcpMsg::cluster_relay_encode(key, key.next_nonce(), &relay.group_name, relay.to, &relay.packet, &mut buff)?;
+                    TcpMsg::write_msg(&mut stream, &buff[..len]).await?;
+                }
+            }
+        }
+    };
+
+    let res: Result<()> = fut.await;
+
+    let mut links = handle.links.write();
+
+    if links.get(&remote_id).is_some_and(|v| v.same_channel(&tx)) {
+        links.remove(&remote_id);
+    }
+    res
+}
+
+pub(super) async fn start<K>(
+    config: ClusterConfigFinalize<K>,
+    handle: Arc<ClusterHandle>,
+    groups: Groups<K>
+) -> Result<()>
+where
+    K: Cipher + Clone + Send + Sync + 'static
+{
+    let config = Arc::new(config);
+    let groups = Arc::new(groups);
+
+    let listener = TcpListener::bind(config.listen_addr)
+        .await
+        .with_context(|| format!("cluster listener bind {} error", config.listen_addr))?;
+
+    info!("cluster: server {:x} listening on {}", handle.server_id, config.listen_addr);
+
+    for peer in config.peers.clone() {
+        let config = config.clone();
+        let handle = handle.clone();
+        let groups = groups.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = outbound(&peer, &config, &handle, &groups).await {
+                    warn!("cluster: link to {} error: {:?}", peer, e);
+                }
+                time::sleep(RECONNECT_INTERVAL).await;
+            }
+        });
+    }
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await.context("accept cluster connection error")?;
+        stream.set_keepalive()?;
+        stream.set_nodelay(true)?;
+
+        let config = config.clone();
+        let handle = handle.clone();
+        let groups = groups.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = inbound(stream, &config, &handle, &groups).await {
+                warn!("cluster: link from {} error: {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+#[test]
+fn link_key_test() {
+    let secret = [1u8; 32];
+    let (a, b): (ClusterChallenge, ClusterChallenge) = (rand::random(), rand::random());
+
+    let tx = LinkKey::new(&secret, CipherType::ChaCha20Poly1305, &a, &b);
+    let rx = LinkKey::new(&secret, CipherType::ChaCha20Poly1305, &a, &b);
+    let other = LinkKey::new(&secret, CipherType::ChaCha20Poly1305, &a, &rand::random());
+
+    let mut buff = [0u8; 1024];
+    let len = TcpMsg::cluster_relay_encode(&tx, tx.next_nonce(), "g", VirtualAddr::new(10, 0, 0, 1), b"packet", &mut buff).unwrap();
+    let frame = buff[..len].to_vec();
+
+    // recorded on another link
+    assert!(TcpMsg::decode_frame(&other, &mut frame.clone()).is_err());
+
+    assert!(matches!(TcpMsg::decode_frame(&rx, &mut frame.clone()), Ok(TcpMsg::ClusterRelay("g", _, b"packet"))));
+    // replayed
+    assert!(TcpMsg::decode_frame(&rx, &mut frame.clone()).is_err());
+
+    let len = TcpMsg::cluster_relay_encode(&tx, tx.next_nonce(), "g", VirtualAddr::new(10, 0, 0, 1), b"packet", &mut buff).unwrap();
+    assert!(TcpMsg::decode_frame(&rx, &mut buff[..len]).is_ok());
+}
@@ -0,0 +1,151 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ahash::{HashMap, HashSet};
+use anyhow::Result;
+use chrono::Utc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::server::store_file::StoreFile;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Lease {
+    pub virtual_addr: Ipv4Addr,
+    pub virtual_addr_v6: Option<Ipv6Addr>,
+    // unix timestamp
+    pub expire: i64,
+}
+
+impl Lease {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expire <= now
+    }
+}
+
+// group name -> node name -> lease
+type Leases = HashMap<String, HashMap<String, Lease>>;
+
+// name -> address bindings, a node gets its previous address back before the lease expires
+pub struct LeaseStore {
+    file: StoreFile,
+    duration: Duration,
+    leases: Mutex<Leases>,
+    // wakes the save task
+    changed: Notify,
+}
+
+impl LeaseStore {
+    pub fn load(path: Option<PathBuf>, duration: Duration) -> Result<Self> {
+        let file = StoreFile::new(path, "lease");
+        let mut leases: Leases = file.load()?;
+
+        let now = Utc::now().timestamp();
+
+        for nodes in leases.values_mut() {
+            nodes.retain(|_, lease| !lease.is_expired(now));
+        }
+
+        let store = LeaseStore {
+            file,
+            duration,
+            leases: Mutex::new(leases),
+            changed: Notify::new(),
+        };
+        Ok(store)
+    }
+
+    // the leases of the connected nodes are renewed at this interval, so they never expire while connected
+    pub fn renew_interval(&self) -> Duration {
+        (self.duration / 2).max(Duration::from_secs(1))
+    }
+
+    pub fn get(&self, group: &str, node_name: &str) -> Option<Lease> {
+        let now = Utc::now().timestamp();
+
+        self.leases.lock()
+            .get(group)?
+            .get(node_name)
+            .filter(|lease| !lease.is_expired(now))
+            .cloned()
+    }
+
+    // addresses held by the unexpired leases of the other nodes
+    pub fn leased_by_others(&self, group: &str, node_name: Option<&str>) -> (HashSet<Ipv4Addr>, HashSet<Ipv6Addr>) {
+        let now = Utc::now().timestamp();
+        let mut v4 = HashSet::default();
+        let mut v6 = HashSet::default();
+
+        if let Some(nodes) = self.leases.lock().get(group) {
+            for (name, lease) in nodes {
+                if Some(name.as_str()) == node_name || lease.is_expired(now) {
+                    continue;
+                }
+
+                v4.insert(lease.virtual_addr);
+
+                if let Some(addr) = lease.virtual_addr_v6 {
+                    v6.insert(addr);
+                }
+            }
+        }
+        (v4, v6)
+    }
+
+    // bind the addresses to the node, the lease expires after the configured duration
+    pub fn renew(
+        &self,
+        group: &str,
+        node_name: &str,
+        virtual_addr: Ipv4Addr,
+        virtual_addr_v6: Option<Ipv6Addr>
+    ) {
+        let lease = Lease {
+            virtual_addr,
+            virtual_addr_v6,
+            expire: Utc::now().timestamp() + self.duration.as_secs() as i64,
+        };
+
+        {
+            let mut guard = self.leases.lock();
+            let nodes = guard.entry(group.to_string()).or_default();
+
+            // the address was handed to another node, drop the stale binding
+            nodes.retain(|name, v| name == node_name || v.virtual_addr != virtual_addr);
+            nodes.insert(node_name.to_string(), lease);
+        }
+
+        self.changed.notify_one();
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(|| serde_json::to_vec_pretty(&*self.leases.lock())).await
+    }
+
+    // write the leases after each change
+    pub async fn save_schedule(&self) {
+        loop {
+            self.changed.notified().await;
+
+            if let Err(e) = self.save().await {
+                warn!("save leases error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let store = LeaseStore::load(None, Duration::from_secs(60)).unwrap();
+    let a = Ipv4Addr::new(10, 0, 0, 1);
+
+    store.renew("g", "n1", a, None);
+    assert_eq!(store.get("g", "n1").unwrap().virtual_addr, a);
+    assert!(store.leased_by_others("g", Some("n2")).0.contains(&a));
+    assert!(!store.leased_by_others("g", Some("n1")).0.contains(&a));
+
+    store.renew("g", "n2", a, None);
+    assert!(store.get("g", "n1").is_none());
+}
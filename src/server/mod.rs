@@ -1,49 +1,117 @@
+use std::fmt::{Display, Formatter};
+use std::io;
 use std::mem::size_of;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::ops::Deref;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::{Deref, Range};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
 use anyhow::{anyhow, Context, Result};
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use chrono::Utc;
-use crossbeam_utils::atomic::AtomicCell;
-use http_body_util::{BodyExt, Empty};
-use hyper::{Method, Request};
-use hyper_util::rt::TokioIo;
-use ipnet::Ipv4Net;
+use ed25519_dalek::VerifyingKey;
+use ipnet::{Ipv4Net, Ipv6Net};
 use parking_lot::{Mutex, RwLock};
 use prettytable::{row, Table};
 use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use tokio::{sync, time};
-use tokio::io::BufReader;
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio::sync::{mpsc, watch};
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, watch, Notify};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
 
-use crate::{GroupFinalize, ServerInfoType};
+use crate::{GroupFinalize, QuotaTarget, RelayQuotaFinalize, ServerInfoType};
 use crate::common::{allocator, utc_to_str};
+use crate::common::acl::{parse_packet, Acl};
+use crate::common::conntrack::FlowTable;
+use crate::common::api::ApiClient;
+use crate::common::event::{Event, EventBus};
 use crate::common::allocator::Bytes;
-use crate::common::cipher::Cipher;
-use crate::common::net::{get_ip_dst_addr, get_ip_src_addr, FlowControl, HeartbeatCache, HeartbeatInfo, PushResult, SocketExt, UdpStatus};
-use crate::common::net::protocol::{AllocateError, GroupContent, HeartbeatType, NetProtocol, Node, PeerStatus, Register, RegisterError, Seq, TcpMsg, UdpMsg, UdpSocketErr, VirtualAddr, SERVER_VIRTUAL_ADDR, TCP_BUFF_SIZE, TCP_MSG_HEADER_LEN, UDP_BUFF_SIZE, UDP_MSG_HEADER_LEN};
-use crate::server::api::api_start;
+use crate::common::cipher::{Cipher, Nonce};
+use crate::common::identity;
+use crate::common::quic::{self, ControlStream};
+use crate::common::net::{FlowControl, HeartbeatCache, HeartbeatInfo, PushResult, ReplayWindow, SocketExt, Traffic, TrafficStats, UdpStatus, UdpStatusCell, format_bytes};
+use crate::common::net::protocol::{v2, AllocateError, Capabilities, CAP_ACL, CAP_IPV6, CAP_PUSH_CONFIG, CAP_QUIC, CAP_UDP_COUNTER, GroupContent, Hello, LEGACY_HEADER_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, ProtocolVersion, HeartbeatType, NetProtocol, Node, PeerStatus, PushedConfig, Register, RegisterError, Seq, TcpMsg, UdpMsg, UdpSocketErr, VirtualAddr, ServerId, SERVER_VIRTUAL_ADDR, TCP_BUFF_SIZE, TCP_MSG_HEADER_LEN, UDP_BUFF_SIZE, UDP_MSG_HEADER_LEN, UdpNonceGenerator, udp_nonce_split};
+use crate::server::api::{api_start, Reload};
+use crate::server::ban::BanStore;
+use crate::server::cluster::ClusterHandle;
+use crate::server::lease::LeaseStore;
+use crate::server::quota::{QuotaInfo, QuotaState, QuotaStore};
+use crate::server::revocation::RevocationStore;
+use crate::server::webhook::webhook_schedule;
 use crate::ServerConfigFinalize;
 
 mod api;
+mod ban;
+mod cluster;
+mod lease;
+mod quota;
+mod revocation;
+mod store_file;
+mod webhook;
 
 pub type NodeMap = HashMap<VirtualAddr, Node>;
 
+// the task draining a relay queue exits after it stays empty this long
+const RELAY_QUEUE_IDLE: Duration = Duration::from_secs(5);
+
+type RelayQueue = mpsc::Sender<(time::Instant, Bytes)>;
+
 struct NodeHandle {
     node: ArcSwap<Node>,
-    udp_status: AtomicCell<UdpStatus>,
+    udp_status: UdpStatusCell,
     udp_heartbeat_cache: RwLock<HeartbeatCache>,
     tcp_heartbeat_cache: RwLock<HeartbeatCache>,
     // (peers, update time)
     peers_status: RwLock<Option<(Vec<PeerStatus>, Instant)>>,
     tx: Sender<Bytes>,
+    // set if the node is connected over quic
+    quic: Option<quinn::Connection>,
+    // tcp or quic address of the node
+    peer_addr: SocketAddr,
+    kick: Notify,
+    udp_replay_window: Mutex<ReplayWindow>,
+    udp_replay_dropped: AtomicU64,
+    // relay traffic only, p2p packets never pass through the server
+    traffic: Traffic,
+    // re-resolved when the quota rules are reloaded
+    quota: ArcSwapOption<QuotaState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+enum BanTarget {
+    Node(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    fn matches(&self, node_name: &str, ip: IpAddr) -> bool {
+        match self {
+            BanTarget::Node(name) => name == node_name,
+            BanTarget::Ip(addr) => addr.to_canonical() == ip.to_canonical()
+        }
+    }
+}
+
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::Node(name) => write!(f, "node {}", name),
+            BanTarget::Ip(addr) => write!(f, "address {}", addr)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Ban {
+    target: BanTarget,
+    // unix timestamp, None means forever
+    expire: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -51,7 +119,9 @@ struct NodeInfo {
     node: Node,
     udp_status: UdpStatus,
     udp_heartbeat_cache: HeartbeatInfo,
-    tcp_heartbeat_cache: HeartbeatInfo
+    tcp_heartbeat_cache: HeartbeatInfo,
+    udp_replay_dropped: u64,
+    traffic: TrafficStats,
 }
 
 impl From<&NodeHandle> for NodeInfo {
@@ -60,7 +130,61 @@ impl From<&NodeHandle> for NodeInfo {
             node: (**value.node.load()).clone(),
             udp_status: value.udp_status.load(),
             udp_heartbeat_cache: HeartbeatInfo::from(&*value.udp_heartbeat_cache.read()),
-            tcp_heartbeat_cache: HeartbeatInfo::from(&*value.tcp_heartbeat_cache.read())
+            tcp_heartbeat_cache: HeartbeatInfo::from(&*value.tcp_heartbeat_cache.read()),
+            udp_replay_dropped: value.udp_replay_dropped.load(Ordering::Relaxed),
+            traffic: TrafficStats::from(&value.traffic),
+        }
+    }
+}
+
+#[derive(Default)]
+struct GroupMetrics {
+    udp_relay_packets: AtomicU64,
+    udp_relay_bytes: AtomicU64,
+    tcp_relay_packets: AtomicU64,
+    tcp_relay_bytes: AtomicU64,
+    quic_relay_packets: AtomicU64,
+    quic_relay_bytes: AtomicU64,
+    flow_control_rejections: AtomicU64,
+    acl_rejections: AtomicU64,
+    // register error kind -> count
+    register_failures: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl GroupMetrics {
+    fn relayed(&self, protocol: NetProtocol, len: usize) {
+        let (packets, bytes) = match protocol {
+            NetProtocol::UDP => (&self.udp_relay_packets, &self.udp_relay_bytes),
+            NetProtocol::TCP => (&self.tcp_relay_packets, &self.tcp_relay_bytes),
+            NetProtocol::QUIC => (&self.quic_relay_packets, &self.quic_relay_bytes),
+        };
+
+        packets.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn register_failed(&self, e: &RegisterError) {
+        *self.register_failures.lock().entry(e.kind()).or_insert(0) += 1;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct HeartbeatParams {
+    tcp_interval: Duration,
+    tcp_continuous_loss: u64,
+    udp_interval: Duration,
+    udp_continuous_loss: u64,
+    udp_continuous_recv: u64,
+}
+
+impl<K> From<&ServerConfigFinalize<K>> for HeartbeatParams {
+    fn from(config: &ServerConfigFinalize<K>) -> Self {
+        HeartbeatParams {
+            tcp_interval: config.tcp_heartbeat_interval,
+            tcp_continuous_loss: config.tcp_heartbeat_continuous_loss,
+            udp_interval: config.udp_heartbeat_interval,
+            udp_continuous_loss: config.udp_heartbeat_continuous_loss,
+            udp_continuous_recv: config.udp_heartbeat_continuous_recv,
         }
     }
 }
@@ -68,48 +192,258 @@ impl From<&NodeHandle> for NodeInfo {
 struct Bridge {
     channel_rx: Receiver<Bytes>,
     watch_rx: watch::Receiver<Arc<HashMap<VirtualAddr, Node>>>,
+    pushed_rx: watch::Receiver<Arc<PushedConfig>>,
 }
 
 struct GroupHandle {
     name: String,
     listen_addr: SocketAddr,
-    address_range: Ipv4Net,
+    // None if the group only has an ipv6 range
+    address_range: Option<Ipv4Net>,
+    address_range_v6: Option<Ipv6Net>,
     limit: usize,
     mapping: RwLock<HashMap<VirtualAddr, Arc<NodeHandle>>>,
     watch: (watch::Sender<Arc<NodeMap>>, watch::Receiver<Arc<NodeMap>>),
-    flow_control: FlowControl
+    // ipv6 address -> ipv4 address of the nodes in watch, for the acl lookups
+    v6_index: ArcSwap<HashMap<Ipv6Addr, VirtualAddr>>,
+    // the settings below can be changed by reloading the config
+    flow_control: ArcSwap<FlowControl>,
+    heartbeat: ArcSwap<HeartbeatParams>,
+    allow_udp_relay: AtomicBool,
+    allow_tcp_relay: AtomicBool,
+    // routes and dns settings sent to the nodes
+    pushed: watch::Sender<Arc<PushedConfig>>,
+    acl: ArcSwapOption<Acl>,
+    // flows allowed by the acl, their replies pass
+    acl_flows: FlowTable,
+    udp_nonce: UdpNonceGenerator,
+    // for the nodes without CAP_UDP_COUNTER
+    legacy_udp_nonce: UdpNonceGenerator,
+    // node name -> highest udp counter of its previous sessions
+    udp_counters: Mutex<HashMap<String, u64>>,
+    // node name -> public key, None means the group only authenticates with the group key
+    identities: RwLock<Option<HashMap<String, VerifyingKey>>>,
+    revocations: Arc<RevocationStore>,
+    bans: Arc<BanStore>,
+    address_pool: Arc<AddressPool>,
+    // nodes registered to this server, shared with the cluster
+    local_nodes: (watch::Sender<Arc<NodeMap>>, watch::Receiver<Arc<NodeMap>>),
+    // server id -> nodes registered to the other servers of the cluster
+    remote_nodes: RwLock<HashMap<ServerId, NodeMap>>,
+    cluster: Option<Arc<ClusterHandle>>,
+    metrics: GroupMetrics,
+    quota_rules: RwLock<Vec<RelayQuotaFinalize>>,
+    quotas: Arc<QuotaStore>,
+    events: Arc<EventBus>,
+    // destination -> packets delayed by flow control
+    relay_queues: Mutex<HashMap<VirtualAddr, RelayQueue>>,
 }
 
 impl GroupHandle {
     fn new<K>(
-        channel_limit: usize,
+        config: &ServerConfigFinalize<K>,
         group_config: &GroupFinalize<K>,
-    ) -> Self {
-        GroupHandle {
+        leases: Arc<LeaseStore>,
+        quotas: Arc<QuotaStore>,
+        bans: Arc<BanStore>,
+        revocations: Arc<RevocationStore>,
+        events: Arc<EventBus>,
+        cluster: Option<Arc<ClusterHandle>>
+    ) -> Result<Self> {
+        let gh = GroupHandle {
             name: group_config.name.clone(),
             listen_addr: group_config.listen_addr,
-            address_range: group_config.address_range,
-            limit: channel_limit,
+            address_range: ternary!(group_config.ipv4, Some(group_config.address_range), None),
+            address_range_v6: group_config.address_range_v6,
+            limit: config.channel_limit,
             mapping: RwLock::new(HashMap::new()),
             watch: watch::channel(Arc::new(HashMap::new())),
-            flow_control: FlowControl::new(group_config.flow_control_rules.clone())
+            v6_index: ArcSwap::from_pointee(HashMap::new()),
+            flow_control: ArcSwap::from_pointee(FlowControl::new(group_config.flow_control_rules.clone())),
+            heartbeat: ArcSwap::from_pointee(HeartbeatParams::from(config)),
+            allow_udp_relay: AtomicBool::new(group_config.allow_udp_relay),
+            allow_tcp_relay: AtomicBool::new(group_config.allow_tcp_relay),
+            pushed: watch::Sender::new(Arc::new(group_config.pushed.clone())),
+            acl: ArcSwapOption::from_pointee(group_config.pushed.acl.clone()),
+            acl_flows: FlowTable::new(),
+            udp_nonce: UdpNonceGenerator::new(),
+            legacy_udp_nonce: UdpNonceGenerator::legacy(),
+            udp_counters: Mutex::new(HashMap::new()),
+            identities: RwLock::new(revocations.filter(&group_config.name, &group_config.identities)),
+            revocations,
+            bans,
+            address_pool: Arc::new(AddressPool::new(
+                group_config.name.clone(),
+                group_config.address_range,
+                group_config.address_range_v6,
+                &group_config.reservations,
+                leases
+            )?),
+            local_nodes: watch::channel(Arc::new(HashMap::new())),
+            remote_nodes: RwLock::new(HashMap::new()),
+            cluster,
+            metrics: GroupMetrics::default(),
+            quota_rules: RwLock::new(group_config.relay_quotas.clone()),
+            quotas,
+            events,
+            relay_queues: Mutex::new(HashMap::new()),
+        };
+        Ok(gh)
+    }
+
+    // apply the reloaded group config, the registered nodes stay connected
+    fn update<K>(&self, config: &ServerConfigFinalize<K>, group_config: &GroupFinalize<K>) {
+        self.heartbeat.store(Arc::new(HeartbeatParams::from(config)));
+        self.allow_udp_relay.store(group_config.allow_udp_relay, Ordering::Relaxed);
+        self.allow_tcp_relay.store(group_config.allow_tcp_relay, Ordering::Relaxed);
+
+        self.pushed.send_if_modified(|pushed| {
+            if **pushed == group_config.pushed {
+                return false;
+            }
+
+            *pushed = Arc::new(group_config.pushed.clone());
+            true
+        });
+
+        self.acl.store(group_config.pushed.acl.clone().map(Arc::new));
+
+        let guard = self.mapping.read();
+
+        let flow_control = FlowControl::new(group_config.flow_control_rules.clone());
+
+        if flow_control.rules() != self.flow_control.load().rules() {
+            // the buckets start over, hold the mapping so no node joins in between
+            for addr in guard.keys() {
+                flow_control.add_address(*addr);
+            }
+            self.flow_control.store(Arc::new(flow_control));
+        }
+
+        *self.quota_rules.write() = group_config.relay_quotas.clone();
+
+        for handle in guard.values() {
+            let node = handle.node.load();
+            handle.quota.store(self.quota(&node.name, node.virtual_addr));
+        }
+
+        let identities = self.revocations.filter(&self.name, &group_config.identities);
+        let old = std::mem::replace(&mut *self.identities.write(), identities.clone());
+
+        // nodes whose identity is not the one they registered with must register again
+        if let Some(identities) = &identities {
+            for handle in guard.values() {
+                let name = &handle.node.load().name;
+
+                if identities.get(name).is_none() || old.as_ref().and_then(|v| v.get(name)) != identities.get(name) {
+                    handle.kick.notify_one();
+                }
+            }
+        }
+
+        self.address_pool.set_reservations(&group_config.reservations);
+    }
+
+    fn verify_identity(&self, version: ProtocolVersion, reg: &Register) -> Result<()> {
+        let guard = self.identities.read();
+
+        let identities = match &*guard {
+            None => return Ok(()),
+            Some(v) => v
+        };
+
+        let public_key = *identities
+            .get(&reg.node_name)
+            .ok_or_else(|| anyhow!("node {} has no identity", reg.node_name))?;
+
+        identity::verify(&public_key, version, reg)
+    }
+
+    // remove the identity and disconnect the nodes using it, the public key stays revoked across reloads and restarts
+    fn revoke_identity(&self, node_name: &str) -> Result<()> {
+        let removed = self.identities.write()
+            .as_mut()
+            .ok_or_else(|| anyhow!("group {} does not use node identities", self.name))?
+            .remove(node_name);
+
+        let public_key = removed.ok_or_else(|| anyhow!("node {} identity not found", node_name))?;
+        self.revocations.insert(&self.name, node_name, &public_key);
+
+        for handle in self.mapping.read().values() {
+            if handle.node.load().name == node_name {
+                handle.kick.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    // disconnect the node, the tunnel cleans up the address and node map
+    fn kick(&self, addr: &VirtualAddr) -> Result<()> {
+        let guard = self.mapping.read();
+        let handle = guard.get(addr).ok_or_else(|| anyhow!("node {} not found", addr))?;
+        handle.kick.notify_one();
+        Ok(())
+    }
+
+    fn ban(&self, target: BanTarget, duration: Option<Duration>) {
+        let expire = duration.map(|d| Utc::now().timestamp() + d.as_secs() as i64);
+        // insert first, so the kicked nodes can't register again
+        self.bans.insert(&self.name, target.clone(), expire);
+
+        for handle in self.mapping.read().values() {
+            if target.matches(&handle.node.load().name, handle.peer_addr.ip()) {
+                handle.kick.notify_one();
+            }
+        }
+    }
+
+    fn unban(&self, target: &BanTarget) -> Result<()> {
+        if self.bans.remove(&self.name, target) {
+            Ok(())
+        } else {
+            Err(anyhow!("ban not found"))
         }
     }
 
-    fn join(&self, node: Node) -> Result<(Bridge, Arc<NodeHandle>)> {
+    fn is_banned(&self, node_name: Option<&str>, ip: IpAddr) -> bool {
+        self.bans.is_banned(&self.name, node_name, ip)
+    }
+
+    fn bans(&self) -> Vec<Ban> {
+        self.bans.list(&self.name)
+    }
+
+    fn join(&self, node: Node, peer_addr: SocketAddr, quic: Option<quinn::Connection>, udp_counter: u64) -> Result<(Bridge, Arc<NodeHandle>)> {
         let (_, watch_rx) = &self.watch;
         let (tx, rx) = mpsc::channel(self.limit);
 
-        let mut mp_guard = self.mapping.write();
+        // the udp messages below the counter of the registration or of a previous session are replays
+        let udp_floor = self.udp_counters.lock()
+            .get(&node.name)
+            .copied()
+            .unwrap_or_default()
+            .max(udp_counter.saturating_sub(1));
+
         let vaddr = node.virtual_addr;
+        let node_name = node.name.clone();
+        let quota = self.quota(&node.name, vaddr);
+
+        let mut mp_guard = self.mapping.write();
 
         let node_handle = NodeHandle {
             node: ArcSwap::from_pointee(node),
             tx,
-            udp_status: AtomicCell::new(UdpStatus::Unavailable),
+            quic,
+            peer_addr,
+            udp_status: UdpStatusCell::new(),
             udp_heartbeat_cache: RwLock::new(HeartbeatCache::new()),
             tcp_heartbeat_cache: RwLock::new(HeartbeatCache::new()),
-            peers_status: RwLock::new(None)
+            peers_status: RwLock::new(None),
+            kick: Notify::new(),
+            udp_replay_window: Mutex::new(ReplayWindow::with_floor(udp_floor)),
+            udp_replay_dropped: AtomicU64::new(0),
+            traffic: Traffic::default(),
+            quota: ArcSwapOption::new(quota),
         };
 
         let node_handle = Arc::new(node_handle);
@@ -119,37 +453,450 @@ impl GroupHandle {
             node_handle.clone()
         );
         self.sync(&mp_guard)?;
+        // under the mapping lock, a reload rebuilding flow control sees either both or neither
+        self.flow_control.load().add_address(vaddr);
         drop(mp_guard);
 
-        self.flow_control.add_address(vaddr);
+        self.event(Event::NodeRegistered { node: node_name, addr: vaddr });
 
         let bridge = Bridge {
             channel_rx: rx,
             watch_rx: watch_rx.clone(),
+            pushed_rx: self.pushed.subscribe(),
         };
         Ok((bridge, node_handle))
     }
 
+    // capabilities of the receiving node
+    fn next_udp_nonce(&self, capabilities: Capabilities) -> Nonce {
+        let generator = ternary!(capabilities & CAP_UDP_COUNTER != 0, &self.udp_nonce, &self.legacy_udp_nonce);
+        generator.next(SERVER_VIRTUAL_ADDR.octets())
+    }
+
+    fn event(&self, event: Event) {
+        self.events.publish(Some(&self.name), event);
+    }
+
+    fn register_failed(&self, node_name: Option<&str>, e: &RegisterError) {
+        self.metrics.register_failed(e);
+
+        self.event(Event::RegisterFailed {
+            node: node_name.map(|v| v.to_string()),
+            reason: Some(e.kind()),
+            error: e.to_string(),
+        });
+    }
+
     fn sync(&self, node_map: &HashMap<VirtualAddr, Arc<NodeHandle>>) -> Result<()> {
         let (tx, _) = &self.watch;
+        let (local_tx, _) = &self.local_nodes;
 
-        let node_list: HashMap<VirtualAddr, Node> = node_map
+        let local_list: HashMap<VirtualAddr, Node> = node_map
             .iter()
             .map(|(addr, handle)| (*addr, (**handle.node.load()).clone()))
             .collect();
 
+        let mut node_list = local_list.clone();
+
+        for nodes in self.remote_nodes.read().values() {
+            for (addr, node) in nodes {
+                node_list.entry(*addr).or_insert_with(|| node.clone());
+            }
+        }
+
+        local_tx.send_replace(Arc::new(local_list));
+
+        let v6_index = node_list
+            .iter()
+            .filter_map(|(addr, node)| Some((node.virtual_addr_v6?, *addr)))
+            .collect();
+
+        self.v6_index.store(Arc::new(v6_index));
+
         tx.send(Arc::new(node_list))
             .map_err(|_| anyhow!("sync node_map error"))?;
         Ok(())
     }
+
+    // replace the nodes of a cluster server, None means the server has left
+    fn update_remote(&self, server_id: ServerId, nodes: Option<NodeMap>) -> Result<()> {
+        {
+            let mut remote = self.remote_nodes.write();
+
+            match nodes {
+                None => remote.remove(&server_id),
+                Some(nodes) => remote.insert(server_id, nodes)
+            };
+
+            self.address_pool.set_remote_leases(remote.values().flat_map(|nodes| nodes.values()));
+        }
+
+        let guard = self.mapping.read();
+
+        // the address is leased by two servers at the same time, the node of the server with the larger id gives it up
+        if let Some(cluster) = &self.cluster {
+            if cluster.server_id > server_id {
+                if let Some(nodes) = self.remote_nodes.read().get(&server_id) {
+                    for (addr, node) in nodes {
+                        if let Some(handle) = guard.get(addr) {
+                            warn!("group {} address {} is also used by node {} on server {:x}", self.name, addr, node.name, server_id);
+                            handle.kick.notify_one();
+                        }
+                    }
+                }
+            }
+        }
+
+        self.sync(&guard)
+    }
+
+    // a node name rule takes precedence over the address range rules
+    fn quota(&self, node_name: &str, addr: VirtualAddr) -> Option<Arc<QuotaState>> {
+        let rules = self.quota_rules.read();
+
+        let rule = rules.iter()
+            .find(|rule| matches!(&rule.target, QuotaTarget::Node(name) if name == node_name))
+            .or_else(|| {
+                rules.iter()
+                    .filter_map(|rule| match rule.target {
+                        QuotaTarget::Cidr(cidr) if cidr.contains(&addr) => Some((cidr.prefix_len(), rule)),
+                        _ => None
+                    })
+                    .max_by_key(|(prefix_len, _)| *prefix_len)
+                    .map(|(_, rule)| rule)
+            })?;
+
+        Some(self.quotas.get(&self.name, node_name, rule))
+    }
+
+    // a node may only relay packets from its own addresses and allowed_ips, so a forged source can't pass the acl
+    fn is_node_source(node: &Node, packet: &[u8]) -> bool {
+        let src = match parse_packet(packet) {
+            None => return false,
+            Some(info) => info.src
+        };
+
+        match src {
+            IpAddr::V4(addr) if addr == node.virtual_addr => true,
+            IpAddr::V6(addr) if Some(addr) == node.virtual_addr_v6 => true,
+            _ => node.allowed_ips.iter().any(|cidr| cidr.contains(&src))
+        }
+    }
+
+    // the packet is relayed by a cluster server for one of its nodes
+    fn is_remote_source(&self, server_id: ServerId, packet: &[u8]) -> bool {
+        self.remote_nodes.read()
+            .get(&server_id)
+            .is_some_and(|nodes| nodes.values().any(|node| Self::is_node_source(node, packet)))
+    }
+
+    // the addresses are resolved with the nodes of the whole cluster
+    fn acl_allows(&self, packet: &[u8]) -> bool {
+        let acl = self.acl.load();
+
+        let acl = match &*acl {
+            None => return true,
+            Some(acl) => acl
+        };
+
+        let nodes = self.watch.1.borrow().clone();
+        let v6_index = self.v6_index.load();
+
+        let allowed = acl.allows(packet, &self.acl_flows, |addr| {
+            let addr = match addr {
+                IpAddr::V4(addr) => addr,
+                IpAddr::V6(addr) => *v6_index.get(&addr)?
+            };
+            nodes.get(&addr).map(|n| n.name.as_str())
+        });
+
+        if !allowed {
+            self.metrics.acl_rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    // relay quotas of both ends, then flow control, the quotas are only used by the admitted packets
+    // the quota of a node registered to another cluster server is counted by that server
+    fn admit(&self, src: Option<VirtualAddr>, dst: VirtualAddr, len: usize) -> PushResult {
+        let guard = self.mapping.read();
+        let handles = [src.and_then(|addr| guard.get(&addr)), guard.get(&dst)];
+        let quotas = handles.map(|handle| handle.map(|h| (h, h.quota.load())));
+        let mut consumed = 0;
+
+        for (handle, quota) in quotas.iter().flatten() {
+            let quota = match &**quota {
+                None => continue,
+                Some(quota) => quota
+            };
+
+            let pass = quota.consume(len as u64, |limit, period| {
+                self.event(Event::QuotaExceeded {
+                    node: handle.node.load().name.clone(),
+                    limit,
+                    period,
+                });
+            });
+
+            if !pass {
+                quotas.iter().flatten().filter_map(|(_, q)| q.as_ref()).take(consumed).for_each(|q| q.refund(len as u64));
+                return PushResult::Reject;
+            }
+            consumed += 1;
+        }
+
+        let res = self.flow_control.load().push(src, dst, len as u64, |addr, direction| {
+            if let Some(handle) = guard.get(&addr) {
+                self.event(Event::FlowControlRejected {
+                    node: handle.node.load().name.clone(),
+                    addr,
+                    direction,
+                });
+            }
+        });
+
+        if res == PushResult::Reject {
+            quotas.iter().flatten().filter_map(|(_, q)| q.as_ref()).for_each(|q| q.refund(len as u64));
+        }
+        res
+    }
+
+    // relay the packet at buff[data_range] to a local node,
+    // there must be room for the udp relay header before the packet
+    async fn relay<K: Cipher>(
+        &self,
+        key: &K,
+        socket: &UdpSocket,
+        tcp_nonce: Nonce,
+        dst_virt_addr: VirtualAddr,
+        buff: &mut [u8],
+        data_range: Range<usize>
+    ) -> Result<()> {
+        let mut udp_dst = None;
+
+        {
+            let guard = self.mapping.read();
+
+            let handle = match guard.get(&dst_virt_addr) {
+                None => return Ok(()),
+                Some(v) => v
+            };
+
+            let node = handle.node.load();
+            let data = &buff[data_range.clone()];
+
+            for np in &node.mode.relay {
+                match np {
+                    NetProtocol::TCP => {
+                        let mut packet = allocator::alloc(TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + data.len());
+                        packet[TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>()..].copy_from_slice(data);
+                        TcpMsg::relay_encode(key, tcp_nonce, dst_virt_addr, data.len(), &mut packet);
+
+                        match handle.tx.try_send(packet) {
+                            Ok(_) => {
+                                self.metrics.relayed(NetProtocol::TCP, data.len());
+                                handle.traffic.tcp_relay.tx(data.len());
+                                debug!("group {}: tcp message relay to node {}", self.name, node.name);
+                                return Ok(());
+                            }
+                            Err(e) => warn!("group {} send packet to tcp channel error: {}", self.name, e)
+                        }
+                    }
+                    NetProtocol::QUIC => {
+                        let conn = match &handle.quic {
+                            None => continue,
+                            Some(conn) => conn
+                        };
+
+                        let mut packet = allocator::alloc(TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + data.len());
+                        packet[TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>()..].copy_from_slice(data);
+                        TcpMsg::relay_encode(key, tcp_nonce, dst_virt_addr, data.len(), &mut packet);
+
+                        let res = match quic::send_datagram(conn, &packet) {
+                            Ok(true) => Ok(()),
+                            Ok(false) => handle.tx.try_send(packet).map_err(|e| anyhow!("{}", e)),
+                            Err(e) => Err(e)
+                        };
+
+                        match res {
+                            Ok(_) => {
+                                self.metrics.relayed(NetProtocol::QUIC, data.len());
+                                handle.traffic.quic_relay.tx(data.len());
+                                debug!("group {}: quic message relay to node {}", self.name, node.name);
+                                return Ok(());
+                            }
+                            Err(e) => warn!("group {} send packet to quic connection error: {}", self.name, e)
+                        }
+                    }
+                    NetProtocol::UDP => {
+                        if let UdpStatus::Available { dst_addr } = handle.udp_status.load() {
+                            self.metrics.relayed(NetProtocol::UDP, data.len());
+                            handle.traffic.udp_relay.tx(data.len());
+                            debug!("group {}: udp message relay to node {}", self.name, node.name);
+
+                            udp_dst = Some((dst_addr, node.capabilities));
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some((dst_addr, capabilities)) = udp_dst {
+            let start = data_range.start - UDP_MSG_HEADER_LEN - size_of::<VirtualAddr>();
+            let len = UdpMsg::relay_encode(key, self.next_udp_nonce(capabilities), dst_virt_addr, data_range.len(), &mut buff[start..]);
+
+            match UdpMsg::send_msg(socket, &buff[start..start + len], dst_addr).await {
+                Ok(_) => (),
+                Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
+                Err(UdpSocketErr::SuppressError(e)) => {
+                    warn!("group {} send udp packet warn {}", self.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // relay the packet sent by a local node over its connection, buff holds the whole relay message
+    async fn relay_from_node<K: Cipher + Clone + Send + Sync + 'static>(
+        self: &Arc<Self>,
+        key: &K,
+        socket: &Arc<UdpSocket>,
+        node_handle: &NodeHandle,
+        protocol: NetProtocol,
+        tcp_nonce: Nonce,
+        dst_virt_addr: VirtualAddr,
+        buff: &mut [u8],
+        packet_len: usize
+    ) -> Result<()> {
+        const START: usize = TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+        let packet = &buff[START..START + packet_len];
+
+        if !self.allow_tcp_relay.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        node_handle.traffic.relay(protocol).rx(packet_len);
+
+        if !Self::is_node_source(&node_handle.node.load(), packet) || !self.acl_allows(packet) {
+            return Ok(());
+        }
+
+        let src = node_handle.node.load().virtual_addr;
+
+        match self.admit(Some(src), dst_virt_addr, packet_len) {
+            PushResult::Accept if !self.mapping.read().contains_key(&dst_virt_addr) => self.cluster_relay(dst_virt_addr, packet),
+            PushResult::Accept => self.relay(key, socket, tcp_nonce, dst_virt_addr, buff, START..START + packet_len).await?,
+            PushResult::Delay(delay) => self.relay_later(key, socket, dst_virt_addr, packet, delay),
+            PushResult::Reject => {
+                self.metrics.flow_control_rejections.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    // the packet is delayed by flow control, each destination has one bounded queue
+    fn relay_later<K: Cipher + Clone + Send + Sync + 'static>(
+        self: &Arc<Self>,
+        key: &K,
+        socket: &Arc<UdpSocket>,
+        dst_virt_addr: VirtualAddr,
+        data: &[u8],
+        delay: Duration
+    ) {
+        const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+
+        let mut buff = allocator::alloc(START + data.len());
+        buff[START..].copy_from_slice(data);
+
+        let mut queues = self.relay_queues.lock();
+
+        let queue = queues.entry(dst_virt_addr).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(self.limit);
+            tokio::spawn(self.clone().drain_relay_queue(key.clone(), Arc::downgrade(socket), dst_virt_addr, rx));
+            tx
+        });
+
+        if queue.try_send((time::Instant::now() + delay, buff)).is_err() {
+            self.metrics.flow_control_rejections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // relay the delayed packets of one destination in order,
+    // the socket is weak so the port is released when the group stops
+    async fn drain_relay_queue<K: Cipher + Send + Sync>(
+        self: Arc<Self>,
+        key: K,
+        socket: Weak<UdpSocket>,
+        dst_virt_addr: VirtualAddr,
+        mut rx: mpsc::Receiver<(time::Instant, Bytes)>
+    ) {
+        const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+
+        loop {
+            let (deadline, mut buff) = match time::timeout(RELAY_QUEUE_IDLE, rx.recv()).await {
+                Ok(Some(v)) => v,
+                Ok(None) => return,
+                Err(_) => {
+                    // packets are queued under the lock, none is left behind once the queue is removed
+                    let mut queues = self.relay_queues.lock();
+
+                    if rx.is_empty() {
+                        queues.remove(&dst_virt_addr);
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            time::sleep_until(deadline).await;
+            let end = buff.len();
+
+            if !self.mapping.read().contains_key(&dst_virt_addr) {
+                self.cluster_relay(dst_virt_addr, &buff[START..end]);
+                continue;
+            }
+
+            let socket = match socket.upgrade() {
+                None => {
+                    self.relay_queues.lock().remove(&dst_virt_addr);
+                    return;
+                }
+                Some(socket) => socket
+            };
+
+            if let Err(e) = self.relay(&key, &socket, rand::random(), dst_virt_addr, &mut buff, START..end).await {
+                error!("group {} relay queued packet error: {:?}", self.name, e);
+            }
+        }
+    }
+
+    // forward the packet to the cluster server the node is registered to
+    fn cluster_relay(&self, to: VirtualAddr, packet: &[u8]) {
+        let cluster = match &self.cluster {
+            None => return,
+            Some(v) => v
+        };
+
+        let server_id = self.remote_nodes.read()
+            .iter()
+            .find(|(_, nodes)| nodes.contains_key(&to))
+            .map(|(id, _)| *id);
+
+        if let Some(server_id) = server_id {
+            cluster.relay(server_id, &self.name, to, packet);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct GroupInfo {
     name: String,
     listen_addr: SocketAddr,
-    address_range: Ipv4Net,
-    node_map: HashMap<VirtualAddr, NodeInfo>
+    address_range: Option<Ipv4Net>,
+    address_range_v6: Option<Ipv6Net>,
+    node_map: HashMap<VirtualAddr, NodeInfo>,
+    relay_quotas: Vec<QuotaInfo>,
 }
 
 impl From<&GroupHandle> for GroupInfo {
@@ -158,38 +905,38 @@ impl From<&GroupHandle> for GroupInfo {
             name: value.name.clone(),
             listen_addr: value.listen_addr,
             address_range: value.address_range,
+            address_range_v6: value.address_range_v6,
             node_map: {
                 value.mapping.read()
                     .iter()
                     .map(|(k, v)| (*k, NodeInfo::from(&**v)))
                     .collect()
-            }
+            },
+            relay_quotas: value.quotas.usage(&value.name),
         }
     }
 }
 
-async fn udp_handler<K: Cipher + Clone + Send + Sync>(
-    group: &'static GroupFinalize<K>,
+async fn udp_handler<K: Cipher + Clone + Send + Sync + 'static>(
+    group: Arc<GroupFinalize<K>>,
     socket: Arc<UdpSocket>,
     group_handle: Arc<GroupHandle>,
-    heartbeat_interval: Duration,
-    packet_loss_limit: u64,
-    packet_continuous_recv: u64,
     notified: watch::Receiver<()>
 ) -> Result<()> {
-    let key = &group.key;
-
     let heartbeat_schedule = async {
+        let group = group.clone();
         let group_handle = group_handle.clone();
         let socket = socket.clone();
         let mut notified = notified.clone();
 
         tokio::spawn(async move {
+            let key = &group.key;
+
             let fut = async {
                 let mut buff = [0u8; UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + size_of::<Seq>() + size_of::<HeartbeatType>()];
-                let mut rng = rand::rngs::SmallRng::from_entropy();
 
                 loop {
+                    let heartbeat = **group_handle.heartbeat.load();
                     let mut list = Vec::new();
 
                     {
@@ -207,18 +954,23 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
                             heartbeat_status.check();
 
                             if node.udp_status.load() != UdpStatus::Unavailable &&
-                                heartbeat_status.packet_continuous_loss_count >= packet_loss_limit
+                                heartbeat_status.packet_continuous_loss_count >= heartbeat.udp_continuous_loss
                             {
                                 node.udp_status.store(UdpStatus::Unavailable);
+
+                                group_handle.event(Event::UdpStatusChanged {
+                                    node: Some(node.node.load().name.clone()),
+                                    status: UdpStatus::Unavailable,
+                                });
                             }
 
                             heartbeat_status.ping();
-                            list.push((socket_addr, heartbeat_status.seq));
+                            list.push((socket_addr, heartbeat_status.seq, node.node.load().capabilities));
                         }
                     };
 
-                    for (sock_addr, seq) in list {
-                        let len = UdpMsg::heartbeat_encode(key, rng.gen(), SERVER_VIRTUAL_ADDR, seq, HeartbeatType::Req, &mut buff);
+                    for (sock_addr, seq, capabilities) in list {
+                        let len = UdpMsg::heartbeat_encode(key, group_handle.next_udp_nonce(capabilities), SERVER_VIRTUAL_ADDR, seq, HeartbeatType::Req, &mut buff);
                         let packet = &mut buff[..len];
 
                         match UdpMsg::send_msg(&socket, packet, sock_addr).await {
@@ -230,7 +982,7 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
                         };
                     }
 
-                    tokio::time::sleep(heartbeat_interval).await;
+                    tokio::time::sleep(heartbeat.udp_interval).await;
                 }
             };
 
@@ -242,11 +994,14 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
     };
 
     let recv_handler = async {
+        let group = group.clone();
         let group_handle = group_handle.clone();
         let socket = socket.clone();
         let mut notified = notified.clone();
 
         tokio::spawn(async move {
+            let key = &group.key;
+
             let fut = async {
                 let mut buff = vec![0u8; UDP_BUFF_SIZE];
                 let mut rng = rand::rngs::SmallRng::from_entropy();
@@ -262,7 +1017,31 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
 
                     let packet = &mut buff[..len];
 
-                    let msg = match UdpMsg::decode(key, packet) {
+                    if let Err(e) = UdpMsg::decrypt(key, packet) {
+                        error!("group {} receive udp message error: {:?}", group.name, e);
+                        continue;
+                    }
+
+                    // the nonce carries the virtual address of the sender and its message counter
+                    let (sender, counter) = udp_nonce_split(&UdpMsg::nonce(packet).unwrap());
+
+                    // the counter of a non-aead cipher can be forged, the window would only let an attacker block the sender
+                    if key.is_aead() {
+                        let guard = group_handle.mapping.read();
+
+                        let handle = match guard.get(&VirtualAddr::from(sender)) {
+                            Some(handle) => handle,
+                            None => continue
+                        };
+
+                        if !handle.udp_replay_window.lock().check(counter) {
+                            handle.udp_replay_dropped.fetch_add(1, Ordering::Relaxed);
+                            debug!("group {} drop replayed udp message from {}", group.name, peer_addr);
+                            continue;
+                        }
+                    }
+
+                    let msg = match UdpMsg::parse(packet) {
                         Ok(msg) => msg,
                         Err(e) => {
                             error!("group {} receive udp message error: {:?}", group.name, e);
@@ -272,14 +1051,15 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
 
                     match msg {
                         UdpMsg::Heartbeat(dst_virt_addr, seq, HeartbeatType::Req) => {
-                            let mut is_known = false;
+                            // capabilities of the known node
+                            let mut known = None;
 
                             {
                                 let guard = group_handle.mapping.read();
 
                                 if let Some(handle) = guard.get(&dst_virt_addr) {
-                                    is_known = true;
                                     let node = handle.node.load();
+                                    known = Some(node.capabilities);
 
                                     if node.wan_udp_addr != Some(peer_addr) {
                                         let mut new_node = (**node).clone();
@@ -292,13 +1072,13 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
                                 }
                             };
 
-                            if is_known {
-                                let len = UdpMsg::heartbeat_encode(key, rng.gen(), SERVER_VIRTUAL_ADDR, seq, HeartbeatType::Resp, &mut buff);
+                            if let Some(capabilities) = known {
+                                let len = UdpMsg::heartbeat_encode(key, group_handle.next_udp_nonce(capabilities), SERVER_VIRTUAL_ADDR, seq, HeartbeatType::Resp, &mut buff);
                                 let packet = &mut buff[..len];
 
                                 match UdpMsg::send_msg(&socket, packet, peer_addr).await {
                                     Ok(_) => (),
-                                    Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
+                                    Err(UdpSocketErr::FatalError(e)) => return Result::<(), _>::Err(anyhow!(e)),
                                     Err(UdpSocketErr::SuppressError(e)) => {
                                         warn!("group {} send udp packet warn {}", group.name, e);
                                     }
@@ -316,94 +1096,48 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
                                 }
 
                                 if node.udp_status.load() == UdpStatus::Unavailable &&
-                                    udp_heartbeat_cache.packet_continuous_recv_count >= packet_continuous_recv
+                                    udp_heartbeat_cache.packet_continuous_recv_count >= group_handle.heartbeat.load().udp_continuous_recv
                                 {
-                                    node.udp_status.store(UdpStatus::Available {dst_addr: peer_addr});
+                                    let status = UdpStatus::Available {dst_addr: peer_addr};
+                                    node.udp_status.store(status);
+
+                                    group_handle.event(Event::UdpStatusChanged {
+                                        node: Some(node.node.load().name.clone()),
+                                        status,
+                                    });
                                 }
                             }
                         }
                         UdpMsg::Relay(dst_virt_addr, data) => {
-                            if !group.allow_udp_relay {
+                            if !group_handle.allow_udp_relay.load(Ordering::Relaxed) {
                                 continue;
                             }
 
-                            let flow_control_res = group_handle.flow_control.push(dst_virt_addr, data.len() as u64);
+                            let is_source = match group_handle.mapping.read().get(&VirtualAddr::from(sender)) {
+                                None => false,
+                                Some(handle) => {
+                                    handle.traffic.udp_relay.rx(data.len());
+                                    GroupHandle::is_node_source(&handle.node.load(), data)
+                                }
+                            };
 
-                            if flow_control_res == PushResult::Reject {
+                            if !is_source || !group_handle.acl_allows(data) {
                                 continue;
                             }
 
-                            let mut fut = None;
-
-                            {
-                                let guard = group_handle.mapping.read();
-
-                                if let Some(handle) = guard.get(&dst_virt_addr) {
-                                    let relay = handle.node.load().mode.relay.clone();
-
-                                    for np in relay {
-                                        match np {
-                                            NetProtocol::TCP => {
-                                                let mut buff = allocator::alloc(TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + data.len());
-                                                buff[TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>()..].copy_from_slice(data);
-                                                TcpMsg::relay_encode(key, rng.gen(), dst_virt_addr, data.len(), &mut buff);
-
-                                                match handle.tx.try_send(buff) {
-                                                    Ok(_) => {
-                                                        if log::max_level() >= log::Level::Debug {
-                                                            let f = || {
-                                                                let src = get_ip_src_addr(data)?;
-                                                                let dst = get_ip_dst_addr(data)?;
-                                                                Result::<_, anyhow::Error>::Ok((src, dst))
-                                                            };
-
-                                                            if let Ok((src, dst)) = f() {
-                                                                debug!("group {} udp handler: tcp message relay to {}; packet {}->{}", group.name, dst_virt_addr, src, dst);
-                                                            }
-                                                        }
-                                                        break;
-                                                    }
-                                                    Err(e) => warn!("group {} send packet to tcp channel error: {}", group.name, e)
-                                                }
-                                            }
-                                            NetProtocol::UDP => {
-                                                let status = handle.udp_status.load();
-
-                                                let dst_addr = match status {
-                                                    UdpStatus::Available { dst_addr } => dst_addr,
-                                                    UdpStatus::Unavailable => continue
-                                                };
-
-                                                drop(guard);
-
-                                                if log::max_level() >= log::Level::Debug {
-                                                    let f = || {
-                                                        let src = get_ip_src_addr(data)?;
-                                                        let dst = get_ip_dst_addr(data)?;
-                                                        Result::<_, anyhow::Error>::Ok((src, dst))
-                                                    };
-
-                                                    if let Ok((src, dst)) = f() {
-                                                        debug!("group {} udp handler: udp message relay to {}; packet {}->{}", group.name, dst_virt_addr, src, dst);
-                                                    }
-                                                }
-
-                                                UdpMsg::relay_encode(key, rng.gen(), dst_virt_addr, data.len(), packet);
-                                                fut = Some(UdpMsg::send_msg(&socket, packet, dst_addr));
-                                                break;
-                                            }
-                                        }
-                                    }
+                            match group_handle.admit(Some(VirtualAddr::from(sender)), dst_virt_addr, data.len()) {
+                                PushResult::Accept if !group_handle.mapping.read().contains_key(&dst_virt_addr) => {
+                                    group_handle.cluster_relay(dst_virt_addr, data);
                                 }
-                            };
+                                PushResult::Accept => {
+                                    const START: usize = UDP_MSG_HEADER_LEN + size_of::<VirtualAddr>();
+                                    let data_len = data.len();
 
-                            if let Some(fut) = fut {
-                                match fut.await {
-                                    Ok(_) => (),
-                                    Err(UdpSocketErr::FatalError(e)) => return Result::<(), _>::Err(anyhow!(e)),
-                                    Err(UdpSocketErr::SuppressError(e)) => {
-                                        warn!("group {} send udp packet warn {}", group.name, e);
-                                    }
+                                    group_handle.relay(key, &socket, rng.gen(), dst_virt_addr, packet, START..START + data_len).await?;
+                                }
+                                PushResult::Delay(delay) => group_handle.relay_later(key, &socket, dst_virt_addr, data, delay),
+                                PushResult::Reject => {
+                                    group_handle.metrics.flow_control_rejections.fetch_add(1, Ordering::Relaxed);
                                 }
                             }
                         }
@@ -423,16 +1157,15 @@ async fn udp_handler<K: Cipher + Clone + Send + Sync>(
     Ok(())
 }
 
-async fn tcp_handler<K: Cipher + Clone + Send + Sync>(
+async fn tcp_handler<K: Cipher + Clone + Send + Sync + 'static>(
     tcp_listener: TcpListener,
     udp_socket: Arc<UdpSocket>,
-    config: &'static ServerConfigFinalize<K>,
-    group: &'static GroupFinalize<K>,
+    group: Arc<GroupFinalize<K>>,
     group_handle: Arc<GroupHandle>,
     notified: watch::Receiver<()>
 ) -> Result<()> {
     let nonce_pool = Arc::new(NoncePool::new());
-    let address_pool = Arc::new(AddressPool::new(group.address_range)?);
+    let address_pool = group_handle.address_pool.clone();
 
     loop {
         let (stream, peer_addr) = tcp_listener
@@ -440,57 +1173,153 @@ async fn tcp_handler<K: Cipher + Clone + Send + Sync>(
             .await
             .context("accept connection error")?;
 
-        let mut tunnel = Tunnel::new(
-            stream,
+        if group_handle.is_banned(None, peer_addr.ip()) {
+            debug!("group {} address {} is banned", group.name, peer_addr);
+            continue;
+        }
+
+        let tunnel = Tunnel::new(
+            ControlStream::Tcp(stream),
             udp_socket.clone(),
-            config,
-            group,
+            group.clone(),
             group_handle.clone(),
             nonce_pool.clone(),
             address_pool.clone(),
         );
 
-        let mut notified = notified.clone();
+        tokio::spawn(run_tunnel(tunnel, peer_addr, notified.clone()));
+    }
+}
 
-        tokio::spawn(async move {
-            let res = tokio::select! {
-                res = tunnel.exec() => res,
-                _ = notified.changed() => Err(anyhow!("abort task"))
-            };
+async fn quic_handler<K: Cipher + Clone + Send + Sync + 'static>(
+    endpoint: quinn::Endpoint,
+    udp_socket: Arc<UdpSocket>,
+    group: Arc<GroupFinalize<K>>,
+    group_handle: Arc<GroupHandle>,
+    notified: watch::Receiver<()>
+) -> Result<()> {
+    let nonce_pool = Arc::new(NoncePool::new());
+    let address_pool = group_handle.address_pool.clone();
+
+    while let Some(incoming) = endpoint.accept().await {
+        let peer_addr = incoming.remote_address();
+
+        if group_handle.is_banned(None, peer_addr.ip()) {
+            debug!("group {} address {} is banned", group.name, peer_addr);
+            incoming.refuse();
+            continue;
+        }
+
+        let group = group.clone();
+        let udp_socket = udp_socket.clone();
+        let group_handle = group_handle.clone();
+        let nonce_pool = nonce_pool.clone();
+        let address_pool = address_pool.clone();
+        let notified = notified.clone();
+
+        tokio::spawn(async move {
+            // the node opens the control stream before anything else
+            let fut = async {
+                let conn = incoming.await?;
+                let (tx, rx) = conn.accept_bi().await?;
+                Result::<_, anyhow::Error>::Ok(ControlStream::Quic(conn, tx, rx))
+            };
 
-            if let Err(e) = res {
-                match &tunnel.register {
-                    None => error!("group {} address {} tunnel error: {:?}", group.name, peer_addr, e),
-                    Some(v) => error!("group {} node {}({}) tunnel error: {:?}", group.name, v.node_name, v.virtual_addr, e)
+            let stream = match time::timeout(Duration::from_secs(10), fut).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("group {} address {} quic handshake error: {:?}", group.name, peer_addr, e);
+                    return;
                 }
-            }
+                Err(_) => {
+                    warn!("group {} address {} quic handshake timeout", group.name, peer_addr);
+                    return;
+                }
+            };
 
-            if let Some(v) = &tunnel.register {
-                warn!("group {} node {}-{} disconnected", group.name, v.node_name, v.virtual_addr);
-            }
+            let tunnel = Tunnel::new(
+                stream,
+                udp_socket,
+                group,
+                group_handle,
+                nonce_pool,
+                address_pool,
+            );
+
+            run_tunnel(tunnel, peer_addr, notified).await;
         });
     }
+    Err(anyhow!("quic endpoint closed"))
+}
+
+async fn run_tunnel<K: Cipher + Clone + Send + Sync + 'static>(
+    mut tunnel: Tunnel<K>,
+    peer_addr: SocketAddr,
+    mut notified: watch::Receiver<()>
+) {
+    let group = tunnel.group.clone();
+
+    let res = tokio::select! {
+        res = tunnel.exec() => res,
+        _ = notified.changed() => Err(anyhow!("abort task"))
+    };
+
+    if let Err(e) = res {
+        match &tunnel.register {
+            // the latency probes of the nodes close the connection right away
+            None if e.is::<ClosedBeforeHello>() => debug!("group {} address {} {}", group.name, peer_addr, e),
+            None => error!("group {} address {} tunnel error: {:?}", group.name, peer_addr, e),
+            Some(v) => error!("group {} node {}({}) tunnel error: {:?}", group.name, v.node_name, v.virtual_addr, e)
+        }
+    }
+
+    if let Some(v) = &tunnel.register {
+        warn!("group {} node {}-{} disconnected", group.name, v.node_name, v.virtual_addr);
+    }
 }
 
-struct Tunnel<K: 'static> {
-    config: &'static ServerConfigFinalize<K>,
-    group: &'static GroupFinalize<K>,
-    stream: Option<TcpStream>,
+#[derive(Debug)]
+struct ClosedBeforeHello;
+
+impl Display for ClosedBeforeHello {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection closed before hello")
+    }
+}
+
+impl std::error::Error for ClosedBeforeHello {}
+
+struct Tunnel<K> {
+    group: Arc<GroupFinalize<K>>,
+    stream: Option<ControlStream>,
     udp_socket: Arc<UdpSocket>,
     group_handle: Arc<GroupHandle>,
     nonce_pool: Arc<NoncePool>,
     address_pool: Arc<AddressPool>,
     register: Option<Register>,
+    // negotiated with the node, hello is the first message
+    hello: Option<Hello>,
     bridge: Option<Bridge>,
     node_handle: Option<Arc<NodeHandle>>
 }
 
-impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
+// the pushed settings the node supports, the server keeps checking the relayed traffic against the acl
+fn pushed_for(hello: &Hello, pushed: &PushedConfig) -> PushedConfig {
+    if !hello.has(CAP_PUSH_CONFIG) {
+        return PushedConfig::default();
+    }
+
+    if !hello.has(CAP_ACL) {
+        return PushedConfig { acl: None, ..pushed.clone() };
+    }
+    pushed.clone()
+}
+
+impl<K: Cipher + Clone + Send + Sync + 'static> Tunnel<K> {
     fn new(
-        stream: TcpStream,
+        stream: ControlStream,
         udp_socket: Arc<UdpSocket>,
-        config: &'static ServerConfigFinalize<K>,
-        group: &'static GroupFinalize<K>,
+        group: Arc<GroupFinalize<K>>,
         group_handle: Arc<GroupHandle>,
         nonce_pool: Arc<NoncePool>,
         address_pool: Arc<AddressPool>,
@@ -498,12 +1327,12 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
         Self {
             stream: Some(stream),
             udp_socket,
-            config,
             group,
             group_handle,
             nonce_pool,
             address_pool,
             register: None,
+            hello: None,
             bridge: None,
             node_handle: None
         }
@@ -515,14 +1344,45 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
         let key = &self.group.key;
         let mut rng = rand::rngs::SmallRng::from_entropy();
 
+        // the nodes before hello use another header, recognize it before reading a frame length from it
+        let mut head = [0u8; LEGACY_HEADER_LEN];
+
+        match stream.read_exact(&mut head).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(ClosedBeforeHello.into()),
+            Err(e) => return Err(e.into()),
+        }
+
+        if TcpMsg::is_legacy_header(key, &head) {
+            return Err(anyhow!("node predates protocol version {} and does not send hello, it must be upgraded", MIN_PROTOCOL_VERSION));
+        }
+
+        let remote = match TcpMsg::read_msg(&mut (&head[..]).chain(&mut *stream), key, buff).await? {
+            Some(TcpMsg::Hello(remote)) => remote,
+            Some(_) => return Err(anyhow!("node did not send hello")),
+            None => return Err(ClosedBeforeHello.into()),
+        };
+
+        let res = Hello::current().negotiate(&remote);
+
+        if let Err(e) = &res {
+            self.group_handle.register_failed(None, e);
+        }
+
+        let len = TcpMsg::hello_res_encode(key, rng.gen(), &res, buff)?;
+        TcpMsg::write_msg(stream, &buff[..len]).await?;
+
+        let hello = res.map_err(|e| anyhow!("node protocol version {} is not supported: {}", remote.version, e))?;
+        self.hello = Some(hello);
+
         loop {
             let nonce_pool = &self.nonce_pool;
             let msg = TcpMsg::read_msg(stream, key, buff).await?
                 .ok_or_else(|| anyhow!("node connection closed"))?;
 
             match msg {
-                TcpMsg::GetIdleVirtualAddr => {
-                    let addr = self.address_pool.get_idle_addr()
+                TcpMsg::GetIdleVirtualAddr(node_name) => {
+                    let addr = self.address_pool.get_idle_addr(node_name)
                         .map(|v| (v, self.group.address_range));
                     let len = TcpMsg::get_idle_virtual_addr_res_encode(key, rng.gen(), addr, buff)?;
                     TcpMsg::write_msg(stream, &buff[..len]).await?;
@@ -532,11 +1392,38 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
                     let remain = now - msg.register_time;
 
                     if !(-300..=300).contains(&remain) {
-                        let len = TcpMsg::register_res_encode(key, rng.gen(), &Err(RegisterError::Timeout), buff)?;
+                        let err = RegisterError::Timeout;
+                        self.group_handle.register_failed(Some(&msg.node_name), &err);
+                        let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
                         TcpMsg::write_msg(stream, &buff[..len]).await?;
                         return Err(anyhow!("register message timeout"));
                     }
 
+                    if let Err(e) = self.group_handle.verify_identity(hello.version, &msg) {
+                        let err = RegisterError::Unauthorized;
+                        self.group_handle.register_failed(Some(&msg.node_name), &err);
+                        let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
+                        TcpMsg::write_msg(stream, &buff[..len]).await?;
+                        return Err(e.context("node identity verification failed"));
+                    }
+
+                    if self.group_handle.is_banned(Some(&msg.node_name), stream.peer_addr()?.ip()) {
+                        let err = RegisterError::Banned;
+                        self.group_handle.register_failed(Some(&msg.node_name), &err);
+                        let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
+                        TcpMsg::write_msg(stream, &buff[..len]).await?;
+                        return Err(anyhow!("node {} is banned", msg.node_name));
+                    }
+
+                    // version 2 requires an ipv4 range
+                    if !self.group.ipv4 && hello.version <= v2::VERSION {
+                        let err = RegisterError::IncompatibleVersion { min: v2::VERSION + 1, max: PROTOCOL_VERSION };
+                        self.group_handle.register_failed(Some(&msg.node_name), &err);
+                        let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
+                        TcpMsg::write_msg(stream, &buff[..len]).await?;
+                        return Err(anyhow!("node {} with protocol version {} can't join a group without an ipv4 range", msg.node_name, hello.version));
+                    }
+
                     let res = {
                         let mut guard = nonce_pool.set.lock();
 
@@ -549,7 +1436,9 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
                     };
 
                     if !res {
-                        let len = TcpMsg::register_res_encode(key, rng.gen(), &Err(RegisterError::NonceRepeat), buff)?;
+                        let err = RegisterError::NonceRepeat;
+                        self.group_handle.register_failed(Some(&msg.node_name), &err);
+                        let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
                         TcpMsg::write_msg(stream, &buff[..len]).await?;
                         return Err(anyhow!("nonce repeat"));
                     }
@@ -561,36 +1450,72 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
                         nonce_pool.set.lock().remove(&msg.nonce);
                     });
 
-                    if let Err(e) = self.address_pool.allocate(msg.virtual_addr) {
-                        let len = TcpMsg::register_res_encode(key, rng.gen(), &Err(RegisterError::InvalidVirtualAddress(e)), buff)?;
+                    if let Err(e) = self.address_pool.allocate(msg.virtual_addr, &msg.node_name) {
+                        let err = RegisterError::InvalidVirtualAddress(e);
+                        self.group_handle.register_failed(Some(&msg.node_name), &err);
+                        let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
                         TcpMsg::write_msg(stream, &buff[..len]).await?;
                         return Err(anyhow!(e))
                     };
 
-                    self.register = Some(msg.clone());
+                    // nodes without ipv6 support only get the ipv4 address
+                    let res = if hello.has(CAP_IPV6) {
+                        self.address_pool.allocate_v6(msg.virtual_addr_v6, &msg.node_name)
+                    } else {
+                        Ok(None)
+                    };
+
+                    let virtual_addr_v6 = match res {
+                        Ok(v) => v,
+                        Err(e) => {
+                            self.address_pool.inner.lock().release(&msg.virtual_addr);
+                            let err = RegisterError::InvalidVirtualAddress(e);
+                            self.group_handle.register_failed(Some(&msg.node_name), &err);
+                            let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Err(err), buff)?;
+                            TcpMsg::write_msg(stream, &buff[..len]).await?;
+                            return Err(anyhow!(e))
+                        }
+                    };
+
+                    self.address_pool.leases.renew(&self.group.name, &msg.node_name, msg.virtual_addr, virtual_addr_v6);
+
+                    self.register = Some(Register {
+                        virtual_addr_v6,
+                        ..msg.clone()
+                    });
 
                     let node = Node {
                         name: msg.node_name,
                         virtual_addr: msg.virtual_addr,
+                        virtual_addr_v6,
                         lan_udp_addr: msg.lan_udp_socket_addr,
                         wan_udp_addr: None,
                         mode: msg.proto_mod,
                         allowed_ips: msg.allowed_ips,
                         register_time: msg.register_time,
-                        register_nonce: msg.nonce
+                        register_nonce: msg.nonce,
+                        capabilities: hello.capabilities
                     };
-                    let (bridge, node_handle) = self.group_handle.join(node)?;
+                    let (bridge, node_handle) = self.group_handle.join(node, stream.peer_addr()?, stream.connection().cloned(), msg.udp_counter)?;
+                    // later changes are pushed by the send handler
+                    let pushed = pushed_for(&hello, &bridge.pushed_rx.borrow());
                     self.bridge = Some(bridge);
-                    self.node_handle = Some(node_handle);
+                    self.node_handle = Some(node_handle.clone());
 
                     let gc = GroupContent {
                         name: self.group.name.clone(),
                         cidr: self.group.address_range,
-                        allow_udp_relay: self.group.allow_udp_relay,
-                        allow_tcp_relay: self.group.allow_tcp_relay
+                        ipv4: self.group.ipv4,
+                        cidr_v6: self.group.address_range_v6,
+                        virtual_addr_v6,
+                        allow_udp_relay: self.group_handle.allow_udp_relay.load(Ordering::Relaxed),
+                        allow_tcp_relay: self.group_handle.allow_tcp_relay.load(Ordering::Relaxed),
+                        pushed,
+                        udp_counter: self.group_handle.udp_nonce.counter(),
+                        node_udp_counter: node_handle.udp_replay_window.lock().top(),
                     };
 
-                    let len = TcpMsg::register_res_encode(key, rng.gen(), &Ok(gc), buff)?;
+                    let len = TcpMsg::register_res_encode(key, rng.gen(), hello.version, &Ok(gc), buff)?;
                     return TcpMsg::write_msg(stream,  &buff[..len]).await;
                 }
                 _ => return Err(anyhow!("init message error")),
@@ -599,9 +1524,10 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
     }
 
     async fn exec(&mut self) -> Result<()> {
-        let stream = self.stream.as_ref().unwrap();
-        stream.set_keepalive()?;
-        stream.set_nodelay(true)?;
+        if let Some(ControlStream::Tcp(stream)) = &self.stream {
+            stream.set_keepalive()?;
+            stream.set_nodelay(true)?;
+        }
 
         self.init().await?;
 
@@ -612,34 +1538,40 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
             _ => unreachable!(),
         };
 
-        let (rx, mut tx) = self.stream
-            .take()
-            .unwrap()
-            .into_split();
+        let stream = self.stream.take().unwrap();
+        let quic_conn = stream.connection().cloned();
 
+        // relay packets on the quic stream are the ones too large for a datagram
+        let relay_protocol = ternary!(quic_conn.is_some(), NetProtocol::QUIC, NetProtocol::TCP);
+
+        let (rx, mut tx) = stream.into_split();
         let mut rx = BufReader::with_capacity(TCP_BUFF_SIZE, rx);
         let (local_channel_tx, mut local_channel_rx) = mpsc::unbounded_channel();
         let (_notify, notified) = sync::watch::channel(());
-        let key = &self.group.key;
 
         let heartbeat_schedule = async {
             let mut notified = notified.clone();
+            let group = self.group.clone();
             let node_handle = node_handle.clone();
-            let config = self.config;
+            let group_handle = self.group_handle.clone();
             let local_channel_tx = local_channel_tx.clone();
             
             tokio::spawn(async move {
+                let key = &group.key;
+
                 let fut = async {
                     let mut rng = rand::rngs::SmallRng::from_entropy();
 
                     loop {
+                        let heartbeat = **group_handle.heartbeat.load();
+
                         let seq = {
                             let hc = &node_handle.tcp_heartbeat_cache;
 
                             let mut hc = hc.write();
                             hc.check();
 
-                            if hc.packet_continuous_loss_count >= config.tcp_heartbeat_continuous_loss {
+                            if hc.packet_continuous_loss_count >= heartbeat.tcp_continuous_loss {
                                 return Err(anyhow!("heartbeat receive timeout"))
                             }
 
@@ -651,7 +1583,7 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
                         TcpMsg::heartbeat_encode(key, rng.gen(), seq, HeartbeatType::Req, &mut buff);
 
                         local_channel_tx.send(buff).map_err(|e| anyhow!("{}", e))?;
-                        tokio::time::sleep(config.tcp_heartbeat_interval).await;
+                        tokio::time::sleep(heartbeat.tcp_interval).await;
                     }
                 };
 
@@ -664,20 +1596,21 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
 
         let recv_handler = async {
             let mut notified = notified.clone();
+            let group = self.group.clone();
             let group_handle = self.group_handle.clone();
             let node_handle = node_handle.clone();
             let udp_socket = self.udp_socket.clone();
-            let group = self.group;
             let local_channel_tx = local_channel_tx.clone();
 
             tokio::spawn(async move {
+                let key = &group.key;
+
                 let fut = async {
                     let mut buff = vec![0u8; TCP_BUFF_SIZE];
                     let mut rng = rand::rngs::SmallRng::from_entropy();
 
                     loop {
-                        let sub_buff = &mut buff[UDP_MSG_HEADER_LEN..];
-                        let msg = TcpMsg::read_msg(&mut rx, key, sub_buff).await?;
+                        let msg = TcpMsg::read_msg(&mut rx, key, &mut buff).await?;
 
                         let msg = match msg {
                             None => return Ok(()),
@@ -686,71 +1619,18 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
 
                         match msg {
                             TcpMsg::Relay(dst_virt_addr, packet) => {
-                                if !group.allow_tcp_relay {
-                                    continue;
-                                }
-
-                                let flow_control_res = group_handle.flow_control.push(dst_virt_addr, packet.len() as u64);
-
-                                if flow_control_res == PushResult::Reject {
-                                    continue;
-                                }
-
-                                let mut fut = None;
-                                {
-                                    let guard = group_handle.mapping.read();
-
-                                    if let Some(handle) = guard.get(&dst_virt_addr) {
-                                        let node = handle.node.load();
-
-                                        for np in &node.mode.relay {
-                                            match np {
-                                                NetProtocol::TCP => {
-                                                    let mut buff = allocator::alloc(TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>() + packet.len());
-                                                    buff[TCP_MSG_HEADER_LEN + size_of::<VirtualAddr>()..].copy_from_slice(packet);
-                                                    TcpMsg::relay_encode(key, rng.gen(), dst_virt_addr, packet.len(), &mut buff);
-
-                                                    match handle.tx.try_send(buff) {
-                                                        Ok(_) => {
-                                                            debug!("tcp handler: tcp message relay to node {}", node.name);
-                                                            break;
-                                                        },
-                                                        Err(e) => warn!("group {} send packet to tcp channel error: {}", group.name, e)
-                                                    }
-                                                }
-                                                NetProtocol::UDP =>  {
-                                                    let udp_status = handle.udp_status.load();
-
-                                                    let addr = match udp_status {
-                                                        UdpStatus::Available { dst_addr } => dst_addr,
-                                                        UdpStatus::Unavailable => continue
-                                                    };
-
-                                                    debug!("tcp handler: udp message relay to node {}", node.name);
-
-                                                    drop(node);
-                                                    drop(guard);
-
-                                                    let packet_len = packet.len();
-                                                    let len = UdpMsg::relay_encode(key, rng.gen(), dst_virt_addr, packet_len, &mut buff);
-
-                                                    fut = Some(UdpMsg::send_msg(&udp_socket, &buff[..len], addr));
-                                                    break;
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-
-                                if let Some(fut) = fut {
-                                    match fut.await {
-                                        Ok(_) => (),
-                                        Err(UdpSocketErr::FatalError(e)) => return Err(anyhow!(e)),
-                                        Err(UdpSocketErr::SuppressError(e)) => {
-                                            warn!("group {} send udp packet warn {}", group.name, e);
-                                        }
-                                    }
-                                }
+                                let packet_len = packet.len();
+
+                                group_handle.relay_from_node(
+                                    key,
+                                    &udp_socket,
+                                    &node_handle,
+                                    relay_protocol,
+                                    rng.gen(),
+                                    dst_virt_addr,
+                                    &mut buff,
+                                    packet_len
+                                ).await?;
                             }
                             TcpMsg::Heartbeat(seq, HeartbeatType::Req) => {
                                 let mut buff = allocator::alloc(TCP_MSG_HEADER_LEN + size_of::<Seq>() + size_of::<HeartbeatType>());
@@ -799,10 +1679,72 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
             }).await?
         };
 
+        let datagram_handler = async {
+            let conn = match &quic_conn {
+                None => return std::future::pending().await,
+                Some(conn) => conn.clone()
+            };
+
+            let mut notified = notified.clone();
+            let group = self.group.clone();
+            let group_handle = self.group_handle.clone();
+            let node_handle = node_handle.clone();
+            let udp_socket = self.udp_socket.clone();
+
+            tokio::spawn(async move {
+                let key = &group.key;
+
+                let fut = async {
+                    let mut buff = vec![0u8; TCP_BUFF_SIZE];
+                    let mut rng = rand::rngs::SmallRng::from_entropy();
+
+                    loop {
+                        let datagram = conn.read_datagram().await?;
+
+                        let frame = match buff.get_mut(..datagram.len()) {
+                            None => continue,
+                            Some(frame) => frame
+                        };
+                        frame.copy_from_slice(&datagram);
+
+                        let (dst_virt_addr, packet_len) = match TcpMsg::decode_frame(key, frame) {
+                            Ok(TcpMsg::Relay(dst_virt_addr, packet)) => (dst_virt_addr, packet.len()),
+                            Ok(_) => continue,
+                            Err(e) => {
+                                warn!("group {} node {} sent invalid quic datagram: {}", group_handle.name, node_handle.node.load().name, e);
+                                continue;
+                            }
+                        };
+
+                        group_handle.relay_from_node(
+                            key,
+                            &udp_socket,
+                            &node_handle,
+                            NetProtocol::QUIC,
+                            rng.gen(),
+                            dst_virt_addr,
+                            &mut buff,
+                            packet_len
+                        ).await?;
+                    }
+                };
+
+                tokio::select! {
+                    res = fut => res,
+                    _ = notified.changed() => Result::<()>::Err(anyhow!("abort task"))
+                }
+            }).await?
+        };
+
         let send_handler = async {
             let mut notified = notified.clone();
+            let group = self.group.clone();
+            let hello = self.hello.unwrap();
+            let push_config = hello.has(CAP_PUSH_CONFIG);
+            let quic_supported = hello.has(CAP_QUIC);
 
             tokio::spawn(async move {
+                let key = &group.key;
                 let mut buff = vec![0u8; TCP_BUFF_SIZE];
                 let mut rng = rand::rngs::SmallRng::from_entropy();
 
@@ -813,8 +1755,27 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
                                 return Result::<(), _>::Err(anyhow!(e));
                             }
 
-                            let node_list: Arc<NodeMap> = bridge.watch_rx.borrow().clone();
-                            let len = TcpMsg::node_map_encode(key, rng.gen(), &node_list, &mut buff)?;
+                            let mut node_list: Arc<NodeMap> = bridge.watch_rx.borrow().clone();
+
+                            // older nodes fail to decode the QUIC protocol
+                            if !quic_supported && node_list.values().any(|node| node.mode.is_use_quic()) {
+                                let legacy = node_list.iter()
+                                    .map(|(addr, node)| (*addr, Node { mode: node.mode.without_quic(), ..node.clone() }))
+                                    .collect();
+
+                                node_list = Arc::new(legacy);
+                            }
+
+                            let len = TcpMsg::node_map_encode(key, rng.gen(), hello.version, &node_list, &mut buff)?;
+                            TcpMsg::write_msg(&mut tx, &buff[..len]).await?;
+                        }
+                        res = bridge.pushed_rx.changed(), if push_config => {
+                            if let Err(e) = res {
+                                return Result::<(), _>::Err(anyhow!(e));
+                            }
+
+                            let pushed = pushed_for(&hello, &bridge.pushed_rx.borrow());
+                            let len = TcpMsg::push_config_encode(key, rng.gen(), &pushed, &mut buff)?;
                             TcpMsg::write_msg(&mut tx, &buff[..len]).await?;
                         }
                         res = bridge.channel_rx.recv() => {
@@ -831,11 +1792,24 @@ impl<K: Cipher + Clone + Send + Sync> Tunnel<K> {
             }).await?
         };
 
+        let lease_renewal = async {
+            let reg = self.register.as_ref().unwrap();
+            let leases = &self.address_pool.leases;
+
+            loop {
+                time::sleep(leases.renew_interval()).await;
+                leases.renew(&self.group_handle.name, &reg.node_name, reg.virtual_addr, reg.virtual_addr_v6);
+            }
+        };
+
         // A single task ends normally
         tokio::select! {
             res = heartbeat_schedule => res,
+            res = lease_renewal => res,
             res = recv_handler => res,
+            res = datagram_handler => res,
             res = send_handler => res,
+            _ = node_handle.kick.notified() => Err(anyhow!("node has been kicked")),
         }
     }
 }
@@ -851,8 +1825,32 @@ impl<T> Drop for Tunnel<T> {
                 }
             }
 
-            self.group_handle.flow_control.remove_address(&reg.virtual_addr);
-            self.address_pool.inner.lock().release(&reg.virtual_addr)
+            if let Some(node_handle) = &self.node_handle {
+                let top = node_handle.udp_replay_window.lock().top();
+                let mut counters = self.group_handle.udp_counters.lock();
+                let counter = counters.entry(reg.node_name.clone()).or_default();
+                *counter = (*counter).max(top);
+                drop(counters);
+
+                self.group_handle.event(Event::NodeDisconnected {
+                    node: reg.node_name.clone(),
+                    addr: reg.virtual_addr,
+                });
+            }
+
+            self.group_handle.flow_control.load().remove_address(&reg.virtual_addr);
+
+            {
+                let mut pool = self.address_pool.inner.lock();
+                pool.release(&reg.virtual_addr);
+
+                if let Some(addr) = &reg.virtual_addr_v6 {
+                    pool.release_v6(addr);
+                }
+            }
+
+            // the lease is kept for the configured duration after the node goes offline
+            self.address_pool.leases.renew(&self.group_handle.name, &reg.node_name, reg.virtual_addr, reg.virtual_addr_v6);
         }
     }
 }
@@ -860,46 +1858,141 @@ impl<T> Drop for Tunnel<T> {
 struct AddressPoolInner {
     used: HashSet<Ipv4Addr>,
     cidr: Ipv4Net,
+    used_v6: HashSet<Ipv6Addr>,
+    cidr_v6: Option<Ipv6Net>,
+    // addresses leased by the other servers of the cluster
+    remote: HashSet<Ipv4Addr>,
+    remote_v6: HashSet<Ipv6Addr>,
+    // reserved address -> owner node name
+    reserved: HashMap<Ipv4Addr, String>,
 }
 
 impl AddressPoolInner {
-    fn new(cidr: Ipv4Net) -> Result<Self> {
+    fn new(cidr: Ipv4Net, cidr_v6: Option<Ipv6Net>, reservations: &HashMap<String, Ipv4Addr>) -> Result<Self> {
         let pool = AddressPoolInner {
             used: HashSet::new(),
             cidr,
+            used_v6: HashSet::new(),
+            cidr_v6,
+            remote: HashSet::new(),
+            remote_v6: HashSet::new(),
+            reserved: reservations.iter().map(|(name, addr)| (*addr, name.clone())).collect(),
         };
         Ok(pool)
     }
 
-    fn get_idle_addr(&mut self) -> Option<Ipv4Addr> {
-        self.cidr
+    fn is_reserved_by_others(&self, addr: &Ipv4Addr, node_name: Option<&str>) -> bool {
+        self.reserved.get(addr).is_some_and(|owner| Some(owner.as_str()) != node_name)
+    }
+
+    fn is_idle(&self, addr: &Ipv4Addr) -> bool {
+        self.cidr.contains(addr) &&
+            self.cidr.network() != *addr &&
+            self.cidr.broadcast() != *addr &&
+            !self.used.contains(addr) &&
+            !self.remote.contains(addr)
+    }
+
+    fn is_idle_v6(&self, addr: &Ipv6Addr) -> bool {
+        match self.cidr_v6 {
+            None => false,
+            Some(cidr) => {
+                cidr.contains(addr) &&
+                    cidr.network() != *addr &&
+                    !self.used_v6.contains(addr) &&
+                    !self.remote_v6.contains(addr)
+            }
+        }
+    }
+
+    // addresses leased to the other nodes are only handed out when nothing else is left
+    fn get_idle_addr(&self, leased: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        let mut idle = self.cidr
             .hosts()
-            .find(|&v| !self.used.contains(&v))
+            .filter(|v| !self.used.contains(v) && !self.remote.contains(v) && !self.reserved.contains_key(v));
+
+        let first = idle.next()?;
+
+        if !leased.contains(&first) {
+            return Some(first);
+        }
+        idle.find(|v| !leased.contains(v)).or(Some(first))
+    }
+
+    fn get_idle_addr_v6(&self, leased: &HashSet<Ipv6Addr>) -> Option<Ipv6Addr> {
+        // skip the subnet-router anycast address
+        let mut idle = self.cidr_v6?
+            .hosts()
+            .skip(1)
+            .filter(|v| !self.used_v6.contains(v) && !self.remote_v6.contains(v));
+
+        let first = idle.next()?;
+
+        if !leased.contains(&first) {
+            return Some(first);
+        }
+        idle.find(|v| !leased.contains(v)).or(Some(first))
     }
 
     fn release(&mut self, addr: &Ipv4Addr) {
         self.used.remove(addr);
     }
+
+    fn release_v6(&mut self, addr: &Ipv6Addr) {
+        self.used_v6.remove(addr);
+    }
 }
 
 struct AddressPool {
+    group_name: String,
     inner: Arc<Mutex<AddressPoolInner>>,
+    leases: Arc<LeaseStore>,
 }
 
 impl AddressPool {
-    fn new(address_range: Ipv4Net) -> Result<Self> {
+    fn new(
+        group_name: String,
+        address_range: Ipv4Net,
+        address_range_v6: Option<Ipv6Net>,
+        reservations: &HashMap<String, Ipv4Addr>,
+        leases: Arc<LeaseStore>
+    ) -> Result<Self> {
         let pool = AddressPool {
-            inner: Arc::new(Mutex::new(AddressPoolInner::new(address_range)?)),
+            group_name,
+            inner: Arc::new(Mutex::new(AddressPoolInner::new(address_range, address_range_v6, reservations)?)),
+            leases,
         };
         Ok(pool)
     }
 
-    fn get_idle_addr(&self) -> Option<Ipv4Addr> {
-        let addr = self.inner.lock().get_idle_addr()?;
-        Some(addr)
+    // the reserved address of the node comes first, then the previous one
+    fn get_idle_addr(&self, node_name: Option<&str>) -> Option<Ipv4Addr> {
+        let guard = self.inner.lock();
+
+        if let Some(name) = node_name {
+            let reserved = guard.reserved
+                .iter()
+                .find(|(_, owner)| *owner == name)
+                .map(|(addr, _)| *addr);
+
+            if let Some(addr) = reserved {
+                if guard.is_idle(&addr) {
+                    return Some(addr);
+                }
+            }
+        }
+
+        if let Some(lease) = node_name.and_then(|name| self.leases.get(&self.group_name, name)) {
+            if guard.is_idle(&lease.virtual_addr) && !guard.is_reserved_by_others(&lease.virtual_addr, node_name) {
+                return Some(lease.virtual_addr);
+            }
+        }
+
+        let (leased, _) = self.leases.leased_by_others(&self.group_name, node_name);
+        guard.get_idle_addr(&leased)
     }
 
-    fn allocate(&self, ip: Ipv4Addr) -> Result<(), AllocateError> {
+    fn allocate(&self, ip: Ipv4Addr, node_name: &str) -> Result<(), AllocateError> {
         let mut guard = self.inner.lock();
 
         if !guard.cidr.contains(&ip) {
@@ -914,13 +2007,78 @@ impl AddressPool {
             return Err(AllocateError::IpSameAsBroadcastAddress);
         }
 
-        if guard.used.contains(&ip) {
+        if guard.is_reserved_by_others(&ip, Some(node_name)) {
+            return Err(AllocateError::IpReserved);
+        }
+
+        if guard.used.contains(&ip) || guard.remote.contains(&ip) {
             return Err(AllocateError::IpAlreadyInUse);
         }
 
         guard.used.insert(ip);
         Ok(())
     }
+
+    // nodes already holding a newly reserved address keep it until they go offline
+    fn set_reservations(&self, reservations: &HashMap<String, Ipv4Addr>) {
+        self.inner.lock().reserved = reservations.iter().map(|(name, addr)| (*addr, name.clone())).collect();
+    }
+
+    fn set_remote_leases<'a>(&self, nodes: impl Iterator<Item = &'a Node>) {
+        let mut guard = self.inner.lock();
+        guard.remote.clear();
+        guard.remote_v6.clear();
+
+        for node in nodes {
+            guard.remote.insert(node.virtual_addr);
+
+            if let Some(addr) = node.virtual_addr_v6 {
+                guard.remote_v6.insert(addr);
+            }
+        }
+    }
+
+    // allocate the requested ipv6 address or an idle one, returns None if the group has no ipv6 range
+    fn allocate_v6(&self, ip: Option<Ipv6Addr>, node_name: &str) -> Result<Option<Ipv6Addr>, AllocateError> {
+        let mut guard = self.inner.lock();
+
+        let cidr = match guard.cidr_v6 {
+            None => return Ok(None),
+            Some(cidr) => cidr
+        };
+
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                let prev = self.leases.get(&self.group_name, node_name)
+                    .and_then(|lease| lease.virtual_addr_v6)
+                    .filter(|addr| guard.is_idle_v6(addr));
+
+                match prev {
+                    Some(addr) => addr,
+                    None => {
+                        let (_, leased) = self.leases.leased_by_others(&self.group_name, Some(node_name));
+                        guard.get_idle_addr_v6(&leased).ok_or(AllocateError::IpAlreadyInUse)?
+                    }
+                }
+            }
+        };
+
+        if !cidr.contains(&ip) {
+            return Err(AllocateError::IpNotBelongNetworkRange);
+        }
+
+        if cidr.network() == ip {
+            return Err(AllocateError::IpSameAsNetworkAddress);
+        }
+
+        if guard.used_v6.contains(&ip) || guard.remote_v6.contains(&ip) {
+            return Err(AllocateError::IpAlreadyInUse);
+        }
+
+        guard.used_v6.insert(ip);
+        Ok(Some(ip))
+    }
 }
 
 struct NoncePool {
@@ -935,144 +2093,495 @@ impl NoncePool {
     }
 }
 
-pub async fn start<K>(config: ServerConfigFinalize<K>) -> Result<()>
+// the sockets and the address pool are rebuilt, every node of the group registers again
+fn needs_restart<K>(old: &GroupFinalize<K>, new: &GroupFinalize<K>) -> bool {
+    old.listen_addr != new.listen_addr ||
+        old.quic.as_ref().map(|q| (q.listen_addr, &q.files)) != new.quic.as_ref().map(|q| (q.listen_addr, &q.files)) ||
+        old.key_config != new.key_config ||
+        old.address_range != new.address_range ||
+        old.ipv4 != new.ipv4 ||
+        old.address_range_v6 != new.address_range_v6
+}
+
+// the handlers of a group stop when abort is dropped
+struct RunningGroup<K> {
+    config: Arc<GroupFinalize<K>>,
+    handle: Arc<GroupHandle>,
+    udp_socket: Arc<UdpSocket>,
+    abort: watch::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl<K> RunningGroup<K> {
+    // the registered nodes are disconnected, wait until the sockets are closed
+    async fn stop(self) {
+        drop(self.abort);
+        let _ = self.task.await;
+    }
+}
+
+async fn run_group<K>(group: Arc<GroupFinalize<K>>, gh: Arc<GroupHandle>) -> Result<RunningGroup<K>>
     where
         K: Cipher + Clone + Send + Sync + 'static
 {
-    let config = &*Box::leak(Box::new(config));
-    let mut futures = Vec::with_capacity(config.groups.len());
-    let mut group_handles = Vec::with_capacity(config.groups.len());
+    let listen_addr = group.listen_addr;
 
-    for group in &config.groups {
-        let gh = Arc::new(GroupHandle::new(config.channel_limit, group));
-        group_handles.push(gh.clone());
+    let udp_socket = UdpSocket::bind(listen_addr)
+        .await
+        .with_context(|| format!("udp socket bind {} error", listen_addr))?;
 
-        let fut = async {
-            let listen_addr = group.listen_addr;
+    let udp_socket = Arc::new(udp_socket);
 
-            let udp_socket = UdpSocket::bind(listen_addr)
-                .await
-                .with_context(|| format!("udp socket bind {} error", listen_addr))?;
+    info!("group {} udp socket listening on {}", group.name, listen_addr);
 
-            let udp_socket = Arc::new(udp_socket);
+    let tcp_listener = TcpListener::bind(listen_addr)
+        .await
+        .with_context(|| format!("tcp socket bind {} error", listen_addr))?;
 
-            info!("group {} udp socket listening on {}", group.name, listen_addr);
+    info!("group {} tcp socket listening on {}", group.name, listen_addr);
 
-            let tcp_listener = TcpListener::bind(listen_addr)
-                .await
-                .with_context(|| format!("tcp socket bind {} error", listen_addr))?;
+    let endpoint = match &group.quic {
+        None => None,
+        Some(quic) => {
+            let endpoint = quinn::Endpoint::server(quic.server_config.clone(), quic.listen_addr)
+                .with_context(|| format!("quic endpoint bind {} error", quic.listen_addr))?;
 
-            info!("group {} tcp socket listening on {}", group.name, listen_addr);
+            info!("group {} quic endpoint listening on {}", group.name, quic.listen_addr);
+            Some(endpoint)
+        }
+    };
 
-            let (_notify, notified) = watch::channel(());
-            let gh1 = gh.clone();
+    let (abort, notified) = watch::channel(());
+    let gh1 = gh.clone();
+    let udp_socket1 = udp_socket.clone();
+    let group1 = group.clone();
+
+    let fut = async move {
+        let udp_handle = async {
+            let fut = udp_handler(
+                group.clone(),
+                udp_socket.clone(),
+                gh1.clone(),
+                notified.clone()
+            );
+
+            fut.await.context("udp handler error")
+        };
 
-            let udp_handle = async {
-                let fut = udp_handler(
-                    group,
-                    udp_socket.clone(),
-                    gh1,
-                    config.udp_heartbeat_interval,
-                    config.udp_heartbeat_continuous_loss,
-                    config.udp_heartbeat_continuous_recv,
-                    notified.clone()
-                );
+        let tcp_handle = async {
+            let mut notified = notified.clone();
+
+            let fut = tcp_handler(
+                tcp_listener,
+                udp_socket.clone(),
+                group.clone(),
+                gh1.clone(),
+                notified.clone()
+            );
+
+            tokio::spawn(async move {
+                tokio::select! {
+                    res = fut => res,
+                    _ = notified.changed() => Err(anyhow!("abort task"))
+                }
+            })
+            .await?
+            .context("tcp handler error")
+        };
 
-                fut.await.context("udp handler error")
+        let quic_handle = async {
+            let endpoint = match &endpoint {
+                None => return std::future::pending().await,
+                Some(endpoint) => endpoint.clone()
             };
 
-            let tcp_handle = async {
-                let mut notified = notified.clone();
+            let mut notified = notified.clone();
 
-                let fut = tcp_handler(
-                    tcp_listener,
-                    udp_socket.clone(),
-                    config,
-                    group,
-                    gh,
-                    notified.clone()
-                );
+            let fut = quic_handler(
+                endpoint,
+                udp_socket.clone(),
+                group.clone(),
+                gh1.clone(),
+                notified.clone()
+            );
 
-                tokio::spawn(async move {
-                    tokio::select! {
-                        res = fut => res,
-                        _ = notified.changed() => Err(anyhow!("abort task"))
+            tokio::spawn(async move {
+                tokio::select! {
+                    res = fut => res,
+                    _ = notified.changed() => Err(anyhow!("abort task"))
+                }
+            })
+            .await?
+            .context("quic handler error")
+        };
+
+        let res = tokio::try_join!(udp_handle, tcp_handle, quic_handle);
+
+        // release the udp port before the group restarts
+        if let Some(endpoint) = &endpoint {
+            endpoint.close(0u32.into(), b"");
+            endpoint.wait_idle().await;
+        }
+
+        match res {
+            Ok(_) => (),
+            // the sender is dropped when the group is removed
+            Err(_) if notified.has_changed().is_err() => info!("group {} server stopped", group.name),
+            Err(e) => error!("group {} server error: {:?}", group.name, e)
+        }
+    };
+
+    info!("group {} server start", group1.name);
+
+    let rg = RunningGroup {
+        config: group1,
+        handle: gh,
+        udp_socket: udp_socket1,
+        abort,
+        task: tokio::spawn(fut),
+    };
+    Ok(rg)
+}
+
+struct Server<K> {
+    config: Arc<ServerConfigFinalize<K>>,
+    groups: Vec<RunningGroup<K>>,
+    // shared with the api
+    group_handles: Arc<ArcSwap<Vec<Arc<GroupHandle>>>>,
+    leases: Arc<LeaseStore>,
+    quotas: Arc<QuotaStore>,
+    bans: Arc<BanStore>,
+    revocations: Arc<RevocationStore>,
+    events: Arc<EventBus>,
+    cluster: Option<Arc<ClusterHandle>>,
+}
+
+impl<K> Server<K>
+    where
+        K: Cipher + Clone + Send + Sync + 'static
+{
+    async fn add_group(&mut self, group: Arc<GroupFinalize<K>>) -> Result<()> {
+        let gh = GroupHandle::new(&self.config, &group, self.leases.clone(), self.quotas.clone(), self.bans.clone(), self.revocations.clone(), self.events.clone(), self.cluster.clone())?;
+        let rg = run_group(group, Arc::new(gh)).await?;
+        self.groups.push(rg);
+        Ok(())
+    }
+
+    fn publish(&self) {
+        let list = self.groups.iter().map(|g| g.handle.clone()).collect();
+        self.group_handles.store(Arc::new(list));
+    }
+
+    async fn reload(&mut self, config: ServerConfigFinalize<K>) {
+        let config = Arc::new(config);
+        let old = std::mem::replace(&mut self.config, config.clone());
+
+        let restart_required = [
+            ("channel_limit", old.channel_limit != config.channel_limit),
+            ("api_addr", old.api_addr != config.api_addr),
+            ("api_token", old.api_token != config.api_token),
+            ("api_tls", old.api_tls.is_some() != config.api_tls.is_some()),
+            ("lease_file", old.lease_file != config.lease_file),
+            ("ban_file", old.ban_file != config.ban_file),
+            ("revocation_file", old.revocation_file != config.revocation_file),
+            ("lease_duration_secs", old.lease_duration != config.lease_duration),
+            ("quota_file", old.quota_file != config.quota_file),
+            ("cluster", old.cluster.as_ref().map(|c| (c.listen_addr, &c.peers)) != config.cluster.as_ref().map(|c| (c.listen_addr, &c.peers))),
+            ("webhooks", old.webhooks != config.webhooks),
+        ];
+
+        for (field, changed) in restart_required {
+            if changed {
+                warn!("reload: {} has changed, it takes effect after restart", field);
+            }
+        }
+
+        let mut stopped = Vec::new();
+        let mut i = 0;
+
+        while i < self.groups.len() {
+            let running = &mut self.groups[i];
+            let new = config.groups.iter().find(|g| g.name == running.config.name);
+
+            match new {
+                Some(new) if !needs_restart(&running.config, new) => {
+                    running.handle.update(&config, new);
+                    running.config = new.clone();
+                    info!("reload: group {} updated", new.name);
+                    i += 1;
+                    continue;
+                }
+                _ => ()
+            }
+
+            stopped.push((self.groups.remove(i), new.cloned()));
+        }
+
+        for (running, new) in stopped {
+            let name = running.config.name.clone();
+            running.stop().await;
+
+            let new = match new {
+                None => {
+                    info!("reload: group {} removed", name);
+                    continue;
+                }
+                Some(v) => v
+            };
+
+            // the old sockets may still be held by queued relay packets for a moment
+            let mut retry = 0;
+
+            let res = loop {
+                match self.add_group(new.clone()).await {
+                    Err(_) if retry < 10 => {
+                        retry += 1;
+                        time::sleep(Duration::from_millis(200)).await;
                     }
-                })
-                .await?
-                .context("tcp handler error")
+                    res => break res
+                }
             };
 
-            tokio::try_join!(udp_handle, tcp_handle).map(|_| ())
-        };
+            match res {
+                Ok(()) => info!("reload: group {} restarted", name),
+                Err(e) => error!("reload: group {} restart error: {:?}", name, e)
+            }
+        }
+
+        for group in &config.groups {
+            if self.groups.iter().any(|g| g.config.name == group.name) {
+                continue;
+            }
+
+            match self.add_group(group.clone()).await {
+                Ok(()) => info!("reload: group {} added", group.name),
+                Err(e) => error!("reload: group {} start error: {:?}", group.name, e)
+            }
+        }
+
+        self.publish();
+    }
+}
+
+// the cluster links are bound to the groups at startup
+fn groups_changed<K>(running: &[Arc<GroupFinalize<K>>], new: &[Arc<GroupFinalize<K>>]) -> bool {
+    running.len() != new.len() ||
+        running.iter().any(|old| {
+            match new.iter().find(|g| g.name == old.name) {
+                None => true,
+                Some(new) => needs_restart(old, new)
+            }
+        })
+}
+
+// config_loader reads the config file again on reload
+pub async fn start<K, F>(config: ServerConfigFinalize<K>, config_loader: F) -> Result<()>
+    where
+        K: Cipher + Clone + Send + Sync + 'static,
+        F: Fn() -> Result<ServerConfigFinalize<K>> + Send + Sync + 'static
+{
+    let config = Arc::new(config);
 
-        futures.push(async {
-            info!("group {} server start", group.name);
+    let cluster_handle = config.cluster.as_ref().map(|_| Arc::new(ClusterHandle::new()));
+    let leases = Arc::new(LeaseStore::load(config.lease_file.clone(), config.lease_duration)?);
+    let quotas = Arc::new(QuotaStore::load(config.quota_file.clone())?);
+    let bans = Arc::new(BanStore::load(config.ban_file.clone())?);
+    let revocations = Arc::new(RevocationStore::load(config.revocation_file.clone())?);
 
-            if let Err(e) = fut.await {
-                error!("group {} server error: {:?}", group.name, e)
+    if config.quota_file.is_some() {
+        let quotas = quotas.clone();
+        tokio::spawn(async move { quotas.save_schedule().await });
+    }
+
+    if config.lease_file.is_some() {
+        let leases = leases.clone();
+        tokio::spawn(async move { leases.save_schedule().await });
+    }
+
+    if config.ban_file.is_some() {
+        let bans = bans.clone();
+        tokio::spawn(async move { bans.save_schedule().await });
+    }
+
+    if config.revocation_file.is_some() {
+        let revocations = revocations.clone();
+        tokio::spawn(async move { revocations.save_schedule().await });
+    }
+
+    // saved again on exit
+    let exit_quotas = quotas.clone();
+    let exit_leases = leases.clone();
+    let exit_bans = bans.clone();
+    let exit_revocations = revocations.clone();
+
+    let mut server = Server {
+        config: config.clone(),
+        groups: Vec::with_capacity(config.groups.len()),
+        group_handles: Arc::new(ArcSwap::from_pointee(Vec::new())),
+        leases,
+        quotas,
+        bans,
+        revocations,
+        events: Arc::new(EventBus::new()),
+        cluster: cluster_handle.clone(),
+    };
+
+    // subscribe before the groups start, so the first registrations are delivered
+    if !config.webhooks.is_empty() {
+        let events = server.events.receiver();
+        let webhooks = config.webhooks.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = webhook_schedule(webhooks, events).await {
+                error!("webhook error: {:?}", e);
             }
         });
     }
 
-    let handle = async {
-        futures_util::future::join_all(futures).await;
+    for group in &config.groups {
+        if let Err(e) = server.add_group(group.clone()).await {
+            error!("group {} server error: {:?}", group.name, e)
+        }
+    }
+    server.publish();
+
+    let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+
+    let fixed_groups: Option<Vec<_>> = config.cluster.as_ref()
+        .map(|_| server.groups.iter().map(|g| g.config.clone()).collect());
+
+    // the config is checked before it is queued, so errors go back to the caller
+    let reload: Reload = Arc::new(move || {
+        let config = config_loader()?;
+
+        if fixed_groups.as_ref().is_some_and(|groups| groups_changed(groups, &config.groups)) {
+            return Err(anyhow!("groups can't be added, removed or restarted in a cluster"));
+        }
+
+        reload_tx.send(config).map_err(|_| anyhow!("server is shutting down"))
+    });
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reload = reload.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("received SIGHUP, reloading config");
+
+                if let Err(e) = reload() {
+                    error!("reload config error: {:?}", e);
+                }
+            }
+        });
+    }
+
+    let cluster_groups = server.groups.iter()
+        .map(|g| (g.config.clone(), g.handle.clone(), g.udp_socket.clone()))
+        .collect();
+
+    let api_handle = api_start(config.api_addr, config.api_token.clone(), config.api_tls.clone(), server.group_handles.clone(), reload, server.events.clone());
+
+    let reload_handle = async {
+        while let Some(config) = reload_rx.recv().await {
+            server.reload(config).await;
+        }
         Ok(())
     };
 
-    let api_handle = api_start(config.api_addr, group_handles);
-    tokio::try_join!(handle, api_handle)?;
+    let cluster_handle = async {
+        match (&config.cluster, cluster_handle) {
+            (Some(cluster_config), Some(cluster_handle)) => {
+                cluster::start(cluster_config.clone(), cluster_handle, cluster_groups).await.context("cluster error")
+            }
+            _ => Ok(())
+        }
+    };
+
+    tokio::select! {
+        res = async { tokio::try_join!(reload_handle, api_handle, cluster_handle) } => {
+            res?;
+        }
+        res = shutdown_signal() => res?,
+    }
+
+    if let Err(e) = exit_quotas.save().await {
+        warn!("save quota usage error: {:?}", e);
+    }
+
+    if let Err(e) = exit_leases.save().await {
+        warn!("save leases error: {:?}", e);
+    }
+
+    if let Err(e) = exit_bans.save().await {
+        warn!("save bans error: {:?}", e);
+    }
+
+    if let Err(e) = exit_revocations.save().await {
+        warn!("save revocations error: {:?}", e);
+    }
     Ok(())
 }
 
-pub async fn info(api_addr: &str, info_type: ServerInfoType) -> Result<()> {
-    let req = Request::builder()
-    .method(Method::GET)
-    .uri(format!("http://{}/info", api_addr))
-    .body(Empty::<hyper::body::Bytes>::new())?;
+// ctrl-c, or SIGTERM on unix
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    let stream = TcpStream::connect(api_addr).await?;
-    let stream = TokioIo::new(stream);
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(stream).await?;
+        let mut terminate = signal(SignalKind::terminate())?;
 
-    tokio::spawn(conn);
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => ()
+        }
+    }
 
-    let resp = sender.send_request(req).await?;
-    let (parts, body) = resp.into_parts();
-    let bytes = body.collect().await?.to_bytes();
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
 
-    if parts.status != 200 {
-        let msg = String::from_utf8(bytes.to_vec())?;
-        return Err(anyhow!("http response code: {}, message: {}", parts.status.as_u16(), msg));
-    }
+    info!("server is shutting down");
+    Ok(())
+}
+
+pub async fn info(client: &ApiClient, info_type: ServerInfoType) -> Result<()> {
+    let bytes = client.get("/info").await?;
 
     let groups: Vec<GroupInfo> = serde_json::from_slice(bytes.deref())?;
     let mut table = Table::new();
 
     match info_type {
         ServerInfoType::Group => {
-            table.add_row(row!["NAME", "LISTENING_ADDRESS", "ADDRESS_RANGE"]);
+            table.add_row(row!["NAME", "LISTENING_ADDRESS", "ADDRESS_RANGE", "ADDRESS_RANGE_V6"]);
 
             for group in groups {
                 table.add_row(row![
                     group.name,
                     group.listen_addr,
-                    group.address_range
+                    group.address_range.map(|v| v.to_string()).unwrap_or_default(),
+                    group.address_range_v6.map(|v| v.to_string()).unwrap_or_default()
                 ]);
             }
         }
         ServerInfoType::NodeMap { group_name, node_ip: None } => {
-            table.add_row(row!["NAME", "IP", "REGISTER_TIME"]);
+            table.add_row(row!["NAME", "IP", "REGISTER_TIME", "RELAY_RX", "RELAY_TX"]);
 
             for group in groups {
                 if group.name == group_name {
                     for node in group.node_map.values() {
                         let register_time = utc_to_str(node.node.register_time)?;
 
+                        let traffic = &node.traffic;
+
                         table.add_row(row![
                             node.node.name,
                             node.node.virtual_addr,
                             register_time,
+                            format_bytes(traffic.udp_relay.rx_bytes + traffic.tcp_relay.rx_bytes + traffic.quic_relay.rx_bytes),
+                            format_bytes(traffic.udp_relay.tx_bytes + traffic.tcp_relay.tx_bytes + traffic.quic_relay.tx_bytes),
                         ]);
                     }
                     break;
@@ -1087,6 +2596,7 @@ pub async fn info(api_addr: &str, info_type: ServerInfoType) -> Result<()> {
 
                         table.add_row(row!["NAME", node.node.name]);
                         table.add_row(row!["IP", node.node.virtual_addr]);
+                        table.add_row(row!["IPV6", node.node.virtual_addr_v6.map(|v| v.to_string()).unwrap_or_default()]);
                         table.add_row(row!["LAN_ADDRESS", format!("{:?}", node.node.lan_udp_addr)]);
                         table.add_row(row!["WAN_ADDRESS", format!("{:?}", node.node.wan_udp_addr)]);
                         table.add_row(row!["PROTOCOL_MODE",  format!("{:?}", node.node.mode)]);
@@ -1102,6 +2612,10 @@ pub async fn info(api_addr: &str, info_type: ServerInfoType) -> Result<()> {
 
                         let tcp_loss_rate = node.tcp_heartbeat_cache.packet_loss_count as f32 / node.tcp_heartbeat_cache.send_count as f32 * 100f32;
                         table.add_row(row!["TCP_LOSS_RATE", ternary!(!tcp_loss_rate.is_nan(), format!("{}%", tcp_loss_rate), String::new())]);
+                        table.add_row(row!["UDP_REPLAY_DROPPED", node.udp_replay_dropped]);
+                        table.add_row(row!["UDP_RELAY_TRAFFIC", node.traffic.udp_relay]);
+                        table.add_row(row!["TCP_RELAY_TRAFFIC", node.traffic.tcp_relay]);
+                        table.add_row(row!["QUIC_RELAY_TRAFFIC", node.traffic.quic_relay]);
                     }
                     break;
                 }
@@ -1111,4 +2625,41 @@ pub async fn info(api_addr: &str, info_type: ServerInfoType) -> Result<()> {
 
     table.printstd();
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn address_pool_reservation_test() {
+    let mut reservations = HashMap::new();
+    reservations.insert(String::from("n1"), Ipv4Addr::new(10, 0, 0, 1));
+
+    let leases = Arc::new(LeaseStore::load(None, Duration::from_secs(60)).unwrap());
+    let pool = AddressPool::new(String::from("g"), "10.0.0.0/24".parse().unwrap(), None, &reservations, leases).unwrap();
+
+    assert_eq!(pool.get_idle_addr(Some("n1")), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(pool.get_idle_addr(Some("n2")), Some(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(pool.allocate(Ipv4Addr::new(10, 0, 0, 1), "n2"), Err(AllocateError::IpReserved));
+    assert_eq!(pool.allocate(Ipv4Addr::new(10, 0, 0, 1), "n1"), Ok(()));
+}
+
+#[test]
+fn relay_source_test() {
+    use crate::common::acl::{ipv4_packet, TCP};
+
+    let node = Node {
+        name: String::from("n1"),
+        virtual_addr: Ipv4Addr::new(10, 0, 0, 2),
+        virtual_addr_v6: None,
+        lan_udp_addr: None,
+        wan_udp_addr: None,
+        mode: Default::default(),
+        allowed_ips: vec!["192.168.1.0/24".parse().unwrap()],
+        register_time: 0,
+        register_nonce: 0,
+        capabilities: 0,
+    };
+
+    assert!(GroupHandle::is_node_source(&node, &ipv4_packet([10, 0, 0, 2], [10, 0, 0, 3], TCP, 40000, 22)));
+    assert!(GroupHandle::is_node_source(&node, &ipv4_packet([192, 168, 1, 5], [10, 0, 0, 3], TCP, 40000, 22)));
+    // forged as the admin node
+    assert!(!GroupHandle::is_node_source(&node, &ipv4_packet([10, 0, 0, 1], [10, 0, 0, 3], TCP, 40000, 22)));
+}
@@ -0,0 +1,301 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use ahash::HashMap;
+use anyhow::Result;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use crate::common::net::{PushResult, TokenBucket};
+use crate::{QuotaPeriod, RelayQuotaFinalize};
+use crate::server::store_file::StoreFile;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// calendar period in UTC, (start, end) unix timestamp
+fn period_bounds(period: QuotaPeriod, now: DateTime<Utc>) -> (i64, i64) {
+    let today = now.date_naive();
+
+    let (start, end) = match period {
+        QuotaPeriod::Day => (today, today.succ_opt().unwrap()),
+        QuotaPeriod::Month => {
+            let start = today.with_day(1).unwrap();
+
+            let end = if today.month() == 12 {
+                NaiveDate::from_ymd_opt(today.year() + 1, 1, 1)
+            } else {
+                NaiveDate::from_ymd_opt(today.year(), today.month() + 1, 1)
+            };
+            (start, end.unwrap())
+        }
+    };
+
+    let ts = |date: NaiveDate| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+    (ts(start), ts(end))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct QuotaUsage {
+    period_start: i64,
+    used: u64,
+}
+
+// relay bytes of a node in the current period, both directions count
+pub struct QuotaState {
+    limit: u64,
+    period: QuotaPeriod,
+    period_start: AtomicI64,
+    period_end: AtomicI64,
+    used: AtomicU64,
+    exceeded_rate: Option<u64>,
+    exceeded: Option<TokenBucket>,
+    // the limit has been reached in the current period
+    over_limit: AtomicBool,
+    roll_lock: Mutex<()>,
+}
+
+impl QuotaState {
+    fn new(rule: &RelayQuotaFinalize, saved: Option<&QuotaUsage>) -> Self {
+        let (start, end) = period_bounds(rule.period, Utc::now());
+
+        let used = match saved {
+            Some(usage) if usage.period_start == start => usage.used,
+            _ => 0
+        };
+
+        QuotaState {
+            limit: rule.limit,
+            period: rule.period,
+            period_start: AtomicI64::new(start),
+            period_end: AtomicI64::new(end),
+            used: AtomicU64::new(used),
+            exceeded_rate: rule.exceeded_rate,
+            exceeded: rule.exceeded_rate.map(|rate| TokenBucket::new(rate, rate, None)),
+            over_limit: AtomicBool::new(false),
+            roll_lock: Mutex::new(()),
+        }
+    }
+
+    fn roll(&self, now: i64) {
+        if now < self.period_end.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let _guard = self.roll_lock.lock();
+
+        if now < self.period_end.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let (start, end) = period_bounds(self.period, Utc::now());
+        self.used.store(0, Ordering::Relaxed);
+        self.over_limit.store(false, Ordering::Relaxed);
+        self.period_start.store(start, Ordering::Relaxed);
+        self.period_end.store(end, Ordering::Relaxed);
+    }
+
+    // false means the packet is dropped, on_exceeded is called once per period when the limit is reached
+    pub fn consume(&self, len: u64, on_exceeded: impl FnOnce(u64, QuotaPeriod)) -> bool {
+        self.roll(Utc::now().timestamp());
+
+        let used = self.used.fetch_add(len, Ordering::Relaxed) + len;
+
+        if used > self.limit &&
+            !self.over_limit.load(Ordering::Relaxed) &&
+            !self.over_limit.swap(true, Ordering::Relaxed)
+        {
+            on_exceeded(self.limit, self.period);
+        }
+
+        let pass = used <= self.limit || match &self.exceeded {
+            None => false,
+            Some(bucket) => bucket.take(len) == PushResult::Accept
+        };
+
+        if !pass {
+            self.used.fetch_sub(len, Ordering::Relaxed);
+        }
+        pass
+    }
+
+    // give back the bytes of a packet dropped after consume, the period may have been reset in between
+    pub fn refund(&self, len: u64) {
+        let _ = self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| Some(used.saturating_sub(len)));
+    }
+
+    fn usage(&self) -> QuotaUsage {
+        QuotaUsage {
+            period_start: self.period_start.load(Ordering::Relaxed),
+            used: self.used.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuotaInfo {
+    pub node: String,
+    pub limit: u64,
+    pub used: u64,
+    pub period: QuotaPeriod,
+    // unix timestamp
+    pub period_start: i64,
+    pub period_end: i64,
+}
+
+// group name -> node name -> usage
+type Usages = HashMap<String, HashMap<String, QuotaUsage>>;
+
+pub struct QuotaStore {
+    file: StoreFile,
+    // loaded from the file, replaced by the live state once the node registers
+    saved: Mutex<Usages>,
+    // group name -> node name -> state, kept after the node goes offline
+    states: RwLock<HashMap<String, HashMap<String, Arc<QuotaState>>>>,
+}
+
+impl QuotaStore {
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let file = StoreFile::new(path, "quota");
+        let saved: Usages = file.load()?;
+
+        let store = QuotaStore {
+            file,
+            saved: Mutex::new(saved),
+            states: RwLock::new(HashMap::default()),
+        };
+        Ok(store)
+    }
+
+    // the usage is carried over if the rule has changed
+    pub fn get(&self, group: &str, node_name: &str, rule: &RelayQuotaFinalize) -> Arc<QuotaState> {
+        let mut guard = self.states.write();
+        let nodes = guard.entry(group.to_string()).or_default();
+
+        match nodes.get(node_name) {
+            Some(state) if state.limit == rule.limit && state.period == rule.period && state.exceeded_rate == rule.exceeded_rate => state.clone(),
+            old => {
+                let saved = old.map(|v| v.usage())
+                    .or_else(|| self.saved.lock().get(group)?.get(node_name).cloned());
+
+                let state = Arc::new(QuotaState::new(rule, saved.as_ref()));
+                nodes.insert(node_name.to_string(), state.clone());
+                state
+            }
+        }
+    }
+
+    pub fn usage(&self, group: &str) -> Vec<QuotaInfo> {
+        let guard = self.states.read();
+
+        let nodes = match guard.get(group) {
+            None => return Vec::new(),
+            Some(v) => v
+        };
+
+        nodes.iter()
+            .map(|(name, state)| {
+                // the period may have ended without traffic
+                state.roll(Utc::now().timestamp());
+
+                QuotaInfo {
+                    node: name.clone(),
+                    limit: state.limit,
+                    used: state.used.load(Ordering::Relaxed),
+                    period: state.period,
+                    period_start: state.period_start.load(Ordering::Relaxed),
+                    period_end: state.period_end.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    // reset the usage of the node, or every node of the group if node_name is None
+    pub fn reset(&self, group: &str, node_name: Option<&str>) -> usize {
+        let mut count = 0;
+
+        if let Some(nodes) = self.states.read().get(group) {
+            for (name, state) in nodes {
+                if node_name.is_none_or(|v| v == name) {
+                    state.used.store(0, Ordering::Relaxed);
+                    state.over_limit.store(false, Ordering::Relaxed);
+                    count += 1;
+                }
+            }
+        }
+
+        if let Some(nodes) = self.saved.lock().get_mut(group) {
+            nodes.retain(|name, _| node_name.is_some_and(|v| v != name));
+        }
+        count
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(|| serde_json::to_vec_pretty(&self.usages())).await
+    }
+
+    fn usages(&self) -> Usages {
+        let mut usages = self.saved.lock().clone();
+
+        for (group, nodes) in self.states.read().iter() {
+            let saved = usages.entry(group.clone()).or_default();
+
+            for (name, state) in nodes {
+                saved.insert(name.clone(), state.usage());
+            }
+        }
+        usages
+    }
+
+    pub async fn save_schedule(&self) {
+        loop {
+            tokio::time::sleep(SAVE_INTERVAL).await;
+
+            if let Err(e) = self.save().await {
+                warn!("save quota usage error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::QuotaTarget;
+
+    let rule = RelayQuotaFinalize {
+        target: QuotaTarget::Node(String::from("n1")),
+        limit: 1000,
+        period: QuotaPeriod::Day,
+        exceeded_rate: None,
+    };
+
+    let store = QuotaStore::load(None).unwrap();
+    let state = store.get("g", "n1", &rule);
+
+    let exceeded = std::cell::Cell::new(0);
+    let consume = |len| state.consume(len, |_, _| exceeded.set(exceeded.get() + 1));
+
+    assert!(consume(600));
+    assert!(!consume(600));
+    assert!(!consume(600));
+    assert!(consume(400));
+    assert_eq!(store.usage("g")[0].used, 1000);
+    assert_eq!(exceeded.get(), 1);
+
+    // dropped by flow control after consume
+    state.refund(400);
+    assert!(consume(400));
+
+    assert_eq!(store.reset("g", Some("n1")), 1);
+    assert!(consume(600));
+    assert!(!consume(600));
+    assert_eq!(exceeded.get(), 2);
+
+    // the usage survives a reconnect
+    assert_eq!(store.get("g", "n1", &rule).used.load(Ordering::Relaxed), 600);
+
+    let (start, end) = period_bounds(QuotaPeriod::Month, "2024-12-15T10:00:00Z".parse().unwrap());
+    assert_eq!(end - start, 31 * 86400);
+}
@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+use ahash::HashMap;
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::server::store_file::StoreFile;
+
+// group name -> node name -> revoked public key(hex)
+type Revoked = HashMap<String, HashMap<String, String>>;
+
+// identities revoked through the api, a node configured with a new public key can register again
+pub struct RevocationStore {
+    file: StoreFile,
+    revoked: Mutex<Revoked>,
+    // wakes the save task
+    changed: Notify,
+}
+
+impl RevocationStore {
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let file = StoreFile::new(path, "revocation");
+        let revoked = file.load()?;
+
+        let store = RevocationStore {
+            file,
+            revoked: Mutex::new(revoked),
+            changed: Notify::new(),
+        };
+        Ok(store)
+    }
+
+    pub fn insert(&self, group: &str, node_name: &str, public_key: &VerifyingKey) {
+        self.revoked.lock()
+            .entry(group.to_string())
+            .or_default()
+            .insert(node_name.to_string(), hex::encode(public_key.to_bytes()));
+
+        self.changed.notify_one();
+    }
+
+    // the configured identities without the revoked ones
+    pub fn filter(&self, group: &str, identities: &Option<HashMap<String, VerifyingKey>>) -> Option<HashMap<String, VerifyingKey>> {
+        let mut identities = identities.clone()?;
+
+        if let Some(revoked) = self.revoked.lock().get(group) {
+            identities.retain(|node_name, public_key| {
+                revoked.get(node_name) != Some(&hex::encode(public_key.to_bytes()))
+            });
+        }
+        Some(identities)
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(|| serde_json::to_vec_pretty(&*self.revoked.lock())).await
+    }
+
+    // write the revocations after each change
+    pub async fn save_schedule(&self) {
+        loop {
+            self.changed.notified().await;
+
+            if let Err(e) = self.save().await {
+                warn!("save revocations error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use ahash::HashMapExt;
+
+    let (_, k1) = crate::common::identity::generate();
+    let (_, k2) = crate::common::identity::generate();
+    let k1 = crate::common::identity::parse_verifying_key(&k1).unwrap();
+    let k2 = crate::common::identity::parse_verifying_key(&k2).unwrap();
+
+    let store = RevocationStore::load(None).unwrap();
+    store.insert("g", "n1", &k1);
+
+    let mut identities = HashMap::new();
+    identities.insert(String::from("n1"), k1);
+    identities.insert(String::from("n2"), k2);
+    let identities = Some(identities);
+
+    let filtered = store.filter("g", &identities).unwrap();
+    assert!(!filtered.contains_key("n1"));
+    assert!(filtered.contains_key("n2"));
+    assert_eq!(store.filter("g2", &identities).unwrap().len(), 2);
+
+    // a new public key is not revoked
+    let mut identities = identities.unwrap();
+    identities.insert(String::from("n1"), k2);
+    assert!(store.filter("g", &Some(identities)).unwrap().contains_key("n1"));
+    assert!(store.filter("g", &None).is_none());
+}
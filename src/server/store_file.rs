@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+
+// "name.json" -> "name.json.tmp", the files sharing a stem get different temporary files
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+// json file backing a store, None means the store is kept in memory only
+pub struct StoreFile {
+    path: Option<PathBuf>,
+    // used in the error messages
+    kind: &'static str,
+    // one write of the file at a time
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl StoreFile {
+    pub fn new(path: Option<PathBuf>, kind: &'static str) -> Self {
+        StoreFile {
+            path,
+            kind,
+            save_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // the default content if the file does not exist yet
+    pub fn load<T: DeserializeOwned + Default>(&self) -> Result<T> {
+        match &self.path {
+            Some(path) if path.exists() => {
+                let data = std::fs::read(path).with_context(|| format!("read {} file {} error", self.kind, path.display()))?;
+                serde_json::from_slice(&data).with_context(|| format!("parse {} file {} error", self.kind, path.display()))
+            }
+            _ => Ok(T::default()),
+        }
+    }
+
+    // the content is serialized after the previous write completes, the file is written off the runtime
+    pub async fn save(&self, serialize: impl FnOnce() -> serde_json::Result<Vec<u8>>) -> Result<()> {
+        let path = match &self.path {
+            None => return Ok(()),
+            Some(path) => path.clone()
+        };
+
+        let _guard = self.save_lock.lock().await;
+        let data = serialize()?;
+
+        tokio::task::spawn_blocking(move || {
+            // write to a temporary file first so the file is never truncated
+            let tmp = tmp_path(&path);
+            std::fs::write(&tmp, data)?;
+            std::fs::rename(&tmp, path)?;
+            Ok(())
+        }).await?
+    }
+}
+
+#[test]
+fn test() {
+    assert_eq!(tmp_path(Path::new("/var/lib/fubuki/bans.json")), Path::new("/var/lib/fubuki/bans.json.tmp"));
+    assert_ne!(tmp_path(Path::new("state.bans")), tmp_path(Path::new("state.leases")));
+}
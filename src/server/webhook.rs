@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http_body_util::Full;
+use hyper::{header, Method, Request, Response};
+use hyper::body::{Bytes, Incoming};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+use tokio_rustls::{rustls, TlsConnector};
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::common::api::crypto_provider;
+use crate::common::event::{Event, Record};
+use crate::{WebhookEvent, WebhookFinalize};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// deliveries waiting for a slow endpoint
+const QUEUE_SIZE: usize = 1024;
+
+fn webhook_event(event: &Event) -> Option<WebhookEvent> {
+    match event {
+        Event::NodeRegistered { .. } => Some(WebhookEvent::NodeRegistered),
+        Event::NodeDisconnected { .. } => Some(WebhookEvent::NodeDisconnected),
+        Event::RegisterFailed { reason: Some("nonce_repeat" | "timeout"), .. } => Some(WebhookEvent::RegisterFailed),
+        Event::QuotaExceeded { .. } => Some(WebhookEvent::QuotaExceeded),
+        Event::FlowControlRejected { .. } => Some(WebhookEvent::FlowControlRejected),
+        _ => None
+    }
+}
+
+fn tls_connector() -> Result<TlsConnector> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()
+    };
+
+    let config = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+async fn send<IO>(io: IO, req: Request<Full<Bytes>>) -> Result<Response<Incoming>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::spawn(conn);
+    Ok(sender.send_request(req).await?)
+}
+
+async fn post(webhook: &WebhookFinalize, tls: &TlsConnector, body: Bytes) -> Result<()> {
+    let url = &webhook.url;
+    let host = url.host().ok_or_else(|| anyhow!("missing host"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let https = url.scheme_str() == Some("https");
+    let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(url.path_and_query().map(|v| v.as_str()).unwrap_or("/"))
+        .header(header::HOST, url.authority().map(|v| v.as_str()).unwrap_or(host))
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, concat!("fubuki/", env!("CARGO_PKG_VERSION")));
+
+    if let Some(token) = &webhook.token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+
+    let req = req.body(Full::new(body))?;
+    let stream = TcpStream::connect((host, port)).await?;
+
+    let resp = if https {
+        let server_name = ServerName::try_from(host.to_string())?;
+        send(tls.connect(server_name, stream).await?, req).await?
+    } else {
+        send(stream, req).await?
+    };
+
+    if !resp.status().is_success() {
+        return Err(anyhow!("http response code: {}", resp.status().as_u16()));
+    }
+    Ok(())
+}
+
+// events of a webhook are delivered in order, a failed delivery blocks the later ones until it gives up
+async fn deliver(webhook: WebhookFinalize, tls: TlsConnector, mut rx: mpsc::Receiver<Bytes>) {
+    while let Some(body) = rx.recv().await {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            let res = match time::timeout(TIMEOUT, post(&webhook, &tls, body.clone())).await {
+                Ok(res) => res,
+                Err(_) => Err(anyhow!("request timeout")),
+            };
+
+            match res {
+                Ok(()) => break,
+                Err(e) if retries < webhook.max_retries => {
+                    warn!("webhook {} delivery error: {:?}, retry in {:?}", webhook.url, e, backoff);
+
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    retries += 1;
+                }
+                Err(e) => {
+                    error!("webhook {} delivery failed after {} retries: {:?}", webhook.url, retries, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub async fn webhook_schedule(
+    webhooks: Vec<WebhookFinalize>,
+    mut events: broadcast::Receiver<Arc<Record>>
+) -> Result<()> {
+    let tls = tls_connector()?;
+    let mut queues = Vec::with_capacity(webhooks.len());
+
+    for webhook in webhooks {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver(webhook.clone(), tls.clone(), rx));
+        queues.push((webhook, tx));
+    }
+
+    loop {
+        let record = match events.recv().await {
+            Ok(record) => record,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("webhook skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let kind = match webhook_event(&record.event) {
+            None => continue,
+            Some(kind) => kind
+        };
+
+        let body = Bytes::from(serde_json::to_vec(&*record)?);
+
+        for (webhook, tx) in &queues {
+            if webhook.events.contains(&kind) && tx.try_send(body.clone()).is_err() {
+                warn!("webhook {} queue is full, event dropped", webhook.url);
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::common::event::EventBus;
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let webhook = WebhookFinalize {
+            url: format!("http://{}/hook", listener.local_addr().unwrap()).parse().unwrap(),
+            token: Some(String::from("secret")),
+            events: vec![WebhookEvent::NodeRegistered, WebhookEvent::RegisterFailed],
+            max_retries: 3,
+        };
+
+        let bus = EventBus::new();
+        tokio::spawn(webhook_schedule(vec![webhook], bus.receiver()));
+
+        bus.publish(Some("g"), Event::NodeDisconnected { node: String::from("n0"), addr: "10.0.0.1".parse().unwrap() });
+        bus.publish(Some("g"), Event::RegisterFailed { node: None, reason: Some("banned"), error: String::from("Banned") });
+        bus.publish(Some("g"), Event::NodeRegistered { node: String::from("n1"), addr: "10.0.0.2".parse().unwrap() });
+
+        let mut requests = Vec::new();
+
+        // the first attempt fails and is retried
+        for status in ["500 Internal Server Error", "200 OK"] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut req = Vec::new();
+
+            while !req.ends_with(b"}") {
+                let mut buff = [0u8; 4096];
+                let n = stream.read(&mut buff).await.unwrap();
+                assert!(n > 0);
+                req.extend_from_slice(&buff[..n]);
+            }
+            requests.push(String::from_utf8(req).unwrap());
+
+            let resp = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            stream.write_all(resp.as_bytes()).await.unwrap();
+        }
+
+        for req in &requests {
+            assert!(req.starts_with("POST /hook HTTP/1.1"));
+            assert!(req.contains("authorization: Bearer secret"));
+            assert!(req.contains(r#""type":"node_registered","node":"n1""#));
+        }
+    });
+}
@@ -1,5 +1,5 @@
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::os::fd::RawFd;
 
 use anyhow::Result;
@@ -51,11 +51,11 @@ impl TunDevice for IOStun {
         Ok(())
     }
 
-    fn add_addr(&self, _addr: Ipv4Addr, _netmask: Ipv4Addr) -> Result<()> {
+    fn add_addr(&self, _addr: IpAddr, _netmask: IpAddr) -> Result<()> {
         Ok(())
     }
 
-    fn delete_addr(&self, _addr: Ipv4Addr, _netmask: Ipv4Addr) -> Result<()> {
+    fn delete_addr(&self, _addr: IpAddr, _netmask: IpAddr) -> Result<()> {
         Ok(())
     }
 
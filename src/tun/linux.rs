@@ -1,10 +1,10 @@
 use std::cell::UnsafeCell;
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
 use ahash::{HashSet, HashSetExt};
 use anyhow::{anyhow, Result};
-use ipnet::{IpNet, Ipv4Net};
+use ipnet::IpNet;
 use netconfig::Interface;
 use parking_lot::Mutex;
 use tun::Device;
@@ -12,7 +12,7 @@ use tun::Device;
 use crate::tun::TunDevice;
 
 pub struct Linuxtun {
-    ips: Mutex<HashSet<Ipv4Addr>>,
+    ips: Mutex<HashSet<IpAddr>>,
     fd: UnsafeCell<tun::AsyncDevice>,
     inter: Interface
 }
@@ -89,7 +89,7 @@ impl TunDevice for Linuxtun {
         Ok(())
     }
 
-    fn add_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    fn add_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
         let mut guard = self.ips.lock();
 
         if guard.contains(&addr) {
@@ -97,14 +97,14 @@ impl TunDevice for Linuxtun {
         }
 
         self.inter
-            .add_address(IpNet::V4(Ipv4Net::with_netmask(addr, netmask)?))
+            .add_address(IpNet::with_netmask(addr, netmask)?)
             .map_err(|e| anyhow!(e.to_string()))?;
 
         guard.insert(addr);
         Ok(())
     }
 
-    fn delete_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    fn delete_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
         let mut guard = self.ips.lock();
 
         if !guard.contains(&addr) {
@@ -112,7 +112,7 @@ impl TunDevice for Linuxtun {
         }
 
         self.inter
-            .remove_address(IpNet::V4(Ipv4Net::with_netmask(addr, netmask)?))
+            .remove_address(IpNet::with_netmask(addr, netmask)?)
             .map_err(|e| anyhow!(e.to_string()))?;
 
         guard.remove(&addr);
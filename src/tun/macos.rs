@@ -1,11 +1,11 @@
 use std::cell::UnsafeCell;
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::process::{Command, Stdio};
 
 use ahash::{HashSet, HashSetExt};
 use anyhow::{anyhow, Result};
-use ipnet::{IpNet, Ipv4Net};
+use ipnet::IpNet;
 use netconfig::Interface;
 use parking_lot::Mutex;
 use tun::Device;
@@ -14,7 +14,7 @@ use crate::common::allocator::alloc;
 use crate::tun::TunDevice;
 
 pub struct Macostun {
-    ips: Mutex<HashSet<Ipv4Addr>>,
+    ips: Mutex<HashSet<IpAddr>>,
     fd: UnsafeCell<tun::AsyncDevice>,
     inter: Interface,
 }
@@ -70,8 +70,8 @@ impl TunDevice for Macostun {
         Ok(())
     }
 
-    fn add_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> anyhow::Result<()> {
-        let cidr = Ipv4Net::with_netmask(addr, netmask)?;
+    fn add_addr(&self, addr: IpAddr, netmask: IpAddr) -> anyhow::Result<()> {
+        let cidr = IpNet::with_netmask(addr, netmask)?;
         let mut guard = self.ips.lock();
 
         if guard.contains(&addr) {
@@ -79,19 +79,12 @@ impl TunDevice for Macostun {
         }
 
         self.inter
-            .add_address(IpNet::V4(cidr))
+            .add_address(cidr)
             .map_err(|e| anyhow!(e.to_string()))?;
 
         let status = Command::new("route")
-            .args([
-                "-n",
-                "add",
-                "-net",
-                cidr.network().to_string().as_str(),
-                "-netmask",
-                netmask.to_string().as_str(),
-                addr.to_string().as_str(),
-            ])
+            .args(["-n", "add"])
+            .args(route_args(cidr))
             .stderr(Stdio::inherit())
             .output()?
             .status;
@@ -104,8 +97,8 @@ impl TunDevice for Macostun {
         Ok(())
     }
 
-    fn delete_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> anyhow::Result<()> {
-        let cidr = Ipv4Net::with_netmask(addr, netmask)?;
+    fn delete_addr(&self, addr: IpAddr, netmask: IpAddr) -> anyhow::Result<()> {
+        let cidr = IpNet::with_netmask(addr, netmask)?;
         let mut guard = self.ips.lock();
 
         if !guard.contains(&addr) {
@@ -113,19 +106,12 @@ impl TunDevice for Macostun {
         }
 
         self.inter
-            .remove_address(IpNet::V4(cidr))
+            .remove_address(cidr)
             .map_err(|e| anyhow!(e.to_string()))?;
 
         let status = Command::new("route")
-            .args([
-                "-n",
-                "delete",
-                "-net",
-                cidr.network().to_string().as_str(),
-                "-netmask",
-                netmask.to_string().as_str(),
-                addr.to_string().as_str(),
-            ])
+            .args(["-n", "delete"])
+            .args(route_args(cidr))
             .stderr(Stdio::inherit())
             .output()?
             .status;
@@ -141,4 +127,25 @@ impl TunDevice for Macostun {
     fn get_index(&self) -> u32 {
         self.inter.index().expect("can't get interface index")
     }
-}
\ No newline at end of file
+}
+
+// route the address range to the tun address
+fn route_args(cidr: IpNet) -> Vec<String> {
+    match cidr {
+        IpNet::V4(cidr) => vec![
+            String::from("-net"),
+            cidr.network().to_string(),
+            String::from("-netmask"),
+            cidr.netmask().to_string(),
+            cidr.addr().to_string(),
+        ],
+        IpNet::V6(cidr) => vec![
+            String::from("-inet6"),
+            String::from("-net"),
+            cidr.network().to_string(),
+            String::from("-prefixlen"),
+            cidr.prefix_len().to_string(),
+            cidr.addr().to_string(),
+        ]
+    }
+}
@@ -1,5 +1,5 @@
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -28,9 +28,10 @@ pub trait TunDevice {
 
     fn set_mtu(&self, mtu: usize) -> Result<()>;
 
-    fn add_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()>;
+    // addr and netmask are of the same ip version
+    fn add_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()>;
 
-    fn delete_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()>;
+    fn delete_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()>;
 
     fn get_index(&self) -> u32;
 }
@@ -51,11 +52,11 @@ impl<T: TunDevice> TunDevice for Arc<T> {
         (**self).set_mtu(mtu)
     }
 
-    fn add_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    fn add_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
         (**self).add_addr(addr, netmask)
     }
 
-    fn delete_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    fn delete_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
         (**self).delete_addr(addr, netmask)
     }
 
@@ -1,11 +1,11 @@
 use ahash::{HashSet, HashSetExt};
 use anyhow::{anyhow, Result};
-use ipnet::{IpNet, Ipv4Net};
+use ipnet::IpNet;
 use netconfig::sys::InterfaceExt;
 use netconfig::Interface;
 use parking_lot::Mutex;
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::process::{Command, Stdio};
 use std::time::Duration;
 
@@ -21,7 +21,7 @@ const ADAPTER_GUID: &str = "{248B1B2B-94FA-0E20-150F-5C2D2FB4FBF9}";
 const ADAPTER_BUFF_SIZE: u32 = 0x100000;
 
 pub struct Wintun {
-    ips: Mutex<HashSet<Ipv4Addr>>,
+    ips: Mutex<HashSet<IpAddr>>,
     inter: Interface,
     session: WintunStream<'static>,
     _adapter: WintunAdapter,
@@ -95,7 +95,7 @@ impl TunDevice for Wintun {
         Ok(())
     }
 
-    fn add_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    fn add_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
         let mut guard = self.ips.lock();
 
         if guard.contains(&addr) {
@@ -103,14 +103,14 @@ impl TunDevice for Wintun {
         }
 
         self.inter
-            .add_address(IpNet::V4(Ipv4Net::with_netmask(addr, netmask)?))
+            .add_address(IpNet::with_netmask(addr, netmask)?)
             .map_err(|e| anyhow!(e.to_string()))?;
 
         guard.insert(addr);
         Ok(())
     }
 
-    fn delete_addr(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    fn delete_addr(&self, addr: IpAddr, netmask: IpAddr) -> Result<()> {
         let mut guard = self.ips.lock();
 
         if !guard.contains(&addr) {
@@ -118,7 +118,7 @@ impl TunDevice for Wintun {
         }
 
         self.inter
-            .remove_address(IpNet::V4(Ipv4Net::with_netmask(addr, netmask)?))
+            .remove_address(IpNet::with_netmask(addr, netmask)?)
             .map_err(|e| anyhow!(e.to_string()))?;
 
         guard.remove(&addr);